@@ -0,0 +1,175 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
+
+pub const COIN: u64 = 100_000_000;
+
+// A number of satoshis. Arithmetic panics on overflow like the integer
+// types do; use the checked versions for untrusted values.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const ONE_BTC: Amount = Amount(COIN);
+    pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+    pub fn from_sat(sat: u64) -> Self {
+        Self(sat)
+    }
+
+    pub fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    // Parses a decimal BTC value with up to 8 decimal places
+    pub fn from_btc(s: &str) -> Result<Self> {
+        let (whole, fraction) = match s.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (s, ""),
+        };
+        let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if !digits(whole) || (s.contains('.') && !digits(fraction)) || fraction.len() > 8 {
+            return Err(anyhow!("Invalid BTC amount {}", s));
+        }
+        let fraction = format!("{:0<8}", fraction);
+        whole
+            .parse::<u64>()
+            .ok()
+            .and_then(|whole| whole.checked_mul(COIN))
+            .and_then(|sat| sat.checked_add(fraction.parse::<u64>().unwrap()))
+            .map(Self)
+            .ok_or_else(|| anyhow!("BTC amount {} is too large", s))
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        Self(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Amount) -> Amount {
+        Self(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, other: Amount) -> Amount {
+        Self(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Amount) {
+        self.0 -= other.0;
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I>(iter: I) -> Self
+    where
+        I: Iterator<Item = Amount>,
+    {
+        iter.fold(Amount::ZERO, Add::add)
+    }
+}
+
+// In BTC with all 8 decimal places
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:08} BTC", self.0 / COIN, self.0 % COIN)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Amount::from_btc(s.strip_suffix(" BTC").unwrap_or(s))
+    }
+}
+
+// Satoshis per 1000 virtual bytes, as Bitcoin Core's relay settings are given
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub fn from_sat_per_kvb(sat: u64) -> Self {
+        Self(sat)
+    }
+
+    pub fn from_sat_per_vb(sat: u64) -> Self {
+        Self(sat * 1000)
+    }
+
+    pub fn to_sat_per_kvb(self) -> u64 {
+        self.0
+    }
+
+    // The fee for `weight` weight units, rounded up
+    pub fn fee(self, weight: usize) -> Amount {
+        Amount((self.0 * weight as u64).div_ceil(4000))
+    }
+
+    pub fn fee_vsize(self, vsize: usize) -> Amount {
+        self.fee(vsize * 4)
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:03} sat/vB", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+#[test]
+fn test_amount() {
+    let amount = Amount::from_sat(123_456_789);
+    assert_eq!(amount.to_string(), "1.23456789 BTC");
+    assert_eq!("1.23456789 BTC".parse::<Amount>().unwrap(), amount);
+    assert_eq!(
+        Amount::from_btc("0.5").unwrap(),
+        Amount::from_sat(50_000_000)
+    );
+    assert_eq!(Amount::from_btc("21000000").unwrap(), Amount::MAX_MONEY);
+    assert_eq!(Amount::from_sat(1).to_string(), "0.00000001 BTC");
+    for bad in ["", ".", "1.", "-1", "0.123456789", "1e3", "184467440738"].iter() {
+        assert!(Amount::from_btc(bad).is_err(), "{}", bad);
+    }
+
+    let total: Amount = [1, 2, 3].iter().map(|sat| Amount::from_sat(*sat)).sum();
+    assert_eq!(total, Amount::from_sat(6));
+    assert_eq!(total - Amount::from_sat(1), Amount::from_sat(5));
+    assert_eq!(Amount::from_sat(1).checked_sub(total), None);
+    assert_eq!(Amount::from_sat(1).saturating_sub(total), Amount::ZERO);
+    assert_eq!(Amount::from_sat(u64::MAX).checked_add(total), None);
+}
+
+#[test]
+fn test_fee_rate() {
+    let rate = FeeRate::from_sat_per_vb(2);
+    assert_eq!(rate.fee(272), Amount::from_sat(136));
+    assert_eq!(rate.fee_vsize(110), Amount::from_sat(220));
+    assert_eq!(FeeRate::from_sat_per_kvb(1001).fee(4), Amount::from_sat(2));
+    assert_eq!(FeeRate::from_sat_per_kvb(1500).to_string(), "1.500 sat/vB");
+}
@@ -0,0 +1,614 @@
+use anyhow::{anyhow, Result};
+use num_bigint::{BigInt, Sign};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::helper::{decode_base58_checksum, encode_base58_checksum, hash160, hmac_sha512};
+use crate::network::Network;
+use crate::point::Point;
+use crate::s256::{PrivateKey, S256Point, G, N};
+
+pub const HARDENED: u32 = 0x80000000;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ChildNumber(pub u32);
+
+impl ChildNumber {
+    pub fn normal(index: u32) -> Result<Self> {
+        if index >= HARDENED {
+            return Err(anyhow!("Child index {} is out of range", index));
+        }
+        Ok(ChildNumber(index))
+    }
+
+    pub fn hardened(index: u32) -> Result<Self> {
+        if index >= HARDENED {
+            return Err(anyhow!("Child index {} is out of range", index));
+        }
+        Ok(ChildNumber(index | HARDENED))
+    }
+
+    pub fn is_hardened(&self) -> bool {
+        self.0 & HARDENED != 0
+    }
+
+    pub fn index(&self) -> u32 {
+        self.0 & !HARDENED
+    }
+}
+
+impl fmt::Display for ChildNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_hardened() {
+            write!(f, "{}'", self.index())
+        } else {
+            write!(f, "{}", self.index())
+        }
+    }
+}
+
+impl FromStr for ChildNumber {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hardened = s.ends_with('\'') || s.ends_with('h') || s.ends_with('H');
+        let digits = if hardened { &s[..s.len() - 1] } else { s };
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(anyhow!("Invalid child number {}", s));
+        }
+        let index: u32 = digits.parse()?;
+        if hardened {
+            ChildNumber::hardened(index)
+        } else {
+            ChildNumber::normal(index)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
+pub struct DerivationPath(pub Vec<ChildNumber>);
+
+impl DerivationPath {
+    pub fn master() -> Self {
+        DerivationPath(vec![])
+    }
+
+    pub fn child(&self, child: ChildNumber) -> Self {
+        let mut path = self.0.clone();
+        path.push(child);
+        DerivationPath(path)
+    }
+
+    pub fn extend(&self, other: &DerivationPath) -> Self {
+        let mut path = self.0.clone();
+        path.extend_from_slice(&other.0);
+        DerivationPath(path)
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for child in &self.0 {
+            write!(f, "/{}", child)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(anyhow!("Derivation path {} must start with m", s));
+        }
+        parts
+            .map(ChildNumber::from_str)
+            .collect::<Result<Vec<_>>>()
+            .map(DerivationPath)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub struct Fingerprint(pub [u8; 4]);
+
+impl Fingerprint {
+    pub fn from_point(point: &S256Point) -> Self {
+        let mut result = [0u8; 4];
+        result.copy_from_slice(&point.hash160(true)[..4]);
+        Fingerprint(result)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+fn split_hmac(key: &[u8], data: &[u8]) -> (BigInt, [u8; 32]) {
+    let i = hmac_sha512(key, data);
+    let il = BigInt::from_bytes_be(Sign::Plus, &i[..32]);
+    let mut chain_code = [0u8; 32];
+    chain_code.copy_from_slice(&i[32..]);
+    (il, chain_code)
+}
+
+// The fields shared by every serialized extended key, in wire order.
+fn serialize_extended(
+    version: [u8; 4],
+    depth: u8,
+    parent_fingerprint: &Fingerprint,
+    child_number: &ChildNumber,
+    chain_code: &[u8; 32],
+    key: &[u8],
+) -> Vec<u8> {
+    let mut result = version.to_vec();
+    result.push(depth);
+    result.append(&mut parent_fingerprint.0.to_vec());
+    result.append(&mut child_number.0.to_be_bytes().to_vec());
+    result.append(&mut chain_code.to_vec());
+    result.append(&mut key.to_vec());
+    result
+}
+
+struct RawExtendedKey {
+    depth: u8,
+    parent_fingerprint: Fingerprint,
+    child_number: ChildNumber,
+    chain_code: [u8; 32],
+    key: [u8; 33],
+}
+
+impl RawExtendedKey {
+    fn parse(b: &[u8]) -> Result<Self> {
+        if b.len() != 78 {
+            return Err(anyhow!("Extended key must be 78 bytes, got {}", b.len()));
+        }
+        let mut parent_fingerprint = [0u8; 4];
+        parent_fingerprint.copy_from_slice(&b[5..9]);
+        let mut child_number = [0u8; 4];
+        child_number.copy_from_slice(&b[9..13]);
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&b[13..45]);
+        let mut key = [0u8; 33];
+        key.copy_from_slice(&b[45..78]);
+
+        let depth = b[4];
+        let parent_fingerprint = Fingerprint(parent_fingerprint);
+        let child_number = ChildNumber(u32::from_be_bytes(child_number));
+        if depth == 0 && (parent_fingerprint != Fingerprint::default() || child_number.0 != 0) {
+            return Err(anyhow!("Master key with non-zero parent or index"));
+        }
+        Ok(Self {
+            depth,
+            parent_fingerprint,
+            child_number,
+            chain_code,
+            key,
+        })
+    }
+}
+
+fn network_from_version(version: [u8; 4], private: bool) -> Result<Network> {
+    for network in &[Network::Mainnet, Network::Testnet3] {
+        let expected = if private {
+            network.xprv_version()
+        } else {
+            network.xpub_version()
+        };
+        if expected == version {
+            return Ok(*network);
+        }
+    }
+    Err(anyhow!("Unknown extended key version {:02x?}", version))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExtendedPrivKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: Fingerprint,
+    pub child_number: ChildNumber,
+    pub chain_code: [u8; 32],
+    pub private_key: PrivateKey<'static>,
+}
+
+impl ExtendedPrivKey {
+    pub fn new_master(seed: &[u8], network: Network) -> Result<Self> {
+        let (secret, chain_code) = split_hmac(b"Bitcoin seed", seed);
+        if secret == 0.into() || secret >= *N {
+            return Err(anyhow!("Seed produces an invalid master key"));
+        }
+        Ok(Self {
+            network,
+            depth: 0,
+            parent_fingerprint: Fingerprint::default(),
+            child_number: ChildNumber(0),
+            chain_code,
+            private_key: PrivateKey::new(secret)?,
+        })
+    }
+
+    pub fn derive_child(&self, child: ChildNumber) -> Result<Self> {
+        let mut data = if child.is_hardened() {
+            let mut data = vec![0x00];
+            data.extend_from_slice(self.private_key.secret_bytes());
+            data
+        } else {
+            self.private_key.point.sec(true)
+        };
+        data.append(&mut child.0.to_be_bytes().to_vec());
+
+        let (il, chain_code) = split_hmac(&self.chain_code, &data);
+        if il >= *N {
+            return Err(anyhow!("Child {} produces an invalid key", child));
+        }
+        let secret =
+            (il + BigInt::from_bytes_be(Sign::Plus, self.private_key.secret_bytes())) % &*N;
+        if secret == 0.into() {
+            return Err(anyhow!("Child {} produces an invalid key", child));
+        }
+        Ok(Self {
+            network: self.network,
+            depth: self
+                .depth
+                .checked_add(1)
+                .ok_or_else(|| anyhow!("Maximum derivation depth exceeded"))?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: child,
+            chain_code,
+            private_key: PrivateKey::new(secret)?,
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self> {
+        let mut key = self.clone();
+        for child in &path.0 {
+            key = key.derive_child(*child)?;
+        }
+        Ok(key)
+    }
+
+    pub fn to_pub(&self) -> ExtendedPubKey {
+        ExtendedPubKey {
+            network: self.network,
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            public_key: self.private_key.point.clone(),
+        }
+    }
+
+    pub fn identifier(&self) -> Vec<u8> {
+        hash160(&self.private_key.point.sec(true))
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::from_point(&self.private_key.point)
+    }
+
+    pub fn serialize_with_version(&self, version: [u8; 4]) -> Vec<u8> {
+        let mut key = vec![0x00];
+        key.extend_from_slice(self.private_key.secret_bytes());
+        serialize_extended(
+            version,
+            self.depth,
+            &self.parent_fingerprint,
+            &self.child_number,
+            &self.chain_code,
+            &key,
+        )
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with_version(self.network.xprv_version())
+    }
+
+    pub fn parse_with_network(b: &[u8], network: Network) -> Result<Self> {
+        let raw = RawExtendedKey::parse(b)?;
+        if raw.key[0] != 0x00 {
+            return Err(anyhow!("Extended private key must start with 0x00"));
+        }
+        let secret = BigInt::from_bytes_be(Sign::Plus, &raw.key[1..]);
+        if secret == 0.into() || secret >= *N {
+            return Err(anyhow!("Extended private key out of range"));
+        }
+        Ok(Self {
+            network,
+            depth: raw.depth,
+            parent_fingerprint: raw.parent_fingerprint,
+            child_number: raw.child_number,
+            chain_code: raw.chain_code,
+            private_key: PrivateKey::new(secret)?,
+        })
+    }
+
+    pub fn parse(b: &[u8]) -> Result<Self> {
+        if b.len() < 4 {
+            return Err(anyhow!("Extended key is too short"));
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&b[..4]);
+        Self::parse_with_network(b, network_from_version(version, true)?)
+    }
+}
+
+impl fmt::Display for ExtendedPrivKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", encode_base58_checksum(&self.serialize()))
+    }
+}
+
+impl FromStr for ExtendedPrivKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(&decode_base58_checksum(s)?)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExtendedPubKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: Fingerprint,
+    pub child_number: ChildNumber,
+    pub chain_code: [u8; 32],
+    pub public_key: S256Point<'static>,
+}
+
+impl ExtendedPubKey {
+    pub fn derive_child(&self, child: ChildNumber) -> Result<Self> {
+        if child.is_hardened() {
+            return Err(anyhow!(
+                "Cannot derive hardened child {} from a public key",
+                child
+            ));
+        }
+        let mut data = self.public_key.sec(true);
+        data.append(&mut child.0.to_be_bytes().to_vec());
+
+        let (il, chain_code) = split_hmac(&self.chain_code, &data);
+        if il >= *N {
+            return Err(anyhow!("Child {} produces an invalid key", child));
+        }
+        let public_key: S256Point = (&*(il * G.clone())? + &*self.public_key)?.into();
+        if public_key.cp.p == Point::Inf {
+            return Err(anyhow!("Child {} produces an invalid key", child));
+        }
+        Ok(Self {
+            network: self.network,
+            depth: self
+                .depth
+                .checked_add(1)
+                .ok_or_else(|| anyhow!("Maximum derivation depth exceeded"))?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: child,
+            chain_code,
+            public_key,
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self> {
+        let mut key = self.clone();
+        for child in &path.0 {
+            key = key.derive_child(*child)?;
+        }
+        Ok(key)
+    }
+
+    pub fn identifier(&self) -> Vec<u8> {
+        hash160(&self.public_key.sec(true))
+    }
+
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::from_point(&self.public_key)
+    }
+
+    pub fn serialize_with_version(&self, version: [u8; 4]) -> Vec<u8> {
+        serialize_extended(
+            version,
+            self.depth,
+            &self.parent_fingerprint,
+            &self.child_number,
+            &self.chain_code,
+            &self.public_key.sec(true),
+        )
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with_version(self.network.xpub_version())
+    }
+
+    pub fn parse_with_network(b: &[u8], network: Network) -> Result<Self> {
+        let raw = RawExtendedKey::parse(b)?;
+        if raw.key[0] != 0x02 && raw.key[0] != 0x03 {
+            return Err(anyhow!("Extended public key must be a compressed point"));
+        }
+        Ok(Self {
+            network,
+            depth: raw.depth,
+            parent_fingerprint: raw.parent_fingerprint,
+            child_number: raw.child_number,
+            chain_code: raw.chain_code,
+            public_key: S256Point::parse(&raw.key)?,
+        })
+    }
+
+    pub fn parse(b: &[u8]) -> Result<Self> {
+        if b.len() < 4 {
+            return Err(anyhow!("Extended key is too short"));
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&b[..4]);
+        Self::parse_with_network(b, network_from_version(version, false)?)
+    }
+}
+
+impl fmt::Display for ExtendedPubKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", encode_base58_checksum(&self.serialize()))
+    }
+}
+
+impl FromStr for ExtendedPubKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(&decode_base58_checksum(s)?)
+    }
+}
+
+#[cfg(test)]
+fn check_path(seed: &ExtendedPrivKey, path: &str, xprv: &str, xpub: &str) {
+    let path: DerivationPath = path.parse().unwrap();
+    let key = seed.derive_path(&path).unwrap();
+    assert_eq!(key.to_string(), xprv);
+    assert_eq!(key.to_pub().to_string(), xpub);
+    assert_eq!(xprv.parse::<ExtendedPrivKey>().unwrap(), key);
+    assert_eq!(xpub.parse::<ExtendedPubKey>().unwrap(), key.to_pub());
+
+    if let Some(last) = path.0.last() {
+        if !last.is_hardened() {
+            let parent = seed
+                .derive_path(&DerivationPath(path.0[..path.0.len() - 1].to_vec()))
+                .unwrap();
+            assert_eq!(
+                parent.to_pub().derive_child(*last).unwrap().to_string(),
+                xpub
+            );
+        }
+    }
+}
+
+#[test]
+fn test_vector_1() {
+    use crate::helper::decode_hex;
+
+    let seed = decode_hex("000102030405060708090a0b0c0d0e0f").unwrap();
+    let master = ExtendedPrivKey::new_master(&seed, Network::Mainnet).unwrap();
+
+    check_path(&master, "m",
+        "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi",
+        "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8");
+    check_path(&master, "m/0h",
+        "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT11eZG7XnxHrnYeSvkzY7d2bhkJ7",
+        "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw");
+    check_path(&master, "m/0h/1",
+        "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg8MSY3H2EU4pWcQDnRnrVA1xe8fs",
+        "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ");
+    check_path(&master, "m/0h/1/2h",
+        "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewRiNMjANTtpgP4mLTj34bhnZX7UiM",
+        "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5");
+    check_path(&master, "m/0h/1/2h/2",
+        "xprvA2JDeKCSNNZky6uBCviVfJSKyQ1mDYahRjijr5idH2WwLsEd4Hsb2Tyh8RfQMuPh7f7RtyzTtdrbdqqsunu5Mm3wDvUAKRHSC34sJ7in334",
+        "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV");
+    check_path(&master, "m/0h/1/2h/2/1000000000",
+        "xprvA41z7zogVVwxVSgdKUHDy1SKmdb533PjDz7J6N6mV6uS3ze1ai8FHa8kmHScGpWmj4WggLyQjgPie1rFSruoUihUZREPSL39UNdE3BBDu76",
+        "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy");
+}
+
+#[test]
+fn test_vector_2() {
+    use crate::helper::decode_hex;
+
+    let seed = decode_hex("fffcf9f6f3f0edeae7e4e1dedbd8d5d2cfccc9c6c3c0bdbab7b4b1aeaba8a5a29f9c999693908d8a8784817e7b7875726f6c696663605d5a5754514e4b484542").unwrap();
+    let master = ExtendedPrivKey::new_master(&seed, Network::Mainnet).unwrap();
+
+    check_path(&master, "m",
+        "xprv9s21ZrQH143K31xYSDQpPDxsXRTUcvj2iNHm5NUtrGiGG5e2DtALGdso3pGz6ssrdK4PFmM8NSpSBHNqPqm55Qn3LqFtT2emdEXVYsCzC2U",
+        "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB");
+    check_path(&master, "m/0",
+        "xprv9vHkqa6EV4sPZHYqZznhT2NPtPCjKuDKGY38FBWLvgaDx45zo9WQRUT3dKYnjwih2yJD9mkrocEZXo1ex8G81dwSM1fwqWpWkeS3v86pgKt",
+        "xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH");
+    check_path(&master, "m/0/2147483647h",
+        "xprv9wSp6B7kry3Vj9m1zSnLvN3xH8RdsPP1Mh7fAaR7aRLcQMKTR2vidYEeEg2mUCTAwCd6vnxVrcjfy2kRgVsFawNzmjuHc2YmYRmagcEPdU9",
+        "xpub6ASAVgeehLbnwdqV6UKMHVzgqAG8Gr6riv3Fxxpj8ksbH9ebxaEyBLZ85ySDhKiLDBrQSARLq1uNRts8RuJiHjaDMBU4Zn9h8LZNnBC5y4a");
+    check_path(&master, "m/0/2147483647h/1",
+        "xprv9zFnWC6h2cLgpmSA46vutJzBcfJ8yaJGg8cX1e5StJh45BBciYTRXSd25UEPVuesF9yog62tGAQtHjXajPPdbRCHuWS6T8XA2ECKADdw4Ef",
+        "xpub6DF8uhdarytz3FWdA8TvFSvvAh8dP3283MY7p2V4SeE2wyWmG5mg5EwVvmdMVCQcoNJxGoWaU9DCWh89LojfZ537wTfunKau47EL2dhHKon");
+    check_path(&master, "m/0/2147483647h/1/2147483646h",
+        "xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc",
+        "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL");
+    check_path(&master, "m/0/2147483647h/1/2147483646h/2",
+        "xprvA2nrNbFZABcdryreWet9Ea4LvTJcGsqrMzxHx98MMrotbir7yrKCEXw7nadnHM8Dq38EGfSh6dqA9QWTyefMLEcBYJUuekgW4BYPJcr9E7j",
+        "xpub6FnCn6nSzZAw5Tw7cgR9bi15UV96gLZhjDstkXXxvCLsUXBGXPdSnLFbdpq8p9HmGsApME5hQTZ3emM2rnY5agb9rXpVGyy3bdW6EEgAtqt");
+}
+
+#[test]
+fn test_vector_3() {
+    use crate::helper::decode_hex;
+
+    // leading zeros in the private key must be retained
+    let seed = decode_hex("4b381541583be4423346c643850da4b320e46a87ae3d2a4e6da11eba819cd4acba45d239319ac14f863b8d5ab5a0d0c64d2e8a1e7d1457df2e5a3c51c73235be").unwrap();
+    let master = ExtendedPrivKey::new_master(&seed, Network::Mainnet).unwrap();
+
+    check_path(&master, "m",
+        "xprv9s21ZrQH143K25QhxbucbDDuQ4naNntJRi4KUfWT7xo4EKsHt2QJDu7KXp1A3u7Bi1j8ph3EGsZ9Xvz9dGuVrtHHs7pXeTzjuxBrCmmhgC6",
+        "xpub661MyMwAqRbcEZVB4dScxMAdx6d4nFc9nvyvH3v4gJL378CSRZiYmhRoP7mBy6gSPSCYk6SzXPTf3ND1cZAceL7SfJ1Z3GC8vBgp2epUt13");
+    check_path(&master, "m/0h",
+        "xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L",
+        "xpub68NZiKmJWnxxS6aaHmn81bvJeTESw724CRDs6HbuccFQN9Ku14VQrADWgqbhhTHBaohPX4CjNLf9fq9MYo6oDaPPLPxSb7gwQN3ih19Zm4Y");
+}
+
+#[test]
+fn test_derivation_path() {
+    let path: DerivationPath = "m/84'/0'/0'/0/5".parse().unwrap();
+    assert_eq!(
+        path.0,
+        vec![
+            ChildNumber(84 | HARDENED),
+            ChildNumber(HARDENED),
+            ChildNumber(HARDENED),
+            ChildNumber(0),
+            ChildNumber(5),
+        ]
+    );
+    assert_eq!(path.to_string(), "m/84'/0'/0'/0/5".to_string());
+    assert_eq!(
+        "m".parse::<DerivationPath>().unwrap(),
+        DerivationPath::master()
+    );
+
+    assert!("84'/0'".parse::<DerivationPath>().is_err());
+    assert!("m/x".parse::<DerivationPath>().is_err());
+    assert!("m/2147483648".parse::<DerivationPath>().is_err());
+    assert!("m//1".parse::<DerivationPath>().is_err());
+}
+
+#[test]
+fn test_invalid_extended_keys() {
+    let xprv: ExtendedPrivKey = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi".parse().unwrap();
+    let xpub = xprv.to_pub();
+    assert!(xpub.derive_child(ChildNumber(HARDENED)).is_err());
+
+    // a private key behind a public version
+    let b = xprv.serialize_with_version(Network::Mainnet.xpub_version());
+    assert!(ExtendedPubKey::parse(&b).is_err());
+    // a public key behind a private version
+    let b = xpub.serialize_with_version(Network::Mainnet.xprv_version());
+    assert!(ExtendedPrivKey::parse(&b).is_err());
+    // unknown version
+    let b = xpub.serialize_with_version([0, 0, 0, 0]);
+    assert!(ExtendedPubKey::parse(&b).is_err());
+    // master key with a parent fingerprint
+    let mut b = xpub.serialize();
+    b[5] = 0x01;
+    assert!(ExtendedPubKey::parse(&b).is_err());
+    // bad checksum
+    assert!("xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet9".parse::<ExtendedPubKey>().is_err());
+
+    let tpub = ExtendedPubKey {
+        network: Network::Testnet3,
+        ..xpub
+    };
+    assert!(tpub.to_string().starts_with("tpub"));
+    assert_eq!(tpub.to_string().parse::<ExtendedPubKey>().unwrap(), tpub);
+}
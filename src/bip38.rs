@@ -0,0 +1,319 @@
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use anyhow::{anyhow, Result};
+use num_bigint::{BigInt, Sign};
+use rand::RngCore;
+use unicode_normalization::UnicodeNormalization;
+use zeroize::Zeroizing;
+
+use crate::helper::{decode_base58_checksum, encode_base58_checksum, hash256};
+use crate::network::Network;
+use crate::s256::{to_32_bytes, PrivateKey, S256Point, G, N};
+
+const NON_EC_PREFIX: [u8; 2] = [0x01, 0x42];
+const EC_PREFIX: [u8; 2] = [0x01, 0x43];
+const MAGIC: [u8; 7] = [0x2c, 0xe9, 0xb3, 0xe1, 0xff, 0x39, 0xe2];
+const MAGIC_NO_LOT: u8 = 0x51;
+const MAGIC_LOT: u8 = 0x53;
+
+const FLAG_NON_EC: u8 = 0xc0;
+const FLAG_COMPRESSED: u8 = 0x20;
+const FLAG_LOT: u8 = 0x04;
+
+// The address hash is always over the mainnet P2PKH address
+fn address_hash(point: &S256Point, compressed: bool) -> Vec<u8> {
+    hash256(point.address(compressed, Network::Mainnet).as_bytes())[..4].to_vec()
+}
+
+fn scrypt(
+    password: &[u8],
+    salt: &[u8],
+    log_n: u8,
+    r: u32,
+    p: u32,
+    len: usize,
+) -> Zeroizing<Vec<u8>> {
+    let params = scrypt::Params::new(log_n, r, p).unwrap();
+    let mut result = Zeroizing::new(vec![0u8; len]);
+    scrypt::scrypt(password, salt, &params, &mut result).unwrap();
+    result
+}
+
+// Passphrases are NFC normalized before hashing
+fn passphrase_scrypt(passphrase: &str, salt: &[u8], len: usize) -> Zeroizing<Vec<u8>> {
+    let normalized = Zeroizing::new(passphrase.nfc().collect::<String>());
+    scrypt(normalized.as_bytes(), salt, 14, 8, 8, len)
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(a.iter().zip(b).map(|(a, b)| a ^ b).collect())
+}
+
+fn aes_encrypt(key: &[u8], block: &[u8]) -> Vec<u8> {
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let mut block = GenericArray::clone_from_slice(block);
+    cipher.encrypt_block(&mut block);
+    block.to_vec()
+}
+
+fn aes_decrypt(key: &[u8], block: &[u8]) -> Zeroizing<Vec<u8>> {
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let mut block = GenericArray::clone_from_slice(block);
+    cipher.decrypt_block(&mut block);
+    Zeroizing::new(block.to_vec())
+}
+
+fn to_secret(b: &[u8]) -> Result<BigInt> {
+    let secret = BigInt::from_bytes_be(Sign::Plus, b);
+    if secret == BigInt::from(0) || secret >= *N {
+        return Err(anyhow!("BIP38 key is out of range"));
+    }
+    Ok(secret)
+}
+
+// Non-EC-multiply mode: the key itself is encrypted with a key derived
+// from the passphrase and the address hash
+pub fn encrypt(key: &PrivateKey, compressed: bool, passphrase: &str) -> String {
+    let address_hash = address_hash(&key.point, compressed);
+    let derived = passphrase_scrypt(passphrase, &address_hash, 64);
+    let secret = key.secret_bytes();
+    let half1 = xor(&secret[..16], &derived[..16]);
+    let half2 = xor(&secret[16..], &derived[16..32]);
+    let mut flag = FLAG_NON_EC;
+    if compressed {
+        flag |= FLAG_COMPRESSED;
+    }
+    let mut result = NON_EC_PREFIX.to_vec();
+    result.push(flag);
+    result.extend_from_slice(&address_hash);
+    result.append(&mut aes_encrypt(&derived[32..], &half1));
+    result.append(&mut aes_encrypt(&derived[32..], &half2));
+    encode_base58_checksum(&result)
+}
+
+// Returns the key and whether its public key is compressed, for either mode
+pub fn decrypt(s: &str, passphrase: &str) -> Result<(PrivateKey<'static>, bool)> {
+    let b = decode_base58_checksum(s)?;
+    if b.len() != 39 {
+        return Err(anyhow!("Invalid BIP38 length for {}", s));
+    }
+    let flag = b[2];
+    let compressed = flag & FLAG_COMPRESSED != 0;
+    let address_hash = &b[3..7];
+    let key = match b[..2] {
+        [0x01, 0x42] if flag & !FLAG_COMPRESSED == FLAG_NON_EC => {
+            let derived = passphrase_scrypt(passphrase, address_hash, 64);
+            let half1 = xor(&aes_decrypt(&derived[32..], &b[7..23]), &derived[..16]);
+            let half2 = xor(&aes_decrypt(&derived[32..], &b[23..]), &derived[16..32]);
+            PrivateKey::new(to_secret(&[&half1[..], &half2[..]].concat())?)?
+        }
+        [0x01, 0x43] if flag & !(FLAG_COMPRESSED | FLAG_LOT) == 0 => {
+            let owner_entropy = &b[7..15];
+            let passfactor = passfactor(passphrase, owner_entropy, flag & FLAG_LOT != 0)?;
+            let passpoint = (passfactor.clone() * G.clone())?.sec(true);
+            let derived = scrypt(&passpoint, &b[3..15], 10, 1, 1, 64);
+            // The second block holds the back half of the first
+            let part2 = xor(&aes_decrypt(&derived[32..], &b[23..]), &derived[16..32]);
+            let part1 = [&b[15..23], &part2[..8]].concat();
+            let seedb = xor(&aes_decrypt(&derived[32..], &part1), &derived[..16]);
+            let factorb = to_secret(&hash256(&[&seedb[..], &part2[8..]].concat()))?;
+            PrivateKey::new(to_secret(&to_32_bytes(&(passfactor * factorb % &*N)))?)?
+        }
+        _ => return Err(anyhow!("Invalid BIP38 prefix for {}", s)),
+    };
+    if self::address_hash(&key.point, compressed) != address_hash {
+        return Err(anyhow!("Wrong BIP38 passphrase"));
+    }
+    Ok((key, compressed))
+}
+
+// With a lot and sequence number only the first 4 bytes of the owner
+// entropy are salt
+fn passfactor(passphrase: &str, owner_entropy: &[u8], lot: bool) -> Result<BigInt> {
+    let factor = if lot {
+        let prefactor = passphrase_scrypt(passphrase, &owner_entropy[..4], 32);
+        hash256(&[&prefactor[..], owner_entropy].concat())
+    } else {
+        passphrase_scrypt(passphrase, owner_entropy, 32).to_vec()
+    };
+    to_secret(&factor)
+}
+
+fn intermediate_code_with_salt(
+    passphrase: &str,
+    owner_salt: &[u8],
+    lot_sequence: Option<(u32, u32)>,
+) -> Result<String> {
+    let mut owner_entropy = owner_salt.to_vec();
+    if let Some((lot, sequence)) = lot_sequence {
+        if lot > 0xfffff || sequence > 0xfff {
+            return Err(anyhow!("Lot {} or sequence {} is too large", lot, sequence));
+        }
+        owner_entropy.truncate(4);
+        owner_entropy.extend_from_slice(&(lot * 4096 + sequence).to_be_bytes());
+    }
+    let passfactor = passfactor(passphrase, &owner_entropy, lot_sequence.is_some())?;
+    let mut result = MAGIC.to_vec();
+    result.push(match lot_sequence {
+        Some(_) => MAGIC_LOT,
+        None => MAGIC_NO_LOT,
+    });
+    result.append(&mut owner_entropy);
+    result.append(&mut (passfactor * G.clone())?.sec(true));
+    Ok(encode_base58_checksum(&result))
+}
+
+// The "passphrase..." code an owner hands to whoever generates keys for
+// them, who can then make encrypted keys without learning the passphrase
+pub fn intermediate_code(passphrase: &str, lot_sequence: Option<(u32, u32)>) -> Result<String> {
+    let mut owner_salt = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut owner_salt);
+    intermediate_code_with_salt(passphrase, &owner_salt, lot_sequence)
+}
+
+fn encrypt_intermediate_with_seed(
+    intermediate: &str,
+    compressed: bool,
+    seedb: &[u8],
+) -> Result<(String, String)> {
+    let b = decode_base58_checksum(intermediate)?;
+    if b.len() != 49 || b[..7] != MAGIC || (b[7] != MAGIC_NO_LOT && b[7] != MAGIC_LOT) {
+        return Err(anyhow!("Invalid intermediate code {}", intermediate));
+    }
+    if b[16] != 0x02 && b[16] != 0x03 {
+        return Err(anyhow!("Invalid passpoint in intermediate code"));
+    }
+    let owner_entropy = &b[8..16];
+    let passpoint = S256Point::parse(&b[16..])?;
+    let factorb = to_secret(&hash256(seedb))?;
+    let point = (factorb * passpoint.clone())?;
+    let address_hash = address_hash(&point, compressed);
+    let salt = [&address_hash[..], owner_entropy].concat();
+    let derived = scrypt(&passpoint.sec(true), &salt, 10, 1, 1, 64);
+    let part1 = aes_encrypt(&derived[32..], &xor(&seedb[..16], &derived[..16]));
+    let part2 = aes_encrypt(
+        &derived[32..],
+        &xor(&[&part1[8..], &seedb[16..]].concat(), &derived[16..32]),
+    );
+    let mut flag = 0;
+    if compressed {
+        flag |= FLAG_COMPRESSED;
+    }
+    if b[7] == MAGIC_LOT {
+        flag |= FLAG_LOT;
+    }
+    let mut result = EC_PREFIX.to_vec();
+    result.push(flag);
+    result.extend_from_slice(&salt);
+    result.extend_from_slice(&part1[..8]);
+    result.extend_from_slice(&part2);
+    Ok((
+        encode_base58_checksum(&result),
+        point.address(compressed, Network::Mainnet),
+    ))
+}
+
+// EC-multiply mode: makes a new encrypted key from an intermediate code.
+// Returns it with its address.
+pub fn encrypt_intermediate(intermediate: &str, compressed: bool) -> Result<(String, String)> {
+    let mut seedb = Zeroizing::new([0u8; 24]);
+    rand::thread_rng().fill_bytes(&mut seedb[..]);
+    encrypt_intermediate_with_seed(intermediate, compressed, &seedb[..])
+}
+
+#[cfg(test)]
+use crate::helper::decode_hex;
+
+#[test]
+fn test_non_ec_multiply() {
+    let vectors = [
+        (
+            "TestingOneTwoThree",
+            "6PRVWUbkzzsbcVac2qwfssoUJAN1Xhrg6bNk8J7Nzm5H7kxEbn2Nh2ZoGg",
+            "5KN7MzqK5wt2TP1fQCYyHBtDrXdJuXbUzm4A9rKAteGu3Qi5CVR",
+        ),
+        (
+            "Satoshi",
+            "6PRNFFkZc2NZ6dJqFfhRoFNMR9Lnyj7dYGrzdgXXVMXcxoKTePPX1dWByq",
+            "5HtasZ6ofTHP6HCwTqTkLDuLQisYPah7aUnSKfC7h4hMUVw2gi5",
+        ),
+        (
+            "\u{03d2}\u{0301}\u{0000}\u{010400}\u{01f4a9}",
+            "6PRW5o9FLp4gJDDVqJQKJFTpMvdsSGJxMYHtHaQBF3ooa8mwD69bapcDQn",
+            "5Jajm8eQ22H3pGWLEVCXyvND8dQZhiQhoLJNKjYXk9roUFTMSZ4",
+        ),
+        (
+            "TestingOneTwoThree",
+            "6PYNKZ1EAgYgmQfmNVamxyXVWHzK5s6DGhwP4J5o44cvXdoY7sRzhtpUeo",
+            "L44B5gGEpqEDRS9vVPz7QT35jcBG2r3CZwSwQ4fCewXAhAhqGVpP",
+        ),
+        (
+            "Satoshi",
+            "6PYLtMnXvfG3oJde97zRyLYFZCYizPU5T3LwgdYJz1fRhh16bU7u6PPmY7",
+            "KwYgW8gcxj1JWJXhPSu4Fqwzfhp5Yfi42mdYmMa4XqK7NJxXUSK7",
+        ),
+    ];
+    for (passphrase, encrypted, wif) in vectors.iter() {
+        let (key, compressed, _) = PrivateKey::parse_wif(wif).unwrap();
+        assert_eq!(encrypt(&key, compressed, passphrase), *encrypted);
+        let (decrypted, decrypted_compressed) = decrypt(encrypted, passphrase).unwrap();
+        assert_eq!(decrypted_compressed, compressed);
+        assert_eq!(decrypted.wif(compressed, Network::Mainnet), *wif);
+    }
+    assert!(decrypt(vectors[0].1, "TestingOneTwoThre").is_err());
+}
+
+#[test]
+fn test_ec_multiply() {
+    let vectors = [
+        (
+            "TestingOneTwoThree",
+            "6PfQu77ygVyJLZjfvMLyhLMQbYnu5uguoJJ4kMCLqWwPEdfpwANVS76gTX",
+            "A43A940577F4E97F5C4D39EB14FF083A98187C64EA7C99EF7CE460833959A519",
+        ),
+        (
+            "Satoshi",
+            "6PfLGnQs6VZnrNpmVKfjotbnQuaJK4KZoPFrAjx1JMJUa1Ft8gnf5WxfKd",
+            "C2C8036DF268F498099350718C4A3EF3984D2BE84618C2650F5171DCC5EB660A",
+        ),
+        (
+            "MOLON LABE",
+            "6PgNBNNzDkKdhkT6uJntUXwwzQV8Rr2tZcbkDcuC9DZRsS6AtHts4Ypo1j",
+            "44EA95AFBF138356A05EA32110DFD627232D0F2991AD221187BE356F19FA8190",
+        ),
+        (
+            "\u{039c}\u{039f}\u{039b}\u{03a9}\u{039d} \u{039b}\u{0391}\u{0392}\u{0395}",
+            "6PgGWtx25kUg8QWvwuJAgorN6k9FbE25rv5dMRwu5SKMnfpfVe5mar2ngH",
+            "CA2759AA4ADB0F96C414F36ABEB8DB59342985BE9FA50FAAC228C8E7D90E3006",
+        ),
+    ];
+    for (passphrase, encrypted, secret) in vectors.iter() {
+        let (key, compressed) = decrypt(encrypted, passphrase).unwrap();
+        assert!(!compressed);
+        assert_eq!(key.secret_bytes().to_vec(), decode_hex(secret).unwrap());
+    }
+
+    // A key made from an intermediate code decrypts with the passphrase
+    for lot_sequence in [None, Some((263183, 1))].iter() {
+        let intermediate =
+            intermediate_code_with_salt("MOLON LABE", &[0x4f; 8], *lot_sequence).unwrap();
+        assert!(intermediate.starts_with("passphrase"));
+        let (encrypted, address) =
+            encrypt_intermediate_with_seed(&intermediate, true, &[0x99; 24]).unwrap();
+        assert!(encrypted.starts_with("6P"));
+        let (key, compressed) = decrypt(&encrypted, "MOLON LABE").unwrap();
+        assert!(compressed);
+        assert_eq!(key.point.address(true, Network::Mainnet), address);
+        assert!(decrypt(&encrypted, "molon labe").is_err());
+    }
+
+    // The passpoint has to be a compressed point
+    let intermediate = intermediate_code_with_salt("MOLON LABE", &[0x4f; 8], None).unwrap();
+    let mut b = decode_base58_checksum(&intermediate).unwrap();
+    for prefix in [0x04, 0x05].iter() {
+        b[16] = *prefix;
+        let intermediate = encode_base58_checksum(&b);
+        assert!(encrypt_intermediate_with_seed(&intermediate, true, &[0x99; 24]).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{anyhow, Result};
+use hmac::Hmac;
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+use zeroize::Zeroizing;
+
+use crate::bip32::ExtendedPrivKey;
+use crate::network::Network;
+
+static ENGLISH: Lazy<Vec<&'static str>> =
+    Lazy::new(|| include_str!("bip39_english.txt").lines().collect());
+
+const PBKDF2_ROUNDS: u32 = 2048;
+
+// The entropy is wiped on drop and left out of Debug output
+#[derive(PartialEq, Clone)]
+pub struct Mnemonic {
+    entropy: Zeroizing<Vec<u8>>,
+}
+
+impl Mnemonic {
+    pub fn from_entropy(entropy: &[u8]) -> Result<Self> {
+        if ![16, 20, 24, 28, 32].contains(&entropy.len()) {
+            return Err(anyhow!(
+                "Entropy must be 16, 20, 24, 28 or 32 bytes, got {}",
+                entropy.len()
+            ));
+        }
+        Ok(Self {
+            entropy: Zeroizing::new(entropy.to_vec()),
+        })
+    }
+
+    pub fn generate(word_count: usize) -> Result<Self> {
+        if ![12, 15, 18, 21, 24].contains(&word_count) {
+            return Err(anyhow!(
+                "Word count must be 12, 15, 18, 21 or 24, got {}",
+                word_count
+            ));
+        }
+        let mut entropy = Zeroizing::new(vec![0u8; word_count / 3 * 4]);
+        rand::thread_rng().fill_bytes(&mut entropy);
+        Self::from_entropy(&entropy)
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let normalized = s.nfkd().collect::<String>();
+        let words: Vec<&str> = normalized.split_whitespace().collect();
+        if ![12, 15, 18, 21, 24].contains(&words.len()) {
+            return Err(anyhow!("Invalid word count {}", words.len()));
+        }
+
+        let mut bits = Vec::with_capacity(words.len() * 11);
+        for word in &words {
+            let index = ENGLISH
+                .binary_search(word)
+                .map_err(|_| anyhow!("Unknown word {}", word))?;
+            for i in (0..11).rev() {
+                bits.push(index >> i & 1 == 1);
+            }
+        }
+
+        let checksum_len = bits.len() / 33;
+        let entropy: Zeroizing<Vec<u8>> = Zeroizing::new(
+            bits[..bits.len() - checksum_len]
+                .chunks(8)
+                .map(|byte| byte.iter().fold(0u8, |acc, b| acc << 1 | *b as u8))
+                .collect(),
+        );
+
+        let mnemonic = Self::from_entropy(&entropy)?;
+        if mnemonic.checksum_bits() != bits[bits.len() - checksum_len..] {
+            return Err(anyhow!("Invalid mnemonic checksum"));
+        }
+        Ok(mnemonic)
+    }
+
+    fn checksum_bits(&self) -> Vec<bool> {
+        let hash = Sha256::digest(&self.entropy[..]);
+        (0..self.entropy.len() / 4)
+            .map(|i| hash[i / 8] >> (7 - i % 8) & 1 == 1)
+            .collect()
+    }
+
+    pub fn entropy(&self) -> &[u8] {
+        &self.entropy
+    }
+
+    pub fn words(&self) -> Vec<&'static str> {
+        let mut bits: Vec<bool> = self
+            .entropy
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+            .collect();
+        bits.append(&mut self.checksum_bits());
+
+        bits.chunks(11)
+            .map(|chunk| {
+                let index = chunk.iter().fold(0usize, |acc, b| acc << 1 | *b as usize);
+                ENGLISH[index]
+            })
+            .collect()
+    }
+
+    pub fn to_seed(&self, passphrase: &str) -> Zeroizing<Vec<u8>> {
+        let password = Zeroizing::new(self.to_string());
+        let salt = Zeroizing::new(format!("mnemonic{}", passphrase).nfkd().collect::<String>());
+        let mut seed = Zeroizing::new(vec![0u8; 64]);
+        pbkdf2::pbkdf2::<Hmac<Sha512>>(
+            password.as_bytes(),
+            salt.as_bytes(),
+            PBKDF2_ROUNDS,
+            &mut seed,
+        );
+        seed
+    }
+
+    pub fn to_master_key(&self, passphrase: &str, network: Network) -> Result<ExtendedPrivKey> {
+        ExtendedPrivKey::new_master(&self.to_seed(passphrase), network)
+    }
+}
+
+impl fmt::Debug for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mnemonic").finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.words().join(" "))
+    }
+}
+
+impl FromStr for Mnemonic {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+#[test]
+fn test_vectors() {
+    use crate::helper::{decode_hex, encode_hex};
+
+    // entropy, mnemonic, seed with the passphrase "TREZOR"
+    let vectors = [
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+        ),
+        (
+            "808080808080808080808080808080808080808080808080",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage absurd amount doctor acoustic avoid letter always",
+            "107d7c02a5aa6f38c58083ff74f04c607c2d2c0ecc55501dadd72d025b751bc27fe913ffb796f841c49b1d33b610cf0e91d3aa239027f5e99fe4ce9e5088cd65",
+        ),
+        (
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art",
+            "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd3097170af7a4d73245cafa9c3cca8d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8",
+        ),
+        (
+            "9e885d952ad362caeb4efe34a8e91bd2",
+            "ozone drill grab fiber curtain grace pudding thank cruise elder eight picnic",
+            "274ddc525802f7c828d8ef7ddbcdc5304e87ac3535913611fbbfa986d0c9e5476c91689f9c8a54fd55bd38606aa6a8595ad213d4c9c9f9aca3fb217069a41028",
+        ),
+        (
+            "066dca1a2bb7e8a1db2832148ce9933eea0f3ac9548d793112d9a95c9407efad",
+            "all hour make first leader extend hole alien behind guard gospel lava path output census museum junior mass reopen famous sing advance salt reform",
+            "26e975ec644423f4a4c4f4215ef09b4bd7ef924e85d1d17c4cf3f136c2863cf6df0a475045652c57eb5fb41513ca2a2d67722b77e954b4b3fc11f7590449191d",
+        ),
+    ];
+
+    for (entropy, words, seed) in vectors.iter() {
+        let mnemonic = Mnemonic::from_entropy(&decode_hex(entropy).unwrap()).unwrap();
+        assert_eq!(mnemonic.to_string(), words.to_string());
+        assert_eq!(words.parse::<Mnemonic>().unwrap(), mnemonic);
+        assert_eq!(encode_hex(&mnemonic.to_seed("TREZOR")), seed.to_string());
+    }
+}
+
+#[test]
+fn test_master_key() {
+    let mnemonic: Mnemonic =
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+            .parse()
+            .unwrap();
+    assert_eq!(
+        mnemonic
+            .to_master_key("TREZOR", Network::Mainnet)
+            .unwrap()
+            .to_string(),
+        "xprv9s21ZrQH143K3h3fDYiay8mocZ3afhfULfb5GX8kCBdno77K4HiA15Tg23wpbeF1pLfs1c5SPmYHrEpTuuRhxMwvKDwqdKiGJS9XFKzUsAF"
+    );
+}
+
+#[test]
+fn test_invalid_mnemonic() {
+    // bad checksum
+    assert!("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon"
+        .parse::<Mnemonic>()
+        .is_err());
+    // unknown word
+    assert!("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon bitcoin"
+        .parse::<Mnemonic>()
+        .is_err());
+    // bad length
+    assert!(
+        "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about"
+            .parse::<Mnemonic>()
+            .is_err()
+    );
+    assert!(Mnemonic::from_entropy(&[0; 15]).is_err());
+    let mnemonic = Mnemonic::from_entropy(&[0xab; 16]).unwrap();
+    assert_eq!(format!("{:?}", mnemonic), "Mnemonic { .. }");
+    assert!(Mnemonic::generate(13).is_err());
+}
+
+#[test]
+fn test_generate() {
+    for word_count in [12, 15, 18, 21, 24].iter() {
+        let mnemonic = Mnemonic::generate(*word_count).unwrap();
+        assert_eq!(mnemonic.words().len(), *word_count);
+        assert_eq!(mnemonic.to_string().parse::<Mnemonic>().unwrap(), mnemonic);
+    }
+}
@@ -0,0 +1,509 @@
+use anyhow::{anyhow, Result};
+use num_bigint::{BigInt, Sign};
+use num_traits::{ToPrimitive, Zero};
+use std::io::Read;
+
+use crate::helper::{
+    encode_hex, encode_variant, hash256, merkle_parent, merkle_root, read_variant,
+};
+use crate::merkle::is_mutated;
+use crate::network::Network;
+use crate::transaction::{Tx, WITNESS_SCALE_FACTOR};
+
+pub const HEADER_SIZE: usize = 80;
+
+// BIP9: the top three version bits are 001 while version bits are in use
+const VERSIONBITS_TOP_MASK: u32 = 0xe0000000;
+const VERSIONBITS_TOP_BITS: u32 = 0x20000000;
+
+// BIP141: OP_RETURN, a 36-byte push and the commitment header
+pub const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// The target of difficulty 1
+pub const MAX_TARGET_BITS: u32 = 0x1d00ffff;
+
+// Expands the compact `bits` encoding. A set sign bit, an overflow past 256
+// bits or a zero target are all invalid.
+pub fn bits_to_target(bits: u32) -> Result<BigInt> {
+    let exponent = bits >> 24;
+    let coefficient = bits & 0x007fffff;
+    if coefficient != 0 && bits & 0x00800000 != 0 {
+        return Err(anyhow!("Target {:08x} is negative", bits));
+    }
+    let target = if exponent <= 3 {
+        BigInt::from(coefficient >> (8 * (3 - exponent)))
+    } else {
+        BigInt::from(coefficient) << (8 * (exponent - 3) as usize)
+    };
+    if target.is_zero() {
+        return Err(anyhow!("Target {:08x} is zero", bits));
+    }
+    if target.bits() > 256 {
+        return Err(anyhow!("Target {:08x} overflows 256 bits", bits));
+    }
+    Ok(target)
+}
+
+pub fn target_to_bits(target: &BigInt) -> u32 {
+    let mut size = (target.bits() as u32).div_ceil(8);
+    let mut coefficient = if size <= 3 {
+        target.to_u32().unwrap() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3) as usize)).to_u32().unwrap()
+    };
+    // The coefficient is signed, so a leading byte with the top bit set
+    // moves into the exponent instead
+    if coefficient & 0x00800000 != 0 {
+        coefficient >>= 8;
+        size += 1;
+    }
+    size << 24 | coefficient
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub prev_block: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub timestamp: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub fn new(
+        version: u32,
+        prev_block: [u8; 32],
+        merkle_root: [u8; 32],
+        timestamp: u32,
+        bits: u32,
+        nonce: u32,
+    ) -> Self {
+        Self {
+            version,
+            prev_block,
+            merkle_root,
+            timestamp,
+            bits,
+            nonce,
+        }
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+
+        let mut prev_block = [0u8; 32];
+        reader.read_exact(&mut prev_block)?;
+
+        let mut merkle_root = [0u8; 32];
+        reader.read_exact(&mut merkle_root)?;
+
+        let mut timestamp = [0u8; 4];
+        reader.read_exact(&mut timestamp)?;
+
+        let mut bits = [0u8; 4];
+        reader.read_exact(&mut bits)?;
+
+        let mut nonce = [0u8; 4];
+        reader.read_exact(&mut nonce)?;
+        Ok(Self::new(
+            u32::from_le_bytes(version),
+            prev_block,
+            merkle_root,
+            u32::from_le_bytes(timestamp),
+            u32::from_le_bytes(bits),
+            u32::from_le_bytes(nonce),
+        ))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.extend_from_slice(&self.prev_block);
+        result.extend_from_slice(&self.merkle_root);
+        result.extend_from_slice(&self.timestamp.to_le_bytes());
+        result.extend_from_slice(&self.bits.to_le_bytes());
+        result.extend_from_slice(&self.nonce.to_le_bytes());
+        result
+    }
+
+    // The block hash in display (big-endian) order
+    pub fn hash(&self) -> Vec<u8> {
+        let mut h = hash256(&self.serialize());
+        h.reverse();
+        h
+    }
+
+    pub fn id(&self) -> String {
+        encode_hex(&self.hash())
+    }
+
+    // The parent's hash in display order, comparable with `hash`
+    pub fn prev_hash(&self) -> Vec<u8> {
+        let mut h = self.prev_block.to_vec();
+        h.reverse();
+        h
+    }
+
+    pub fn prev_block_id(&self) -> String {
+        encode_hex(&self.prev_hash())
+    }
+
+    pub fn bip9(&self) -> bool {
+        self.version & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS
+    }
+
+    pub fn signals(&self, bit: u8) -> bool {
+        bit < 29 && self.bip9() && (self.version >> bit) & 1 == 1
+    }
+
+    pub fn bip91(&self) -> bool {
+        self.signals(4)
+    }
+
+    pub fn bip141(&self) -> bool {
+        self.signals(1)
+    }
+
+    pub fn target(&self) -> Result<BigInt> {
+        bits_to_target(self.bits)
+    }
+
+    pub fn difficulty(&self) -> Result<f64> {
+        let max = bits_to_target(MAX_TARGET_BITS)?;
+        Ok(max.to_f64().unwrap() / self.target()?.to_f64().unwrap())
+    }
+
+    pub fn check_pow(&self) -> bool {
+        match self.target() {
+            Ok(target) => BigInt::from_bytes_be(Sign::Plus, &self.hash()) <= target,
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub txs: Vec<Tx>,
+}
+
+impl Block {
+    pub fn new(header: BlockHeader, txs: Vec<Tx>) -> Self {
+        Self { header, txs }
+    }
+
+    pub fn parse<R>(reader: &mut R, network: Network) -> Result<Self>
+    where
+        R: Read,
+    {
+        let header = BlockHeader::parse(reader)?;
+        let tx_len = read_variant(reader)?;
+        let mut txs = Vec::new();
+        for _ in 0..tx_len {
+            txs.push(Tx::parse(reader, network)?);
+        }
+        Ok(Self::new(header, txs))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.header.serialize();
+        result.append(&mut encode_variant(self.txs.len() as u64));
+        for tx in &self.txs {
+            result.append(&mut tx.serialize());
+        }
+        result
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        self.header.hash()
+    }
+
+    pub fn id(&self) -> String {
+        self.header.id()
+    }
+
+    pub fn weight(&self) -> usize {
+        let overhead = HEADER_SIZE + encode_variant(self.txs.len() as u64).len();
+        overhead * WITNESS_SCALE_FACTOR + self.txs.iter().map(|tx| tx.weight()).sum::<usize>()
+    }
+
+    // Txids in internal order, the leaves of the header's merkle tree
+    pub fn tx_hashes(&self) -> Vec<Vec<u8>> {
+        self.txs
+            .iter()
+            .map(|tx| hash256(&tx.serialize_legacy()))
+            .collect()
+    }
+
+    pub fn merkle_root(&self) -> Option<Vec<u8>> {
+        merkle_root(&self.tx_hashes())
+    }
+
+    // The coinbase's wtxid is replaced by zeros
+    pub fn witness_root(&self) -> Option<Vec<u8>> {
+        let hashes: Vec<Vec<u8>> = self
+            .txs
+            .iter()
+            .enumerate()
+            .map(|(i, tx)| {
+                if i == 0 {
+                    vec![0u8; 32]
+                } else {
+                    hash256(&tx.serialize())
+                }
+            })
+            .collect();
+        merkle_root(&hashes)
+    }
+
+    // The last coinbase output carrying the BIP141 header wins
+    pub fn witness_commitment(&self) -> Option<Vec<u8>> {
+        self.txs.first()?.tx_outs.iter().rev().find_map(|tx_out| {
+            let script = tx_out.script_pubkey.as_bytes();
+            if script.len() >= 38 && script[..6] == WITNESS_COMMITMENT_HEADER {
+                Some(script[6..38].to_vec())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn check_merkle_root(&self) -> Result<()> {
+        let hashes = self.tx_hashes();
+        if is_mutated(&hashes) {
+            return Err(anyhow!("Merkle tree is mutated (CVE-2012-2459)"));
+        }
+        let computed = merkle_root(&hashes).ok_or_else(|| anyhow!("Block has no transactions"))?;
+        if computed != self.header.merkle_root {
+            return Err(anyhow!(
+                "Merkle root mismatch: header {}, computed {}",
+                encode_hex(&self.header.merkle_root),
+                encode_hex(&computed)
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_witness_commitment(&self) -> Result<()> {
+        let commitment = match self.witness_commitment() {
+            Some(commitment) => commitment,
+            None => {
+                if self.txs.iter().any(|tx| tx.is_segwit()) {
+                    return Err(anyhow!("Witness data without a witness commitment"));
+                }
+                return Ok(());
+            }
+        };
+        let coinbase = &self.txs[0];
+        let reserved = match coinbase.tx_ins[..] {
+            [ref tx_in] if tx_in.witness.len() == 1 && tx_in.witness[0].len() == 32 => {
+                &tx_in.witness[0]
+            }
+            _ => return Err(anyhow!("Coinbase witness must be a single 32-byte item")),
+        };
+        let computed = merkle_parent(&self.witness_root().unwrap(), reserved);
+        if computed != commitment {
+            return Err(anyhow!(
+                "Witness commitment mismatch: coinbase {}, computed {}",
+                encode_hex(&commitment),
+                encode_hex(&computed)
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self.txs.first() {
+            Some(tx) if tx.is_coinbase() => {}
+            Some(_) => return Err(anyhow!("First transaction is not a coinbase")),
+            None => return Err(anyhow!("Block has no transactions")),
+        }
+        if self.txs[1..].iter().any(|tx| tx.is_coinbase()) {
+            return Err(anyhow!("Block has more than one coinbase"));
+        }
+        self.check_merkle_root()?;
+        self.check_witness_commitment()
+    }
+}
+
+#[cfg(test)]
+use crate::helper::decode_hex;
+
+// Testnet3 block 1263442, a coinbase and one segwit spend
+#[cfg(test)]
+const SEGWIT_BLOCK: &str = "000000201c8d1a529c39a396db2db234d5ec152fa651a2872966daccbde028b400000000083f14492679151dbfaa1a825ef4c18518e780c1f91044180280a7d33f4a98ff5f45765aaddc001d38333b9a02010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff230352471300fe5f45765afe94690a000963676d696e6572343208000000000000000000ffffffff024423a804000000001976a914f2c25ac3d59f3d674b1d1d0a25c27339aaac0ba688ac0000000000000000266a24aa21a9edcb26cb3052426b9ebb4d19c819ef87c19677bbf3a7c46ef0855bd1b2abe83491012000000000000000000000000000000000000000000000000000000000000000000000000002000000000101d20978463906ba4ff5e7192494b88dd5eb0de85d900ab253af909106faa22cc5010000000004000000014777ff000000000016001446c29eabe8208a33aa1023c741fa79aa92e881ff0347304402207d7ca96134f2bcfdd6b536536fdd39ad17793632016936f777ebb32c22943fda02206014d2fb8a6aa58279797f861042ba604ebd2f8f61e5bddbd9d3be5a245047b201004b632103eeaeba7ce5dc2470221e9517fb498e8d6bd4e73b85b8be655196972eb9ccd5566754b2752103a40b74d43df244799d041f32ce1ad515a6cd99501701540e38750d883ae21d3a68ac00000000";
+
+#[test]
+fn test_parse_header() {
+    let raw = decode_hex("020000208ec39428b17323fa0ddec8e887b4a7c53b8c0a0a220cfd0000000000000000005b0750fce0a889502d40508d39576821155e9c9e3f5c3157f961db38fd8b25be1e77a759e93c0118a4ffd71d").unwrap();
+    let header = BlockHeader::parse(&mut raw.as_slice()).unwrap();
+    assert_eq!(header.version, 0x20000002);
+    assert_eq!(
+        header.prev_block_id(),
+        "000000000000000000fd0c220a0a8c3bc5a7b487e8c8de0dfa2373b12894c38e"
+    );
+    assert_eq!(header.timestamp, 0x59a7771e);
+    assert_eq!(header.bits, 0x18013ce9);
+    assert_eq!(header.nonce, 0x1dd7ffa4);
+    assert_eq!(header.serialize(), raw);
+    assert_eq!(
+        header.id(),
+        "0000000000000000007e9e4c586439b0cdbe13b1370bdd9435d76a644d047523"
+    );
+    assert!(BlockHeader::parse(&mut &raw[..79]).is_err());
+}
+
+#[test]
+fn test_version_bits() {
+    let raw = decode_hex("020000208ec39428b17323fa0ddec8e887b4a7c53b8c0a0a220cfd0000000000000000005b0750fce0a889502d40508d39576821155e9c9e3f5c3157f961db38fd8b25be1e77a759e93c0118a4ffd71d").unwrap();
+    let mut header = BlockHeader::parse(&mut raw.as_slice()).unwrap();
+    assert!(header.bip9());
+    assert!(!header.bip91());
+    assert!(header.bip141());
+
+    header.version = 0x20000010;
+    assert!(header.bip91());
+    assert!(!header.bip141());
+
+    // Pre-BIP9 versions never signal
+    header.version = 0x00000012;
+    assert!(!header.bip9());
+    assert!(!header.signals(1));
+    assert!(!header.signals(4));
+}
+
+#[test]
+fn test_target() {
+    let raw = decode_hex("020000208ec39428b17323fa0ddec8e887b4a7c53b8c0a0a220cfd0000000000000000005b0750fce0a889502d40508d39576821155e9c9e3f5c3157f961db38fd8b25be1e77a759e93c0118a4ffd71d").unwrap();
+    let header = BlockHeader::parse(&mut raw.as_slice()).unwrap();
+    assert_eq!(
+        format!("{:064x}", header.target().unwrap()),
+        "0000000000000000013ce9000000000000000000000000000000000000000000"
+    );
+    assert_eq!(header.difficulty().unwrap() as u64, 888171856257);
+
+    for bits in &[0x1d00ffff, 0x18013ce9, 0x1b0404cb, 0x207fffff, 0x05009234] {
+        assert_eq!(target_to_bits(&bits_to_target(*bits).unwrap()), *bits);
+    }
+    assert_eq!(
+        bits_to_target(0x01003456).unwrap_err().to_string(),
+        "Target 01003456 is zero"
+    );
+    assert!(bits_to_target(0x04923456).is_err());
+    assert!(bits_to_target(0xff123456).is_err());
+    assert_eq!(target_to_bits(&BigInt::from(0x80)), 0x02008000);
+}
+
+#[test]
+fn test_check_pow() {
+    use crate::network::Network;
+
+    let raw = decode_hex("04000000fbedbbf0cfdaf278c094f187f2eb987c86a199da22bbb20400000000000000007b7697b29129648fa08b4bcd13c9d5e60abb973a1efac9c8d573c71c807c56c3d6213557faa80518c3737ec1").unwrap();
+    let header = BlockHeader::parse(&mut raw.as_slice()).unwrap();
+    assert!(header.check_pow());
+
+    let raw = decode_hex("04000000fbedbbf0cfdaf278c094f187f2eb987c86a199da22bbb20400000000000000007b7697b29129648fa08b4bcd13c9d5e60abb973a1efac9c8d573c71c807c56c3d6213557faa80518c3737ec0").unwrap();
+    let header = BlockHeader::parse(&mut raw.as_slice()).unwrap();
+    assert!(!header.check_pow());
+
+    for network in Network::ALL.iter() {
+        let block = network.genesis_block();
+        let header = BlockHeader::parse(&mut &block[..HEADER_SIZE]).unwrap();
+        assert_eq!(header.hash(), network.genesis_hash());
+        assert!(header.check_pow());
+    }
+}
+
+#[test]
+fn test_parse_block() {
+    for network in Network::ALL.iter() {
+        let raw = network.genesis_block();
+        let block = Block::parse(&mut raw.as_slice(), *network).unwrap();
+        assert_eq!(block.txs.len(), 1);
+        assert_eq!(block.serialize(), raw);
+        assert_eq!(block.hash(), network.genesis_hash());
+        assert!(block.witness_commitment().is_none());
+        block.validate().unwrap();
+    }
+
+    let raw = decode_hex(SEGWIT_BLOCK).unwrap();
+    let block = Block::parse(&mut raw.as_slice(), Network::Testnet3).unwrap();
+    assert_eq!(
+        block.id(),
+        "000000006f27ddfe1dd680044a34548f41bed47eba9e6f0b310da21423bc5f33"
+    );
+    assert!(block.header.check_pow());
+    assert_eq!(block.txs.len(), 2);
+    assert!(block.txs[1].is_segwit());
+    assert_eq!(block.serialize(), raw);
+    assert_eq!(
+        block.weight(),
+        block.txs[0].weight() + block.txs[1].weight() + 81 * 4
+    );
+    assert_eq!(
+        encode_hex(&block.witness_commitment().unwrap()),
+        "cb26cb3052426b9ebb4d19c819ef87c19677bbf3a7c46ef0855bd1b2abe83491"
+    );
+    block.validate().unwrap();
+
+    assert!(Block::parse(&mut &raw[..raw.len() - 1], Network::Testnet3).is_err());
+}
+
+#[test]
+fn test_validate_block() {
+    let raw = decode_hex(SEGWIT_BLOCK).unwrap();
+    let block = Block::parse(&mut raw.as_slice(), Network::Testnet3).unwrap();
+
+    let mut mutated = block.clone();
+    mutated.txs[1].tx_outs[0].amount += 1;
+    assert!(mutated
+        .check_merkle_root()
+        .unwrap_err()
+        .to_string()
+        .starts_with("Merkle root mismatch"));
+
+    // Witness data is not covered by the header's merkle root
+    let mut mutated = block.clone();
+    mutated.txs[1].tx_ins[0].witness[0][4] ^= 1;
+    mutated.check_merkle_root().unwrap();
+    assert!(mutated
+        .check_witness_commitment()
+        .unwrap_err()
+        .to_string()
+        .starts_with("Witness commitment mismatch"));
+
+    let mut mutated = block.clone();
+    mutated.txs[0].tx_ins[0].witness.clear();
+    assert!(mutated.check_witness_commitment().is_err());
+
+    let mut mutated = block.clone();
+    mutated.txs[0].tx_outs.pop();
+    assert_eq!(
+        mutated.check_witness_commitment().unwrap_err().to_string(),
+        "Witness data without a witness commitment"
+    );
+
+    // CVE-2012-2459: [coinbase, tx, tx] has the same root as [coinbase, tx, tx, tx]
+    let mut mutated = block.clone();
+    mutated.txs.push(block.txs[1].clone());
+    mutated.txs.push(block.txs[1].clone());
+    assert_eq!(
+        mutated.check_merkle_root().unwrap_err().to_string(),
+        "Merkle tree is mutated (CVE-2012-2459)"
+    );
+
+    let mut mutated = block.clone();
+    mutated.txs.swap(0, 1);
+    assert_eq!(
+        mutated.validate().unwrap_err().to_string(),
+        "First transaction is not a coinbase"
+    );
+
+    let mut mutated = block;
+    mutated.txs.clear();
+    assert!(mutated.validate().is_err());
+}
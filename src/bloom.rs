@@ -0,0 +1,311 @@
+use anyhow::{anyhow, Result};
+use std::f64::consts::LN_2;
+use std::io::Read;
+
+use crate::helper::{encode_variant, murmur3, read_variant};
+use crate::op::{OP_1, OP_16, OP_CHECKMULTISIG, OP_CHECKSIG};
+use crate::script::{Cmd, Script};
+use crate::transaction::Tx;
+
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+const BLOOM_UPDATE_MASK: u8 = 3;
+
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+const BIP37_CONSTANT: u32 = 0xfba4c795;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BloomFilter {
+    pub filter: Vec<u8>,
+    pub function_count: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl BloomFilter {
+    pub fn new(size: usize, function_count: u32, tweak: u32, flags: u8) -> Self {
+        Self {
+            filter: vec![0u8; size],
+            function_count,
+            tweak,
+            flags,
+        }
+    }
+
+    // Sized like Bitcoin Core does for the expected number of elements and
+    // false positive rate
+    pub fn with_rate(elements: usize, fp_rate: f64, tweak: u32, flags: u8) -> Self {
+        let elements = elements.max(1) as f64;
+        let bits = (-1.0 / (LN_2 * LN_2) * elements * fp_rate.ln()) as usize;
+        let size = bits.min(MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        let function_count = ((size * 8) as f64 / elements * LN_2) as u32;
+        Self::new(size, function_count.min(MAX_HASH_FUNCS), tweak, flags)
+    }
+
+    pub fn is_within_size_constraints(&self) -> bool {
+        self.filter.len() <= MAX_BLOOM_FILTER_SIZE && self.function_count <= MAX_HASH_FUNCS
+    }
+
+    fn bit_indexes(&self, data: &[u8]) -> Vec<usize> {
+        let bits = self.filter.len() as u32 * 8;
+        (0..self.function_count)
+            .map(|i| {
+                let seed = i.wrapping_mul(BIP37_CONSTANT).wrapping_add(self.tweak);
+                (murmur3(data, seed) % bits) as usize
+            })
+            .collect()
+    }
+
+    pub fn add(&mut self, data: &[u8]) {
+        if self.filter.is_empty() {
+            return;
+        }
+        for index in self.bit_indexes(data) {
+            self.filter[index >> 3] |= 1 << (index & 7);
+        }
+    }
+
+    // An empty filter matches everything, as in Bitcoin Core
+    pub fn contains(&self, data: &[u8]) -> bool {
+        if self.filter.is_empty() {
+            return true;
+        }
+        self.bit_indexes(data)
+            .iter()
+            .all(|index| self.filter[index >> 3] & (1 << (index & 7)) != 0)
+    }
+
+    // Matches the txid, data pushes in the outputs, spent outpoints and data
+    // pushes in the inputs, as a full node does. Matched outputs are added
+    // as outpoints according to the update flags, so that spends of them
+    // match later.
+    pub fn is_relevant_and_update(&mut self, tx: &Tx) -> bool {
+        if self.filter.is_empty() {
+            return true;
+        }
+        let mut txid = tx.hash();
+        txid.reverse();
+        let mut found = self.contains(&txid);
+        for (index, tx_out) in tx.tx_outs.iter().enumerate() {
+            let script = &tx_out.script_pubkey;
+            if !data_pushes(script).any(|data| self.contains(&data)) {
+                continue;
+            }
+            found = true;
+            let update = match self.flags & BLOOM_UPDATE_MASK {
+                BLOOM_UPDATE_ALL => true,
+                BLOOM_UPDATE_P2PUBKEY_ONLY => is_pubkey_or_multisig(script),
+                _ => false,
+            };
+            if update {
+                let mut outpoint = txid.clone();
+                outpoint.extend_from_slice(&(index as u32).to_le_bytes());
+                self.add(&outpoint);
+            }
+        }
+        if found {
+            return true;
+        }
+        tx.tx_ins.iter().any(|tx_in| {
+            self.contains(&tx_in.outpoint())
+                || data_pushes(&tx_in.script_sig).any(|data| self.contains(&data))
+        })
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let size = read_variant(reader)?;
+        if size > MAX_BLOOM_FILTER_SIZE as u64 {
+            return Err(anyhow!("Bloom filter of {} bytes is too large", size));
+        }
+        let mut filter = vec![0u8; size as usize];
+        reader.read_exact(&mut filter)?;
+        let mut buffer = [0u8; 4];
+        reader.read_exact(&mut buffer)?;
+        let function_count = u32::from_le_bytes(buffer);
+        reader.read_exact(&mut buffer)?;
+        let tweak = u32::from_le_bytes(buffer);
+        let mut flags = [0u8; 1];
+        reader.read_exact(&mut flags)?;
+        let result = Self {
+            filter,
+            function_count,
+            tweak,
+            flags: flags[0],
+        };
+        if !result.is_within_size_constraints() {
+            return Err(anyhow!(
+                "Bloom filter has too many hash functions: {}",
+                function_count
+            ));
+        }
+        Ok(result)
+    }
+
+    // The filterload payload
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = encode_variant(self.filter.len() as u64);
+        result.extend_from_slice(&self.filter);
+        result.extend_from_slice(&self.function_count.to_le_bytes());
+        result.extend_from_slice(&self.tweak.to_le_bytes());
+        result.push(self.flags);
+        result
+    }
+}
+
+// The non-empty data pushes; a malformed script has none
+fn data_pushes(script: &Script) -> impl Iterator<Item = Vec<u8>> {
+    script
+        .cmds()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|cmd| match cmd {
+            Cmd::Data(data) if !data.is_empty() => Some(data),
+            _ => None,
+        })
+}
+
+fn is_pubkey_or_multisig(script: &Script) -> bool {
+    let cmds = match script.cmds() {
+        Ok(cmds) => cmds,
+        Err(_) => return false,
+    };
+    let is_key = |cmd: &Cmd| matches!(cmd, Cmd::Data(key) if key.len() == 33 || key.len() == 65);
+    match cmds.as_slice() {
+        [key, Cmd::Op(OP_CHECKSIG)] => is_key(key),
+        [Cmd::Op(m), keys @ .., Cmd::Op(n), Cmd::Op(OP_CHECKMULTISIG)] => {
+            let range = OP_1..=OP_16;
+            range.contains(m)
+                && range.contains(n)
+                && m <= n
+                && keys.len() == (n - OP_1 + 1) as usize
+                && keys.iter().all(is_key)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+use crate::helper::{decode_hex, encode_hex, hash160};
+#[cfg(test)]
+use crate::network::Network;
+#[cfg(test)]
+use crate::transaction::{TxIn, TxOut};
+
+#[test]
+fn test_bloom_filter() {
+    // Programming Bitcoin, chapter 12
+    let mut filter = BloomFilter::new(10, 5, 99, BLOOM_UPDATE_ALL);
+    filter.add(b"Hello World");
+    filter.add(b"Goodbye!");
+    assert_eq!(encode_hex(&filter.filter), "4000600a080000010940");
+    assert_eq!(
+        encode_hex(&filter.serialize()),
+        "0a4000600a080000010940050000006300000001"
+    );
+    assert!(filter.contains(b"Hello World"));
+    assert!(!filter.contains(b"Hello World!"));
+
+    // Bitcoin Core's bloom_create_insert_serialize
+    for (tweak, expected) in [
+        (0, "03614e9b050000000000000001"),
+        (2147483649, "03ce4299050000000100008001"),
+    ]
+    .iter()
+    {
+        let mut filter = BloomFilter::with_rate(3, 0.01, *tweak, BLOOM_UPDATE_ALL);
+        let first = decode_hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        filter.add(&first);
+        assert!(filter.contains(&first));
+        assert!(!filter.contains(&decode_hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap()));
+        filter.add(&decode_hex("b5a2c786d9ef4658287ced5914b37a1b4aa32eee").unwrap());
+        filter.add(&decode_hex("b9300670b4c5366e95b2699e8b18bc75e5f729c5").unwrap());
+        let raw = filter.serialize();
+        assert_eq!(encode_hex(&raw), *expected);
+        assert_eq!(BloomFilter::parse(&mut raw.as_slice()).unwrap(), filter);
+    }
+
+    let mut raw = BloomFilter::new(1, 51, 0, 0).serialize();
+    assert!(BloomFilter::parse(&mut raw.as_slice()).is_err());
+    raw = encode_variant(MAX_BLOOM_FILTER_SIZE as u64 + 1);
+    assert!(BloomFilter::parse(&mut raw.as_slice()).is_err());
+
+    let empty = BloomFilter::new(0, 10, 0, 0);
+    assert!(empty.contains(b""));
+    assert!(empty.contains(b"Hello World"));
+}
+
+#[test]
+fn test_relevant_transactions() {
+    let sec =
+        decode_hex("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+    let h160 = hash160(&sec);
+    let funding = Tx::new(
+        1,
+        vec![TxIn::new([7u8; 32], 0, None, 0xffffffff)],
+        vec![
+            TxOut::new(1000, Script::p2pkh(&[9u8; 20])),
+            TxOut::new(2000, Script::p2pkh(&h160)),
+        ],
+        0,
+        Network::Mainnet,
+    );
+    let mut prev_tx = [0u8; 32];
+    prev_tx.copy_from_slice(&funding.hash());
+    prev_tx.reverse();
+    let spending = Tx::new(
+        1,
+        vec![TxIn::new(prev_tx, 1, None, 0xffffffff)],
+        vec![TxOut::new(1500, Script::p2pkh(&[9u8; 20]))],
+        0,
+        Network::Mainnet,
+    );
+
+    for (flags, spend_matches) in [
+        (BLOOM_UPDATE_ALL, true),
+        (BLOOM_UPDATE_P2PUBKEY_ONLY, false),
+        (BLOOM_UPDATE_NONE, false),
+    ]
+    .iter()
+    {
+        let mut filter = BloomFilter::with_rate(10, 0.000001, 0, *flags);
+        filter.add(&h160);
+        assert!(filter.is_relevant_and_update(&funding));
+        assert_eq!(
+            filter.clone().is_relevant_and_update(&spending),
+            *spend_matches
+        );
+    }
+
+    // Pay-to-pubkey outputs are followed with BLOOM_UPDATE_P2PUBKEY_ONLY
+    let mut p2pk = funding.clone();
+    p2pk.tx_outs[1].script_pubkey = Script::p2pk(&sec);
+    let mut spending = spending;
+    spending.tx_ins[0].prev_tx.copy_from_slice(&p2pk.hash());
+    spending.tx_ins[0].prev_tx.reverse();
+    let mut filter = BloomFilter::with_rate(10, 0.000001, 0, BLOOM_UPDATE_P2PUBKEY_ONLY);
+    filter.add(&sec);
+    assert!(filter.is_relevant_and_update(&p2pk));
+    assert!(filter.is_relevant_and_update(&spending));
+
+    // The txid itself and data pushes in the script sig match too
+    let mut filter = BloomFilter::with_rate(10, 0.000001, 0, BLOOM_UPDATE_NONE);
+    let mut txid = funding.hash();
+    txid.reverse();
+    filter.add(&txid);
+    assert!(filter.is_relevant_and_update(&funding));
+    let mut filter = BloomFilter::with_rate(10, 0.000001, 0, BLOOM_UPDATE_NONE);
+    filter.add(&sec);
+    let mut signed = spending.clone();
+    signed.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(vec![0x30; 71]), Cmd::Data(sec)]);
+    assert!(!filter.is_relevant_and_update(&spending));
+    assert!(filter.is_relevant_and_update(&signed));
+    let mut empty = BloomFilter::new(0, 0, 0, BLOOM_UPDATE_ALL);
+    assert!(empty.is_relevant_and_update(&signed));
+    assert!(empty.is_relevant_and_update(&funding));
+    assert!(empty.filter.is_empty());
+}
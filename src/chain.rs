@@ -0,0 +1,568 @@
+use anyhow::{anyhow, Result};
+use num_bigint::BigInt;
+use std::collections::HashMap;
+
+use crate::block::{bits_to_target, target_to_bits, BlockHeader, HEADER_SIZE};
+use crate::helper::encode_hex;
+use crate::network::Network;
+
+pub const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = 2016;
+pub const TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+pub const TARGET_SPACING: u32 = 10 * 60;
+
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// BIP94: the first block of a period may be at most this much older than
+// its parent
+const MAX_TIMEWARP: u32 = 600;
+
+// The retargeted bits after a period which took `timespan` seconds. The
+// change is clamped to a factor of 4 either way and never goes past the
+// network's pow limit.
+pub fn calculate_new_bits(bits: u32, timespan: u32, network: Network) -> Result<u32> {
+    let timespan = timespan.clamp(TARGET_TIMESPAN / 4, TARGET_TIMESPAN * 4);
+    let pow_limit = bits_to_target(network.pow_limit_bits())?;
+    let target = bits_to_target(bits)? * timespan / TARGET_TIMESPAN;
+    Ok(target_to_bits(&target.min(pow_limit)))
+}
+
+// The expected number of hashes to find a header with these bits
+pub fn work(bits: u32) -> Result<BigInt> {
+    let target = bits_to_target(bits)?;
+    Ok((BigInt::from(1) << 256) / (target + 1))
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChainEntry {
+    pub header: BlockHeader,
+    pub height: u32,
+    pub chainwork: BigInt,
+}
+
+// Every valid header seen so far, forks included, with the most-work chain
+// kept as a height index
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    pub network: Network,
+    entries: HashMap<Vec<u8>, ChainEntry>,
+    active: Vec<Vec<u8>>,
+}
+
+impl HeaderChain {
+    pub fn new(network: Network) -> Self {
+        let genesis = BlockHeader::parse(&mut &network.genesis_block()[..HEADER_SIZE]).unwrap();
+        let hash = genesis.hash();
+        let entry = ChainEntry {
+            chainwork: work(genesis.bits).unwrap(),
+            header: genesis,
+            height: 0,
+        };
+        let mut entries = HashMap::new();
+        entries.insert(hash.clone(), entry);
+        Self {
+            network,
+            entries,
+            active: vec![hash],
+        }
+    }
+
+    pub fn tip(&self) -> &ChainEntry {
+        &self.entries[self.active.last().unwrap()]
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tip().height
+    }
+
+    // Hashes are in display order, as returned by `BlockHeader::hash`
+    pub fn get(&self, hash: &[u8]) -> Option<&ChainEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn parent(&self, entry: &ChainEntry) -> Option<&ChainEntry> {
+        self.entries.get(&entry.header.prev_hash())
+    }
+
+    pub fn is_active(&self, hash: &[u8]) -> bool {
+        match self.entries.get(hash) {
+            Some(entry) => self
+                .active
+                .get(entry.height as usize)
+                .is_some_and(|active| active == hash),
+            None => false,
+        }
+    }
+
+    // The entry at `height` on the chain ending in `hash`
+    pub fn ancestor(&self, hash: &[u8], height: u32) -> Option<&ChainEntry> {
+        let mut entry = self.entries.get(hash)?;
+        if height > entry.height {
+            return None;
+        }
+        if self.is_active(hash) {
+            return self.entries.get(&self.active[height as usize]);
+        }
+        while entry.height > height {
+            entry = self.parent(entry)?;
+            if self.is_active(&entry.header.hash()) {
+                return self.entries.get(&self.active[height as usize]);
+            }
+        }
+        Some(entry)
+    }
+
+    pub fn header_at(&self, height: u32) -> Option<&BlockHeader> {
+        let hash = self.active.get(height as usize)?;
+        Some(&self.entries[hash].header)
+    }
+
+    // The active chain from the tip back, ten blocks one by one and then in
+    // doubling steps, always ending with genesis
+    pub fn locator(&self) -> Vec<Vec<u8>> {
+        let mut result = vec![];
+        let mut height = self.height() as usize;
+        let mut step = 1;
+        while height > 0 {
+            result.push(self.active[height].clone());
+            if result.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        result.push(self.active[0].clone());
+        result
+    }
+
+    // The median timestamp of the block and the 10 before it
+    pub fn median_time_past(&self, hash: &[u8]) -> Option<u32> {
+        let mut entry = self.entries.get(hash)?;
+        let mut timestamps = vec![entry.header.timestamp];
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match self.parent(entry) {
+                Some(parent) => entry = parent,
+                None => break,
+            }
+            timestamps.push(entry.header.timestamp);
+        }
+        timestamps.sort_unstable();
+        Some(timestamps[timestamps.len() / 2])
+    }
+
+    // The bits a child of `prev` with this timestamp must have
+    pub fn next_bits(&self, prev: &ChainEntry, timestamp: u32) -> Result<u32> {
+        let pow_limit = self.network.pow_limit_bits();
+        let height = prev.height + 1;
+        if !height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+            if !self.network.allows_min_difficulty_blocks() {
+                return Ok(prev.header.bits);
+            }
+            if timestamp > prev.header.timestamp.saturating_add(TARGET_SPACING * 2) {
+                return Ok(pow_limit);
+            }
+            // Otherwise the bits of the last block not mined under the
+            // 20-minute rule
+            let mut entry = prev;
+            while !entry.height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
+                && entry.header.bits == pow_limit
+            {
+                match self.parent(entry) {
+                    Some(parent) => entry = parent,
+                    None => break,
+                }
+            }
+            return Ok(entry.header.bits);
+        }
+        if self.network.no_retargeting() {
+            return Ok(prev.header.bits);
+        }
+        // The timespan covers 2015 intervals, an off-by-one that consensus
+        // has to keep
+        let first = self
+            .ancestor(&prev.header.hash(), height - DIFFICULTY_ADJUSTMENT_INTERVAL)
+            .ok_or_else(|| anyhow!("Missing the first block of the period"))?;
+        let timespan = prev.header.timestamp.saturating_sub(first.header.timestamp);
+        let bits = if self.network.enforces_bip94() {
+            first.header.bits
+        } else {
+            prev.header.bits
+        };
+        calculate_new_bits(bits, timespan, self.network)
+    }
+
+    // Returns whether the header became the new tip. Headers already in the
+    // chain are ignored.
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<bool> {
+        self.connect(header, true)
+    }
+
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<bool> {
+        let mut reorged = false;
+        for header in headers {
+            reorged |= self.add_header(header.clone())?;
+        }
+        Ok(reorged)
+    }
+
+    // Proof of work and the checks against the header's ancestors
+    pub fn check_header(&self, header: &BlockHeader) -> Result<()> {
+        self.check(header, true)
+    }
+
+    fn check(&self, header: &BlockHeader, check_pow: bool) -> Result<()> {
+        let hash = header.hash();
+        let prev = self
+            .entries
+            .get(&header.prev_hash())
+            .ok_or_else(|| anyhow!("Unknown previous block {}", header.prev_block_id()))?;
+        if check_pow && !header.check_pow() {
+            return Err(anyhow!("Block {} fails proof of work", encode_hex(&hash)));
+        }
+        let expected = self.next_bits(prev, header.timestamp)?;
+        if header.bits != expected {
+            return Err(anyhow!(
+                "Block {} has bits {:08x}, expected {:08x}",
+                encode_hex(&hash),
+                header.bits,
+                expected
+            ));
+        }
+        let median_time_past = self.median_time_past(&header.prev_hash()).unwrap();
+        if header.timestamp <= median_time_past {
+            return Err(anyhow!(
+                "Block {} timestamp {} is not after median time past {}",
+                encode_hex(&hash),
+                header.timestamp,
+                median_time_past
+            ));
+        }
+        let height = prev.height + 1;
+        if self.network.enforces_bip94()
+            && height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
+            && header.timestamp < prev.header.timestamp.saturating_sub(MAX_TIMEWARP)
+        {
+            return Err(anyhow!(
+                "Block {} timestamp {} is too far before its parent",
+                encode_hex(&hash),
+                header.timestamp
+            ));
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self, header: BlockHeader, check_pow: bool) -> Result<bool> {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return Ok(false);
+        }
+        self.check(&header, check_pow)?;
+        let prev = &self.entries[&header.prev_hash()];
+        let height = prev.height + 1;
+        let entry = ChainEntry {
+            chainwork: &prev.chainwork + work(header.bits)?,
+            header,
+            height,
+        };
+        let best = entry.chainwork > self.tip().chainwork;
+        self.entries.insert(hash.clone(), entry);
+        if best {
+            self.activate(hash);
+        }
+        Ok(best)
+    }
+
+    // Rewinds the height index to the fork point and replays the new branch
+    fn activate(&mut self, hash: Vec<u8>) {
+        let mut branch = vec![];
+        let mut entry = &self.entries[&hash];
+        while !self.is_active(&entry.header.hash()) {
+            branch.push(entry.header.hash());
+            entry = self.parent(entry).unwrap();
+        }
+        self.active.truncate(entry.height as usize + 1);
+        self.active.extend(branch.into_iter().rev());
+    }
+}
+
+#[cfg(test)]
+fn child(prev: &BlockHeader, timestamp: u32, bits: u32) -> BlockHeader {
+    let mut prev_block = [0u8; 32];
+    prev_block.copy_from_slice(&prev.hash());
+    prev_block.reverse();
+    BlockHeader::new(0x20000000, prev_block, [0u8; 32], timestamp, bits, 0)
+}
+
+#[cfg(test)]
+fn mine(mut header: BlockHeader) -> BlockHeader {
+    while !header.check_pow() {
+        header.nonce += 1;
+    }
+    header
+}
+
+// Appends `count` headers `spacing` seconds apart to the tip, with the bits
+// the chain expects and no proof of work
+#[cfg(test)]
+fn extend(chain: &mut HeaderChain, count: u32, spacing: u32) {
+    for _ in 0..count {
+        let tip = chain.tip().clone();
+        let timestamp = tip.header.timestamp + spacing;
+        let bits = chain.next_bits(&tip, timestamp).unwrap();
+        chain
+            .connect(child(&tip.header, timestamp, bits), false)
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_calculate_new_bits() {
+    assert_eq!(
+        calculate_new_bits(0x1801d854, 302400, Network::Mainnet).unwrap(),
+        0x17761500
+    );
+
+    // Signet's first two retargets
+    assert_eq!(
+        calculate_new_bits(503543726, 1599332177 - 1598918400, Network::Signet).unwrap(),
+        503394215
+    );
+    assert_eq!(
+        calculate_new_bits(503394215, 1600591200 - 1599332844, Network::Signet).unwrap(),
+        503397348
+    );
+
+    // Clamped to 4x either way, and to the pow limit
+    let target = bits_to_target(0x1801d854).unwrap();
+    assert_eq!(
+        calculate_new_bits(0x1801d854, 60, Network::Mainnet).unwrap(),
+        target_to_bits(&(&target / 4))
+    );
+    assert_eq!(
+        calculate_new_bits(0x1801d854, TARGET_TIMESPAN * 10, Network::Mainnet).unwrap(),
+        target_to_bits(&(&target * 4))
+    );
+    assert_eq!(
+        calculate_new_bits(0x1d00ffff, TARGET_TIMESPAN * 2, Network::Mainnet).unwrap(),
+        0x1d00ffff
+    );
+}
+
+#[test]
+fn test_chainwork() {
+    assert_eq!(work(0x1d00ffff).unwrap(), BigInt::from(0x100010001u64));
+    assert_eq!(work(0x207fffff).unwrap(), BigInt::from(2));
+
+    let mut chain = HeaderChain::new(Network::Mainnet);
+    assert_eq!(chain.tip().chainwork, BigInt::from(0x100010001u64));
+    extend(&mut chain, 1, TARGET_SPACING);
+    assert_eq!(chain.tip().chainwork, BigInt::from(0x200020002u64));
+}
+
+#[test]
+fn test_header_chain() {
+    let mut chain = HeaderChain::new(Network::Regtest);
+    let genesis = chain.tip().header.clone();
+    let mut headers = vec![];
+    let mut prev = genesis.clone();
+    for i in 1..=20 {
+        let header = mine(child(&prev, genesis.timestamp + i * 600, 0x207fffff));
+        headers.push(header.clone());
+        prev = header;
+    }
+    assert!(chain.add_headers(&headers).unwrap());
+    assert_eq!(chain.height(), 20);
+    assert_eq!(chain.tip().header, headers[19]);
+    assert_eq!(chain.header_at(5), Some(&headers[4]));
+    assert_eq!(chain.tip().chainwork, BigInt::from(42));
+    assert_eq!(
+        chain.median_time_past(&headers[19].hash()),
+        Some(headers[14].timestamp)
+    );
+    assert!(!chain.add_header(headers[3].clone()).unwrap());
+
+    let tip = chain.tip().header.clone();
+    let orphan = mine(child(
+        &child(&tip, tip.timestamp + 1, 0x207fffff),
+        tip.timestamp + 2,
+        0x207fffff,
+    ));
+    assert!(chain
+        .add_header(orphan)
+        .unwrap_err()
+        .to_string()
+        .starts_with("Unknown previous block"));
+
+    let mut header = child(&tip, tip.timestamp + 600, 0x207fffff);
+    while header.check_pow() {
+        header.nonce += 1;
+    }
+    assert!(chain
+        .add_header(header)
+        .unwrap_err()
+        .to_string()
+        .ends_with("fails proof of work"));
+
+    let header = mine(child(&tip, tip.timestamp + 600, 0x2000ffff));
+    assert!(chain
+        .add_header(header)
+        .unwrap_err()
+        .to_string()
+        .ends_with("has bits 2000ffff, expected 207fffff"));
+
+    let header = mine(child(&tip, headers[14].timestamp, 0x207fffff));
+    assert!(chain
+        .add_header(header)
+        .unwrap_err()
+        .to_string()
+        .contains("is not after median time past"));
+    assert_eq!(chain.len(), 21);
+
+    // A longer fork from block 10 takes over
+    let mut prev = headers[9].clone();
+    let mut fork = vec![];
+    for i in 11..=22 {
+        let header = mine(child(&prev, genesis.timestamp + i * 600 + 1, 0x207fffff));
+        fork.push(header.clone());
+        prev = header;
+    }
+    assert!(!chain.add_headers(&fork[..10]).unwrap());
+    assert_eq!(chain.tip().header, headers[19]);
+    assert!(chain.add_headers(&fork[10..]).unwrap());
+    assert_eq!(chain.height(), 22);
+    assert_eq!(chain.header_at(11), Some(&fork[0]));
+    assert_eq!(chain.header_at(10), Some(&headers[9]));
+    assert!(!chain.is_active(&headers[19].hash()));
+    assert_eq!(
+        chain.ancestor(&headers[19].hash(), 10).unwrap().header,
+        headers[9]
+    );
+    assert_eq!(
+        chain.ancestor(&headers[19].hash(), 15).unwrap().header,
+        headers[14]
+    );
+}
+
+#[test]
+fn test_retarget() {
+    // Blocks every 5 minutes about halve the target, and every second hit
+    // the clamp
+    let pow_limit = bits_to_target(0x1d00ffff).unwrap();
+    let cases = [
+        (300, &pow_limit * 300 * 2015 / TARGET_TIMESPAN),
+        (1, &pow_limit / 4),
+    ];
+    for (spacing, target) in cases.iter() {
+        let mut chain = HeaderChain::new(Network::Mainnet);
+        extend(&mut chain, DIFFICULTY_ADJUSTMENT_INTERVAL - 1, *spacing);
+        assert_eq!(chain.tip().header.bits, 0x1d00ffff);
+
+        extend(&mut chain, 1, *spacing);
+        assert_eq!(chain.height(), DIFFICULTY_ADJUSTMENT_INTERVAL);
+        assert_eq!(chain.tip().header.bits, target_to_bits(target));
+
+        // Mainnet has no minimum-difficulty exception
+        let tip = chain.tip().clone();
+        let late = child(&tip.header, tip.header.timestamp + 3600, 0x1d00ffff);
+        assert!(chain.connect(late, false).is_err());
+    }
+}
+
+#[test]
+fn test_min_difficulty_blocks() {
+    let mut chain = HeaderChain::new(Network::Testnet3);
+    extend(&mut chain, DIFFICULTY_ADJUSTMENT_INTERVAL, 300);
+    let bits = chain.tip().header.bits;
+    assert_ne!(bits, 0x1d00ffff);
+    extend(&mut chain, 5, 300);
+
+    // More than 20 minutes after its parent a block may use the pow limit,
+    // and the block after it goes back to the real difficulty
+    let tip = chain.tip().clone();
+    assert_eq!(
+        chain.next_bits(&tip, tip.header.timestamp + 1200).unwrap(),
+        bits
+    );
+    assert_eq!(
+        chain.next_bits(&tip, tip.header.timestamp + 1201).unwrap(),
+        0x1d00ffff
+    );
+    extend(&mut chain, 1, 1201);
+    assert_eq!(chain.tip().header.bits, 0x1d00ffff);
+    extend(&mut chain, 1, 300);
+    assert_eq!(chain.tip().header.bits, bits);
+
+    // A parent at the end of time doesn't overflow
+    let mut tip = chain.tip().clone();
+    tip.header.timestamp = u32::MAX - 100;
+    assert_eq!(chain.next_bits(&tip, u32::MAX).unwrap(), bits);
+}
+
+#[test]
+fn test_bip94() {
+    // The second period ends with a minimum-difficulty block. Testnet3
+    // retargets from it, testnet4 from the first block of the period.
+    let mut results = vec![];
+    for network in &[Network::Testnet3, Network::Testnet4] {
+        let mut chain = HeaderChain::new(*network);
+        extend(&mut chain, DIFFICULTY_ADJUSTMENT_INTERVAL, 300);
+        let bits = chain.tip().header.bits;
+        extend(&mut chain, DIFFICULTY_ADJUSTMENT_INTERVAL - 2, 300);
+        extend(&mut chain, 1, 1201);
+        assert_eq!(chain.tip().header.bits, 0x1d00ffff);
+
+        let tip = chain.tip().clone();
+        let timespan = tip.header.timestamp
+            - chain
+                .header_at(DIFFICULTY_ADJUSTMENT_INTERVAL)
+                .unwrap()
+                .timestamp;
+        let next = chain.next_bits(&tip, tip.header.timestamp + 300).unwrap();
+        results.push(next);
+        if network.enforces_bip94() {
+            assert_eq!(next, calculate_new_bits(bits, timespan, *network).unwrap());
+
+            // The first block of a period can't be 10 minutes older than its parent
+            let early = child(&tip.header, tip.header.timestamp - 601, next);
+            assert!(chain
+                .connect(early, false)
+                .unwrap_err()
+                .to_string()
+                .ends_with("is too far before its parent"));
+            let early = child(&tip.header, tip.header.timestamp - 600, next);
+            chain.connect(early, false).unwrap();
+        } else {
+            assert_eq!(
+                next,
+                calculate_new_bits(0x1d00ffff, timespan, *network).unwrap()
+            );
+        }
+    }
+    assert_ne!(results[0], results[1]);
+}
+
+#[test]
+fn test_locator() {
+    let mut chain = HeaderChain::new(Network::Regtest);
+    assert_eq!(chain.locator(), vec![Network::Regtest.genesis_hash()]);
+    extend(&mut chain, 100, TARGET_SPACING);
+    let locator = chain.locator();
+    let heights: Vec<u32> = locator
+        .iter()
+        .map(|hash| chain.get(hash).unwrap().height)
+        .collect();
+    assert_eq!(
+        heights,
+        vec![100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 89, 85, 77, 61, 29, 0]
+    );
+}
@@ -0,0 +1,484 @@
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use num_bigint::{BigInt, Sign};
+use rand::RngCore;
+use serde_json::{json, Value};
+use std::io::Cursor;
+use std::str::FromStr;
+
+use crate::bip32::{DerivationPath, ExtendedPubKey};
+use crate::helper::{
+    convert_bits, decode_base58, decode_base58_checksum, decode_bech32, decode_hex,
+    decode_segwit_address, encode_base58, encode_base58_checksum, encode_bech32, encode_hex,
+    encode_segwit_address, hash160, hash256, Bech32Variant,
+};
+use crate::network::Network;
+use crate::s256::{PrivateKey, S256Point};
+use crate::script::Script;
+use crate::signmessage::{sign_message, verify_message};
+use crate::transaction::Tx;
+
+#[derive(Debug, Parser)]
+#[command(name = "programming-bitcoin", about = "Bitcoin utilities")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Decode a raw transaction to JSON")]
+    DecodeTx {
+        hex: String,
+        #[arg(long, default_value = "main", help = "Network for output addresses")]
+        network: Network,
+    },
+    #[command(about = "Derive an address from a WIF key or an xpub")]
+    Address {
+        key: String,
+        #[arg(long = "type", value_enum, default_value = "p2wpkh")]
+        address_type: AddressType,
+        #[arg(long, help = "Path below the xpub, like m/0/5")]
+        path: Option<DerivationPath>,
+        #[arg(long, help = "Defaults to the key's own network")]
+        network: Option<Network>,
+    },
+    #[command(about = "Sign a message with a WIF key, Bitcoin Core style")]
+    SignMessage { wif: String, message: String },
+    #[command(about = "Verify a signed message against an address")]
+    VerifyMessage {
+        address: String,
+        signature: String,
+        message: String,
+    },
+    #[command(about = "RIPEMD160(SHA256(hex))")]
+    Hash160 { hex: String },
+    #[command(about = "SHA256(SHA256(hex))")]
+    Hash256 { hex: String },
+    #[command(about = "Encode or decode base58")]
+    Base58 {
+        #[command(subcommand)]
+        action: Base58Action,
+    },
+    #[command(about = "Encode or decode bech32 and bech32m")]
+    Bech32 {
+        #[command(subcommand)]
+        action: Bech32Action,
+    },
+    #[command(about = "Generate a new private key")]
+    NewKey {
+        #[arg(long, default_value = "main")]
+        network: Network,
+        #[arg(long)]
+        uncompressed: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Base58Action {
+    Encode {
+        hex: String,
+        #[arg(long, help = "Append a 4-byte checksum")]
+        check: bool,
+    },
+    Decode {
+        s: String,
+        #[arg(long, help = "Verify and strip the 4-byte checksum")]
+        check: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Bech32Action {
+    Encode {
+        hrp: String,
+        hex: String,
+        #[arg(long)]
+        bech32m: bool,
+        #[arg(long, help = "Encode a segwit address with this version")]
+        witness_version: Option<u8>,
+    },
+    Decode {
+        s: String,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ValueEnum)]
+pub enum AddressType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+}
+
+fn address(
+    point: &S256Point,
+    compressed: bool,
+    address_type: AddressType,
+    network: Network,
+) -> Result<String> {
+    if !compressed && address_type != AddressType::P2pkh {
+        return Err(anyhow!("Uncompressed keys only have P2PKH addresses"));
+    }
+    let h160 = point.hash160(compressed);
+    let script = match address_type {
+        AddressType::P2pkh => Script::p2pkh(&h160),
+        AddressType::P2shP2wpkh => Script::p2sh(&hash160(&Script::p2wpkh(&h160).raw_serialize())),
+        AddressType::P2wpkh => Script::p2wpkh(&h160),
+        AddressType::P2tr => Script::p2tr(&point.tap_tweak(None)?.xonly()),
+    };
+    Ok(script.address(network).unwrap())
+}
+
+fn script_json(script: &Script, network: Network) -> Value {
+    let mut result = json!({
+        "asm": script.to_string(),
+        "hex": encode_hex(&script.raw_serialize()),
+    });
+    if let Some(address) = script.address(network) {
+        result["address"] = json!(address);
+    }
+    result
+}
+
+fn decode_tx(hex: &str, network: Network) -> Result<Value> {
+    let raw = decode_hex(hex.trim())?;
+    let mut reader = Cursor::new(&raw);
+    let tx = Tx::parse(&mut reader, network)?;
+    if reader.position() != raw.len() as u64 {
+        return Err(anyhow!("Trailing bytes after transaction"));
+    }
+    let vin: Vec<Value> = tx
+        .tx_ins
+        .iter()
+        .map(|tx_in| {
+            json!({
+                "txid": tx_in.prev_tx_id(),
+                "vout": tx_in.prev_index,
+                "script_sig": script_json(&tx_in.script_sig, network),
+                "witness": tx_in.witness.iter().map(|item| encode_hex(item)).collect::<Vec<_>>(),
+                "sequence": tx_in.sequence,
+            })
+        })
+        .collect();
+    let vout: Vec<Value> = tx
+        .tx_outs
+        .iter()
+        .enumerate()
+        .map(|(n, tx_out)| {
+            json!({
+                "n": n,
+                "value": tx_out.amount,
+                "script_pubkey": script_json(&tx_out.script_pubkey, network),
+            })
+        })
+        .collect();
+    Ok(json!({
+        "txid": tx.id(),
+        "wtxid": tx.wtxid(),
+        "version": tx.version,
+        "locktime": tx.locktime,
+        "size": raw.len(),
+        "vsize": tx.vsize(),
+        "weight": tx.weight(),
+        "vin": vin,
+        "vout": vout,
+    }))
+}
+
+fn new_key(network: Network, compressed: bool) -> Result<Value> {
+    let key = loop {
+        let mut b = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut b);
+        if let Ok(key) = PrivateKey::new(BigInt::from_bytes_be(Sign::Plus, &b)) {
+            break key;
+        }
+    };
+    let mut result = json!({
+        "wif": key.wif(compressed, network),
+        "public_key": encode_hex(&key.point.sec(compressed)),
+        "p2pkh": address(&key.point, compressed, AddressType::P2pkh, network)?,
+    });
+    if compressed {
+        result["p2wpkh"] = json!(address(&key.point, true, AddressType::P2wpkh, network)?);
+        result["p2tr"] = json!(address(&key.point, true, AddressType::P2tr, network)?);
+    }
+    Ok(result)
+}
+
+// BIP173: 1 to 83 printable ASCII characters, written in lowercase
+fn bech32_hrp(hrp: &str) -> Result<String> {
+    if hrp.is_empty() || hrp.len() > 83 || hrp.bytes().any(|b| !(33..=126).contains(&b)) {
+        return Err(anyhow!("Invalid bech32 hrp {}", hrp));
+    }
+    if hrp.to_lowercase() != hrp && hrp.to_uppercase() != hrp {
+        return Err(anyhow!("Mixed case bech32 hrp {}", hrp));
+    }
+    Ok(hrp.to_lowercase())
+}
+
+fn bech32_decode(s: &str) -> Result<Value> {
+    let (hrp, data, variant) = decode_bech32(s)?;
+    let mut result = json!({
+        "hrp": hrp,
+        "encoding": match variant {
+            Bech32Variant::Bech32 => "bech32",
+            Bech32Variant::Bech32m => "bech32m",
+        },
+    });
+    if let Ok((_, version, program)) = decode_segwit_address(s) {
+        result["witness_version"] = json!(version);
+        result["witness_program"] = json!(encode_hex(&program));
+    } else {
+        result["data"] = json!(encode_hex(&convert_bits(&data, 5, 8, false)?));
+    }
+    Ok(result)
+}
+
+pub fn run(command: Command) -> Result<String> {
+    Ok(match command {
+        Command::DecodeTx { hex, network } => {
+            serde_json::to_string_pretty(&decode_tx(&hex, network)?)?
+        }
+        Command::Address {
+            key,
+            address_type,
+            path,
+            network,
+        } => match PrivateKey::parse_wif(&key) {
+            Ok((private_key, compressed, wif_network)) => {
+                if path.is_some() {
+                    return Err(anyhow!("A path needs an xpub, not a WIF key"));
+                }
+                let network = network.unwrap_or(wif_network);
+                address(&private_key.point, compressed, address_type, network)?
+            }
+            Err(_) => {
+                let mut xpub = ExtendedPubKey::from_str(&key)
+                    .map_err(|_| anyhow!("{} is neither a WIF key nor an xpub", key))?;
+                if let Some(path) = path {
+                    xpub = xpub.derive_path(&path)?;
+                }
+                let network = network.unwrap_or(xpub.network);
+                address(&xpub.public_key, true, address_type, network)?
+            }
+        },
+        Command::SignMessage { wif, message } => {
+            let (key, compressed, _) = PrivateKey::parse_wif(&wif)?;
+            sign_message(&key, compressed, &message)
+        }
+        Command::VerifyMessage {
+            address,
+            signature,
+            message,
+        } => {
+            if !verify_message(&address, &signature, &message)? {
+                return Err(anyhow!("Signature does not match {}", address));
+            }
+            "true".to_string()
+        }
+        Command::Hash160 { hex } => encode_hex(&hash160(&decode_hex(&hex)?)),
+        Command::Hash256 { hex } => encode_hex(&hash256(&decode_hex(&hex)?)),
+        Command::Base58 { action } => match action {
+            Base58Action::Encode { hex, check: true } => encode_base58_checksum(&decode_hex(&hex)?),
+            Base58Action::Encode { hex, check: false } => encode_base58(&decode_hex(&hex)?),
+            Base58Action::Decode { s, check: true } => encode_hex(&decode_base58_checksum(&s)?),
+            Base58Action::Decode { s, check: false } => encode_hex(&decode_base58(&s)?),
+        },
+        Command::Bech32 { action } => match action {
+            Bech32Action::Encode {
+                hrp,
+                hex,
+                bech32m,
+                witness_version,
+            } => {
+                let hrp = bech32_hrp(&hrp)?;
+                let data = decode_hex(&hex)?;
+                match witness_version {
+                    Some(version) => {
+                        if version > 16 {
+                            return Err(anyhow!("Invalid witness version {}", version));
+                        }
+                        let s = encode_segwit_address(&hrp, version, &data);
+                        // Catches bad versions and program lengths
+                        decode_segwit_address(&s)?;
+                        s
+                    }
+                    None => {
+                        let variant = if bech32m {
+                            Bech32Variant::Bech32m
+                        } else {
+                            Bech32Variant::Bech32
+                        };
+                        encode_bech32(&hrp, &convert_bits(&data, 8, 5, true)?, variant)
+                    }
+                }
+            }
+            Bech32Action::Decode { s } => serde_json::to_string_pretty(&bech32_decode(&s)?)?,
+        },
+        Command::NewKey {
+            network,
+            uncompressed,
+        } => serde_json::to_string_pretty(&new_key(network, !uncompressed)?)?,
+    })
+}
+
+#[cfg(test)]
+fn test_run(args: &[&str]) -> Result<String> {
+    let cli = Cli::try_parse_from([&["programming-bitcoin"], args].concat())?;
+    run(cli.command)
+}
+
+#[test]
+fn test_decode_tx() {
+    let hex = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+    let json: Value = serde_json::from_str(&test_run(&["decode-tx", hex]).unwrap()).unwrap();
+    assert_eq!(
+        json["txid"],
+        "452c629d67e41baec3ac6f04fe744b4b9617f8f859c63b3002f8684e7a4fee03"
+    );
+    assert_eq!(json["locktime"], 410393);
+    assert_eq!(
+        json["vin"][0]["txid"],
+        "d1c789a9c60383bf715f3f6ad9d14b91fe55f3deb369fe5d9280cb1a01793f81"
+    );
+    assert_eq!(json["vin"][0]["sequence"], 0xfffffffeu32);
+    assert_eq!(json["vout"][0]["value"], 32454049);
+    assert_eq!(
+        json["vout"][1]["script_pubkey"]["address"],
+        "13achaY7hdFTEHCzWC1Cvuo1FDKzDtAvRt"
+    );
+    assert_eq!(
+        json["vout"][1]["script_pubkey"]["asm"],
+        "OP_DUP OP_HASH160 1c4bc762dd5423e332166702cb75f40df79fea12 OP_EQUALVERIFY OP_CHECKSIG"
+    );
+    assert!(test_run(&["decode-tx", &format!("{}00", hex)]).is_err());
+    assert!(test_run(&["decode-tx", "zz"]).is_err());
+}
+
+#[test]
+fn test_address() {
+    let wif = PrivateKey::new(BigInt::from(1))
+        .unwrap()
+        .wif(true, Network::Mainnet);
+    assert_eq!(
+        test_run(&["address", &wif, "--type", "p2pkh"]).unwrap(),
+        "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH"
+    );
+    assert_eq!(
+        test_run(&["address", &wif]).unwrap(),
+        "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+    );
+    assert_eq!(
+        test_run(&["address", &wif, "--type", "p2wpkh", "--network", "regtest"]).unwrap(),
+        "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080"
+    );
+    let uncompressed = PrivateKey::new(BigInt::from(1))
+        .unwrap()
+        .wif(false, Network::Mainnet);
+    assert!(test_run(&["address", &uncompressed]).is_err());
+
+    // m/0H/1 from BIP32 test vector 1. Hardened children need the xprv.
+    let xpub = "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ";
+    assert_eq!(
+        test_run(&["address", xpub, "--type", "p2pkh"]).unwrap(),
+        "1JQheacLPdM5ySCkrZkV66G2ApAXe1mqLj"
+    );
+    assert!(test_run(&["address", xpub, "--path", "m/2h"]).is_err());
+    assert!(
+        test_run(&["address", xpub, "--path", "m/2", "--type", "p2tr"])
+            .unwrap()
+            .starts_with("bc1p")
+    );
+    assert!(test_run(&["address", "not-a-key"]).is_err());
+}
+
+#[test]
+fn test_messages() {
+    let wif = PrivateKey::new(BigInt::from(12345))
+        .unwrap()
+        .wif(true, Network::Mainnet);
+    let address = test_run(&["address", &wif, "--type", "p2pkh"]).unwrap();
+    let signature = test_run(&["sign-message", &wif, "hello world"]).unwrap();
+    assert_eq!(
+        test_run(&["verify-message", &address, &signature, "hello world"]).unwrap(),
+        "true"
+    );
+    assert!(test_run(&["verify-message", &address, &signature, "hello"]).is_err());
+}
+
+#[test]
+fn test_encodings() {
+    assert_eq!(
+        test_run(&[
+            "hash160",
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        ])
+        .unwrap(),
+        "751e76e8199196d454941c45d1b3a323f1433bd6"
+    );
+    assert_eq!(
+        test_run(&["hash256", ""]).unwrap(),
+        "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456"
+    );
+    assert_eq!(test_run(&["base58", "encode", "00000102"]).unwrap(), "115T");
+    assert_eq!(test_run(&["base58", "decode", "115T"]).unwrap(), "00000102");
+    let checked = test_run(&["base58", "encode", "--check", "00000102"]).unwrap();
+    assert_eq!(
+        test_run(&["base58", "decode", "--check", &checked]).unwrap(),
+        "00000102"
+    );
+    assert!(test_run(&["base58", "decode", "--check", "115T"]).is_err());
+
+    let address = test_run(&[
+        "bech32",
+        "encode",
+        "bc",
+        "751e76e8199196d454941c45d1b3a323f1433bd6",
+        "--witness-version",
+        "0",
+    ])
+    .unwrap();
+    assert_eq!(address, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+    let json: Value =
+        serde_json::from_str(&test_run(&["bech32", "decode", &address]).unwrap()).unwrap();
+    assert_eq!(json["encoding"], "bech32");
+    assert_eq!(json["witness_version"], 0);
+    assert_eq!(
+        json["witness_program"],
+        "751e76e8199196d454941c45d1b3a323f1433bd6"
+    );
+    let encoded = test_run(&["bech32", "encode", "test", "cafe", "--bech32m"]).unwrap();
+    let json: Value =
+        serde_json::from_str(&test_run(&["bech32", "decode", &encoded]).unwrap()).unwrap();
+    assert_eq!(json["hrp"], "test");
+    assert_eq!(json["encoding"], "bech32m");
+    assert_eq!(json["data"], "cafe");
+
+    // Uppercase HRPs are written in lowercase, anything else invalid is an error
+    assert_eq!(
+        test_run(&["bech32", "encode", "BC", "cafe"]).unwrap(),
+        test_run(&["bech32", "encode", "bc", "cafe"]).unwrap()
+    );
+    for hrp in ["", "Bc", "\u{fc}", &"a".repeat(84)].iter() {
+        assert!(test_run(&["bech32", "encode", hrp, "cafe"]).is_err());
+    }
+    assert!(test_run(&["bech32", "encode", "bc", "cafe", "--witness-version", "40"]).is_err());
+}
+
+#[test]
+fn test_new_key() {
+    let json: Value =
+        serde_json::from_str(&test_run(&["new-key", "--network", "test"]).unwrap()).unwrap();
+    let (key, compressed, network) = PrivateKey::parse_wif(json["wif"].as_str().unwrap()).unwrap();
+    assert!(compressed);
+    assert_eq!(network, Network::Testnet3);
+    assert_eq!(json["p2pkh"], key.point.address(true, Network::Testnet3));
+    assert!(json["p2wpkh"].as_str().unwrap().starts_with("tb1q"));
+    assert!(json["p2tr"].as_str().unwrap().starts_with("tb1p"));
+
+    let json: Value =
+        serde_json::from_str(&test_run(&["new-key", "--uncompressed"]).unwrap()).unwrap();
+    assert!(json["wif"].as_str().unwrap().starts_with('5'));
+    assert!(json.get("p2wpkh").is_none());
+}
@@ -0,0 +1,696 @@
+use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use rand::RngCore;
+
+use crate::amount::{Amount, FeeRate};
+use crate::script::Script;
+use crate::utxo::OutPoint;
+
+// Weight of an input without its scriptSig or witness: outpoint, sequence
+// and the scriptSig length
+const TXIN_BASE_WEIGHT: usize = (32 + 4 + 4 + 1) * 4;
+
+// Branch and bound gives up after this many steps
+pub const BNB_TOTAL_TRIES: usize = 100_000;
+
+// Knapsack's rounds of random subsets
+const KNAPSACK_ITERATIONS: usize = 1000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InputType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+}
+
+impl InputType {
+    // Nested segwit can't be told apart from other P2SH, so it's assumed
+    pub fn from_script(script_pubkey: &Script) -> Option<Self> {
+        if script_pubkey.is_p2pkh() {
+            Some(InputType::P2pkh)
+        } else if script_pubkey.is_p2sh() {
+            Some(InputType::P2shP2wpkh)
+        } else if script_pubkey.is_p2wpkh() {
+            Some(InputType::P2wpkh)
+        } else if script_pubkey.is_p2tr() {
+            Some(InputType::P2tr)
+        } else {
+            None
+        }
+    }
+
+    // Worst case weight of the whole input once signed, with 72-byte DER
+    // signatures and compressed keys
+    pub fn input_weight(&self) -> usize {
+        // Item count, then a signature and key with their lengths
+        let p2wpkh_witness = 1 + 1 + 72 + 1 + 33;
+        match self {
+            InputType::P2pkh => TXIN_BASE_WEIGHT + (1 + 72 + 1 + 33) * 4,
+            InputType::P2shP2wpkh => TXIN_BASE_WEIGHT + 23 * 4 + p2wpkh_witness,
+            InputType::P2wpkh => TXIN_BASE_WEIGHT + p2wpkh_witness,
+            InputType::P2tr => TXIN_BASE_WEIGHT + 1 + 1 + 64,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Candidate {
+    pub outpoint: OutPoint,
+    pub amount: Amount,
+    pub input_weight: usize,
+}
+
+impl Candidate {
+    pub fn new(outpoint: OutPoint, amount: Amount, input_type: InputType) -> Self {
+        Self {
+            outpoint,
+            amount,
+            input_weight: input_type.input_weight(),
+        }
+    }
+
+    // What the coin adds to the transaction after paying for its own input.
+    // Negative for coins which aren't worth spending at this fee rate.
+    pub fn effective_value(&self, fee_rate: FeeRate) -> i64 {
+        self.amount.to_sat() as i64 - fee_rate.fee(self.input_weight).to_sat() as i64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SelectionParams {
+    // The sum of the payment outputs
+    pub target: Amount,
+    pub fee_rate: FeeRate,
+    // The fee rate the wallet expects to spend change at later
+    pub long_term_fee_rate: FeeRate,
+    // Weight of the transaction without inputs or change
+    pub base_weight: usize,
+    pub change_weight: usize,
+    pub change_spend_weight: usize,
+    // Change below this goes to fees instead
+    pub min_change: Amount,
+}
+
+impl SelectionParams {
+    // Defaults for a P2WPKH wallet
+    pub fn new(target: Amount, fee_rate: FeeRate, base_weight: usize) -> Self {
+        Self {
+            target,
+            fee_rate,
+            long_term_fee_rate: FeeRate::from_sat_per_vb(10),
+            base_weight,
+            change_weight: (8 + 1 + 22) * 4,
+            change_spend_weight: InputType::P2wpkh.input_weight(),
+            min_change: Amount::from_sat(294),
+        }
+    }
+
+    // The effective value the inputs must add up to
+    fn selection_target(&self) -> i64 {
+        (self.target + self.fee_rate.fee(self.base_weight)).to_sat() as i64
+    }
+
+    fn change_fee(&self) -> i64 {
+        self.fee_rate.fee(self.change_weight).to_sat() as i64
+    }
+
+    // Creating change now and spending it later
+    fn cost_of_change(&self) -> i64 {
+        self.change_fee()
+            + self
+                .long_term_fee_rate
+                .fee(self.change_spend_weight)
+                .to_sat() as i64
+    }
+
+    // Enough for the payment, a change output and change worth keeping
+    fn target_with_change(&self) -> i64 {
+        self.selection_target() + self.change_fee() + self.min_change.to_sat() as i64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub selected: Vec<Candidate>,
+    pub fee: Amount,
+    pub change: Option<Amount>,
+    // Bitcoin Core's waste metric: how much more this selection costs than
+    // spending the same inputs at the long term fee rate without change
+    pub waste: i64,
+}
+
+impl Selection {
+    // Pays the payment and fees, keeping any excess as change when it is
+    // worth a change output and giving it to the miner otherwise
+    fn new(selected: Vec<Candidate>, params: &SelectionParams, allow_change: bool) -> Self {
+        let fee_rate = params.fee_rate;
+        let input_value: i64 = selected
+            .iter()
+            .map(|candidate| candidate.effective_value(fee_rate))
+            .sum();
+        let excess = input_value - params.selection_target();
+        let input_fees: Amount = selected
+            .iter()
+            .map(|candidate| fee_rate.fee(candidate.input_weight))
+            .sum();
+        let mut waste: i64 = selected
+            .iter()
+            .map(|candidate| {
+                fee_rate.fee(candidate.input_weight).to_sat() as i64
+                    - params
+                        .long_term_fee_rate
+                        .fee(candidate.input_weight)
+                        .to_sat() as i64
+            })
+            .sum();
+        let fee = fee_rate.fee(params.base_weight) + input_fees;
+        let change = excess - params.change_fee();
+        if allow_change && change >= params.min_change.to_sat() as i64 {
+            waste += params.cost_of_change();
+            Self {
+                selected,
+                fee: fee + fee_rate.fee(params.change_weight),
+                change: Some(Amount::from_sat(change as u64)),
+                waste,
+            }
+        } else {
+            waste += excess;
+            Self {
+                selected,
+                fee: fee + Amount::from_sat(excess as u64),
+                change: None,
+                waste,
+            }
+        }
+    }
+
+    pub fn input_value(&self) -> Amount {
+        self.selected.iter().map(|candidate| candidate.amount).sum()
+    }
+}
+
+pub trait CoinSelector {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        params: &SelectionParams,
+        rng: &mut dyn RngCore,
+    ) -> Result<Selection>;
+}
+
+// Coins worth spending at the fee rate, most valuable first
+fn spendable(candidates: &[Candidate], fee_rate: FeeRate) -> Vec<Candidate> {
+    let mut result: Vec<Candidate> = candidates
+        .iter()
+        .filter(|candidate| candidate.effective_value(fee_rate) > 0)
+        .cloned()
+        .collect();
+    result.sort_by_key(|candidate| -candidate.effective_value(fee_rate));
+    result
+}
+
+fn insufficient_funds(
+    candidates: &[Candidate],
+    params: &SelectionParams,
+    needed: i64,
+) -> anyhow::Error {
+    let available: i64 = candidates
+        .iter()
+        .map(|candidate| candidate.effective_value(params.fee_rate))
+        .sum();
+    anyhow!(
+        "Insufficient funds: {} satoshis available after fees, {} needed",
+        available,
+        needed
+    )
+}
+
+// Searches for an input set which needs no change, within the cost of
+// change of the target, with the least waste
+pub struct BranchAndBound;
+
+impl CoinSelector for BranchAndBound {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        params: &SelectionParams,
+        _rng: &mut dyn RngCore,
+    ) -> Result<Selection> {
+        let candidates = spendable(candidates, params.fee_rate);
+        let values: Vec<i64> = candidates
+            .iter()
+            .map(|candidate| candidate.effective_value(params.fee_rate))
+            .collect();
+        let wastes: Vec<i64> = candidates
+            .iter()
+            .map(|candidate| {
+                params.fee_rate.fee(candidate.input_weight).to_sat() as i64
+                    - params
+                        .long_term_fee_rate
+                        .fee(candidate.input_weight)
+                        .to_sat() as i64
+            })
+            .collect();
+        let target = params.selection_target();
+        let upper = target + params.cost_of_change();
+        let mut available: i64 = values.iter().sum();
+        if available < target {
+            return Err(insufficient_funds(&candidates, params, target));
+        }
+
+        let mut selected: Vec<usize> = vec![];
+        let mut best: Option<Vec<usize>> = None;
+        let mut best_waste = i64::MAX;
+        let mut value = 0;
+        let mut waste = 0;
+        let mut next = 0;
+        for _ in 0..BNB_TOTAL_TRIES {
+            // While fees are high, more inputs only add waste
+            let wasteful = waste > best_waste && params.fee_rate > params.long_term_fee_rate;
+            let backtrack = if value + available < target || value > upper || wasteful {
+                true
+            } else if value >= target {
+                if waste + value - target <= best_waste {
+                    best_waste = waste + value - target;
+                    best = Some(selected.clone());
+                }
+                true
+            } else {
+                false
+            };
+
+            if backtrack {
+                let last = match selected.pop() {
+                    Some(last) => last,
+                    None => break,
+                };
+                // Everything after the last inclusion is available again,
+                // and the last inclusion becomes an omission
+                available += values[last + 1..next].iter().sum::<i64>();
+                value -= values[last];
+                waste -= wastes[last];
+                next = last + 1;
+            } else {
+                available -= values[next];
+                // Including a coin equal to one just omitted finds nothing new
+                let duplicate = next > 0
+                    && selected.last() != Some(&(next - 1))
+                    && values[next] == values[next - 1]
+                    && wastes[next] == wastes[next - 1];
+                if !duplicate {
+                    selected.push(next);
+                    value += values[next];
+                    waste += wastes[next];
+                }
+                next += 1;
+            }
+        }
+
+        let best = best.ok_or_else(|| anyhow!("No changeless input set found"))?;
+        let selected = best.iter().map(|i| candidates[*i].clone()).collect();
+        Ok(Selection::new(selected, params, false))
+    }
+}
+
+// Bitcoin Core's original algorithm: an exact match, all the smaller coins,
+// the smallest larger coin, or the best of many random subsets
+pub struct Knapsack;
+
+impl Knapsack {
+    // Random subsets adding up to at least the target, keeping the smallest
+    fn best_subset(values: &[i64], target: i64, rng: &mut dyn RngCore) -> (Vec<bool>, i64) {
+        let total: i64 = values.iter().sum();
+        let mut best = vec![true; values.len()];
+        let mut best_value = total;
+        for _ in 0..KNAPSACK_ITERATIONS {
+            if best_value == target {
+                break;
+            }
+            let mut included = vec![false; values.len()];
+            let mut value = 0;
+            let mut reached = false;
+            // A random pass, then one adding everything left
+            for pass in 0..2 {
+                for i in 0..values.len() {
+                    let pick = if pass == 0 {
+                        rng.next_u32() & 1 == 1
+                    } else {
+                        !included[i]
+                    };
+                    if !pick || included[i] {
+                        continue;
+                    }
+                    value += values[i];
+                    included[i] = true;
+                    if value >= target {
+                        reached = true;
+                        if value < best_value {
+                            best_value = value;
+                            best = included.clone();
+                        }
+                        value -= values[i];
+                        included[i] = false;
+                    }
+                }
+                if reached {
+                    break;
+                }
+            }
+        }
+        (best, best_value)
+    }
+}
+
+impl CoinSelector for Knapsack {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        params: &SelectionParams,
+        rng: &mut dyn RngCore,
+    ) -> Result<Selection> {
+        let mut candidates = spendable(candidates, params.fee_rate);
+        candidates.shuffle(&mut RngWrapper(rng));
+        let value = |candidate: &Candidate| candidate.effective_value(params.fee_rate);
+        let exact = params.selection_target();
+        let target = params.target_with_change();
+
+        if let Some(candidate) = candidates.iter().find(|c| value(c) == exact) {
+            return Ok(Selection::new(vec![candidate.clone()], params, true));
+        }
+        let (smaller, larger): (Vec<Candidate>, Vec<Candidate>) =
+            candidates.into_iter().partition(|c| value(c) < target);
+        let lowest_larger = larger.into_iter().min_by_key(|c| value(c));
+        let smaller_total: i64 = smaller.iter().map(value).sum();
+        if smaller_total == exact || smaller_total == target {
+            return Ok(Selection::new(smaller, params, true));
+        }
+        if smaller_total < target {
+            if let Some(candidate) = lowest_larger {
+                return Ok(Selection::new(vec![candidate], params, true));
+            }
+            if smaller_total >= exact {
+                return Ok(Selection::new(smaller, params, true));
+            }
+            return Err(insufficient_funds(&smaller, params, exact));
+        }
+
+        let mut smaller = smaller;
+        smaller.sort_by_key(|c| -value(c));
+        let values: Vec<i64> = smaller.iter().map(value).collect();
+        let (mut best, mut best_value) = Knapsack::best_subset(&values, exact, rng);
+        if best_value != exact {
+            let (with_change, value) = Knapsack::best_subset(&values, target, rng);
+            best = with_change;
+            best_value = value;
+        }
+        // A single larger coin wins if it is closer to the target
+        if let Some(candidate) = lowest_larger {
+            if best_value != exact && value(&candidate) <= best_value {
+                return Ok(Selection::new(vec![candidate], params, true));
+            }
+        }
+        let selected = smaller
+            .into_iter()
+            .zip(best)
+            .filter(|(_, included)| *included)
+            .map(|(candidate, _)| candidate)
+            .collect();
+        Ok(Selection::new(selected, params, true))
+    }
+}
+
+// Random coins until there is enough for the payment and change
+pub struct SingleRandomDraw;
+
+impl CoinSelector for SingleRandomDraw {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        params: &SelectionParams,
+        rng: &mut dyn RngCore,
+    ) -> Result<Selection> {
+        let mut candidates = spendable(candidates, params.fee_rate);
+        candidates.shuffle(&mut RngWrapper(rng));
+        accumulate(candidates, params)
+    }
+}
+
+// The biggest coins first, for the fewest inputs
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(
+        &self,
+        candidates: &[Candidate],
+        params: &SelectionParams,
+        _rng: &mut dyn RngCore,
+    ) -> Result<Selection> {
+        accumulate(spendable(candidates, params.fee_rate), params)
+    }
+}
+
+// Takes coins in order until there is room for change, settling for a
+// changeless selection if the coins run out first
+fn accumulate(candidates: Vec<Candidate>, params: &SelectionParams) -> Result<Selection> {
+    let target = params.target_with_change();
+    let mut selected = vec![];
+    let mut value = 0;
+    for candidate in candidates.iter() {
+        value += candidate.effective_value(params.fee_rate);
+        selected.push(candidate.clone());
+        if value >= target {
+            break;
+        }
+    }
+    if value < params.selection_target() {
+        return Err(insufficient_funds(
+            &candidates,
+            params,
+            params.selection_target(),
+        ));
+    }
+    Ok(Selection::new(selected, params, true))
+}
+
+// `shuffle` needs a sized `Rng`
+struct RngWrapper<'a>(&'a mut dyn RngCore);
+
+impl<'a> RngCore for RngWrapper<'a> {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+// Runs every algorithm and keeps the selection with the least waste, as
+// Bitcoin Core does
+pub fn select_coins(
+    candidates: &[Candidate],
+    params: &SelectionParams,
+    rng: &mut dyn RngCore,
+) -> Result<Selection> {
+    let selectors: [&dyn CoinSelector; 4] =
+        [&BranchAndBound, &Knapsack, &SingleRandomDraw, &LargestFirst];
+    let mut best: Option<Selection> = None;
+    let mut error = None;
+    for selector in selectors.iter() {
+        match selector.select(candidates, params, rng) {
+            Ok(selection) => {
+                if best
+                    .as_ref()
+                    .is_none_or(|best| selection.waste < best.waste)
+                {
+                    best = Some(selection);
+                }
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    best.ok_or_else(|| error.unwrap())
+}
+
+#[cfg(test)]
+use rand::rngs::StdRng;
+#[cfg(test)]
+use rand::SeedableRng;
+
+#[cfg(test)]
+fn test_candidates(amounts: &[u64]) -> Vec<Candidate> {
+    amounts
+        .iter()
+        .enumerate()
+        .map(|(i, amount)| {
+            Candidate::new(
+                OutPoint::new(vec![i as u8; 32], 0),
+                Amount::from_sat(*amount),
+                InputType::P2wpkh,
+            )
+        })
+        .collect()
+}
+
+// Every selection pays exactly the target, fee and change
+#[cfg(test)]
+fn check_selection(selection: &Selection, params: &SelectionParams) {
+    let change = selection.change.unwrap_or(Amount::ZERO);
+    assert_eq!(
+        selection.input_value(),
+        params.target + selection.fee + change
+    );
+    let weight: usize = params.base_weight
+        + selection
+            .selected
+            .iter()
+            .map(|candidate| candidate.input_weight)
+            .sum::<usize>();
+    if selection.change.is_some() {
+        let min_fee = params.fee_rate.fee(weight + params.change_weight);
+        assert!(selection.fee >= min_fee);
+        assert!(change >= params.min_change);
+    } else {
+        assert!(selection.fee >= params.fee_rate.fee(weight));
+    }
+}
+
+#[test]
+fn test_input_weight() {
+    assert_eq!(InputType::P2pkh.input_weight(), 592);
+    assert_eq!(InputType::P2shP2wpkh.input_weight(), 364);
+    assert_eq!(InputType::P2wpkh.input_weight(), 272);
+    assert_eq!(InputType::P2tr.input_weight(), 230);
+    assert_eq!(
+        InputType::from_script(&Script::p2wpkh(&[0u8; 20])),
+        Some(InputType::P2wpkh)
+    );
+    assert_eq!(InputType::from_script(&Script::op_return(b"")), None);
+
+    let candidate = &test_candidates(&[10_000])[0];
+    assert_eq!(
+        candidate.effective_value(FeeRate::from_sat_per_vb(10)),
+        9320
+    );
+    assert!(candidate.effective_value(FeeRate::from_sat_per_vb(200)) < 0);
+}
+
+#[test]
+fn test_branch_and_bound() {
+    let fee_rate = FeeRate::from_sat_per_vb(5);
+    let input_fee = fee_rate.fee(InputType::P2wpkh.input_weight()).to_sat();
+    let base_weight = 200;
+    let base_fee = fee_rate.fee(base_weight).to_sat();
+    let mut rng = StdRng::seed_from_u64(1);
+
+    // 3 and 5 add up to the target exactly once fees are paid
+    let amounts = [1, 2, 3, 5, 8].map(|n| n * 100_000 + input_fee);
+    let candidates = test_candidates(&amounts);
+    let mut params =
+        SelectionParams::new(Amount::from_sat(800_000 - base_fee), fee_rate, base_weight);
+    // Above the long term fee rate fewer inputs waste less
+    params.long_term_fee_rate = FeeRate::from_sat_per_vb(1);
+    let selection = BranchAndBound
+        .select(&candidates, &params, &mut rng)
+        .unwrap();
+    check_selection(&selection, &params);
+    assert_eq!(selection.change, None);
+    assert_eq!(selection.selected.len(), 1);
+    assert_eq!(selection.selected[0].amount.to_sat(), amounts[4]);
+
+    // Below it more inputs waste less
+    params.long_term_fee_rate = FeeRate::from_sat_per_vb(20);
+    let selection = BranchAndBound
+        .select(&candidates, &params, &mut rng)
+        .unwrap();
+    check_selection(&selection, &params);
+    assert_eq!(selection.selected.len(), 3);
+    assert_eq!(selection.fee.to_sat(), base_fee + 3 * input_fee);
+
+    // Nothing within the cost of change
+    params.target = Amount::from_sat(50_000);
+    assert!(BranchAndBound
+        .select(&candidates, &params, &mut rng)
+        .is_err());
+    params.target = Amount::from_sat(10_000_000);
+    let err = BranchAndBound
+        .select(&candidates, &params, &mut rng)
+        .unwrap_err();
+    assert!(err.to_string().starts_with("Insufficient funds"));
+}
+
+#[test]
+fn test_change_selectors() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let candidates = test_candidates(&[5_000, 20_000, 50_000, 100_000, 1_000_000, 300]);
+    let params = SelectionParams::new(Amount::from_sat(60_000), FeeRate::from_sat_per_vb(5), 400);
+    let selectors: [&dyn CoinSelector; 3] = [&Knapsack, &SingleRandomDraw, &LargestFirst];
+    for selector in selectors.iter() {
+        for _ in 0..20 {
+            let selection = selector.select(&candidates, &params, &mut rng).unwrap();
+            check_selection(&selection, &params);
+            // The 300 satoshi coin costs more than it is worth
+            assert!(selection.selected.iter().all(|c| c.amount.to_sat() != 300));
+        }
+    }
+
+    let selection = LargestFirst.select(&candidates, &params, &mut rng).unwrap();
+    assert_eq!(selection.selected.len(), 1);
+    assert_eq!(selection.selected[0].amount, Amount::from_sat(1_000_000));
+    assert!(selection.change.is_some());
+
+    // Knapsack gets closest to the target with the smaller coins
+    let selection = Knapsack.select(&candidates, &params, &mut rng).unwrap();
+    let mut selected: Vec<u64> = selection
+        .selected
+        .iter()
+        .map(|c| c.amount.to_sat())
+        .collect();
+    selected.sort_unstable();
+    assert_eq!(selected, vec![20_000, 50_000]);
+
+    // Everything together isn't enough
+    let mut params = params;
+    params.target = Amount::from_sat(1_175_000);
+    for selector in selectors.iter() {
+        assert!(selector.select(&candidates, &params, &mut rng).is_err());
+    }
+}
+
+#[test]
+fn test_select_coins() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let fee_rate = FeeRate::from_sat_per_vb(10);
+    let input_fee = fee_rate.fee(InputType::P2wpkh.input_weight()).to_sat();
+    let base_fee = fee_rate.fee(400).to_sat();
+    let candidates = test_candidates(&[30_000 + input_fee, 1_000_000, 2_000_000]);
+
+    // A changeless match beats paying for change
+    let params = SelectionParams::new(Amount::from_sat(30_000 - base_fee), fee_rate, 400);
+    let selection = select_coins(&candidates, &params, &mut rng).unwrap();
+    check_selection(&selection, &params);
+    assert_eq!(selection.change, None);
+    assert_eq!(selection.selected[0].amount.to_sat(), 30_000 + input_fee);
+    assert_eq!(selection.waste, 0);
+
+    let params = SelectionParams::new(Amount::from_sat(1_500_000), fee_rate, 400);
+    let selection = select_coins(&candidates, &params, &mut rng).unwrap();
+    check_selection(&selection, &params);
+    assert_eq!(selection.selected.len(), 1);
+    assert_eq!(
+        selection.change.unwrap().to_sat(),
+        500_000 - selection.fee.to_sat()
+    );
+
+    let params = SelectionParams::new(Amount::from_sat(5_000_000), fee_rate, 400);
+    assert!(select_coins(&candidates, &params, &mut rng).is_err());
+}
@@ -0,0 +1,1136 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint};
+use crate::helper::{decode_hex, encode_hex, hash160, sha256};
+use crate::interpreter::{MAX_PUBKEYS_PER_MULTISIG, MAX_SCRIPT_ELEMENT_SIZE};
+use crate::miniscript::Miniscript;
+use crate::network::Network;
+use crate::op::*;
+use crate::s256::{PrivateKey, S256Point};
+use crate::script::{Cmd, Script};
+use crate::taproot::TapTree;
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATORS: [u64; 5] = [
+    0xf5dee51989,
+    0xa9fdca3312,
+    0x1bab10e32d,
+    0x3706b1677a,
+    0x644d626ffd,
+];
+
+// Bare multisig beyond this isn't standard
+const MAX_BARE_MULTISIG_KEYS: usize = 3;
+const MAX_MULTI_A_KEYS: usize = 999;
+const MAX_TAPTREE_DEPTH: usize = 128;
+
+fn polymod(c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (i, generator) in GENERATORS.iter().enumerate() {
+        if c0 >> i & 1 == 1 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+// BIP380: the 8 character checksum appended after '#'
+pub fn descriptor_checksum(s: &str) -> Result<String> {
+    let mut c = 1;
+    let mut cls = 0;
+    let mut cls_count = 0;
+    for ch in s.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .ok_or_else(|| anyhow!("Invalid character {:?} in descriptor", ch))?
+            as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Ok((0..8)
+        .map(|i| CHECKSUM_CHARSET[(c >> (5 * (7 - i)) & 31) as usize] as char)
+        .collect())
+}
+
+// Where a script expression appears, which decides the functions and key
+// encodings it may use.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Context {
+    Top,
+    Sh,
+    Wsh,
+    Tap,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeyOrigin {
+    pub fingerprint: Fingerprint,
+    pub path: DerivationPath,
+}
+
+impl fmt::Display for KeyOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}", self.fingerprint)?;
+        for child in &self.path.0 {
+            write!(f, "/{}", child)?;
+        }
+        write!(f, "]")
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Wildcard {
+    None,
+    Unhardened,
+    Hardened,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum KeyExpr {
+    // hex encoded: 33 or 65 byte SEC, or a 32 byte x-only key inside tr()
+    Public(Vec<u8>),
+    Wif {
+        key: PrivateKey<'static>,
+        compressed: bool,
+        network: Network,
+    },
+    Xpub {
+        xpub: ExtendedPubKey,
+        path: DerivationPath,
+        wildcard: Wildcard,
+    },
+    Xprv {
+        xprv: ExtendedPrivKey,
+        path: DerivationPath,
+        wildcard: Wildcard,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DescriptorKey {
+    pub origin: Option<KeyOrigin>,
+    pub key: KeyExpr,
+}
+
+fn parse_path_and_wildcard(parts: &[&str]) -> Result<(DerivationPath, Wildcard)> {
+    let mut path = DerivationPath::master();
+    let mut wildcard = Wildcard::None;
+    for (i, part) in parts.iter().enumerate() {
+        match *part {
+            "*" | "*'" | "*h" if i == parts.len() - 1 => {
+                wildcard = if *part == "*" {
+                    Wildcard::Unhardened
+                } else {
+                    Wildcard::Hardened
+                };
+            }
+            _ => path = path.child(part.parse()?),
+        }
+    }
+    Ok((path, wildcard))
+}
+
+impl DescriptorKey {
+    pub(crate) fn parse(s: &str, ctx: Context) -> Result<Self> {
+        let (origin, s) = if let Some(rest) = s.strip_prefix('[') {
+            let end = rest
+                .find(']')
+                .ok_or_else(|| anyhow!("Key origin is missing ']'"))?;
+            let mut parts = rest[..end].split('/');
+            let fingerprint = decode_hex(parts.next().unwrap())?;
+            if fingerprint.len() != 4 {
+                return Err(anyhow!("Key origin fingerprint must be 4 bytes"));
+            }
+            let mut path = DerivationPath::master();
+            for part in parts {
+                path = path.child(part.parse()?);
+            }
+            let origin = KeyOrigin {
+                fingerprint: Fingerprint([
+                    fingerprint[0],
+                    fingerprint[1],
+                    fingerprint[2],
+                    fingerprint[3],
+                ]),
+                path,
+            };
+            (Some(origin), &rest[end + 1..])
+        } else {
+            (None, s)
+        };
+
+        let parts: Vec<&str> = s.split('/').collect();
+        let key = if let Ok(b) = decode_hex(s) {
+            match (b.len(), b.first()) {
+                (32, _) if ctx == Context::Tap => {
+                    S256Point::lift_x(&b)?;
+                }
+                (33, Some(0x02)) | (33, Some(0x03)) => {
+                    S256Point::parse(&b)?;
+                }
+                (65, Some(0x04)) if ctx != Context::Wsh && ctx != Context::Tap => {
+                    S256Point::parse(&b)?;
+                }
+                _ => return Err(anyhow!("Invalid public key {} in this context", s)),
+            }
+            KeyExpr::Public(b)
+        } else if let Ok(xpub) = ExtendedPubKey::from_str(parts[0]) {
+            let (path, wildcard) = parse_path_and_wildcard(&parts[1..])?;
+            if wildcard == Wildcard::Hardened || path.0.iter().any(|c| c.is_hardened()) {
+                return Err(anyhow!("Cannot derive hardened children from {}", s));
+            }
+            KeyExpr::Xpub {
+                xpub,
+                path,
+                wildcard,
+            }
+        } else if let Ok(xprv) = ExtendedPrivKey::from_str(parts[0]) {
+            let (path, wildcard) = parse_path_and_wildcard(&parts[1..])?;
+            KeyExpr::Xprv {
+                xprv,
+                path,
+                wildcard,
+            }
+        } else if let Ok((key, compressed, network)) = PrivateKey::parse_wif(s) {
+            if !compressed && (ctx == Context::Wsh || ctx == Context::Tap) {
+                return Err(anyhow!("Uncompressed keys are not allowed in segwit"));
+            }
+            KeyExpr::Wif {
+                key,
+                compressed,
+                network,
+            }
+        } else {
+            return Err(anyhow!("Invalid key {}", s));
+        };
+        Ok(Self { origin, key })
+    }
+
+    pub fn is_ranged(&self) -> bool {
+        match &self.key {
+            KeyExpr::Xpub { wildcard, .. } | KeyExpr::Xprv { wildcard, .. } => {
+                wildcard != &Wildcard::None
+            }
+            _ => false,
+        }
+    }
+
+    pub fn has_secret(&self) -> bool {
+        matches!(self.key, KeyExpr::Wif { .. } | KeyExpr::Xprv { .. })
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        match &self.key {
+            KeyExpr::Public(b) => b.len() != 65,
+            KeyExpr::Wif { compressed, .. } => *compressed,
+            _ => true,
+        }
+    }
+
+    // The path below the extended key, including the wildcard step
+    fn path_at(path: &DerivationPath, wildcard: Wildcard, index: u32) -> Result<DerivationPath> {
+        Ok(match wildcard {
+            Wildcard::None => path.clone(),
+            Wildcard::Unhardened => path.child(ChildNumber::normal(index)?),
+            Wildcard::Hardened => path.child(ChildNumber::hardened(index)?),
+        })
+    }
+
+    pub fn public_key(&self, index: u32) -> Result<S256Point<'static>> {
+        match &self.key {
+            KeyExpr::Public(b) if b.len() == 32 => S256Point::lift_x(b),
+            KeyExpr::Public(b) => S256Point::parse(b),
+            KeyExpr::Wif { key, .. } => Ok(key.point.clone()),
+            KeyExpr::Xpub {
+                xpub,
+                path,
+                wildcard,
+            } => Ok(xpub
+                .derive_path(&Self::path_at(path, *wildcard, index)?)?
+                .public_key),
+            KeyExpr::Xprv {
+                xprv,
+                path,
+                wildcard,
+            } => Ok(xprv
+                .derive_path(&Self::path_at(path, *wildcard, index)?)?
+                .private_key
+                .point),
+        }
+    }
+
+    pub fn private_key(&self, index: u32) -> Result<Option<PrivateKey<'static>>> {
+        match &self.key {
+            KeyExpr::Wif { key, .. } => Ok(Some(key.clone())),
+            KeyExpr::Xprv {
+                xprv,
+                path,
+                wildcard,
+            } => Ok(Some(
+                xprv.derive_path(&Self::path_at(path, *wildcard, index)?)?
+                    .private_key,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    // The master fingerprint and full path of the key at `index`. Keys without
+    // an origin are their own master.
+    pub fn full_derivation(&self, index: u32) -> Result<(Fingerprint, DerivationPath)> {
+        let (fingerprint, mut path) = match &self.origin {
+            Some(origin) => (origin.fingerprint, origin.path.clone()),
+            None => {
+                let fingerprint = match &self.key {
+                    KeyExpr::Xpub { xpub, .. } => xpub.fingerprint(),
+                    KeyExpr::Xprv { xprv, .. } => xprv.fingerprint(),
+                    _ => Fingerprint::from_point(&self.public_key(index)?),
+                };
+                (fingerprint, DerivationPath::master())
+            }
+        };
+        match &self.key {
+            KeyExpr::Xpub {
+                path: p, wildcard, ..
+            }
+            | KeyExpr::Xprv {
+                path: p, wildcard, ..
+            } => {
+                path = path.extend(&Self::path_at(p, *wildcard, index)?);
+            }
+            _ => {}
+        }
+        Ok((fingerprint, path))
+    }
+
+    // Strips private keys, deriving through any hardened steps so that the
+    // remaining path can be followed from the xpub.
+    pub fn to_public(&self) -> Result<Self> {
+        match &self.key {
+            KeyExpr::Wif {
+                key, compressed, ..
+            } => Ok(Self {
+                origin: self.origin.clone(),
+                key: KeyExpr::Public(key.point.sec(*compressed)),
+            }),
+            KeyExpr::Xprv {
+                xprv,
+                path,
+                wildcard,
+            } => {
+                if wildcard == &Wildcard::Hardened {
+                    return Err(anyhow!("Hardened wildcards need the private key"));
+                }
+                let split = path
+                    .0
+                    .iter()
+                    .rposition(|c| c.is_hardened())
+                    .map_or(0, |i| i + 1);
+                let hardened = DerivationPath(path.0[..split].to_vec());
+                let origin = match &self.origin {
+                    Some(origin) => KeyOrigin {
+                        fingerprint: origin.fingerprint,
+                        path: origin.path.extend(&hardened),
+                    },
+                    None if split == 0 => {
+                        return Ok(Self {
+                            origin: None,
+                            key: KeyExpr::Xpub {
+                                xpub: xprv.to_pub(),
+                                path: path.clone(),
+                                wildcard: *wildcard,
+                            },
+                        })
+                    }
+                    None => KeyOrigin {
+                        fingerprint: xprv.fingerprint(),
+                        path: hardened.clone(),
+                    },
+                };
+                Ok(Self {
+                    origin: Some(origin),
+                    key: KeyExpr::Xpub {
+                        xpub: xprv.derive_path(&hardened)?.to_pub(),
+                        path: DerivationPath(path.0[split..].to_vec()),
+                        wildcard: *wildcard,
+                    },
+                })
+            }
+            _ => Ok(self.clone()),
+        }
+    }
+
+    fn serialize_at(&self, index: u32, ctx: Context) -> Result<Vec<u8>> {
+        let point = self.public_key(index)?;
+        if ctx == Context::Tap {
+            Ok(point.xonly())
+        } else {
+            Ok(point.sec(self.is_compressed()))
+        }
+    }
+}
+
+impl fmt::Display for DescriptorKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(origin) = &self.origin {
+            write!(f, "{}", origin)?;
+        }
+        let (path, wildcard) = match &self.key {
+            KeyExpr::Public(b) => return write!(f, "{}", encode_hex(b)),
+            KeyExpr::Wif {
+                key,
+                compressed,
+                network,
+            } => return write!(f, "{}", key.wif(*compressed, *network)),
+            KeyExpr::Xpub {
+                xpub,
+                path,
+                wildcard,
+            } => {
+                write!(f, "{}", xpub)?;
+                (path, wildcard)
+            }
+            KeyExpr::Xprv {
+                xprv,
+                path,
+                wildcard,
+            } => {
+                write!(f, "{}", xprv)?;
+                (path, wildcard)
+            }
+        };
+        for child in &path.0 {
+            write!(f, "/{}", child)?;
+        }
+        match wildcard {
+            Wildcard::None => Ok(()),
+            Wildcard::Unhardened => write!(f, "/*"),
+            Wildcard::Hardened => write!(f, "/*'"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TapTreeDescriptor {
+    Leaf(Box<Descriptor>),
+    Branch(Box<TapTreeDescriptor>, Box<TapTreeDescriptor>),
+}
+
+impl TapTreeDescriptor {
+    fn parse(s: &str, depth: usize) -> Result<Self> {
+        if depth > MAX_TAPTREE_DEPTH {
+            return Err(anyhow!("Script tree is deeper than {}", MAX_TAPTREE_DEPTH));
+        }
+        if let Some(inner) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            let args = split_args(inner)?;
+            if args.len() != 2 {
+                return Err(anyhow!("Script tree branches must have two children"));
+            }
+            return Ok(TapTreeDescriptor::Branch(
+                Box::new(Self::parse(args[0], depth + 1)?),
+                Box::new(Self::parse(args[1], depth + 1)?),
+            ));
+        }
+        Ok(TapTreeDescriptor::Leaf(Box::new(Descriptor::parse_in(
+            s,
+            Context::Tap,
+        )?)))
+    }
+
+    pub fn tap_tree(&self, index: u32) -> Result<TapTree> {
+        match self {
+            TapTreeDescriptor::Leaf(leaf) => {
+                Ok(TapTree::leaf(leaf.script_at(index, Context::Tap)?))
+            }
+            TapTreeDescriptor::Branch(left, right) => Ok(TapTree::branch(
+                left.tap_tree(index)?,
+                right.tap_tree(index)?,
+            )),
+        }
+    }
+
+    fn leaves(&self) -> Vec<&Descriptor> {
+        match self {
+            TapTreeDescriptor::Leaf(leaf) => vec![leaf],
+            TapTreeDescriptor::Branch(left, right) => {
+                let mut result = left.leaves();
+                result.append(&mut right.leaves());
+                result
+            }
+        }
+    }
+
+    fn to_public(&self) -> Result<Self> {
+        Ok(match self {
+            TapTreeDescriptor::Leaf(leaf) => TapTreeDescriptor::Leaf(Box::new(leaf.to_public()?)),
+            TapTreeDescriptor::Branch(left, right) => {
+                TapTreeDescriptor::Branch(Box::new(left.to_public()?), Box::new(right.to_public()?))
+            }
+        })
+    }
+}
+
+impl fmt::Display for TapTreeDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TapTreeDescriptor::Leaf(leaf) => write!(f, "{}", leaf),
+            TapTreeDescriptor::Branch(left, right) => write!(f, "{{{},{}}}", left, right),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Descriptor {
+    Pk(DescriptorKey),
+    Pkh(DescriptorKey),
+    Wpkh(DescriptorKey),
+    Sh(Box<Descriptor>),
+    Wsh(Box<Descriptor>),
+    Multi(usize, Vec<DescriptorKey>),
+    SortedMulti(usize, Vec<DescriptorKey>),
+    MultiA(usize, Vec<DescriptorKey>),
+    SortedMultiA(usize, Vec<DescriptorKey>),
+    Tr(DescriptorKey, Option<TapTreeDescriptor>),
+    Addr(String),
+    Raw(Script),
+    Miniscript(Box<Miniscript>),
+}
+
+// Splits "name(args)" into the name and the text between the outer
+// parentheses.
+pub(crate) fn split_function(s: &str) -> Result<(&str, &str)> {
+    let open = s
+        .find('(')
+        .ok_or_else(|| anyhow!("Expected a script expression, got {}", s))?;
+    if !s.ends_with(')') {
+        return Err(anyhow!("Unbalanced parentheses in {}", s));
+    }
+    Ok((&s[..open], &s[open + 1..s.len() - 1]))
+}
+
+// Splits at the commas that are not nested in brackets
+pub(crate) fn split_args(s: &str) -> Result<Vec<&str>> {
+    let mut result = vec![];
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (i, ch) in s.char_indices() {
+        match ch {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        if depth < 0 {
+            return Err(anyhow!("Unbalanced brackets in {}", s));
+        }
+    }
+    if depth != 0 {
+        return Err(anyhow!("Unbalanced brackets in {}", s));
+    }
+    result.push(&s[start..]);
+    Ok(result)
+}
+
+fn parse_multi(args: &[&str], ctx: Context) -> Result<(usize, Vec<DescriptorKey>)> {
+    if args.len() < 2 {
+        return Err(anyhow!("Multisig needs a threshold and at least one key"));
+    }
+    let k: usize = args[0]
+        .parse()
+        .map_err(|_| anyhow!("Invalid multisig threshold {}", args[0]))?;
+    let keys = args[1..]
+        .iter()
+        .map(|s| DescriptorKey::parse(s, ctx))
+        .collect::<Result<Vec<_>>>()?;
+    let max = match ctx {
+        Context::Tap => MAX_MULTI_A_KEYS,
+        Context::Top => MAX_BARE_MULTISIG_KEYS,
+        _ => MAX_PUBKEYS_PER_MULTISIG,
+    };
+    if k == 0 || k > keys.len() || keys.len() > max {
+        return Err(anyhow!(
+            "Invalid multisig threshold {} of {} keys",
+            k,
+            keys.len()
+        ));
+    }
+    if ctx == Context::Sh {
+        let size: usize = 3 + keys
+            .iter()
+            .map(|key| if key.is_compressed() { 34 } else { 66 })
+            .sum::<usize>();
+        if size > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(anyhow!("P2SH multisig script is {} bytes", size));
+        }
+    }
+    Ok((k, keys))
+}
+
+impl Descriptor {
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = match s.find('#') {
+            Some(i) => {
+                let (body, checksum) = (&s[..i], &s[i + 1..]);
+                if checksum != descriptor_checksum(body)? {
+                    return Err(anyhow!("Invalid descriptor checksum {}", checksum));
+                }
+                body
+            }
+            None => s,
+        };
+        Self::parse_in(s, Context::Top)
+    }
+
+    fn parse_in(s: &str, ctx: Context) -> Result<Self> {
+        let (name, inner) = split_function(s)?;
+        let args = split_args(inner)?;
+        let single = || {
+            if args.len() != 1 {
+                return Err(anyhow!("{}() takes a single argument", name));
+            }
+            Ok(args[0])
+        };
+        let descriptor = match (name, ctx) {
+            ("pk", _) => Descriptor::Pk(DescriptorKey::parse(single()?, ctx)?),
+            ("pkh", Context::Top) | ("pkh", Context::Sh) | ("pkh", Context::Wsh) => {
+                Descriptor::Pkh(DescriptorKey::parse(single()?, ctx)?)
+            }
+            ("wpkh", Context::Top) | ("wpkh", Context::Sh) => {
+                let key = DescriptorKey::parse(single()?, Context::Wsh)?;
+                Descriptor::Wpkh(key)
+            }
+            ("sh", Context::Top) => {
+                Descriptor::Sh(Box::new(Self::parse_in(single()?, Context::Sh)?))
+            }
+            ("wsh", Context::Top) | ("wsh", Context::Sh) => {
+                Descriptor::Wsh(Box::new(Self::parse_in(single()?, Context::Wsh)?))
+            }
+            ("multi", Context::Top) | ("multi", Context::Sh) | ("multi", Context::Wsh) => {
+                let (k, keys) = parse_multi(&args, ctx)?;
+                Descriptor::Multi(k, keys)
+            }
+            ("sortedmulti", Context::Top)
+            | ("sortedmulti", Context::Sh)
+            | ("sortedmulti", Context::Wsh) => {
+                let (k, keys) = parse_multi(&args, ctx)?;
+                Descriptor::SortedMulti(k, keys)
+            }
+            ("multi_a", Context::Tap) => {
+                let (k, keys) = parse_multi(&args, ctx)?;
+                Descriptor::MultiA(k, keys)
+            }
+            ("sortedmulti_a", Context::Tap) => {
+                let (k, keys) = parse_multi(&args, ctx)?;
+                Descriptor::SortedMultiA(k, keys)
+            }
+            ("tr", Context::Top) => {
+                let key = DescriptorKey::parse(args[0], Context::Tap)?;
+                let tree = match args.len() {
+                    1 => None,
+                    2 => Some(TapTreeDescriptor::parse(args[1], 0)?),
+                    _ => return Err(anyhow!("tr() takes a key and an optional tree")),
+                };
+                Descriptor::Tr(key, tree)
+            }
+            ("addr", Context::Top) => {
+                let address = single()?;
+                Script::from_address(address)?;
+                Descriptor::Addr(address.to_string())
+            }
+            ("raw", Context::Top) => Descriptor::Raw(Script::from_bytes(decode_hex(single()?)?)),
+            (_, Context::Wsh) => {
+                let ms = Miniscript::parse(s)?;
+                if !ms.is_sane() {
+                    return Err(anyhow!("{} is not a sane miniscript", s));
+                }
+                Descriptor::Miniscript(Box::new(ms))
+            }
+            _ => return Err(anyhow!("{}() is not allowed here", name)),
+        };
+        Ok(descriptor)
+    }
+
+    pub fn to_string_with_checksum(&self) -> String {
+        let s = self.to_string();
+        let checksum = descriptor_checksum(&s).unwrap();
+        format!("{}#{}", s, checksum)
+    }
+
+    pub fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            Descriptor::Pk(key) | Descriptor::Pkh(key) | Descriptor::Wpkh(key) => vec![key],
+            Descriptor::Sh(inner) | Descriptor::Wsh(inner) => inner.keys(),
+            Descriptor::Multi(_, keys)
+            | Descriptor::SortedMulti(_, keys)
+            | Descriptor::MultiA(_, keys)
+            | Descriptor::SortedMultiA(_, keys) => keys.iter().collect(),
+            Descriptor::Tr(key, tree) => {
+                let mut result = vec![key];
+                if let Some(tree) = tree {
+                    for leaf in tree.leaves() {
+                        result.append(&mut leaf.keys());
+                    }
+                }
+                result
+            }
+            Descriptor::Addr(_) | Descriptor::Raw(_) => vec![],
+            Descriptor::Miniscript(ms) => ms.keys(),
+        }
+    }
+
+    pub fn is_ranged(&self) -> bool {
+        self.keys().iter().any(|key| key.is_ranged())
+    }
+
+    pub fn has_secrets(&self) -> bool {
+        self.keys().iter().any(|key| key.has_secret())
+    }
+
+    pub fn to_public(&self) -> Result<Self> {
+        let keys = |keys: &[DescriptorKey]| -> Result<Vec<DescriptorKey>> {
+            keys.iter().map(|key| key.to_public()).collect()
+        };
+        Ok(match self {
+            Descriptor::Pk(key) => Descriptor::Pk(key.to_public()?),
+            Descriptor::Pkh(key) => Descriptor::Pkh(key.to_public()?),
+            Descriptor::Wpkh(key) => Descriptor::Wpkh(key.to_public()?),
+            Descriptor::Sh(inner) => Descriptor::Sh(Box::new(inner.to_public()?)),
+            Descriptor::Wsh(inner) => Descriptor::Wsh(Box::new(inner.to_public()?)),
+            Descriptor::Multi(k, k_keys) => Descriptor::Multi(*k, keys(k_keys)?),
+            Descriptor::SortedMulti(k, k_keys) => Descriptor::SortedMulti(*k, keys(k_keys)?),
+            Descriptor::MultiA(k, k_keys) => Descriptor::MultiA(*k, keys(k_keys)?),
+            Descriptor::SortedMultiA(k, k_keys) => Descriptor::SortedMultiA(*k, keys(k_keys)?),
+            Descriptor::Tr(key, tree) => Descriptor::Tr(
+                key.to_public()?,
+                tree.as_ref().map(|t| t.to_public()).transpose()?,
+            ),
+            Descriptor::Addr(_) | Descriptor::Raw(_) => self.clone(),
+            Descriptor::Miniscript(ms) => {
+                Descriptor::Miniscript(Box::new(ms.translate_keys(&|key| key.to_public())?))
+            }
+        })
+    }
+
+    fn script_at(&self, index: u32, ctx: Context) -> Result<Script> {
+        let serialized = |keys: &[DescriptorKey], sorted: bool| -> Result<Vec<Vec<u8>>> {
+            let mut result = keys
+                .iter()
+                .map(|key| key.serialize_at(index, ctx))
+                .collect::<Result<Vec<_>>>()?;
+            if sorted {
+                result.sort();
+            }
+            Ok(result)
+        };
+        Ok(match self {
+            Descriptor::Pk(key) if ctx == Context::Tap => Script::from_cmds(&[
+                Cmd::Data(key.serialize_at(index, ctx)?),
+                Cmd::Op(OP_CHECKSIG),
+            ]),
+            Descriptor::Pk(key) => Script::p2pk(&key.serialize_at(index, ctx)?),
+            Descriptor::Pkh(key) => Script::p2pkh(&hash160(&key.serialize_at(index, ctx)?)),
+            Descriptor::Wpkh(key) => Script::p2wpkh(&hash160(&key.serialize_at(index, ctx)?)),
+            Descriptor::Sh(inner) => {
+                Script::p2sh(&hash160(inner.script_at(index, Context::Sh)?.as_bytes()))
+            }
+            Descriptor::Wsh(inner) => {
+                Script::p2wsh(&sha256(inner.script_at(index, Context::Wsh)?.as_bytes()))
+            }
+            Descriptor::Multi(k, keys) => Script::multisig(*k, &serialized(keys, false)?),
+            Descriptor::SortedMulti(k, keys) => Script::multisig(*k, &serialized(keys, true)?),
+            Descriptor::MultiA(k, keys) | Descriptor::SortedMultiA(k, keys) => {
+                let sorted = matches!(self, Descriptor::SortedMultiA(..));
+                let mut cmds = vec![];
+                for (i, key) in serialized(keys, sorted)?.into_iter().enumerate() {
+                    cmds.push(Cmd::Data(key));
+                    cmds.push(Cmd::Op(if i == 0 { OP_CHECKSIG } else { OP_CHECKSIGADD }));
+                }
+                cmds.push(Cmd::num(*k as i64));
+                cmds.push(Cmd::Op(OP_NUMEQUAL));
+                Script::from_cmds(&cmds)
+            }
+            Descriptor::Tr(key, tree) => {
+                let root = match tree {
+                    Some(tree) => Some(tree.tap_tree(index)?.merkle_root()),
+                    None => None,
+                };
+                let output_key = key.public_key(index)?.tap_tweak(root.as_deref())?;
+                Script::p2tr(&output_key.xonly())
+            }
+            Descriptor::Addr(address) => Script::from_address(address)?.0,
+            Descriptor::Raw(script) => script.clone(),
+            Descriptor::Miniscript(ms) => ms.encode(index)?,
+        })
+    }
+
+    pub fn script_pubkey(&self, index: u32) -> Result<Script> {
+        self.script_at(index, Context::Top)
+    }
+
+    pub fn redeem_script(&self, index: u32) -> Result<Option<Script>> {
+        match self {
+            Descriptor::Sh(inner) => Ok(Some(inner.script_at(index, Context::Sh)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn witness_script(&self, index: u32) -> Result<Option<Script>> {
+        match self {
+            Descriptor::Wsh(inner) => Ok(Some(inner.script_at(index, Context::Wsh)?)),
+            Descriptor::Sh(inner) => inner.witness_script(index),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn tap_tree(&self, index: u32) -> Result<Option<TapTree>> {
+        match self {
+            Descriptor::Tr(_, Some(tree)) => Ok(Some(tree.tap_tree(index)?)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn address(&self, index: u32, network: Network) -> Result<String> {
+        self.script_pubkey(index)?
+            .address(network)
+            .ok_or_else(|| anyhow!("{} has no address form", self))
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let multi = |f: &mut fmt::Formatter, name: &str, k: &usize, keys: &[DescriptorKey]| {
+            write!(f, "{}({}", name, k)?;
+            for key in keys {
+                write!(f, ",{}", key)?;
+            }
+            write!(f, ")")
+        };
+        match self {
+            Descriptor::Pk(key) => write!(f, "pk({})", key),
+            Descriptor::Pkh(key) => write!(f, "pkh({})", key),
+            Descriptor::Wpkh(key) => write!(f, "wpkh({})", key),
+            Descriptor::Sh(inner) => write!(f, "sh({})", inner),
+            Descriptor::Wsh(inner) => write!(f, "wsh({})", inner),
+            Descriptor::Multi(k, keys) => multi(f, "multi", k, keys),
+            Descriptor::SortedMulti(k, keys) => multi(f, "sortedmulti", k, keys),
+            Descriptor::MultiA(k, keys) => multi(f, "multi_a", k, keys),
+            Descriptor::SortedMultiA(k, keys) => multi(f, "sortedmulti_a", k, keys),
+            Descriptor::Tr(key, None) => write!(f, "tr({})", key),
+            Descriptor::Tr(key, Some(tree)) => write!(f, "tr({},{})", key, tree),
+            Descriptor::Addr(address) => write!(f, "addr({})", address),
+            Descriptor::Raw(script) => write!(f, "raw({})", encode_hex(script.as_bytes())),
+            Descriptor::Miniscript(ms) => write!(f, "{}", ms),
+        }
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+#[test]
+fn test_checksum() {
+    let descriptor: Descriptor = "raw(deadbeef)#89f8spxm".parse().unwrap();
+    assert_eq!(
+        descriptor.to_string_with_checksum(),
+        "raw(deadbeef)#89f8spxm"
+    );
+    assert_eq!(
+        encode_hex(descriptor.script_pubkey(0).unwrap().as_bytes()),
+        "deadbeef"
+    );
+
+    for s in &[
+        "raw(deadbeef)#",
+        "raw(deadbeef)#89f8spxmx",
+        "raw(deadbeef)#89f8spx",
+        "raw(deadbeef)#89f8spxn",
+        "raw(deedbeef)#89f8spxm",
+        "raw(deadbeef)##9f8spxm",
+        "raw(Ü)#00000000",
+    ] {
+        assert!(s.parse::<Descriptor>().is_err(), "{}", s);
+    }
+}
+
+#[test]
+fn test_script_pubkeys() {
+    let cases = [
+        (
+            "pk(0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)",
+            "210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac",
+        ),
+        (
+            "pkh(02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5)",
+            "76a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac",
+        ),
+        (
+            "wpkh(02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9)",
+            "00147dd65592d0ab2fe0d0257d571abf032cd9db93dc",
+        ),
+        (
+            "sh(wpkh(03fff97bd5755eeea420453a14355235d382f6472f8568a18b2f057a1460297556))",
+            "a914cc6ffbc0bf31af759451068f90ba7a0272b6b33287",
+        ),
+        (
+            "sh(multi(2,022f01e5e15cca351daff3843fb70f3c2f0a1bdd05e5af888a67784ef3e10a2a01,03acd484e2f0c7f65309ad178a9f559abde09796974c57e714c35f110dfc27ccbe))",
+            "a914a6a8b030a38762f4c1f5cbe387b61a3c5da5cd2687",
+        ),
+        (
+            "sh(sortedmulti(2,03acd484e2f0c7f65309ad178a9f559abde09796974c57e714c35f110dfc27ccbe,022f01e5e15cca351daff3843fb70f3c2f0a1bdd05e5af888a67784ef3e10a2a01))",
+            "a914a6a8b030a38762f4c1f5cbe387b61a3c5da5cd2687",
+        ),
+        (
+            "wsh(multi(2,03a0434d9e47f3c86235477c7b1ae6ae5d3442d49b1943c2b752a68e2a47e247c7,03774ae7f858a9411e5ef4246b70c65aac5649980be5c17891bbec17895da008cb,03d01115d548e7561b15c38f004d734633687cf4419620095bc5b0f47070afe85a))",
+            "0020773d709598b76c4e3b575c08aad40658963f9322affc0f8c28d1d9a68d0c944a",
+        ),
+        (
+            "sh(wsh(multi(1,03f28773c2d975288bc7d1d205c3748651b075fbc6610e58cddeeddf8f19405aa8,03499fdf9e895e719cfd64e67f07d38e3226aa7b63678949e6e49b241a60e823e4,02d7924d4f7d43ea965a465ae3095ff41131e5946f3c85f79e44adbcf8e27e080e)))",
+            "a914aec509e284f909f769bb7dda299a717c87cc97ac87",
+        ),
+        // BIP341 wallet test vectors
+        (
+            "tr(d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d)",
+            "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+        ),
+        (
+            "tr(187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27,pk(d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8))",
+            "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+        ),
+        (
+            "tr(e0dfe2300b0dd746a3f8674dfd4525623639042569d829c7f0eed9602d263e6f,{pk(72ea6adcf1d371dea8fba1035a09f3d24ed5a059799bae114084130ee5898e69),{pk(2352d137f2f3ab38d1eaa976758873377fa5ebb817372c71e2c542313d4abda8),pk(7337c0dd4253cb86f2c43a2351aadd82cccb12a172cd120452b9bb8324f2186a)}})",
+            "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605",
+        ),
+        (
+            "addr(bc1pwyjywgrd0ffr3tx8laflh6228dj98xkjj8rum0zfpd6h0e930h6saqxrrm)",
+            "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5",
+        ),
+    ];
+    for (s, script_pubkey) in cases.iter() {
+        let descriptor: Descriptor = s.parse().unwrap();
+        assert_eq!(&descriptor.to_string(), s);
+        assert!(!descriptor.is_ranged());
+        assert_eq!(
+            encode_hex(descriptor.script_pubkey(0).unwrap().as_bytes()),
+            script_pubkey.to_string()
+        );
+        assert_eq!(
+            descriptor
+                .to_string_with_checksum()
+                .parse::<Descriptor>()
+                .unwrap(),
+            descriptor
+        );
+    }
+}
+
+#[test]
+fn test_ranged_accounts() {
+    use crate::slip132::ScriptType;
+
+    let master = ExtendedPrivKey::new_master(&decode_hex("5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4").unwrap(), Network::Mainnet).unwrap();
+    assert_eq!(master.fingerprint().to_string(), "73c5da0a");
+
+    let cases = [
+        (
+            ScriptType::P2pkh,
+            "pkh(KEY)",
+            "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA",
+        ),
+        (
+            ScriptType::P2shP2wpkh,
+            "sh(wpkh(KEY))",
+            "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf",
+        ),
+        (
+            ScriptType::P2wpkh,
+            "wpkh(KEY)",
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+        ),
+        (
+            ScriptType::P2tr,
+            "tr(KEY)",
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+        ),
+    ];
+    for (script_type, template, address) in cases.iter() {
+        let path = script_type.account_path(Network::Mainnet, 0).unwrap();
+        let xpub = master.derive_path(&path).unwrap().to_pub();
+        let key = format!("[73c5da0a{}]{}/0/*", &path.to_string()[1..], xpub);
+        let s = template.replace("KEY", &key);
+        let descriptor: Descriptor = s.parse().unwrap();
+        assert!(descriptor.is_ranged());
+        assert_eq!(&descriptor.address(0, Network::Mainnet).unwrap(), address);
+
+        let key = descriptor.keys()[0];
+        let (fingerprint, full_path) = key.full_derivation(0).unwrap();
+        assert_eq!(fingerprint, master.fingerprint());
+        assert_eq!(full_path, path.child(ChildNumber(0)).child(ChildNumber(0)));
+    }
+}
+
+#[test]
+fn test_private_keys() {
+    let master = ExtendedPrivKey::new_master(&[1; 32], Network::Testnet3).unwrap();
+    let account = master.derive_path(&"m/84'/1'/0'".parse().unwrap()).unwrap();
+    let descriptor: Descriptor = format!("wpkh({}/84h/1h/0h/0/*)", master).parse().unwrap();
+    assert!(descriptor.has_secrets());
+    assert_eq!(
+        descriptor.to_string(),
+        format!("wpkh({}/84'/1'/0'/0/*)", master)
+    );
+
+    let public = descriptor.to_public().unwrap();
+    assert!(!public.has_secrets());
+    assert_eq!(
+        public.to_string(),
+        format!(
+            "wpkh([{}/84'/1'/0']{}/0/*)",
+            master.fingerprint(),
+            account.to_pub()
+        )
+    );
+    for index in 0..2 {
+        assert_eq!(
+            public.script_pubkey(index).unwrap(),
+            descriptor.script_pubkey(index).unwrap()
+        );
+        assert_eq!(
+            descriptor.keys()[0].full_derivation(index).unwrap(),
+            public.keys()[0].full_derivation(index).unwrap()
+        );
+    }
+
+    let wif = master.private_key.wif(true, Network::Testnet3);
+    let descriptor: Descriptor = format!("pkh({})", wif).parse().unwrap();
+    assert_eq!(
+        descriptor.to_public().unwrap().to_string(),
+        format!("pkh({})", encode_hex(&master.private_key.point.sec(true)))
+    );
+}
+
+#[test]
+fn test_invalid_descriptors() {
+    let xpub = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+    for s in &[
+        // uncompressed keys in segwit
+        "wpkh(0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8)".to_string(),
+        "wsh(pk(0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8))".to_string(),
+        // x-only keys outside tr
+        "pk(79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)".to_string(),
+        // misplaced functions
+        "sh(sh(pk(0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)))".to_string(),
+        "wsh(wpkh(0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798))".to_string(),
+        "sh(tr(79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798))".to_string(),
+        "multi_a(1,0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)".to_string(),
+        // bad thresholds
+        "multi(0,0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)".to_string(),
+        "multi(2,0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)".to_string(),
+        // hardened derivation from an xpub
+        format!("pkh({}/1'/*)", xpub),
+        format!("pkh({}/*')", xpub),
+        format!("pkh({}/*/1)", xpub),
+        // malformed
+        "pkh(0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".to_string(),
+        "tr(79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798,{pk(79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)})".to_string(),
+        "addr(bc1qinvalid)".to_string(),
+        "[d34db33f]pkh(0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798)".to_string(),
+    ] {
+        assert!(s.parse::<Descriptor>().is_err(), "{}", s);
+    }
+
+    let descriptor: Descriptor = format!("pkh([d34db33f/44h/0h/0h]{}/1/*)", xpub)
+        .parse()
+        .unwrap();
+    assert_eq!(
+        descriptor.to_string(),
+        format!("pkh([d34db33f/44'/0'/0']{}/1/*)", xpub)
+    );
+    assert!(descriptor.address(0, Network::Mainnet).is_ok());
+
+    // Bare multisig takes at most 3 keys, P2SH up to 20
+    let keys = |n| {
+        (0..n)
+            .map(|i| format!("{}/{}", xpub, i))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    assert!(format!("multi(1,{})", keys(3))
+        .parse::<Descriptor>()
+        .is_ok());
+    assert!(format!("multi(1,{})", keys(4))
+        .parse::<Descriptor>()
+        .is_err());
+    assert!(format!("sh(multi(1,{}))", keys(4))
+        .parse::<Descriptor>()
+        .is_ok());
+    assert!("raw(6a00)"
+        .parse::<Descriptor>()
+        .unwrap()
+        .address(0, Network::Mainnet)
+        .is_err());
+}
+
+#[test]
+fn test_miniscript_descriptors() {
+    let xpub = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+    let key = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let descriptor: Descriptor = format!("wsh(and_v(v:pk({}),older(144)))", key)
+        .parse()
+        .unwrap();
+    let witness_script = descriptor.witness_script(0).unwrap().unwrap();
+    assert_eq!(
+        encode_hex(witness_script.as_bytes()),
+        format!("21{}ad029000b2", key)
+    );
+    assert_eq!(
+        descriptor.script_pubkey(0).unwrap(),
+        Script::p2wsh(&sha256(witness_script.as_bytes()))
+    );
+
+    let s = format!(
+        "wsh(or_d(pk({}/0/*),and_v(v:pkh({}/1/*),older(4032))))",
+        xpub, xpub
+    );
+    let descriptor: Descriptor = s.parse().unwrap();
+    assert_eq!(descriptor.to_string(), s);
+    assert!(descriptor.is_ranged());
+    assert_eq!(descriptor.keys().len(), 2);
+    assert_ne!(
+        descriptor.script_pubkey(0).unwrap(),
+        descriptor.script_pubkey(1).unwrap()
+    );
+
+    // miniscript that is not sane
+    for s in &[
+        "wsh(older(144))".to_string(),
+        format!("wsh(and_v(v:pk({}),pk({})))", key, key),
+        format!("sh(and_v(v:pk({}),older(144)))", key),
+    ] {
+        assert!(s.parse::<Descriptor>().is_err(), "{}", s);
+    }
+}
@@ -61,7 +61,7 @@ fn modulo(a: &'_ BigInt, b: &'_ BigInt) -> BigInt {
 
 impl<'a> FieldElement<'a> {
     fn round(&self, num: &'_ BigInt) -> FieldElement<'a> {
-        let num = modulo(&num, self.prime.as_ref());
+        let num = modulo(num, self.prime.as_ref());
         self.prime.field_element(num)
     }
 
@@ -70,10 +70,10 @@ impl<'a> FieldElement<'a> {
     }
 }
 
-impl<'a, 'b, 'c> ops::Add<&'b FieldElement<'c>> for &'a FieldElement<'c> {
+impl<'c> ops::Add<&FieldElement<'c>> for &FieldElement<'c> {
     type Output = Result<FieldElement<'c>>;
 
-    fn add(self, other: &'b FieldElement) -> Result<FieldElement<'c>> {
+    fn add(self, other: &FieldElement) -> Result<FieldElement<'c>> {
         if self.prime != other.prime {
             return Err(anyhow!("Cannot add two numbers in deffirent Fields"));
         }
@@ -81,10 +81,10 @@ impl<'a, 'b, 'c> ops::Add<&'b FieldElement<'c>> for &'a FieldElement<'c> {
     }
 }
 
-impl<'a, 'b, 'c> ops::Sub<&'b FieldElement<'c>> for &'a FieldElement<'c> {
+impl<'c> ops::Sub<&FieldElement<'c>> for &FieldElement<'c> {
     type Output = Result<FieldElement<'c>>;
 
-    fn sub(self, other: &'b FieldElement) -> Result<FieldElement<'c>> {
+    fn sub(self, other: &FieldElement) -> Result<FieldElement<'c>> {
         if self.prime != other.prime {
             return Err(anyhow!("Cannot sub two numbers in deffirent Fields"));
         }
@@ -92,10 +92,10 @@ impl<'a, 'b, 'c> ops::Sub<&'b FieldElement<'c>> for &'a FieldElement<'c> {
     }
 }
 
-impl<'a, 'b, 'c> ops::Mul<&'b FieldElement<'c>> for &'a FieldElement<'c> {
+impl<'c> ops::Mul<&FieldElement<'c>> for &FieldElement<'c> {
     type Output = Result<FieldElement<'c>>;
 
-    fn mul(self, other: &'b FieldElement) -> Result<FieldElement<'c>> {
+    fn mul(self, other: &FieldElement) -> Result<FieldElement<'c>> {
         if self.prime != other.prime {
             return Err(anyhow!("Cannot mul two numbers in deffirent Fields"));
         }
@@ -103,18 +103,18 @@ impl<'a, 'b, 'c> ops::Mul<&'b FieldElement<'c>> for &'a FieldElement<'c> {
     }
 }
 
-impl<'a, 'b, 'c> ops::Mul<&'b FieldElement<'c>> for &'a BigInt {
+impl<'c> ops::Mul<&FieldElement<'c>> for &BigInt {
     type Output = Result<FieldElement<'c>>;
 
-    fn mul(self, other: &'b FieldElement<'c>) -> Result<FieldElement<'c>> {
+    fn mul(self, other: &FieldElement<'c>) -> Result<FieldElement<'c>> {
         Ok(other.round(&(self * &other.num)))
     }
 }
 
-impl<'a, 'b, 'c> ops::Div<&'b FieldElement<'c>> for &'a FieldElement<'c> {
+impl<'c> ops::Div<&FieldElement<'c>> for &FieldElement<'c> {
     type Output = Result<FieldElement<'c>>;
 
-    fn div(self, other: &'b FieldElement<'c>) -> Result<FieldElement<'c>> {
+    fn div(self, other: &FieldElement<'c>) -> Result<FieldElement<'c>> {
         if self.prime != other.prime {
             return Err(anyhow!("Cannot div two numbers in deffirent Fields"));
         }
@@ -122,10 +122,10 @@ impl<'a, 'b, 'c> ops::Div<&'b FieldElement<'c>> for &'a FieldElement<'c> {
     }
 }
 
-impl<'a, 'b, 'c> Pow<&'b BigInt> for &'a FieldElement<'c> {
+impl<'c> Pow<&BigInt> for &FieldElement<'c> {
     type Output = FieldElement<'c>;
 
-    fn pow(self, exponent: &'b BigInt) -> FieldElement<'c> {
+    fn pow(self, exponent: &BigInt) -> FieldElement<'c> {
         let n = modulo(exponent, &(self.prime.as_ref() - BigInt::from(1)));
         let num = self.num.modpow(&n, self.prime.as_ref());
         self.prime.field_element(num)
@@ -0,0 +1,314 @@
+use anyhow::{anyhow, Result};
+use siphasher::sip::SipHasher24;
+use std::convert::TryInto;
+
+use crate::block::Block;
+use crate::helper::{encode_variant, hash256, read_variant};
+use crate::script::Script;
+
+// BIP158 basic filter parameters
+pub const BASIC_FILTER_TYPE: u8 = 0;
+pub const BASIC_FILTER_P: u8 = 19;
+pub const BASIC_FILTER_M: u64 = 784931;
+
+// Bits are written most significant first
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: vec![],
+            used: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or_else(|| anyhow!("Filter ends unexpectedly"))?;
+        let bit = (byte >> (7 - self.position % 8)) & 1 == 1;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<u64> {
+        let mut result = 0;
+        for _ in 0..count {
+            result = (result << 1) | self.read_bit()? as u64;
+        }
+        Ok(result)
+    }
+}
+
+// The quotient in unary, then the remainder in p bits
+fn golomb_rice_encode(writer: &mut BitWriter, value: u64, p: u8) {
+    for _ in 0..(value >> p) {
+        writer.write_bit(true);
+    }
+    writer.write_bit(false);
+    writer.write_bits(value, p);
+}
+
+fn golomb_rice_decode(reader: &mut BitReader, p: u8) -> Result<u64> {
+    let mut quotient = 0u64;
+    while reader.read_bit()? {
+        quotient += 1;
+    }
+    Ok((quotient << p) | reader.read_bits(p)?)
+}
+
+// SipHash-2-4 keyed with the first 16 bytes of the block hash in internal
+// byte order
+fn hasher(block_hash: &[u8]) -> SipHasher24 {
+    let mut key = block_hash.to_vec();
+    key.reverse();
+    SipHasher24::new_with_keys(
+        u64::from_le_bytes(key[..8].try_into().unwrap()),
+        u64::from_le_bytes(key[8..16].try_into().unwrap()),
+    )
+}
+
+// Maps items uniformly onto [0, n * M), sorted
+fn hashed_set(block_hash: &[u8], items: &[Vec<u8>], n: u64) -> Vec<u64> {
+    let hasher = hasher(block_hash);
+    let range = n as u128 * BASIC_FILTER_M as u128;
+    let mut result: Vec<u64> = items
+        .iter()
+        .map(|item| ((hasher.hash(item) as u128 * range) >> 64) as u64)
+        .collect();
+    result.sort_unstable();
+    result
+}
+
+// A serialized Golomb-coded set: the item count followed by the coded
+// differences between the sorted hashed items
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BlockFilter {
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn new(content: Vec<u8>) -> Self {
+        Self { content }
+    }
+
+    // Duplicated items are only encoded once
+    pub fn build(block_hash: &[u8], items: &[Vec<u8>]) -> Self {
+        let mut items = items.to_vec();
+        items.sort();
+        items.dedup();
+        let n = items.len() as u64;
+        let mut writer = BitWriter::new();
+        let mut last = 0;
+        for value in hashed_set(block_hash, &items, n) {
+            golomb_rice_encode(&mut writer, value - last, BASIC_FILTER_P);
+            last = value;
+        }
+        let mut content = encode_variant(n);
+        content.append(&mut writer.bytes);
+        Self { content }
+    }
+
+    // BIP158 basic filter: every output script except OP_RETURNs, and the
+    // scripts of the outputs spent, given in input order. Empty scripts are
+    // left out.
+    pub fn basic(block: &Block, prevout_scripts: &[Script]) -> Result<Self> {
+        let inputs: usize = block
+            .txs
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.tx_ins.len())
+            .sum();
+        if prevout_scripts.len() != inputs {
+            return Err(anyhow!(
+                "Block has {} inputs, got {} previous output scripts",
+                inputs,
+                prevout_scripts.len()
+            ));
+        }
+        let outputs = block.txs.iter().flat_map(|tx| tx.tx_outs.iter());
+        let items: Vec<Vec<u8>> = outputs
+            .map(|tx_out| &tx_out.script_pubkey)
+            .filter(|script| !script.is_op_return())
+            .chain(prevout_scripts.iter())
+            .filter(|script| !script.is_empty())
+            .map(|script| script.raw_serialize())
+            .collect();
+        Ok(Self::build(&block.hash(), &items))
+    }
+
+    // Whether any of the items is probably in the set, with a false positive
+    // rate of 1/M per item
+    pub fn match_any(&self, block_hash: &[u8], items: &[Vec<u8>]) -> Result<bool> {
+        let reader = &mut self.content.as_slice();
+        let n = read_variant(reader)?;
+        if n == 0 || items.is_empty() {
+            return Ok(false);
+        }
+        let queries = hashed_set(block_hash, items, n);
+        let mut queries = queries.iter().peekable();
+        let mut bits = BitReader::new(reader);
+        let mut value = 0u64;
+        for _ in 0..n {
+            value = value
+                .checked_add(golomb_rice_decode(&mut bits, BASIC_FILTER_P)?)
+                .ok_or_else(|| anyhow!("Filter value overflows"))?;
+            while queries.next_if(|query| **query < value).is_some() {}
+            match queries.peek() {
+                Some(query) if **query == value => return Ok(true),
+                Some(_) => {}
+                None => return Ok(false),
+            }
+        }
+        Ok(false)
+    }
+
+    // In display order, like block hashes
+    pub fn hash(&self) -> Vec<u8> {
+        let mut result = hash256(&self.content);
+        result.reverse();
+        result
+    }
+
+    pub fn header(&self, prev_header: &[u8]) -> Vec<u8> {
+        filter_header(&self.hash(), prev_header)
+    }
+}
+
+// BIP157: each filter header commits to the filter hash and the previous
+// header, which is all zeros before the genesis block. Everything is in
+// display order.
+pub fn filter_header(filter_hash: &[u8], prev_header: &[u8]) -> Vec<u8> {
+    let mut data = filter_hash.to_vec();
+    data.reverse();
+    let mut prev = prev_header.to_vec();
+    prev.reverse();
+    data.append(&mut prev);
+    let mut result = hash256(&data);
+    result.reverse();
+    result
+}
+
+#[cfg(test)]
+use crate::helper::{decode_hex, encode_hex};
+#[cfg(test)]
+use crate::network::Network;
+
+#[test]
+fn test_golomb_rice() {
+    let values = [0u64, 1, 18, (1 << 19) - 1, 1 << 19, 3 << 20, 123456789];
+    let mut writer = BitWriter::new();
+    for value in values.iter() {
+        golomb_rice_encode(&mut writer, *value, BASIC_FILTER_P);
+    }
+    let mut reader = BitReader::new(&writer.bytes);
+    for value in values.iter() {
+        assert_eq!(
+            golomb_rice_decode(&mut reader, BASIC_FILTER_P).unwrap(),
+            *value
+        );
+    }
+    assert!(golomb_rice_decode(&mut reader, BASIC_FILTER_P).is_err());
+}
+
+#[test]
+fn test_block_filter() {
+    // BIP158 test vector for the testnet genesis block
+    let block_hash =
+        decode_hex("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943").unwrap();
+    let script = decode_hex("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac").unwrap();
+    let filter = BlockFilter::build(&block_hash, &[script.clone(), script.clone()]);
+    assert_eq!(encode_hex(&filter.content), "019dfca8");
+    assert_eq!(
+        encode_hex(&filter.header(&[0u8; 32])),
+        "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+    );
+    assert!(filter
+        .match_any(&block_hash, std::slice::from_ref(&script))
+        .unwrap());
+    assert!(!filter.match_any(&block_hash, &[vec![0x51]]).unwrap());
+    assert!(!filter.match_any(&[0u8; 32], &[script]).unwrap());
+
+    let items: Vec<Vec<u8>> = (0u32..1000).map(|i| i.to_le_bytes().to_vec()).collect();
+    let filter = BlockFilter::build(&block_hash, &items);
+    for item in items.iter() {
+        assert!(filter
+            .match_any(&block_hash, std::slice::from_ref(item))
+            .unwrap());
+    }
+    let others: Vec<Vec<u8>> = (1000u32..2000).map(|i| i.to_le_bytes().to_vec()).collect();
+    assert!(!filter.match_any(&block_hash, &others).unwrap());
+    assert!(!filter.match_any(&block_hash, &[]).unwrap());
+
+    let empty = BlockFilter::build(&block_hash, &[]);
+    assert_eq!(empty.content, vec![0]);
+    assert!(!empty.match_any(&block_hash, &items).unwrap());
+    assert!(BlockFilter::new(vec![2, 0xff])
+        .match_any(&block_hash, &items)
+        .is_err());
+}
+
+#[test]
+fn test_bip158_vectors() {
+    let vectors: serde_json::Value =
+        serde_json::from_str(include_str!("bip158_vectors.json")).unwrap();
+    for vector in vectors.as_array().unwrap().iter().skip(1) {
+        let field = |i: usize| decode_hex(vector[i].as_str().unwrap()).unwrap();
+        let block = Block::parse(&mut field(2).as_slice(), Network::Testnet3).unwrap();
+        assert_eq!(block.hash(), field(1));
+        let prevout_scripts: Vec<Script> = vector[3]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|script| Script::from_bytes(decode_hex(script.as_str().unwrap()).unwrap()))
+            .collect();
+        let filter = BlockFilter::basic(&block, &prevout_scripts).unwrap();
+        assert_eq!(filter.content, field(5), "{}", vector[7]);
+        assert_eq!(filter.header(&field(4)), field(6), "{}", vector[7]);
+
+        let outputs = block.txs.iter().flat_map(|tx| tx.tx_outs.iter());
+        let scripts = outputs.map(|tx_out| &tx_out.script_pubkey);
+        for script in scripts.chain(prevout_scripts.iter()) {
+            let expected = !script.is_empty() && !script.is_op_return();
+            let matched = filter
+                .match_any(&block.hash(), &[script.raw_serialize()])
+                .unwrap();
+            assert!(matched || !expected);
+        }
+    }
+
+    let genesis = Network::Testnet3.genesis_block();
+    let block = Block::parse(&mut genesis.as_slice(), Network::Testnet3).unwrap();
+    assert!(BlockFilter::basic(&block, &[Script::new()]).is_err());
+}
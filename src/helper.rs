@@ -1,8 +1,11 @@
+use anyhow::{anyhow, Result};
 use digest::Digest;
+use hmac::{Hmac, Mac, NewMac};
 use num_bigint::{BigInt, Sign};
 use num_traits::ToPrimitive;
 use ripemd160::Ripemd160;
-use sha2::Sha256;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
 use std::io::Read;
 
 static BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
@@ -22,7 +25,7 @@ pub fn encode_base58(bytes: &[u8]) -> String {
     let mut result = String::new();
     while num > 0.into() {
         let mod_num = (num.clone() % BigInt::from(58)).to_u8().unwrap();
-        num = num / 58;
+        num /= 58;
         result = format!(
             "{}{}",
             BASE58_ALPHABET.chars().nth(mod_num.into()).unwrap(),
@@ -32,6 +35,220 @@ pub fn encode_base58(bytes: &[u8]) -> String {
     format!("{}{}", prefix, result)
 }
 
+pub fn decode_base58(s: &str) -> Result<Vec<u8>> {
+    let mut num = BigInt::from(0);
+    for c in s.chars() {
+        let i = BASE58_ALPHABET
+            .find(c)
+            .ok_or_else(|| anyhow!("Invalid base58 character {}", c))?;
+        num = num * 58 + i;
+    }
+    let count = s.chars().take_while(|c| c == &'1').count();
+    let mut result = vec![0; count];
+    if num > 0.into() {
+        result.append(&mut num.to_bytes_be().1);
+    }
+    Ok(result)
+}
+
+pub fn decode_base58_checksum(s: &str) -> Result<Vec<u8>> {
+    let mut b = decode_base58(s)?;
+    if b.len() < 4 {
+        return Err(anyhow!("Base58 string {} is too short", s));
+    }
+    let checksum = b.split_off(b.len() - 4);
+    if hash256(&b)[..4] != checksum[..] {
+        return Err(anyhow!("Bad base58 checksum for {}", s));
+    }
+    Ok(b)
+}
+
+static BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Bech32Variant {
+    Bech32,
+    Bech32m,
+}
+
+impl Bech32Variant {
+    fn constant(&self) -> u32 {
+        match self {
+            Bech32Variant::Bech32 => 1,
+            Bech32Variant::Bech32m => 0x2bc830a3,
+        }
+    }
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    let generator = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ *v as u32;
+        for (i, g) in generator.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn bech32_hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut result: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    result.push(0);
+    result.extend(hrp.bytes().map(|b| b & 31));
+    result
+}
+
+// `data` is a sequence of 5 bit values
+pub fn encode_bech32(hrp: &str, data: &[u8], variant: Bech32Variant) -> String {
+    let mut values = bech32_hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.append(&mut vec![0; 6]);
+    let polymod = bech32_polymod(&values) ^ variant.constant();
+
+    let mut result = format!("{}1", hrp);
+    for v in data
+        .iter()
+        .copied()
+        .chain((0..6).map(|i| (polymod >> (5 * (5 - i)) & 31) as u8))
+    {
+        result.push(BECH32_CHARSET.as_bytes()[v as usize] as char);
+    }
+    result
+}
+
+pub fn decode_bech32(s: &str) -> Result<(String, Vec<u8>, Bech32Variant)> {
+    if s.to_lowercase() != s && s.to_uppercase() != s {
+        return Err(anyhow!("Mixed case bech32 string {}", s));
+    }
+    let s = s.to_lowercase();
+    let pos = s
+        .rfind('1')
+        .ok_or_else(|| anyhow!("Missing bech32 separator in {}", s))?;
+    if pos == 0 || pos + 7 > s.len() || s.len() > 90 {
+        return Err(anyhow!("Invalid bech32 length {}", s));
+    }
+    let hrp = &s[..pos];
+    if hrp.bytes().any(|b| !(33..=126).contains(&b)) {
+        return Err(anyhow!("Invalid bech32 hrp {}", hrp));
+    }
+    let data = s[pos + 1..]
+        .chars()
+        .map(|c| {
+            BECH32_CHARSET
+                .find(c)
+                .map(|i| i as u8)
+                .ok_or_else(|| anyhow!("Invalid bech32 character {}", c))
+        })
+        .collect::<Result<Vec<u8>>>()?;
+
+    let mut values = bech32_hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    let variant = match bech32_polymod(&values) {
+        1 => Bech32Variant::Bech32,
+        0x2bc830a3 => Bech32Variant::Bech32m,
+        _ => return Err(anyhow!("Bad bech32 checksum for {}", s)),
+    };
+    Ok((hrp.to_string(), data[..data.len() - 6].to_vec(), variant))
+}
+
+pub fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let mut result = Vec::new();
+    let maxv = (1 << to) - 1;
+    for value in data {
+        let value = *value as u32;
+        if value >> from != 0 {
+            return Err(anyhow!("Invalid value {} for {} bit group", value, from));
+        }
+        acc = acc << from | value;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push((acc >> bits & maxv) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push((acc << (to - bits) & maxv) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & maxv != 0 {
+        return Err(anyhow!("Invalid padding"));
+    }
+    Ok(result)
+}
+
+pub fn encode_segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let variant = if version == 0 {
+        Bech32Variant::Bech32
+    } else {
+        Bech32Variant::Bech32m
+    };
+    let mut data = vec![version];
+    data.append(&mut convert_bits(program, 8, 5, true).unwrap());
+    encode_bech32(hrp, &data, variant)
+}
+
+// Returns the hrp, witness version and witness program
+pub fn decode_segwit_address(s: &str) -> Result<(String, u8, Vec<u8>)> {
+    let (hrp, data, variant) = decode_bech32(s)?;
+    if data.is_empty() || data[0] > 16 {
+        return Err(anyhow!("Invalid witness version in {}", s));
+    }
+    let version = data[0];
+    let program = convert_bits(&data[1..], 5, 8, false)?;
+    if program.len() < 2 || program.len() > 40 {
+        return Err(anyhow!("Invalid witness program length in {}", s));
+    }
+    if version == 0 && program.len() != 20 && program.len() != 32 {
+        return Err(anyhow!("Invalid witness v0 program length in {}", s));
+    }
+    let expected = if version == 0 {
+        Bech32Variant::Bech32
+    } else {
+        Bech32Variant::Bech32m
+    };
+    if variant != expected {
+        return Err(anyhow!("Wrong checksum variant for witness v{}", version));
+    }
+    Ok((hrp, version, program))
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|n| format!("{:02x}", n)).collect()
+}
+
+pub fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(anyhow!("Odd length hex string"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex string {}", s))
+        })
+        .collect()
+}
+
+pub fn sha256(b: &[u8]) -> Vec<u8> {
+    Sha256::digest(b).to_vec()
+}
+
+pub fn tagged_hash(tag: &str, msg: &[u8]) -> Vec<u8> {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher.update(msg);
+    hasher.finalize().to_vec()
+}
+
 pub fn hash256(b: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.update(b);
@@ -41,10 +258,50 @@ pub fn hash256(b: &[u8]) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
+// Merkle hashes are all in internal (little-endian) order
+pub fn merkle_parent(left: &[u8], right: &[u8]) -> Vec<u8> {
+    hash256(&[left, right].concat())
+}
+
+pub fn merkle_parent_level(hashes: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    hashes
+        .chunks(2)
+        .map(|pair| merkle_parent(&pair[0], pair.last().unwrap()))
+        .collect()
+}
+
+pub fn merkle_root(hashes: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = merkle_parent_level(&level);
+    }
+    level.pop()
+}
+
 pub fn encode_base58_checksum(b: &[u8]) -> String {
     encode_base58(&[b, &hash256(b)[..4]].concat())
 }
 
+pub fn hmac_sha512(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha512>::new_varkey(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn ripemd160(b: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(b).to_vec()
+}
+
+pub fn sha1(b: &[u8]) -> Vec<u8> {
+    Sha1::digest(b).to_vec()
+}
+
 pub fn hash160(s: &[u8]) -> Vec<u8> {
     let mut sha_hasher = Sha256::new();
     let mut ripemd_hasher = Ripemd160::new();
@@ -53,29 +310,67 @@ pub fn hash160(s: &[u8]) -> Vec<u8> {
     ripemd_hasher.finalize().to_vec()
 }
 
-pub fn read_variant<R>(reader: &mut R) -> u64
+// MurmurHash3 (x86, 32-bit), as used by BIP37 bloom filters
+pub fn murmur3(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k ^= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
+
+pub fn read_variant<R>(reader: &mut R) -> Result<u64>
 where
     R: Read,
 {
     let mut i = [0u8; 1];
-    reader.read_exact(&mut i).unwrap();
-    let i = i[0];
+    reader.read_exact(&mut i)?;
+    read_variant_with_prefix(reader, i[0])
+}
+
+// Finishes a varint whose first byte has already been consumed
+pub fn read_variant_with_prefix<R>(reader: &mut R, i: u8) -> Result<u64>
+where
+    R: Read,
+{
     if i == 0xfd {
         let mut b = [0u8; 2];
-        reader.read_exact(&mut b).unwrap();
-        return u16::from_le_bytes(b) as u64;
+        reader.read_exact(&mut b)?;
+        return Ok(u16::from_le_bytes(b) as u64);
     }
     if i == 0xfe {
         let mut b = [0u8; 4];
-        reader.read_exact(&mut b).unwrap();
-        return u32::from_le_bytes(b) as u64;
+        reader.read_exact(&mut b)?;
+        return Ok(u32::from_le_bytes(b) as u64);
     }
     if i == 0xff {
         let mut b = [0u8; 8];
-        reader.read_exact(&mut b).unwrap();
-        return u64::from_le_bytes(b);
+        reader.read_exact(&mut b)?;
+        return Ok(u64::from_le_bytes(b));
     }
-    i as u64
+    Ok(i as u64)
 }
 
 pub fn encode_variant(i: u64) -> Vec<u8> {
@@ -84,20 +379,17 @@ pub fn encode_variant(i: u64) -> Vec<u8> {
     }
     if i < 0x10000 {
         let mut result = vec![0xfd];
-        result.append(&mut i.to_le_bytes().to_vec());
+        result.extend_from_slice(&(i as u16).to_le_bytes());
         return result;
     }
     if i < 0x100000000 {
         let mut result = vec![0xfe];
-        result.append(&mut i.to_le_bytes().to_vec());
-        return result;
-    }
-    if i < 0x1000000000000 {
-        let mut result = vec![0xff];
-        result.append(&mut i.to_le_bytes().to_vec());
+        result.extend_from_slice(&(i as u32).to_le_bytes());
         return result;
     }
-    unreachable!();
+    let mut result = vec![0xff];
+    result.extend_from_slice(&i.to_le_bytes());
+    result
 }
 
 #[test]
@@ -117,3 +409,154 @@ fn test_exam_4_4() {
         "9MA8fRQrT4u8Zj8ZRd6MAiiyaxb2Y1CMpvVkHQu5hVM6".to_string()
     );
 }
+
+#[test]
+fn test_decode_base58() {
+    let b = decode_base58("9MA8fRQrT4u8Zj8ZRd6MAiiyaxb2Y1CMpvVkHQu5hVM6").unwrap();
+    assert_eq!(
+        encode_hex(&b),
+        "7c076ff316692a3d7eb3c3bb0f8b1488cf72e1afcd929e29307032997a838a3d".to_string()
+    );
+
+    let b = decode_base58_checksum("mmTPbXQFxboEtNRkwfh6K51jvdtHLxGeMA").unwrap();
+    assert_eq!(
+        encode_hex(&b),
+        "6f41243614aecd13819d7a7f348a4a07fbcb29d8e5".to_string()
+    );
+    assert_eq!(
+        encode_base58_checksum(&b),
+        "mmTPbXQFxboEtNRkwfh6K51jvdtHLxGeMA"
+    );
+    assert!(decode_base58_checksum("mmTPbXQFxboEtNRkwfh6K51jvdtHLxGeMB").is_err());
+
+    assert_eq!(decode_base58("11").unwrap(), vec![0, 0]);
+    assert!(decode_base58("0OIl").is_err());
+}
+
+#[test]
+fn test_merkle() {
+    let left =
+        decode_hex("c117ea8ec828342f4dfb0ad6bd140e03a50720ece40169ee38bdc15d9eb64cf5").unwrap();
+    let right =
+        decode_hex("c131474164b412e3406696da1ee20ab0fc9bf41c8f05fa8ceea7a08d672d7cc5").unwrap();
+    let parent = merkle_parent(&left, &right);
+    assert_eq!(
+        encode_hex(&parent),
+        "8b30c5ba100f6f2e5ad1e2a742e5020491240f8eb514fe97c713c31718ad7ecd"
+    );
+
+    // An odd level pairs its last hash with itself
+    let level = merkle_parent_level(&[left.clone(), right.clone(), left.clone()]);
+    assert_eq!(level, vec![parent.clone(), merkle_parent(&left, &left)]);
+    assert_eq!(
+        merkle_root(&[left.clone(), right.clone(), left.clone()]),
+        Some(merkle_parent(&parent, &merkle_parent(&left, &left)))
+    );
+    assert_eq!(merkle_root(std::slice::from_ref(&left)), Some(left));
+    assert_eq!(merkle_root(&[]), None);
+}
+
+#[test]
+fn test_hmac_sha512() {
+    // RFC 4231 test case 2
+    assert_eq!(
+        encode_hex(&hmac_sha512(b"Jefe", b"what do ya want for nothing?")),
+        "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737".to_string()
+    );
+}
+
+#[test]
+fn test_segwit_address() {
+    let cases = [
+        (
+            "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+            "bc",
+            0,
+            "751e76e8199196d454941c45d1b3a323f1433bd6",
+        ),
+        (
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+            "tb",
+            0,
+            "1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+        ),
+        (
+            "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+            "bc",
+            1,
+            "751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6",
+        ),
+        ("BC1SW50QGDZ25J", "bc", 16, "751e"),
+    ];
+    for (address, hrp, version, program) in cases.iter() {
+        let decoded = decode_segwit_address(address).unwrap();
+        assert_eq!(
+            decoded,
+            (hrp.to_string(), *version, decode_hex(program).unwrap())
+        );
+        assert_eq!(
+            encode_segwit_address(hrp, *version, &decoded.2),
+            address.to_lowercase()
+        );
+    }
+
+    // mixed case
+    assert!(decode_segwit_address(
+        "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sL5k7"
+    )
+    .is_err());
+    // bad checksum
+    assert!(decode_segwit_address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5").is_err());
+    // v1 program with a bech32 checksum
+    let data = [vec![1], convert_bits(&[0x75; 32], 8, 5, true).unwrap()].concat();
+    assert!(decode_segwit_address(&encode_bech32("bc", &data, Bech32Variant::Bech32)).is_err());
+}
+
+#[test]
+fn test_hex() {
+    let b = decode_hex("00ff10ab").unwrap();
+    assert_eq!(b, vec![0x00, 0xff, 0x10, 0xab]);
+    assert_eq!(encode_hex(&b), "00ff10ab".to_string());
+    assert!(decode_hex("0").is_err());
+    assert!(decode_hex("zz").is_err());
+}
+
+#[test]
+fn test_variant() {
+    for (i, hex) in &[
+        (0x00, "00"),
+        (0xfc, "fc"),
+        (0xfd, "fdfd00"),
+        (0xffff, "fdffff"),
+        (0x10000, "fe00000100"),
+        (0x100000000, "ff0000000001000000"),
+    ] {
+        let encoded = encode_variant(*i);
+        assert_eq!(encode_hex(&encoded), *hex);
+        assert_eq!(read_variant(&mut encoded.as_slice()).unwrap(), *i);
+    }
+    assert!(read_variant(&mut [0xfd, 0x00].as_ref()).is_err());
+}
+
+#[test]
+fn test_murmur3() {
+    let cases = [
+        (0x00000000, 0x00000000, ""),
+        (0x6a396f08, 0xfba4c795, ""),
+        (0x81f16f39, 0xffffffff, ""),
+        (0x514e28b7, 0x00000000, "00"),
+        (0xea3f0b17, 0xfba4c795, "00"),
+        (0xfd6cf10d, 0x00000000, "ff"),
+        (0x16c6b7ab, 0x00000000, "0011"),
+        (0x8eb51c3d, 0x00000000, "001122"),
+        (0xb4471bf8, 0x00000000, "00112233"),
+        (0xe2301fa8, 0x00000000, "0011223344"),
+        (0xfc2e4a15, 0x00000000, "001122334455"),
+        (0xb074502c, 0x00000000, "00112233445566"),
+        (0x8034d2a0, 0x00000000, "0011223344556677"),
+        (0xb4698def, 0x00000000, "001122334455667788"),
+    ];
+    for (expected, seed, data) in cases.iter() {
+        assert_eq!(murmur3(&decode_hex(data).unwrap(), *seed), *expected);
+    }
+}
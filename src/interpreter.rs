@@ -0,0 +1,1407 @@
+use anyhow::{anyhow, Result};
+use num_bigint::{BigInt, Sign};
+
+use crate::helper::{hash160, hash256, ripemd160, sha1, sha256};
+use crate::op::*;
+use crate::s256::{S256Point, Signature};
+use crate::script::{decode_num, encode_num, read_instruction, Cmd, Script, MAX_SCRIPT_SIZE};
+use crate::taproot::{control_block_root, tap_leaf_hash, TAPSCRIPT_LEAF_VERSION};
+use crate::transaction::{
+    Tx, TxOut, LOCKTIME_THRESHOLD, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_DISABLE_FLAG,
+    SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+
+// Script verification flags, with Bitcoin Core's bit positions
+pub const VERIFY_NONE: u32 = 0;
+pub const VERIFY_P2SH: u32 = 1 << 0;
+pub const VERIFY_DERSIG: u32 = 1 << 2;
+pub const VERIFY_NULLDUMMY: u32 = 1 << 4;
+pub const VERIFY_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+pub const VERIFY_CHECKSEQUENCEVERIFY: u32 = 1 << 10;
+pub const VERIFY_WITNESS: u32 = 1 << 11;
+pub const VERIFY_TAPROOT: u32 = 1 << 17;
+
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+const MAX_OPS_PER_SCRIPT: usize = 201;
+const MAX_STACK_SIZE: usize = 1000;
+
+// BIP342 signature budget
+const VALIDATION_WEIGHT_PER_SIGOP: i64 = 50;
+const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+
+const TAPROOT_CONTROL_BASE_SIZE: usize = 33;
+const TAPROOT_CONTROL_NODE_SIZE: usize = 32;
+const TAPROOT_CONTROL_MAX_NODE_COUNT: usize = 128;
+const ANNEX_TAG: u8 = 0x50;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum SigVersion {
+    Base,
+    WitnessV0,
+    Taproot,
+    Tapscript,
+}
+
+// What taproot signatures commit to besides the transaction
+#[derive(Debug, Default)]
+struct ExecData {
+    annex: Option<Vec<u8>>,
+    leaf_hash: Vec<u8>,
+    codesep_pos: u32,
+    weight_left: i64,
+}
+
+struct Checker<'a> {
+    tx: &'a Tx,
+    input_index: usize,
+    prevouts: &'a [TxOut],
+}
+
+impl<'a> Checker<'a> {
+    fn check_ecdsa(
+        &self,
+        sig: &[u8],
+        sec: &[u8],
+        script_code: &Script,
+        sigversion: SigVersion,
+        flags: u32,
+    ) -> Result<bool> {
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        if flags & VERIFY_DERSIG != 0 && !is_valid_signature_encoding(sig) {
+            return Err(anyhow!("Signature is not strict DER"));
+        }
+        let point = match sec.first() {
+            Some(0x02) | Some(0x03) if sec.len() == 33 => S256Point::parse(sec),
+            Some(0x04) if sec.len() == 65 => S256Point::parse(sec),
+            // Hybrid keys, whose prefix also gives the parity of y
+            Some(0x06) | Some(0x07) if sec.len() == 65 && sec[64] & 1 == sec[0] & 1 => {
+                S256Point::parse(&[&[0x04], &sec[1..]].concat())
+            }
+            _ => return Ok(false),
+        };
+        // As in Bitcoin Core the encoding is only checked by DERSIG, and
+        // parsing is lax either way
+        let (der, sighash_type) = sig.split_at(sig.len() - 1);
+        let (point, sig) = match (point, Signature::parse_der_lax(der)) {
+            (Ok(point), Ok(sig)) => (point, sig),
+            _ => return Ok(false),
+        };
+        let sighash_type = sighash_type[0] as u32;
+        let z = if sigversion == SigVersion::Base {
+            self.tx
+                .sig_hash(self.input_index, script_code, sighash_type)?
+        } else {
+            let amount = self.prevouts[self.input_index].amount;
+            self.tx
+                .sig_hash_bip143(self.input_index, script_code, amount, sighash_type)?
+        };
+        Ok(point.verify(BigInt::from_bytes_be(Sign::Plus, &z), sig))
+    }
+
+    // A bad size or sighash type fails the script, a bad signature only
+    // returns false
+    fn check_schnorr(
+        &self,
+        sig: &[u8],
+        xonly: &[u8],
+        sigversion: SigVersion,
+        exec: &ExecData,
+    ) -> Result<bool> {
+        let (sig, sighash_type) = match sig.len() {
+            64 => (sig, 0x00),
+            65 if sig[64] != 0x00 => (&sig[..64], sig[64] as u32),
+            _ => return Err(anyhow!("Invalid schnorr signature size {}", sig.len())),
+        };
+        let leaf = if sigversion == SigVersion::Tapscript {
+            Some((&exec.leaf_hash[..], exec.codesep_pos))
+        } else {
+            None
+        };
+        let msg = self.tx.sig_hash_bip341(
+            self.input_index,
+            self.prevouts,
+            sighash_type,
+            exec.annex.as_deref(),
+            leaf,
+        )?;
+        Ok(match S256Point::lift_x(xonly) {
+            Ok(point) => point.verify_schnorr(&msg, sig),
+            Err(_) => false,
+        })
+    }
+
+    // BIP65
+    fn check_locktime(&self, locktime: i64) -> bool {
+        let tx_locktime = self.tx.locktime as i64;
+        let threshold = LOCKTIME_THRESHOLD as i64;
+        if (tx_locktime < threshold) != (locktime < threshold) || locktime > tx_locktime {
+            return false;
+        }
+        self.tx.tx_ins[self.input_index].sequence != SEQUENCE_FINAL
+    }
+
+    // BIP112
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.tx.tx_ins[self.input_index].sequence as i64;
+        if self.tx.version < 2 || tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 != 0 {
+            return false;
+        }
+        let type_flag = SEQUENCE_LOCKTIME_TYPE_FLAG as i64;
+        let mask = type_flag | SEQUENCE_LOCKTIME_MASK as i64;
+        let (tx_sequence, sequence) = (tx_sequence & mask, sequence & mask);
+        (tx_sequence < type_flag) == (sequence < type_flag) && sequence <= tx_sequence
+    }
+}
+
+// BIP66
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 || sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+    !(len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0)
+}
+
+// Negative zero is false too
+fn cast_to_bool(b: &[u8]) -> bool {
+    match b.split_last() {
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+fn is_disabled(op: u8) -> bool {
+    matches!(
+        op,
+        OP_CAT
+            | OP_SUBSTR
+            | OP_LEFT
+            | OP_RIGHT
+            | OP_INVERT
+            | OP_AND
+            | OP_OR
+            | OP_XOR
+            | OP_2MUL
+            | OP_2DIV
+            | OP_MUL
+            | OP_DIV
+            | OP_MOD
+            | OP_LSHIFT
+            | OP_RSHIFT
+    )
+}
+
+// BIP342: these make a tapscript succeed unconditionally
+fn is_op_success(op: u8) -> bool {
+    matches!(
+        op,
+        80 | 98 | 126..=129 | 131..=134 | 137..=138 | 141..=142 | 149..=153 | 187..=254
+    )
+}
+
+// Removes every push of `data` at an opcode boundary, as legacy signature
+// hashing does with the signatures being checked
+fn find_and_delete(raw: &[u8], data: &[u8]) -> Vec<u8> {
+    let pattern = Script::from_cmds(&[Cmd::Data(data.to_vec())]).raw_serialize();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i..].starts_with(&pattern) {
+            i += pattern.len();
+            continue;
+        }
+        let next = match read_instruction(raw, i) {
+            Ok((_, _, next)) => next,
+            Err(_) => raw.len(),
+        };
+        result.extend_from_slice(&raw[i..next]);
+        i = next;
+    }
+    result
+}
+
+// The script from the last executed OP_CODESEPARATOR. Legacy signatures
+// don't cover the signatures themselves or any OP_CODESEPARATOR.
+fn script_code(raw: &[u8], sigs: &[Vec<u8>], sigversion: SigVersion) -> Script {
+    if sigversion != SigVersion::Base {
+        return Script::from_bytes(raw.to_vec());
+    }
+    let mut raw = raw.to_vec();
+    for sig in sigs {
+        raw = find_and_delete(&raw, sig);
+    }
+    let mut result = vec![];
+    let mut i = 0;
+    while i < raw.len() {
+        let next = match read_instruction(&raw, i) {
+            Ok((op, _, next)) if op == OP_CODESEPARATOR => {
+                i = next;
+                continue;
+            }
+            Ok((_, _, next)) => next,
+            Err(_) => raw.len(),
+        };
+        result.extend_from_slice(&raw[i..next]);
+        i = next;
+    }
+    Script::from_bytes(result)
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    stack.pop().ok_or_else(|| anyhow!("Stack is empty"))
+}
+
+fn to_num(b: &[u8], max_len: usize) -> Result<i64> {
+    if b.len() > max_len {
+        return Err(anyhow!("Script number is longer than {} bytes", max_len));
+    }
+    decode_num(b)
+}
+
+fn pop_num(stack: &mut Vec<Vec<u8>>) -> Result<i64> {
+    to_num(&pop(stack)?, 4)
+}
+
+fn need(stack: &[Vec<u8>], n: usize) -> Result<()> {
+    if stack.len() < n {
+        return Err(anyhow!("Stack has fewer than {} items", n));
+    }
+    Ok(())
+}
+
+fn push_bool(stack: &mut Vec<Vec<u8>>, b: bool) {
+    stack.push(if b { vec![1] } else { vec![] });
+}
+
+fn check_tapscript_sig(
+    sig: &[u8],
+    xonly: &[u8],
+    checker: &Checker,
+    exec: &mut ExecData,
+) -> Result<bool> {
+    if !sig.is_empty() {
+        exec.weight_left -= VALIDATION_WEIGHT_PER_SIGOP;
+        if exec.weight_left < 0 {
+            return Err(anyhow!("Tapscript exceeds its signature budget"));
+        }
+    }
+    if xonly.is_empty() {
+        return Err(anyhow!("Empty tapscript public key"));
+    }
+    // Other key sizes are reserved for upgrades and always succeed
+    if xonly.len() == 32
+        && !sig.is_empty()
+        && !checker.check_schnorr(sig, xonly, SigVersion::Tapscript, exec)?
+    {
+        return Err(anyhow!("Invalid tapscript signature"));
+    }
+    Ok(!sig.is_empty())
+}
+
+fn eval_script(
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+    flags: u32,
+    checker: &Checker,
+    sigversion: SigVersion,
+    exec: &mut ExecData,
+) -> Result<()> {
+    let raw = script.as_bytes();
+    if sigversion != SigVersion::Tapscript && raw.len() > MAX_SCRIPT_SIZE {
+        return Err(anyhow!("Script is larger than {} bytes", MAX_SCRIPT_SIZE));
+    }
+    let mut alt_stack: Vec<Vec<u8>> = vec![];
+    let mut conditions: Vec<bool> = vec![];
+    let mut op_count = 0;
+    // Where the script code for signatures starts
+    let mut code_start = 0;
+    let mut pc = 0;
+    let mut op_index = 0u32;
+    while pc < raw.len() {
+        let (op, cmd, next) = read_instruction(raw, pc)?;
+        pc = next;
+        let executing = conditions.iter().all(|c| *c);
+        if let Cmd::Data(data) = &cmd {
+            if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                return Err(anyhow!(
+                    "Push is larger than {} bytes",
+                    MAX_SCRIPT_ELEMENT_SIZE
+                ));
+            }
+        }
+        if sigversion != SigVersion::Tapscript && op > OP_16 {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(anyhow!(
+                    "Script has more than {} opcodes",
+                    MAX_OPS_PER_SCRIPT
+                ));
+            }
+        }
+        // These fail even in unexecuted branches
+        if is_disabled(op) {
+            return Err(anyhow!("{} is disabled", op_name(op)));
+        }
+        if op == OP_VERIF || op == OP_VERNOTIF {
+            return Err(anyhow!("{} is invalid", op_name(op)));
+        }
+
+        if let Cmd::Data(data) = cmd {
+            if executing {
+                stack.push(data);
+            }
+        } else if executing || (OP_IF..=OP_ENDIF).contains(&op) {
+            match op {
+                OP_0 => stack.push(vec![]),
+                OP_1NEGATE => stack.push(encode_num(-1)),
+                OP_1..=OP_16 => stack.push(encode_num((op - OP_1 + 1) as i64)),
+                OP_NOP | OP_NOP1 | OP_NOP4..=OP_NOP10 => {}
+                OP_CHECKLOCKTIMEVERIFY => {
+                    if flags & VERIFY_CHECKLOCKTIMEVERIFY != 0 {
+                        need(stack, 1)?;
+                        let locktime = to_num(stack.last().unwrap(), 5)?;
+                        if locktime < 0 || !checker.check_locktime(locktime) {
+                            return Err(anyhow!("Locktime requirement not satisfied"));
+                        }
+                    }
+                }
+                OP_CHECKSEQUENCEVERIFY => {
+                    if flags & VERIFY_CHECKSEQUENCEVERIFY != 0 {
+                        need(stack, 1)?;
+                        let sequence = to_num(stack.last().unwrap(), 5)?;
+                        if sequence < 0
+                            || (sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 == 0
+                                && !checker.check_sequence(sequence))
+                        {
+                            return Err(anyhow!("Sequence requirement not satisfied"));
+                        }
+                    }
+                }
+                OP_IF | OP_NOTIF => {
+                    let mut condition = false;
+                    if executing {
+                        let top = pop(stack)?;
+                        if sigversion == SigVersion::Tapscript
+                            && (top.len() > 1 || (top.len() == 1 && top[0] != 1))
+                        {
+                            return Err(anyhow!("Tapscript requires a minimal {}", op_name(op)));
+                        }
+                        condition = cast_to_bool(&top) == (op == OP_IF);
+                    }
+                    conditions.push(condition);
+                }
+                OP_ELSE => {
+                    let last = conditions
+                        .last_mut()
+                        .ok_or_else(|| anyhow!("OP_ELSE without OP_IF"))?;
+                    *last = !*last;
+                }
+                OP_ENDIF => {
+                    conditions
+                        .pop()
+                        .ok_or_else(|| anyhow!("OP_ENDIF without OP_IF"))?;
+                }
+                OP_VERIFY => {
+                    if !cast_to_bool(&pop(stack)?) {
+                        return Err(anyhow!("OP_VERIFY failed"));
+                    }
+                }
+                OP_RETURN => return Err(anyhow!("OP_RETURN was executed")),
+                OP_TOALTSTACK => alt_stack.push(pop(stack)?),
+                OP_FROMALTSTACK => stack.push(pop(&mut alt_stack)?),
+                OP_2DROP => {
+                    need(stack, 2)?;
+                    stack.truncate(stack.len() - 2);
+                }
+                OP_2DUP | OP_3DUP | OP_2OVER => {
+                    let (count, depth) = match op {
+                        OP_2DUP => (2, 2),
+                        OP_3DUP => (3, 3),
+                        _ => (2, 4),
+                    };
+                    need(stack, depth)?;
+                    let start = stack.len() - depth;
+                    let items = stack[start..start + count].to_vec();
+                    stack.extend(items);
+                }
+                OP_2ROT => {
+                    need(stack, 6)?;
+                    let start = stack.len() - 6;
+                    let items: Vec<Vec<u8>> = stack.drain(start..start + 2).collect();
+                    stack.extend(items);
+                }
+                OP_2SWAP => {
+                    need(stack, 4)?;
+                    let len = stack.len();
+                    stack.swap(len - 4, len - 2);
+                    stack.swap(len - 3, len - 1);
+                }
+                OP_IFDUP => {
+                    need(stack, 1)?;
+                    let top = stack.last().unwrap().clone();
+                    if cast_to_bool(&top) {
+                        stack.push(top);
+                    }
+                }
+                OP_DEPTH => stack.push(encode_num(stack.len() as i64)),
+                OP_DROP => {
+                    pop(stack)?;
+                }
+                OP_DUP => {
+                    need(stack, 1)?;
+                    stack.push(stack.last().unwrap().clone());
+                }
+                OP_NIP => {
+                    need(stack, 2)?;
+                    stack.remove(stack.len() - 2);
+                }
+                OP_OVER => {
+                    need(stack, 2)?;
+                    stack.push(stack[stack.len() - 2].clone());
+                }
+                OP_PICK | OP_ROLL => {
+                    let n = pop_num(stack)?;
+                    if n < 0 || n as usize >= stack.len() {
+                        return Err(anyhow!("{} index {} is out of range", op_name(op), n));
+                    }
+                    let index = stack.len() - 1 - n as usize;
+                    let item = if op == OP_ROLL {
+                        stack.remove(index)
+                    } else {
+                        stack[index].clone()
+                    };
+                    stack.push(item);
+                }
+                OP_ROT => {
+                    need(stack, 3)?;
+                    let item = stack.remove(stack.len() - 3);
+                    stack.push(item);
+                }
+                OP_SWAP => {
+                    need(stack, 2)?;
+                    let len = stack.len();
+                    stack.swap(len - 2, len - 1);
+                }
+                OP_TUCK => {
+                    need(stack, 2)?;
+                    let top = stack.last().unwrap().clone();
+                    stack.insert(stack.len() - 2, top);
+                }
+                OP_SIZE => {
+                    need(stack, 1)?;
+                    stack.push(encode_num(stack.last().unwrap().len() as i64));
+                }
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    need(stack, 2)?;
+                    let equal = pop(stack)? == pop(stack)?;
+                    if op == OP_EQUALVERIFY {
+                        if !equal {
+                            return Err(anyhow!("OP_EQUALVERIFY failed"));
+                        }
+                    } else {
+                        push_bool(stack, equal);
+                    }
+                }
+                OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                    let n = pop_num(stack)?;
+                    let result = match op {
+                        OP_1ADD => n + 1,
+                        OP_1SUB => n - 1,
+                        OP_NEGATE => -n,
+                        OP_ABS => n.abs(),
+                        OP_NOT => (n == 0) as i64,
+                        _ => (n != 0) as i64,
+                    };
+                    stack.push(encode_num(result));
+                }
+                OP_ADD..=OP_SUB | OP_BOOLAND..=OP_MAX => {
+                    need(stack, 2)?;
+                    let b = pop_num(stack)?;
+                    let a = pop_num(stack)?;
+                    let result = match op {
+                        OP_ADD => a + b,
+                        OP_SUB => a - b,
+                        OP_BOOLAND => (a != 0 && b != 0) as i64,
+                        OP_BOOLOR => (a != 0 || b != 0) as i64,
+                        OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                        OP_NUMNOTEQUAL => (a != b) as i64,
+                        OP_LESSTHAN => (a < b) as i64,
+                        OP_GREATERTHAN => (a > b) as i64,
+                        OP_LESSTHANOREQUAL => (a <= b) as i64,
+                        OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                        OP_MIN => a.min(b),
+                        _ => a.max(b),
+                    };
+                    if op == OP_NUMEQUALVERIFY {
+                        if result == 0 {
+                            return Err(anyhow!("OP_NUMEQUALVERIFY failed"));
+                        }
+                    } else {
+                        stack.push(encode_num(result));
+                    }
+                }
+                OP_WITHIN => {
+                    need(stack, 3)?;
+                    let max = pop_num(stack)?;
+                    let min = pop_num(stack)?;
+                    let n = pop_num(stack)?;
+                    push_bool(stack, min <= n && n < max);
+                }
+                OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                    let data = pop(stack)?;
+                    stack.push(match op {
+                        OP_RIPEMD160 => ripemd160(&data),
+                        OP_SHA1 => sha1(&data),
+                        OP_SHA256 => sha256(&data),
+                        OP_HASH160 => hash160(&data),
+                        _ => hash256(&data),
+                    });
+                }
+                OP_CODESEPARATOR => {
+                    code_start = pc;
+                    exec.codesep_pos = op_index;
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    need(stack, 2)?;
+                    let sec = pop(stack)?;
+                    let sig = pop(stack)?;
+                    let valid = if sigversion == SigVersion::Tapscript {
+                        check_tapscript_sig(&sig, &sec, checker, exec)?
+                    } else {
+                        let script_code =
+                            script_code(&raw[code_start..], std::slice::from_ref(&sig), sigversion);
+                        checker.check_ecdsa(&sig, &sec, &script_code, sigversion, flags)?
+                    };
+                    if op == OP_CHECKSIGVERIFY {
+                        if !valid {
+                            return Err(anyhow!("OP_CHECKSIGVERIFY failed"));
+                        }
+                    } else {
+                        push_bool(stack, valid);
+                    }
+                }
+                OP_CHECKSIGADD if sigversion == SigVersion::Tapscript => {
+                    need(stack, 3)?;
+                    let xonly = pop(stack)?;
+                    let n = pop_num(stack)?;
+                    let sig = pop(stack)?;
+                    let valid = check_tapscript_sig(&sig, &xonly, checker, exec)?;
+                    stack.push(encode_num(n + valid as i64));
+                }
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    if sigversion == SigVersion::Tapscript {
+                        return Err(anyhow!("{} is disabled in tapscript", op_name(op)));
+                    }
+                    let key_count = pop_num(stack)?;
+                    if key_count < 0 || key_count as usize > MAX_PUBKEYS_PER_MULTISIG {
+                        return Err(anyhow!("Invalid multisig key count {}", key_count));
+                    }
+                    op_count += key_count as usize;
+                    if op_count > MAX_OPS_PER_SCRIPT {
+                        return Err(anyhow!(
+                            "Script has more than {} opcodes",
+                            MAX_OPS_PER_SCRIPT
+                        ));
+                    }
+                    need(stack, key_count as usize)?;
+                    // Both keys and signatures come off the stack last first
+                    let keys = stack.split_off(stack.len() - key_count as usize);
+                    let sig_count = pop_num(stack)?;
+                    if sig_count < 0 || sig_count > key_count {
+                        return Err(anyhow!("Invalid multisig signature count {}", sig_count));
+                    }
+                    need(stack, sig_count as usize + 1)?;
+                    let sigs = stack.split_off(stack.len() - sig_count as usize);
+                    // The extra item consumed by an off-by-one bug
+                    let dummy = pop(stack)?;
+                    if flags & VERIFY_NULLDUMMY != 0 && !dummy.is_empty() {
+                        return Err(anyhow!("Multisig dummy is not empty"));
+                    }
+
+                    let script_code = script_code(&raw[code_start..], &sigs, sigversion);
+                    let mut sigs = sigs.iter().rev().peekable();
+                    let mut keys = keys.iter().rev();
+                    let mut keys_left = key_count;
+                    let mut sigs_left = sig_count;
+                    let mut valid = true;
+                    while sigs_left > 0 {
+                        let sig = sigs.peek().unwrap();
+                        let key = keys.next().unwrap();
+                        if checker.check_ecdsa(sig, key, &script_code, sigversion, flags)? {
+                            sigs.next();
+                            sigs_left -= 1;
+                        }
+                        keys_left -= 1;
+                        if sigs_left > keys_left {
+                            valid = false;
+                            break;
+                        }
+                    }
+                    if op == OP_CHECKMULTISIGVERIFY {
+                        if !valid {
+                            return Err(anyhow!("OP_CHECKMULTISIGVERIFY failed"));
+                        }
+                    } else {
+                        push_bool(stack, valid);
+                    }
+                }
+                _ => return Err(anyhow!("Bad opcode {}", op_name(op))),
+            }
+        }
+
+        if stack.len() + alt_stack.len() > MAX_STACK_SIZE {
+            return Err(anyhow!("Stack has more than {} items", MAX_STACK_SIZE));
+        }
+        op_index += 1;
+    }
+    if !conditions.is_empty() {
+        return Err(anyhow!("Unbalanced conditional"));
+    }
+    Ok(())
+}
+
+// Witness scripts must leave exactly one true item
+fn execute_witness_script(
+    mut stack: Vec<Vec<u8>>,
+    script: &Script,
+    flags: u32,
+    checker: &Checker,
+    sigversion: SigVersion,
+    exec: &mut ExecData,
+) -> Result<()> {
+    if sigversion == SigVersion::Tapscript {
+        let raw = script.as_bytes();
+        let mut pc = 0;
+        while pc < raw.len() {
+            let (op, _, next) = read_instruction(raw, pc)?;
+            if is_op_success(op) {
+                return Ok(());
+            }
+            pc = next;
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(anyhow!("Stack has more than {} items", MAX_STACK_SIZE));
+        }
+    }
+    if stack
+        .iter()
+        .any(|item| item.len() > MAX_SCRIPT_ELEMENT_SIZE)
+    {
+        return Err(anyhow!(
+            "Witness item is larger than {} bytes",
+            MAX_SCRIPT_ELEMENT_SIZE
+        ));
+    }
+    eval_script(&mut stack, script, flags, checker, sigversion, exec)?;
+    if stack.len() != 1 || !cast_to_bool(&stack[0]) {
+        return Err(anyhow!("Witness script must leave exactly one true item"));
+    }
+    Ok(())
+}
+
+fn verify_witness_program(
+    witness: &[Vec<u8>],
+    version: u8,
+    program: &[u8],
+    flags: u32,
+    checker: &Checker,
+    is_p2sh: bool,
+) -> Result<()> {
+    let mut exec = ExecData::default();
+    match (version, program.len()) {
+        (0, 32) => {
+            let (script, stack) = witness
+                .split_last()
+                .ok_or_else(|| anyhow!("Witness is empty"))?;
+            if sha256(script) != program {
+                return Err(anyhow!("Witness script doesn't match the program"));
+            }
+            let script = Script::from_bytes(script.clone());
+            execute_witness_script(
+                stack.to_vec(),
+                &script,
+                flags,
+                checker,
+                SigVersion::WitnessV0,
+                &mut exec,
+            )
+        }
+        (0, 20) => {
+            if witness.len() != 2 {
+                return Err(anyhow!("P2WPKH witness must have two items"));
+            }
+            let script = Script::p2pkh(program);
+            execute_witness_script(
+                witness.to_vec(),
+                &script,
+                flags,
+                checker,
+                SigVersion::WitnessV0,
+                &mut exec,
+            )
+        }
+        (0, _) => Err(anyhow!("Invalid witness program length {}", program.len())),
+        (1, 32) if !is_p2sh && flags & VERIFY_TAPROOT != 0 => {
+            let mut stack = witness.to_vec();
+            if stack.is_empty() {
+                return Err(anyhow!("Witness is empty"));
+            }
+            if stack.len() >= 2 && stack.last().unwrap().first() == Some(&ANNEX_TAG) {
+                exec.annex = stack.pop();
+            }
+            if stack.len() == 1 {
+                if !checker.check_schnorr(&stack[0], program, SigVersion::Taproot, &exec)? {
+                    return Err(anyhow!("Invalid taproot key path signature"));
+                }
+                return Ok(());
+            }
+
+            let control = stack.pop().unwrap();
+            let script = Script::from_bytes(stack.pop().unwrap());
+            let nodes = control.len().saturating_sub(TAPROOT_CONTROL_BASE_SIZE);
+            if control.len() < TAPROOT_CONTROL_BASE_SIZE
+                || !nodes.is_multiple_of(TAPROOT_CONTROL_NODE_SIZE)
+                || nodes / TAPROOT_CONTROL_NODE_SIZE > TAPROOT_CONTROL_MAX_NODE_COUNT
+            {
+                return Err(anyhow!("Invalid control block size {}", control.len()));
+            }
+            let leaf_version = control[0] & 0xfe;
+            let root = control_block_root(&control, &script)?;
+            let output_key = S256Point::lift_x(&control[1..TAPROOT_CONTROL_BASE_SIZE])
+                .and_then(|internal| internal.tap_tweak(Some(&root)));
+            match output_key {
+                Ok(key) if key.xonly() == program && key.has_even_y() == (control[0] & 1 == 0) => {}
+                _ => return Err(anyhow!("Taproot commitment doesn't match the program")),
+            }
+            // Unknown leaf versions are left for upgrades
+            if leaf_version != TAPSCRIPT_LEAF_VERSION {
+                return Ok(());
+            }
+            let witness_size = checker.tx.tx_ins[checker.input_index]
+                .serialize_witness()
+                .len();
+            exec.leaf_hash = tap_leaf_hash(leaf_version, &script);
+            exec.codesep_pos = 0xffffffff;
+            exec.weight_left = witness_size as i64 + VALIDATION_WEIGHT_OFFSET;
+            execute_witness_script(
+                stack,
+                &script,
+                flags,
+                checker,
+                SigVersion::Tapscript,
+                &mut exec,
+            )
+        }
+        // Other versions are left for upgrades
+        _ => Ok(()),
+    }
+}
+
+// Runs the scriptSig, the script pubkey of the spent output and any P2SH or
+// witness script. `prevouts` are the outputs spent by every input of `tx`.
+pub fn verify_input(tx: &Tx, input_index: usize, prevouts: &[TxOut], flags: u32) -> Result<()> {
+    let tx_in = tx
+        .tx_ins
+        .get(input_index)
+        .ok_or_else(|| anyhow!("Input {} is out of range", input_index))?;
+    if prevouts.len() != tx.tx_ins.len() {
+        return Err(anyhow!(
+            "Expected {} spent outputs, got {}",
+            tx.tx_ins.len(),
+            prevouts.len()
+        ));
+    }
+    let checker = Checker {
+        tx,
+        input_index,
+        prevouts,
+    };
+    let script_sig = &tx_in.script_sig;
+    let script_pubkey = &prevouts[input_index].script_pubkey;
+    let witness = &tx_in.witness;
+    let mut exec = ExecData::default();
+
+    let mut stack = vec![];
+    eval_script(
+        &mut stack,
+        script_sig,
+        flags,
+        &checker,
+        SigVersion::Base,
+        &mut exec,
+    )?;
+    let p2sh_stack = stack.clone();
+    eval_script(
+        &mut stack,
+        script_pubkey,
+        flags,
+        &checker,
+        SigVersion::Base,
+        &mut exec,
+    )?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(anyhow!("Script evaluated to false"));
+    }
+
+    let mut had_witness = false;
+    if flags & VERIFY_WITNESS != 0 {
+        if let Some((version, program)) = script_pubkey.witness_program() {
+            had_witness = true;
+            if !script_sig.is_empty() {
+                return Err(anyhow!("Native witness spends need an empty scriptSig"));
+            }
+            verify_witness_program(witness, version, program, flags, &checker, false)?;
+        }
+    }
+
+    if flags & VERIFY_P2SH != 0 && script_pubkey.is_p2sh() {
+        if !script_sig.is_push_only() {
+            return Err(anyhow!("P2SH scriptSig must only push data"));
+        }
+        let mut stack = p2sh_stack;
+        let redeem_script = Script::from_bytes(pop(&mut stack)?);
+        eval_script(
+            &mut stack,
+            &redeem_script,
+            flags,
+            &checker,
+            SigVersion::Base,
+            &mut exec,
+        )?;
+        if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+            return Err(anyhow!("P2SH script evaluated to false"));
+        }
+        if flags & VERIFY_WITNESS != 0 {
+            if let Some((version, program)) = redeem_script.witness_program() {
+                had_witness = true;
+                let push = Script::from_cmds(&[Cmd::Data(redeem_script.raw_serialize())]);
+                if script_sig != &push {
+                    return Err(anyhow!("P2SH witness scriptSig must be a single push"));
+                }
+                verify_witness_program(witness, version, program, flags, &checker, true)?;
+            }
+        }
+    }
+
+    if flags & VERIFY_WITNESS != 0 && !had_witness && !witness.is_empty() {
+        return Err(anyhow!("Input has an unexpected witness"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use crate::helper::decode_hex;
+#[cfg(test)]
+use crate::network::Network;
+#[cfg(test)]
+use crate::s256::PrivateKey;
+#[cfg(test)]
+use crate::taproot::TapTree;
+#[cfg(test)]
+use crate::transaction::{TxIn, SIGHASH_ALL, SIGHASH_DEFAULT};
+
+#[cfg(test)]
+const ALL_FLAGS: u32 = VERIFY_P2SH
+    | VERIFY_DERSIG
+    | VERIFY_NULLDUMMY
+    | VERIFY_CHECKLOCKTIMEVERIFY
+    | VERIFY_CHECKSEQUENCEVERIFY
+    | VERIFY_WITNESS
+    | VERIFY_TAPROOT;
+
+// A transaction spending a single 100000 satoshi output
+#[cfg(test)]
+fn test_tx(script_pubkey: &Script) -> (Tx, Vec<TxOut>) {
+    let tx = Tx::new(
+        2,
+        vec![TxIn::new([7u8; 32], 0, None, 0xffffffff)],
+        vec![TxOut::new(90_000, Script::p2wpkh(&[1u8; 20]))],
+        0,
+        Network::Regtest,
+    );
+    (tx, vec![TxOut::new(100_000, script_pubkey.clone())])
+}
+
+#[cfg(test)]
+fn run(script_sig: &[Cmd], script_pubkey: &[Cmd], flags: u32) -> Result<()> {
+    let (mut tx, prevouts) = test_tx(&Script::from_cmds(script_pubkey));
+    tx.tx_ins[0].script_sig = Script::from_cmds(script_sig);
+    verify_input(&tx, 0, &prevouts, flags)
+}
+
+#[cfg(test)]
+fn ecdsa_sig(key: &PrivateKey, z: &[u8]) -> Vec<u8> {
+    let mut sig = key.sign(BigInt::from_bytes_be(Sign::Plus, z)).der();
+    sig.push(SIGHASH_ALL as u8);
+    sig
+}
+
+#[test]
+fn test_script_ops() {
+    use Cmd::Op;
+
+    let n = Cmd::num;
+    let data = |s: &str| Cmd::Data(decode_hex(s).unwrap());
+    let ok: Vec<(Vec<Cmd>, Vec<Cmd>)> = vec![
+        (vec![n(1), n(2)], vec![Op(OP_ADD), n(3), Op(OP_EQUAL)]),
+        (
+            vec![],
+            vec![
+                n(1),
+                Op(OP_IF),
+                n(2),
+                Op(OP_ELSE),
+                Op(OP_RETURN),
+                Op(OP_ENDIF),
+            ],
+        ),
+        (
+            vec![],
+            vec![n(0), Op(OP_IF), Op(OP_VER), Op(OP_ENDIF), n(1)],
+        ),
+        (
+            vec![n(0)],
+            vec![Op(OP_NOTIF), n(1), Op(OP_ELSE), n(0), Op(OP_ENDIF)],
+        ),
+        (vec![data("80")], vec![Op(OP_NOT)]),
+        (
+            vec![n(1), n(2), n(3), n(2)],
+            vec![
+                Op(OP_ROLL),
+                n(1),
+                Op(OP_EQUALVERIFY),
+                n(3),
+                Op(OP_EQUALVERIFY),
+                n(2),
+                Op(OP_EQUAL),
+            ],
+        ),
+        (
+            vec![n(1), n(2), n(3), n(4), n(5), n(6)],
+            vec![
+                Op(OP_2ROT),
+                n(2),
+                Op(OP_EQUALVERIFY),
+                n(1),
+                Op(OP_EQUALVERIFY),
+                n(6),
+                Op(OP_EQUALVERIFY),
+                n(5),
+                Op(OP_EQUALVERIFY),
+                n(4),
+                Op(OP_EQUALVERIFY),
+                n(3),
+                Op(OP_EQUAL),
+            ],
+        ),
+        (
+            vec![n(1), n(2)],
+            vec![
+                Op(OP_2DUP),
+                Op(OP_ADD),
+                Op(OP_ADD),
+                Op(OP_ADD),
+                n(6),
+                Op(OP_EQUAL),
+            ],
+        ),
+        (
+            vec![n(2), n(1), n(3)],
+            vec![
+                Op(OP_WITHIN),
+                Op(OP_VERIFY),
+                Op(OP_DEPTH),
+                Op(OP_0NOTEQUAL),
+                Op(OP_NOT),
+            ],
+        ),
+        (
+            vec![n(-5)],
+            vec![Op(OP_ABS), Op(OP_NEGATE), n(-5), Op(OP_NUMEQUAL)],
+        ),
+        (
+            vec![n(3), n(9)],
+            vec![Op(OP_TUCK), Op(OP_MIN), Op(OP_SUB), n(6), Op(OP_EQUAL)],
+        ),
+        (
+            vec![Cmd::Data(b"abc".to_vec())],
+            vec![
+                Op(OP_DUP),
+                Op(OP_SHA1),
+                data("a9993e364706816aba3e25717850c26c9cd0d89d"),
+                Op(OP_EQUALVERIFY),
+                Op(OP_SHA256),
+                data("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+                Op(OP_EQUAL),
+            ],
+        ),
+        (vec![n(7)], vec![Op(OP_TOALTSTACK), Op(OP_FROMALTSTACK)]),
+        (vec![n(0)], vec![Op(OP_IFDUP), Op(OP_DEPTH)]),
+        (vec![n(1)], vec![Op(OP_SIZE), Op(OP_NIP)]),
+    ];
+    for (script_sig, script_pubkey) in ok.iter() {
+        run(script_sig, script_pubkey, ALL_FLAGS).unwrap();
+    }
+
+    let fail: Vec<(Vec<Cmd>, Vec<Cmd>)> = vec![
+        (vec![], vec![n(0)]),
+        (vec![], vec![]),
+        (vec![n(1)], vec![Op(OP_RETURN)]),
+        // Disabled opcodes fail even when not executed
+        (
+            vec![],
+            vec![n(0), Op(OP_IF), Op(OP_CAT), Op(OP_ENDIF), n(1)],
+        ),
+        (
+            vec![],
+            vec![n(0), Op(OP_IF), Op(OP_VERIF), Op(OP_ENDIF), n(1)],
+        ),
+        (vec![], vec![n(1), Op(OP_VER)]),
+        (vec![], vec![n(1), Op(OP_IF), n(1)]),
+        (vec![], vec![n(1), Op(OP_ENDIF)]),
+        (vec![data("0000008000")], vec![Op(OP_1ADD)]),
+        (vec![n(1)], vec![n(1), Op(OP_PICK)]),
+        (vec![n(1)], vec![Op(OP_FROMALTSTACK)]),
+        (vec![n(1)], vec![Op(OP_CHECKSIGADD)]),
+        (vec![Cmd::Data(vec![1; 521])], vec![Op(OP_SIZE)]),
+        (vec![n(1); 1001], vec![]),
+        (vec![n(1)], vec![Op(OP_NOP); 202]),
+    ];
+    for (script_sig, script_pubkey) in fail.iter() {
+        assert!(run(script_sig, script_pubkey, ALL_FLAGS).is_err());
+    }
+    run(&[n(1)], &vec![Op(OP_NOP); 201], ALL_FLAGS).unwrap();
+
+    // Locktime opcodes are NOPs until their soft forks activate
+    let script = [n(100), Op(OP_CHECKLOCKTIMEVERIFY)];
+    run(&[], &script, VERIFY_NONE).unwrap();
+    assert!(run(&[], &script, VERIFY_CHECKLOCKTIMEVERIFY).is_err());
+}
+
+#[test]
+fn test_legacy_signatures() {
+    let key = PrivateKey::new(BigInt::from(1001)).unwrap();
+    let sec = key.point.sec(true);
+    let (mut tx, prevouts) = test_tx(&Script::p2pkh(&key.point.hash160(true)));
+    let z = tx
+        .sig_hash(0, &prevouts[0].script_pubkey, SIGHASH_ALL)
+        .unwrap();
+    tx.tx_ins[0].script_sig =
+        Script::from_cmds(&[Cmd::Data(ecdsa_sig(&key, &z)), Cmd::Data(sec.clone())]);
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+    let mut changed = tx.clone();
+    changed.tx_outs[0].amount += 1;
+    assert!(verify_input(&changed, 0, &prevouts, ALL_FLAGS).is_err());
+    assert!(verify_input(&tx, 0, &prevouts[..0], ALL_FLAGS).is_err());
+
+    // An empty signature is a valid failure
+    let script = Script::from_cmds(&[
+        Cmd::Data(sec.clone()),
+        Cmd::Op(OP_CHECKSIG),
+        Cmd::Op(OP_NOT),
+    ]);
+    let (mut tx, prevouts) = test_tx(&script);
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::num(0)]);
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+
+    // Signatures only cover the script after the last OP_CODESEPARATOR
+    let script = Script::from_cmds(&[
+        Cmd::Op(OP_CODESEPARATOR),
+        Cmd::Data(sec.clone()),
+        Cmd::Op(OP_CHECKSIG),
+    ]);
+    let (mut tx, prevouts) = test_tx(&script);
+    let z = tx.sig_hash(0, &script, SIGHASH_ALL).unwrap();
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(ecdsa_sig(&key, &z))]);
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+    let script_code = Script::from_cmds(&[Cmd::Data(sec), Cmd::Op(OP_CHECKSIG)]);
+    let z = tx.sig_hash(0, &script_code, SIGHASH_ALL).unwrap();
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(ecdsa_sig(&key, &z))]);
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+
+    // 2-of-3 multisig, bare and in P2SH
+    let keys: Vec<PrivateKey> = (1..=3)
+        .map(|i| PrivateKey::new(BigInt::from(i)).unwrap())
+        .collect();
+    let secs: Vec<Vec<u8>> = keys.iter().map(|key| key.point.sec(true)).collect();
+    let multisig = Script::multisig(2, &secs);
+    for p2sh in [false, true].iter() {
+        let script_pubkey = if *p2sh {
+            Script::p2sh(&hash160(multisig.as_bytes()))
+        } else {
+            multisig.clone()
+        };
+        let (mut tx, prevouts) = test_tx(&script_pubkey);
+        let z = tx.sig_hash(0, &multisig, SIGHASH_ALL).unwrap();
+        let sig = |i: usize| Cmd::Data(ecdsa_sig(&keys[i], &z));
+        let redeem = Cmd::Data(multisig.raw_serialize());
+        let spend = |cmds: &[Cmd]| {
+            let mut cmds = cmds.to_vec();
+            if *p2sh {
+                cmds.push(redeem.clone());
+            }
+            Script::from_cmds(&cmds)
+        };
+        tx.tx_ins[0].script_sig = spend(&[Cmd::num(0), sig(0), sig(2)]);
+        verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+        tx.tx_ins[0].script_sig = spend(&[Cmd::num(0), sig(2), sig(0)]);
+        assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+        tx.tx_ins[0].script_sig = spend(&[Cmd::num(1), sig(0), sig(1)]);
+        assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+        verify_input(&tx, 0, &prevouts, ALL_FLAGS & !VERIFY_NULLDUMMY).unwrap();
+    }
+
+    // P2SH scriptSigs may only push
+    let redeem = Script::from_cmds(&[Cmd::num(1)]);
+    let (mut tx, prevouts) = test_tx(&Script::p2sh(&hash160(redeem.as_bytes())));
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(redeem.raw_serialize())]);
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+    tx.tx_ins[0].script_sig =
+        Script::from_cmds(&[Cmd::Op(OP_NOP), Cmd::Data(redeem.raw_serialize())]);
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+    verify_input(&tx, 0, &prevouts, VERIFY_NONE).unwrap();
+
+    // Hybrid keys, and signatures that are only valid before BIP66
+    let mut hybrid = key.point.sec(false);
+    hybrid[0] = 0x06 | (hybrid[64] & 1);
+    let script = Script::from_cmds(&[Cmd::Data(hybrid.clone()), Cmd::Op(OP_CHECKSIG)]);
+    let (mut tx, prevouts) = test_tx(&script);
+    let z = tx.sig_hash(0, &script, SIGHASH_ALL).unwrap();
+    let sig = ecdsa_sig(&key, &z);
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(sig.clone())]);
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+    hybrid[0] ^= 1;
+    let script = Script::from_cmds(&[Cmd::Data(hybrid), Cmd::Op(OP_CHECKSIG)]);
+    let (mut tx, prevouts) = test_tx(&script);
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(sig.clone())]);
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+
+    let script = Script::from_cmds(&[Cmd::Data(key.point.sec(true)), Cmd::Op(OP_CHECKSIG)]);
+    let (mut tx, prevouts) = test_tx(&script);
+    let z = tx.sig_hash(0, &script, SIGHASH_ALL).unwrap();
+    let sig = ecdsa_sig(&key, &z);
+    // The sequence length covers a trailing byte
+    let mut padded = sig[..sig.len() - 1].to_vec();
+    padded[1] += 1;
+    padded.extend_from_slice(&[0x00, SIGHASH_ALL as u8]);
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(padded)]);
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS & !VERIFY_DERSIG).unwrap();
+}
+
+#[test]
+fn test_segwit_signatures() {
+    let key = PrivateKey::new(BigInt::from(2002)).unwrap();
+    let sec = key.point.sec(true);
+    let h160 = key.point.hash160(true);
+    let redeem = Script::p2wpkh(&h160);
+    let nested = Script::p2sh(&hash160(redeem.as_bytes()));
+    for script_pubkey in [redeem.clone(), nested.clone()].iter() {
+        let (mut tx, prevouts) = test_tx(script_pubkey);
+        let z = tx
+            .sig_hash_bip143(0, &Script::p2pkh(&h160), 100_000, SIGHASH_ALL)
+            .unwrap();
+        tx.tx_ins[0].witness = vec![ecdsa_sig(&key, &z), sec.clone()];
+        if script_pubkey == &nested {
+            assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+            tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::Data(redeem.raw_serialize())]);
+        }
+        verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+
+        // The amount is signed
+        let mut prevouts = prevouts;
+        prevouts[0].amount += 1;
+        assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+        verify_input(&tx, 0, &prevouts, ALL_FLAGS & !VERIFY_WITNESS).unwrap();
+    }
+
+    // P2WSH with a relative locktime of 10 blocks
+    let witness_script = Script::from_cmds(&[
+        Cmd::num(10),
+        Cmd::Op(OP_CHECKSEQUENCEVERIFY),
+        Cmd::Op(OP_DROP),
+        Cmd::Data(sec),
+        Cmd::Op(OP_CHECKSIG),
+    ]);
+    let (mut tx, prevouts) = test_tx(&Script::p2wsh(&sha256(witness_script.as_bytes())));
+    for (sequence, ok) in [(10, true), (9, false), (0xffffffff, false)].iter() {
+        tx.tx_ins[0].sequence = *sequence;
+        let z = tx
+            .sig_hash_bip143(0, &witness_script, 100_000, SIGHASH_ALL)
+            .unwrap();
+        tx.tx_ins[0].witness = vec![ecdsa_sig(&key, &z), witness_script.raw_serialize()];
+        assert_eq!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_ok(), *ok);
+        let flags = ALL_FLAGS & !VERIFY_CHECKSEQUENCEVERIFY;
+        verify_input(&tx, 0, &prevouts, flags).unwrap();
+    }
+    tx.tx_ins[0].witness.push(vec![1]);
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+
+    // Witnesses are only allowed for witness programs
+    let (mut tx, prevouts) = test_tx(&Script::from_cmds(&[Cmd::num(1)]));
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+    tx.tx_ins[0].witness = vec![vec![1]];
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+}
+
+#[test]
+fn test_bip341_spends() {
+    let vectors: serde_json::Value =
+        serde_json::from_str(include_str!("bip341_vectors.json")).unwrap();
+    let vector = &vectors["keyPathSpending"][0];
+    let prevouts: Vec<TxOut> = vector["given"]["utxosSpent"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|utxo| {
+            let script = decode_hex(utxo["scriptPubKey"].as_str().unwrap()).unwrap();
+            TxOut::new(
+                utxo["amountSats"].as_u64().unwrap(),
+                Script::from_bytes(script),
+            )
+        })
+        .collect();
+    let raw = decode_hex(vector["auxiliary"]["fullySignedTx"].as_str().unwrap()).unwrap();
+    let tx = Tx::parse(&mut raw.as_slice(), Network::Mainnet).unwrap();
+    for i in 0..tx.tx_ins.len() {
+        verify_input(&tx, i, &prevouts, ALL_FLAGS).unwrap();
+    }
+
+    let mut bad = tx.clone();
+    bad.tx_ins[0].witness[0][10] ^= 1;
+    assert_eq!(
+        verify_input(&bad, 0, &prevouts, ALL_FLAGS)
+            .unwrap_err()
+            .to_string(),
+        "Invalid taproot key path signature"
+    );
+    verify_input(&bad, 0, &prevouts, ALL_FLAGS & !VERIFY_TAPROOT).unwrap();
+    bad.tx_ins[0].witness[0].push(0x00);
+    assert!(verify_input(&bad, 0, &prevouts, ALL_FLAGS).is_err());
+}
+
+#[test]
+fn test_tapscript() {
+    let alice = PrivateKey::new(BigInt::from(3003)).unwrap();
+    let bob = PrivateKey::new(BigInt::from(4004)).unwrap();
+    let multisig = Script::from_cmds(&[
+        Cmd::Data(alice.point.xonly()),
+        Cmd::Op(OP_CHECKSIG),
+        Cmd::Data(bob.point.xonly()),
+        Cmd::Op(OP_CHECKSIGADD),
+        Cmd::num(2),
+        Cmd::Op(OP_NUMEQUAL),
+    ]);
+    let success = Script::from_cmds(&[Cmd::Op(0x50), Cmd::Op(OP_RETURN)]);
+    let tree = TapTree::branch(
+        TapTree::leaf(multisig.clone()),
+        TapTree::leaf(success.clone()),
+    );
+    let internal = PrivateKey::new(BigInt::from(5005)).unwrap().point;
+    let output_key = internal.tap_tweak(Some(&tree.merkle_root())).unwrap();
+    let (mut tx, prevouts) = test_tx(&Script::p2tr(&output_key.xonly()));
+    let control = tree
+        .control_block(&internal, TAPSCRIPT_LEAF_VERSION, &multisig)
+        .unwrap();
+
+    let leaf_hash = tap_leaf_hash(TAPSCRIPT_LEAF_VERSION, &multisig);
+    let sign = |key: &PrivateKey, tx: &Tx, annex: Option<&[u8]>| {
+        let msg = tx
+            .sig_hash_bip341(
+                0,
+                &prevouts,
+                SIGHASH_DEFAULT,
+                annex,
+                Some((&leaf_hash, 0xffffffff)),
+            )
+            .unwrap();
+        key.sign_schnorr(&msg, &[0u8; 32])
+    };
+    let alice_sig = sign(&alice, &tx, None);
+    let bob_sig = sign(&bob, &tx, None);
+    let script = multisig.raw_serialize();
+    tx.tx_ins[0].witness = vec![
+        bob_sig.clone(),
+        alice_sig.clone(),
+        script.clone(),
+        control.clone(),
+    ];
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+
+    // Bob abstaining leaves one signature of two
+    tx.tx_ins[0].witness[0] = vec![];
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+    // A bad signature fails the script rather than counting as zero
+    tx.tx_ins[0].witness[0] = alice_sig.clone();
+    assert_eq!(
+        verify_input(&tx, 0, &prevouts, ALL_FLAGS)
+            .unwrap_err()
+            .to_string(),
+        "Invalid tapscript signature"
+    );
+    let mut wrong_parity = control.clone();
+    wrong_parity[0] ^= 1;
+    tx.tx_ins[0].witness = vec![bob_sig, alice_sig, script.clone(), wrong_parity];
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+
+    // The annex is signed
+    let annex = vec![ANNEX_TAG, 1, 2, 3];
+    let with_annex = |alice_sig, bob_sig| {
+        vec![
+            bob_sig,
+            alice_sig,
+            script.clone(),
+            control.clone(),
+            annex.clone(),
+        ]
+    };
+    tx.tx_ins[0].witness = with_annex(sign(&alice, &tx, None), sign(&bob, &tx, None));
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+    tx.tx_ins[0].witness = with_annex(
+        sign(&alice, &tx, Some(&annex)),
+        sign(&bob, &tx, Some(&annex)),
+    );
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+
+    // OP_SUCCESS leaves succeed whatever follows
+    let control = tree
+        .control_block(&internal, TAPSCRIPT_LEAF_VERSION, &success)
+        .unwrap();
+    tx.tx_ins[0].witness = vec![success.raw_serialize(), control];
+    verify_input(&tx, 0, &prevouts, ALL_FLAGS).unwrap();
+
+    // Multisig opcodes are disabled
+    let script = Script::from_cmds(&[
+        Cmd::num(0),
+        Cmd::num(0),
+        Cmd::num(0),
+        Cmd::Op(OP_CHECKMULTISIG),
+    ]);
+    let tree = TapTree::leaf(script.clone());
+    let output_key = internal.tap_tweak(Some(&tree.merkle_root())).unwrap();
+    let (mut tx, prevouts) = test_tx(&Script::p2tr(&output_key.xonly()));
+    let control = tree
+        .control_block(&internal, TAPSCRIPT_LEAF_VERSION, &script)
+        .unwrap();
+    tx.tx_ins[0].witness = vec![script.raw_serialize(), control];
+    assert!(verify_input(&tx, 0, &prevouts, ALL_FLAGS).is_err());
+}
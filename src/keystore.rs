@@ -0,0 +1,410 @@
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use num_bigint::{BigInt, Sign};
+use rand::RngCore;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::bip32::ExtendedPrivKey;
+use crate::helper::{encode_variant, read_variant};
+use crate::network::Network;
+use crate::s256::{PrivateKey, N};
+
+const MAGIC: &[u8; 4] = b"PBKS";
+const VERSION: u8 = 1;
+
+// scrypt with N = 2^15, r = 8, p = 1 as BIP38 uses, about 32MiB of memory
+pub const DEFAULT_LOG_N: u8 = 15;
+// 2^20 rounds take 1 GiB, as far as a file read from disk may ask for
+pub const MAX_LOG_N: u8 = 20;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+const KEY_ENTRY: u8 = 0;
+const SEED_ENTRY: u8 = 1;
+
+pub enum Secret {
+    Key {
+        key: PrivateKey<'static>,
+        compressed: bool,
+        network: Network,
+    },
+    // A BIP32 seed, 16 to 64 bytes
+    Seed(Zeroizing<Vec<u8>>),
+}
+
+pub struct Entry {
+    pub label: String,
+    pub secret: Secret,
+}
+
+// Labelled keys and seeds, kept in a file encrypted with ChaCha20-Poly1305
+// under a key derived from the password with scrypt. The file header is
+// authenticated along with the entries.
+//
+// Layout: "PBKS" | version | log_n | salt (16) | nonce (12) | ciphertext
+pub struct Keystore {
+    log_n: u8,
+    salt: [u8; 16],
+    key: Zeroizing<[u8; 32]>,
+    entries: Vec<Entry>,
+}
+
+fn derive_key(password: &str, salt: &[u8], log_n: u8) -> Result<Zeroizing<[u8; 32]>> {
+    if log_n > MAX_LOG_N {
+        return Err(anyhow!("Scrypt cost 2^{} is too high", log_n));
+    }
+    let params = scrypt::Params::new(log_n, SCRYPT_R, SCRYPT_P)
+        .map_err(|_| anyhow!("Invalid scrypt cost 2^{}", log_n))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(password.as_bytes(), salt, &params, &mut key[..])
+        .map_err(|_| anyhow!("scrypt output length is invalid"))?;
+    Ok(key)
+}
+
+fn write_bytes(buf: &mut Vec<u8>, b: &[u8]) {
+    buf.extend_from_slice(&encode_variant(b.len() as u64));
+    buf.extend_from_slice(b);
+}
+
+fn read_bytes<R>(reader: &mut R) -> Result<Zeroizing<Vec<u8>>>
+where
+    R: Read,
+{
+    let len = read_variant(reader)? as usize;
+    let mut b = Zeroizing::new(vec![0u8; len]);
+    reader.read_exact(&mut b)?;
+    Ok(b)
+}
+
+impl Keystore {
+    pub fn new(password: &str, log_n: u8) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Ok(Self {
+            log_n,
+            salt,
+            key: derive_key(password, &salt, log_n)?,
+            entries: vec![],
+        })
+    }
+
+    pub fn change_password(&mut self, password: &str) -> Result<()> {
+        rand::thread_rng().fill_bytes(&mut self.salt);
+        self.key = derive_key(password, &self.salt, self.log_n)?;
+        Ok(())
+    }
+
+    pub fn labels(&self) -> Vec<&str> {
+        self.entries
+            .iter()
+            .map(|entry| entry.label.as_str())
+            .collect()
+    }
+
+    pub fn get(&self, label: &str) -> Option<&Secret> {
+        self.entries
+            .iter()
+            .find(|entry| entry.label == label)
+            .map(|entry| &entry.secret)
+    }
+
+    pub fn insert(&mut self, label: &str, secret: Secret) -> Result<()> {
+        if label.is_empty() {
+            return Err(anyhow!("Keystore labels can't be empty"));
+        }
+        if self.get(label).is_some() {
+            return Err(anyhow!("Keystore already has an entry labelled {}", label));
+        }
+        if let Secret::Seed(seed) = &secret {
+            if seed.len() < 16 || seed.len() > 64 {
+                return Err(anyhow!("Seed must be 16 to 64 bytes, got {}", seed.len()));
+            }
+        }
+        self.entries.push(Entry {
+            label: label.to_string(),
+            secret,
+        });
+        Ok(())
+    }
+
+    pub fn import_wif(&mut self, label: &str, wif: &str) -> Result<()> {
+        let (key, compressed, network) = PrivateKey::parse_wif(wif)?;
+        self.insert(
+            label,
+            Secret::Key {
+                key,
+                compressed,
+                network,
+            },
+        )
+    }
+
+    pub fn import_seed(&mut self, label: &str, seed: &[u8]) -> Result<()> {
+        self.insert(label, Secret::Seed(Zeroizing::new(seed.to_vec())))
+    }
+
+    pub fn remove(&mut self, label: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.label != label);
+        self.entries.len() != len
+    }
+
+    pub fn private_key(&self, label: &str) -> Result<&PrivateKey<'static>> {
+        match self.get(label) {
+            Some(Secret::Key { key, .. }) => Ok(key),
+            Some(Secret::Seed(_)) => Err(anyhow!("{} is a seed, not a key", label)),
+            None => Err(anyhow!("No keystore entry labelled {}", label)),
+        }
+    }
+
+    pub fn master_key(&self, label: &str, network: Network) -> Result<ExtendedPrivKey> {
+        match self.get(label) {
+            Some(Secret::Seed(seed)) => ExtendedPrivKey::new_master(seed, network),
+            Some(Secret::Key { .. }) => Err(anyhow!("{} is a key, not a seed", label)),
+            None => Err(anyhow!("No keystore entry labelled {}", label)),
+        }
+    }
+
+    fn header(&self, nonce: &[u8]) -> Vec<u8> {
+        let mut result = MAGIC.to_vec();
+        result.push(VERSION);
+        result.push(self.log_n);
+        result.extend_from_slice(&self.salt);
+        result.extend_from_slice(nonce);
+        result
+    }
+
+    fn serialize_entries(&self) -> Zeroizing<Vec<u8>> {
+        let mut result = Zeroizing::new(encode_variant(self.entries.len() as u64));
+        for entry in &self.entries {
+            write_bytes(&mut result, entry.label.as_bytes());
+            match &entry.secret {
+                Secret::Key {
+                    key,
+                    compressed,
+                    network,
+                } => {
+                    result.push(KEY_ENTRY);
+                    let mut payload = Zeroizing::new(key.secret_bytes().to_vec());
+                    payload.push(*compressed as u8);
+                    payload.extend_from_slice(&network.magic());
+                    write_bytes(&mut result, &payload);
+                }
+                Secret::Seed(seed) => {
+                    result.push(SEED_ENTRY);
+                    write_bytes(&mut result, seed);
+                }
+            }
+        }
+        result
+    }
+
+    fn parse_entries(&mut self, plaintext: &[u8]) -> Result<()> {
+        let mut reader = Cursor::new(plaintext);
+        let count = read_variant(&mut reader)?;
+        for _ in 0..count {
+            let label = String::from_utf8(read_bytes(&mut reader)?.to_vec())?;
+            let mut kind = [0u8; 1];
+            reader.read_exact(&mut kind)?;
+            let payload = read_bytes(&mut reader)?;
+            let secret = match kind[0] {
+                KEY_ENTRY if payload.len() == 37 => {
+                    let secret = BigInt::from_bytes_be(Sign::Plus, &payload[..32]);
+                    if secret == BigInt::from(0) || secret >= *N {
+                        return Err(anyhow!("Key {} is out of range", label));
+                    }
+                    let mut magic = [0u8; 4];
+                    magic.copy_from_slice(&payload[33..]);
+                    Secret::Key {
+                        key: PrivateKey::new(secret)?,
+                        compressed: payload[32] == 1,
+                        network: Network::from_magic(magic)?,
+                    }
+                }
+                SEED_ENTRY => Secret::Seed(payload),
+                _ => return Err(anyhow!("Invalid keystore entry {}", label)),
+            };
+            self.insert(&label, secret)?;
+        }
+        if reader.position() != plaintext.len() as u64 {
+            return Err(anyhow!("Keystore has trailing data"));
+        }
+        Ok(())
+    }
+
+    // A fresh nonce is used on every save
+    pub fn save<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let header = self.header(&nonce);
+        let plaintext = self.serialize_entries();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key[..]));
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| anyhow!("Keystore encryption failed"))?;
+        writer.write_all(&header)?;
+        writer.write_all(&ciphertext)?;
+        Ok(())
+    }
+
+    pub fn load<R>(reader: &mut R, password: &str) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut header = [0u8; 34];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(anyhow!("Not a keystore file"));
+        }
+        if header[4] != VERSION {
+            return Err(anyhow!("Unsupported keystore version {}", header[4]));
+        }
+        let log_n = header[5];
+        let mut salt = [0u8; 16];
+        salt.copy_from_slice(&header[6..22]);
+        let mut ciphertext = vec![];
+        reader.read_to_end(&mut ciphertext)?;
+        let key = derive_key(password, &salt, log_n)?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..]));
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(
+                    Nonce::from_slice(&header[22..]),
+                    Payload {
+                        msg: &ciphertext,
+                        aad: &header,
+                    },
+                )
+                .map_err(|_| anyhow!("Wrong password or corrupted keystore"))?,
+        );
+        let mut keystore = Self {
+            log_n,
+            salt,
+            key,
+            entries: vec![],
+        };
+        keystore.parse_entries(&plaintext)?;
+        Ok(keystore)
+    }
+
+    // Writes a temporary file first so a crash never leaves half a keystore
+    pub fn save_file<P>(&self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut buf = vec![];
+        self.save(&mut buf)?;
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load_file<P>(path: P, password: &str) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::load(&mut File::open(path)?, password)
+    }
+}
+
+#[cfg(test)]
+const TEST_LOG_N: u8 = 4;
+
+#[test]
+fn test_keystore_roundtrip() {
+    let wif = PrivateKey::new(BigInt::from(0x5eed_1234u64))
+        .unwrap()
+        .wif(true, Network::Testnet3);
+    let seed = [0x42u8; 32];
+    let mut keystore = Keystore::new("correct horse", TEST_LOG_N).unwrap();
+    keystore.import_wif("hot", &wif).unwrap();
+    keystore.import_seed("backup", &seed).unwrap();
+    assert!(keystore.import_seed("hot", &seed).is_err());
+    assert!(keystore.import_seed("short", &seed[..15]).is_err());
+    assert_eq!(keystore.labels(), vec!["hot", "backup"]);
+
+    let mut buf = vec![];
+    keystore.save(&mut buf).unwrap();
+    // Nothing secret in the clear
+    let secret = keystore.private_key("hot").unwrap().secret_bytes().to_vec();
+    assert!(!buf.windows(32).any(|w| w == &secret[..] || w == &seed[..]));
+    assert!(!buf.windows(3).any(|w| w == b"hot"));
+
+    let loaded = Keystore::load(&mut Cursor::new(&buf), "correct horse").unwrap();
+    assert_eq!(loaded.labels(), vec!["hot", "backup"]);
+    match loaded.get("hot").unwrap() {
+        Secret::Key {
+            key,
+            compressed,
+            network,
+        } => assert_eq!(key.wif(*compressed, *network), wif),
+        _ => panic!("hot should be a key"),
+    }
+    assert_eq!(
+        loaded
+            .master_key("backup", Network::Mainnet)
+            .unwrap()
+            .to_pub(),
+        ExtendedPrivKey::new_master(&seed, Network::Mainnet)
+            .unwrap()
+            .to_pub()
+    );
+    assert!(loaded.private_key("backup").is_err());
+    assert!(loaded.master_key("hot", Network::Mainnet).is_err());
+    assert!(loaded.private_key("missing").is_err());
+
+    // Debug output leaves the secret out
+    assert!(!format!("{:?}", loaded.private_key("hot").unwrap()).contains("secret"));
+}
+
+#[test]
+fn test_keystore_tampering() {
+    let mut keystore = Keystore::new("hunter2", TEST_LOG_N).unwrap();
+    keystore.import_seed("seed", &[7u8; 16]).unwrap();
+    let mut buf = vec![];
+    keystore.save(&mut buf).unwrap();
+    assert!(Keystore::load(&mut Cursor::new(&buf), "hunter3").is_err());
+
+    // Flipping any bit of the header or ciphertext fails authentication
+    for i in [0, 5, 10, 30, 40, buf.len() - 1].iter() {
+        let mut tampered = buf.clone();
+        tampered[*i] ^= 1;
+        assert!(Keystore::load(&mut Cursor::new(&tampered), "hunter2").is_err());
+    }
+    let mut expensive = buf.clone();
+    expensive[5] = 40;
+    let err = Keystore::load(&mut Cursor::new(&expensive), "hunter2")
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("too high"));
+    assert!(Keystore::new("hunter2", MAX_LOG_N + 1).is_err());
+
+    // A new password re-encrypts everything
+    keystore.change_password("hunter3").unwrap();
+    assert!(keystore.remove("seed"));
+    assert!(!keystore.remove("seed"));
+    let path = std::env::temp_dir().join(format!("keystore-{}.dat", std::process::id()));
+    keystore.save_file(&path).unwrap();
+    assert!(Keystore::load_file(&path, "hunter2").is_err());
+    let loaded = Keystore::load_file(&path, "hunter3").unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(loaded.labels().is_empty());
+}
@@ -0,0 +1,1125 @@
+use anyhow::{anyhow, Result};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::block::Block;
+use crate::helper::encode_hex;
+use crate::interpreter::verify_input;
+use crate::op::*;
+use crate::script::{Cmd, Script};
+use crate::transaction::{Tx, TxOut, SEQUENCE_FINAL};
+use crate::utxo::{Coin, OutPoint, UtxoStore};
+use crate::validation::{
+    check_sequence_locks, check_transaction, script_flags, sigop_cost, PrevState,
+    COINBASE_MATURITY, MAX_MONEY,
+};
+
+// Fee rates are in satoshis per 1000 virtual bytes
+pub const DEFAULT_MIN_RELAY_FEE: u64 = 1000;
+pub const DUST_RELAY_FEE: u64 = 3000;
+pub const DEFAULT_MAX_MEMPOOL_VSIZE: usize = 300_000_000;
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+pub const MAX_STANDARD_TX_SIGOPS_COST: usize = 16_000;
+pub const MAX_STANDARD_SCRIPT_SIG_SIZE: usize = 1650;
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+pub const MAX_ANCESTORS: usize = 25;
+pub const MAX_DESCENDANTS: usize = 25;
+pub const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+
+// Compares fee / vsize ratios without rounding
+fn cmp_fee_rate(a: (u64, usize), b: (u64, usize)) -> Ordering {
+    (a.0 as u128 * b.1 as u128).cmp(&(b.0 as u128 * a.1 as u128))
+}
+
+fn required_fee(fee_rate: u64, vsize: usize) -> u64 {
+    fee_rate * vsize as u64 / 1000
+}
+
+// Outputs cheaper than the fee to spend them at the dust relay fee
+pub fn dust_threshold(tx_out: &TxOut, dust_relay_fee: u64) -> u64 {
+    if tx_out.script_pubkey.is_unspendable() {
+        return 0;
+    }
+    let mut size = tx_out.serialize().len();
+    size += if tx_out.script_pubkey.witness_program().is_some() {
+        // Outpoint, sequence and a discounted 107 byte witness
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    required_fee(dust_relay_fee, size)
+}
+
+// Bare multisig with at most 3 keys
+fn is_standard_multisig(script: &Script) -> bool {
+    let cmds = match script.cmds() {
+        Ok(cmds) => cmds,
+        Err(_) => return false,
+    };
+    match &cmds[..] {
+        [Cmd::Op(m), keys @ .., Cmd::Op(n), Cmd::Op(OP_CHECKMULTISIG)] => {
+            (OP_1..OP_1 + 3).contains(n)
+                && (OP_1..=*n).contains(m)
+                && keys.len() == (n - OP_1 + 1) as usize
+                && keys
+                    .iter()
+                    .all(|key| matches!(key, Cmd::Data(sec) if sec.len() == 33 || sec.len() == 65))
+        }
+        _ => false,
+    }
+}
+
+pub fn is_standard_script(script: &Script) -> bool {
+    if script.is_op_return() {
+        let data = Script::from_bytes(script.as_bytes()[1..].to_vec());
+        return script.len() <= MAX_OP_RETURN_RELAY && data.is_push_only();
+    }
+    match script.witness_program() {
+        Some((0, program)) => program.len() == 20 || program.len() == 32,
+        Some(_) => true,
+        None => {
+            script.is_p2pk()
+                || script.is_p2pkh()
+                || script.is_p2sh()
+                || is_standard_multisig(script)
+        }
+    }
+}
+
+// Policy checks which don't need the spent outputs. Version 3 (TRUC)
+// transactions are left out, as their topology rules aren't implemented.
+pub fn check_standard(tx: &Tx) -> Result<()> {
+    if tx.version < 1 || tx.version > 2 {
+        return Err(anyhow!(
+            "Transaction version {} is not standard",
+            tx.version
+        ));
+    }
+    if tx.weight() > MAX_STANDARD_TX_WEIGHT {
+        return Err(anyhow!("Transaction weight {} is too large", tx.weight()));
+    }
+    for tx_in in &tx.tx_ins {
+        if tx_in.script_sig.len() > MAX_STANDARD_SCRIPT_SIG_SIZE {
+            return Err(anyhow!("ScriptSig is too large"));
+        }
+        if !tx_in.script_sig.is_push_only() {
+            return Err(anyhow!("ScriptSig is not push only"));
+        }
+    }
+    let mut op_returns = 0;
+    for tx_out in &tx.tx_outs {
+        if !is_standard_script(&tx_out.script_pubkey) {
+            return Err(anyhow!(
+                "Output script {} is not standard",
+                tx_out.script_pubkey
+            ));
+        }
+        if tx_out.script_pubkey.is_op_return() {
+            op_returns += 1;
+        } else if tx_out.amount < dust_threshold(tx_out, DUST_RELAY_FEE) {
+            return Err(anyhow!("Output of {} satoshis is dust", tx_out.amount));
+        }
+    }
+    if op_returns > 1 {
+        return Err(anyhow!("Transaction has more than one OP_RETURN output"));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Tx,
+    pub fee: u64,
+    pub vsize: usize,
+    pub sigop_cost: usize,
+    // Txids of in-mempool parents and children
+    parents: HashSet<Vec<u8>>,
+    children: HashSet<Vec<u8>>,
+    // Fee and vsize totals including all in-mempool ancestors or
+    // descendants, kept up to date as the pool changes
+    ancestor_fee: u64,
+    ancestor_vsize: usize,
+    descendant_fee: u64,
+    descendant_vsize: usize,
+}
+
+impl MempoolEntry {
+    // Satoshis per 1000 virtual bytes
+    pub fn fee_rate(&self) -> u64 {
+        self.fee * 1000 / self.vsize as u64
+    }
+}
+
+// A transaction with the fee and vsize of its package of ancestors not yet
+// in the block, ordered by that feerate and then by lowest txid
+#[derive(Debug, PartialEq, Eq)]
+struct Candidate {
+    fee: u64,
+    vsize: usize,
+    txid: Vec<u8>,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_fee_rate((self.fee, self.vsize), (other.fee, other.vsize))
+            .then_with(|| other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// What accepting a transaction would do to the pool
+struct Admission {
+    entry: MempoolEntry,
+    replaced: HashSet<Vec<u8>>,
+}
+
+pub struct Mempool {
+    pub min_relay_fee: u64,
+    pub max_vsize: usize,
+    entries: HashMap<Vec<u8>, MempoolEntry>,
+    // Outpoints spent by mempool transactions
+    spends: HashMap<OutPoint, Vec<u8>>,
+    total_vsize: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MEMPOOL_VSIZE)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_vsize: usize) -> Self {
+        Self {
+            min_relay_fee: DEFAULT_MIN_RELAY_FEE,
+            max_vsize,
+            entries: HashMap::new(),
+            spends: HashMap::new(),
+            total_vsize: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn total_vsize(&self) -> usize {
+        self.total_vsize
+    }
+
+    // Txids are in display order, as returned by `Tx::hash`
+    pub fn get(&self, txid: &[u8]) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn contains(&self, txid: &[u8]) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    // The mempool transaction spending an outpoint
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&Vec<u8>> {
+        self.spends.get(outpoint)
+    }
+
+    fn walk<F>(&self, mut todo: Vec<Vec<u8>>, next: F) -> HashSet<Vec<u8>>
+    where
+        F: Fn(&MempoolEntry) -> &HashSet<Vec<u8>>,
+    {
+        let mut result = HashSet::new();
+        while let Some(txid) = todo.pop() {
+            if let Some(entry) = self.entries.get(&txid) {
+                for other in next(entry) {
+                    if result.insert(other.clone()) {
+                        todo.push(other.clone());
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    // In-mempool ancestors, not including the transaction itself
+    pub fn ancestors(&self, txid: &[u8]) -> HashSet<Vec<u8>> {
+        self.walk(vec![txid.to_vec()], |e| &e.parents)
+    }
+
+    // In-mempool descendants, not including the transaction itself
+    pub fn descendants(&self, txid: &[u8]) -> HashSet<Vec<u8>> {
+        self.walk(vec![txid.to_vec()], |e| &e.children)
+    }
+
+    fn package(&self, txids: &HashSet<Vec<u8>>) -> (u64, usize) {
+        txids.iter().fold((0, 0), |(fee, vsize), txid| {
+            let entry = &self.entries[txid];
+            (fee + entry.fee, vsize + entry.vsize)
+        })
+    }
+
+    // Fee and vsize of the transaction with all its unconfirmed ancestors,
+    // what a miner gets for including it
+    pub fn ancestor_package(&self, txid: &[u8]) -> (u64, usize) {
+        let entry = &self.entries[txid];
+        (entry.ancestor_fee, entry.ancestor_vsize)
+    }
+
+    pub fn descendant_package(&self, txid: &[u8]) -> (u64, usize) {
+        let entry = &self.entries[txid];
+        (entry.descendant_fee, entry.descendant_vsize)
+    }
+
+    // BIP125: a transaction is replaceable if it or an unconfirmed ancestor
+    // has an input sequence below 0xfffffffe
+    pub fn signals_rbf(&self, txid: &[u8]) -> bool {
+        let mut txids = self.ancestors(txid);
+        txids.insert(txid.to_vec());
+        txids.iter().any(|txid| {
+            self.entries[txid]
+                .tx
+                .tx_ins
+                .iter()
+                .any(|tx_in| tx_in.sequence < SEQUENCE_FINAL - 1)
+        })
+    }
+
+    // Accepts a transaction, replacing conflicting ones if BIP125 allows.
+    // Returns the txids of the transactions it replaced.
+    pub fn accept<S>(&mut self, tx: Tx, prev: &PrevState<S>) -> Result<Vec<Vec<u8>>>
+    where
+        S: UtxoStore,
+    {
+        let admission = self.check(&tx, prev)?;
+        if admission.entry.fee < required_fee(self.min_relay_fee, admission.entry.vsize) {
+            return Err(anyhow!(
+                "Transaction {} pays {} satoshis, less than the minimum relay fee",
+                tx.id(),
+                admission.entry.fee
+            ));
+        }
+        self.check_replacement(&admission)?;
+        let replaced: Vec<Vec<u8>> = admission.replaced.iter().cloned().collect();
+        let mut removed = self.remove_package(&admission.replaced);
+        let txid = tx.hash();
+        self.insert(admission.entry);
+        let evicted = self.trim();
+        if !self.contains(&txid) {
+            // Put back what was replaced and what was evicted to make room
+            removed.extend(evicted.into_iter().filter(|entry| entry.tx.hash() != txid));
+            self.restore(removed);
+            return Err(anyhow!("Mempool is full"));
+        }
+        Ok(replaced)
+    }
+
+    // Accepts parents and a child together when the package's feerate pays
+    // for parents which are below the minimum relay fee on their own (CPFP).
+    // Transactions must be in order, and may not replace others.
+    pub fn accept_package<S>(&mut self, txs: Vec<Tx>, prev: &PrevState<S>) -> Result<()>
+    where
+        S: UtxoStore,
+    {
+        let mut added = vec![];
+        let mut result = Ok(());
+        for tx in txs {
+            if self.contains(&tx.hash()) {
+                continue;
+            }
+            match self.check(&tx, prev) {
+                Ok(admission) if admission.replaced.is_empty() => {
+                    added.push(tx.hash());
+                    self.insert(admission.entry);
+                }
+                Ok(_) => {
+                    result = Err(anyhow!("Package transaction {} conflicts", tx.id()));
+                    break;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            let (fee, vsize) = self.package(&added.iter().cloned().collect());
+            if fee < required_fee(self.min_relay_fee, vsize) {
+                result = Err(anyhow!(
+                    "Package pays {} satoshis for {} vbytes, less than the minimum relay fee",
+                    fee,
+                    vsize
+                ));
+            }
+        }
+        if result.is_err() {
+            for txid in added.iter().rev() {
+                self.remove_entry(txid);
+            }
+            return result;
+        }
+        let evicted = self.trim();
+        if added.iter().any(|txid| !self.contains(txid)) {
+            self.remove_package(&added.iter().cloned().collect());
+            self.restore(
+                evicted
+                    .into_iter()
+                    .filter(|entry| !added.contains(&entry.tx.hash()))
+                    .collect(),
+            );
+            return Err(anyhow!("Mempool is full"));
+        }
+        Ok(())
+    }
+
+    fn check<S>(&self, tx: &Tx, prev: &PrevState<S>) -> Result<Admission>
+    where
+        S: UtxoStore,
+    {
+        let txid = tx.hash();
+        if tx.is_coinbase() {
+            return Err(anyhow!("Coinbase {} can't be relayed", tx.id()));
+        }
+        if self.contains(&txid) {
+            return Err(anyhow!("Transaction {} is already in the mempool", tx.id()));
+        }
+        check_transaction(tx)?;
+        check_standard(tx)?;
+
+        let chain = prev.chain;
+        let tip_hash = chain.tip().header.hash();
+        let height = chain.height() + 1;
+        let mtp = chain.median_time_past(&tip_hash).unwrap();
+        if !tx.is_final(height, mtp) {
+            return Err(anyhow!("Transaction {} is not final", tx.id()));
+        }
+        let tx_outs = tx.tx_outs.len() as u32;
+        if (0..tx_outs).any(|vout| prev.utxos.get(&OutPoint::new(txid.clone(), vout)).is_some()) {
+            return Err(anyhow!("Transaction {} is already confirmed", tx.id()));
+        }
+
+        let mut coins = vec![];
+        let mut parents = HashSet::new();
+        let mut conflicts = HashSet::new();
+        for tx_in in &tx.tx_ins {
+            let outpoint = OutPoint::from_tx_in(tx_in);
+            if let Some(spender) = self.spends.get(&outpoint) {
+                conflicts.insert(spender.clone());
+            }
+            let coin = match self.entries.get(&outpoint.txid) {
+                Some(parent) => {
+                    parents.insert(outpoint.txid.clone());
+                    parent
+                        .tx
+                        .tx_outs
+                        .get(outpoint.vout as usize)
+                        .map(|tx_out| Coin::new(tx_out.clone(), height, false))
+                }
+                None => prev.utxos.get(&outpoint).cloned(),
+            }
+            .ok_or_else(|| anyhow!("Transaction {} spends missing output {}", tx.id(), outpoint))?;
+            if coin.is_coinbase && height - coin.height < COINBASE_MATURITY {
+                return Err(anyhow!(
+                    "Transaction {} spends immature coinbase {}",
+                    tx.id(),
+                    outpoint
+                ));
+            }
+            coins.push(coin);
+        }
+        check_sequence_locks(tx, &coins, chain, &tip_hash, height)?;
+
+        let value_in: u64 = coins.iter().map(|coin| coin.tx_out.amount).sum();
+        let value_out: u64 = tx.tx_outs.iter().map(|tx_out| tx_out.amount).sum();
+        if value_in > MAX_MONEY || value_in < value_out {
+            return Err(anyhow!(
+                "Transaction {} spends {} but pays {}",
+                tx.id(),
+                value_in,
+                value_out
+            ));
+        }
+        let prevouts: Vec<TxOut> = coins.into_iter().map(|coin| coin.tx_out).collect();
+        let flags = script_flags(chain.network, height, &[]);
+        let sigops = sigop_cost(tx, &prevouts, flags);
+        if sigops > MAX_STANDARD_TX_SIGOPS_COST {
+            return Err(anyhow!("Transaction {} has too many sigops", tx.id()));
+        }
+        for i in 0..tx.tx_ins.len() {
+            verify_input(tx, i, &prevouts, flags)
+                .map_err(|e| anyhow!("Transaction {} input {} failed: {}", tx.id(), i, e))?;
+        }
+
+        let mut replaced = HashSet::new();
+        for conflict in &conflicts {
+            replaced.insert(conflict.clone());
+            replaced.extend(self.descendants(conflict));
+        }
+        let ancestors = self.walk(parents.iter().cloned().collect(), |e| &e.parents);
+        let ancestors: HashSet<Vec<u8>> = ancestors.union(&parents).cloned().collect();
+        if ancestors.len() + 1 > MAX_ANCESTORS {
+            return Err(anyhow!("Transaction {} has too many ancestors", tx.id()));
+        }
+        for ancestor in &ancestors {
+            if self.descendants(ancestor).len() + 2 > MAX_DESCENDANTS {
+                return Err(anyhow!("Transaction {} has too many descendants", tx.id()));
+            }
+        }
+        Ok(Admission {
+            entry: MempoolEntry {
+                tx: tx.clone(),
+                fee: value_in - value_out,
+                vsize: tx.vsize(),
+                sigop_cost: sigops,
+                parents,
+                children: HashSet::new(),
+                ancestor_fee: 0,
+                ancestor_vsize: 0,
+                descendant_fee: 0,
+                descendant_vsize: 0,
+            },
+            replaced,
+        })
+    }
+
+    // The BIP125 rules, plus Bitcoin Core's rule that the replacement pays a
+    // higher feerate than what it directly replaces
+    fn check_replacement(&self, admission: &Admission) -> Result<()> {
+        let entry = &admission.entry;
+        let id = entry.tx.id();
+        let direct: HashSet<&Vec<u8>> = entry
+            .tx
+            .tx_ins
+            .iter()
+            .filter_map(|tx_in| self.spends.get(&OutPoint::from_tx_in(tx_in)))
+            .collect();
+        if direct.is_empty() {
+            return Ok(());
+        }
+        if let Some(txid) = direct.iter().find(|txid| !self.signals_rbf(txid)) {
+            return Err(anyhow!(
+                "Transaction {} conflicts with non-replaceable {}",
+                id,
+                encode_hex(txid)
+            ));
+        }
+        if admission.replaced.len() > MAX_REPLACEMENT_EVICTIONS {
+            return Err(anyhow!("Transaction {} replaces too many transactions", id));
+        }
+        let ancestors = self.walk(entry.parents.iter().cloned().collect(), |e| &e.parents);
+        if ancestors
+            .iter()
+            .chain(&entry.parents)
+            .any(|txid| admission.replaced.contains(txid))
+        {
+            return Err(anyhow!(
+                "Transaction {} spends a transaction it replaces",
+                id
+            ));
+        }
+        let original_parents: HashSet<&Vec<u8>> = direct
+            .iter()
+            .flat_map(|txid| self.entries[*txid].parents.iter())
+            .collect();
+        if entry
+            .parents
+            .iter()
+            .any(|txid| !original_parents.contains(txid))
+        {
+            return Err(anyhow!("Replacement {} adds unconfirmed inputs", id));
+        }
+        for txid in &direct {
+            let original = &self.entries[*txid];
+            if cmp_fee_rate((entry.fee, entry.vsize), (original.fee, original.vsize))
+                != Ordering::Greater
+            {
+                return Err(anyhow!("Replacement {} has a lower feerate", id));
+            }
+        }
+        let (replaced_fee, _) = self.package(&admission.replaced);
+        if entry.fee < replaced_fee {
+            return Err(anyhow!(
+                "Replacement {} pays {}, less than the {} it replaces",
+                id,
+                entry.fee,
+                replaced_fee
+            ));
+        }
+        if entry.fee - replaced_fee < required_fee(self.min_relay_fee, entry.vsize) {
+            return Err(anyhow!("Replacement {} doesn't pay for its own relay", id));
+        }
+        Ok(())
+    }
+
+    // Adds a transaction whose in-mempool parents are all present, linking
+    // it to them and updating the package totals
+    fn insert(&mut self, mut entry: MempoolEntry) {
+        let txid = entry.tx.hash();
+        entry.parents = entry
+            .tx
+            .tx_ins
+            .iter()
+            .map(|tx_in| OutPoint::from_tx_in(tx_in).txid)
+            .filter(|parent| self.entries.contains_key(parent))
+            .collect();
+        entry.children.clear();
+        let ancestors = self.walk(entry.parents.iter().cloned().collect(), |e| &e.parents);
+        let (fee, vsize) = self.package(&ancestors.union(&entry.parents).cloned().collect());
+        entry.ancestor_fee = entry.fee + fee;
+        entry.ancestor_vsize = entry.vsize + vsize;
+        entry.descendant_fee = entry.fee;
+        entry.descendant_vsize = entry.vsize;
+        for ancestor in ancestors.union(&entry.parents) {
+            let ancestor = self.entries.get_mut(ancestor).unwrap();
+            ancestor.descendant_fee += entry.fee;
+            ancestor.descendant_vsize += entry.vsize;
+        }
+        for parent in &entry.parents {
+            self.entries
+                .get_mut(parent)
+                .unwrap()
+                .children
+                .insert(txid.clone());
+        }
+        for tx_in in &entry.tx.tx_ins {
+            self.spends
+                .insert(OutPoint::from_tx_in(tx_in), txid.clone());
+        }
+        self.total_vsize += entry.vsize;
+        self.entries.insert(txid, entry);
+    }
+
+    // Removes one transaction, leaving its descendants orphaned
+    fn remove_entry(&mut self, txid: &[u8]) -> Option<MempoolEntry> {
+        let (fee, vsize) = {
+            let entry = self.entries.get(txid)?;
+            (entry.fee, entry.vsize)
+        };
+        for ancestor in self.ancestors(txid) {
+            let ancestor = self.entries.get_mut(&ancestor).unwrap();
+            ancestor.descendant_fee -= fee;
+            ancestor.descendant_vsize -= vsize;
+        }
+        for descendant in self.descendants(txid) {
+            let descendant = self.entries.get_mut(&descendant).unwrap();
+            descendant.ancestor_fee -= fee;
+            descendant.ancestor_vsize -= vsize;
+        }
+        let entry = self.entries.remove(txid)?;
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        for tx_in in &entry.tx.tx_ins {
+            self.spends.remove(&OutPoint::from_tx_in(tx_in));
+        }
+        self.total_vsize -= entry.vsize;
+        Some(entry)
+    }
+
+    // Removes transactions children first, so that the ancestors of each
+    // are still linked when its totals are taken off theirs
+    fn remove_package(&mut self, txids: &HashSet<Vec<u8>>) -> Vec<MempoolEntry> {
+        let mut txids: Vec<&Vec<u8>> = txids.iter().collect();
+        txids.sort_by_cached_key(|txid| Reverse(self.ancestors(txid).len()));
+        txids
+            .into_iter()
+            .filter_map(|txid| self.remove_entry(txid))
+            .collect()
+    }
+
+    // Removes a transaction and everything spending it
+    pub fn remove(&mut self, txid: &[u8]) -> Vec<Tx> {
+        let mut txids = self.descendants(txid);
+        txids.insert(txid.to_vec());
+        self.remove_package(&txids)
+            .into_iter()
+            .map(|entry| entry.tx)
+            .collect()
+    }
+
+    // Re-adds removed transactions, parents before children
+    fn restore(&mut self, mut entries: Vec<MempoolEntry>) {
+        while !entries.is_empty() {
+            let pending: HashSet<Vec<u8>> = entries.iter().map(|entry| entry.tx.hash()).collect();
+            let (ready, rest): (Vec<MempoolEntry>, Vec<MempoolEntry>) =
+                entries.into_iter().partition(|entry| {
+                    entry
+                        .tx
+                        .tx_ins
+                        .iter()
+                        .all(|tx_in| !pending.contains(&OutPoint::from_tx_in(tx_in).txid))
+                });
+            for entry in ready {
+                self.insert(entry);
+            }
+            entries = rest;
+        }
+    }
+
+    // Drops the block's transactions and anything conflicting with them.
+    // Children of confirmed transactions stay.
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in &block.txs {
+            let txid = tx.hash();
+            if self.remove_entry(&txid).is_some() {
+                continue;
+            }
+            for tx_in in tx.tx_ins.iter().filter(|_| !tx.is_coinbase()) {
+                if let Some(spender) = self.spends.get(&OutPoint::from_tx_in(tx_in)).cloned() {
+                    self.remove(&spender);
+                }
+            }
+        }
+    }
+
+    // Evicts the packages with the lowest descendant feerate until the pool
+    // fits. Returns what it evicted.
+    fn trim(&mut self) -> Vec<MempoolEntry> {
+        let mut evicted = vec![];
+        while self.total_vsize > self.max_vsize {
+            let worst = self
+                .entries
+                .iter()
+                .min_by(|(a, x), (b, y)| {
+                    cmp_fee_rate(
+                        (x.descendant_fee, x.descendant_vsize),
+                        (y.descendant_fee, y.descendant_vsize),
+                    )
+                    .then_with(|| a.cmp(b))
+                })
+                .map(|(txid, _)| txid.clone())
+                .unwrap();
+            let mut txids = self.descendants(&worst);
+            txids.insert(worst);
+            evicted.extend(self.remove_package(&txids));
+        }
+        evicted
+    }
+
+    // The transactions a miner would pick next, by ancestor feerate, in an
+    // order where parents come before children. The feerate of a package
+    // drops the ancestors already picked, so candidates are pushed again
+    // when that changes and stale ones skipped.
+    pub fn next_block(&self, max_weight: usize, max_sigop_cost: usize) -> Vec<&MempoolEntry> {
+        let mut included: HashSet<Vec<u8>> = HashSet::new();
+        let mut skipped: HashSet<Vec<u8>> = HashSet::new();
+        let mut modified: HashMap<Vec<u8>, (u64, usize)> = HashMap::new();
+        let mut candidates: BinaryHeap<Candidate> = self
+            .entries
+            .iter()
+            .map(|(txid, entry)| Candidate {
+                fee: entry.ancestor_fee,
+                vsize: entry.ancestor_vsize,
+                txid: txid.clone(),
+            })
+            .collect();
+        let mut result = vec![];
+        let mut weight = 0;
+        let mut sigops = 0;
+        while let Some(Candidate { fee, vsize, txid }) = candidates.pop() {
+            if included.contains(&txid) || skipped.contains(&txid) {
+                continue;
+            }
+            let entry = &self.entries[&txid];
+            let current = modified
+                .get(&txid)
+                .cloned()
+                .unwrap_or((entry.ancestor_fee, entry.ancestor_vsize));
+            if current != (fee, vsize) {
+                continue;
+            }
+            let mut package: HashSet<Vec<u8>> = self
+                .ancestors(&txid)
+                .difference(&included)
+                .cloned()
+                .collect();
+            package.insert(txid.clone());
+            let package_weight: usize = package
+                .iter()
+                .map(|txid| self.entries[txid].tx.weight())
+                .sum();
+            let package_sigops: usize = package
+                .iter()
+                .map(|txid| self.entries[txid].sigop_cost)
+                .sum();
+            if weight + package_weight > max_weight || sigops + package_sigops > max_sigop_cost {
+                skipped.insert(txid);
+                continue;
+            }
+            weight += package_weight;
+            sigops += package_sigops;
+            // Parents have fewer ancestors than their children
+            let mut package: Vec<Vec<u8>> = package.into_iter().collect();
+            package.sort_by_cached_key(|txid| (self.ancestors(txid).len(), txid.clone()));
+            for txid in &package {
+                result.push(&self.entries[txid]);
+                included.insert(txid.clone());
+            }
+            for txid in &package {
+                let entry = &self.entries[txid];
+                for descendant in self.descendants(txid) {
+                    if included.contains(&descendant) {
+                        continue;
+                    }
+                    let other = &self.entries[&descendant];
+                    let totals = modified
+                        .entry(descendant.clone())
+                        .or_insert((other.ancestor_fee, other.ancestor_vsize));
+                    totals.0 -= entry.fee;
+                    totals.1 -= entry.vsize;
+                    candidates.push(Candidate {
+                        fee: totals.0,
+                        vsize: totals.1,
+                        txid: descendant,
+                    });
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+use crate::chain::HeaderChain;
+#[cfg(test)]
+use crate::network::Network;
+#[cfg(test)]
+use crate::s256::PrivateKey;
+#[cfg(test)]
+use crate::testutil::test_spend;
+#[cfg(test)]
+use crate::transaction::TxIn;
+#[cfg(test)]
+use crate::utxo::{MemoryStore, UtxoSet};
+#[cfg(test)]
+use num_bigint::BigInt;
+
+// Ten confirmed 1 BTC outputs to the key
+#[cfg(test)]
+fn test_utxos(key: &PrivateKey) -> (UtxoSet<MemoryStore>, Vec<(OutPoint, u64)>) {
+    let mut store = MemoryStore::new();
+    let mut coins = vec![];
+    for i in 1..=10u8 {
+        let outpoint = OutPoint::new(vec![i; 32], 0);
+        let tx_out = TxOut::new(100_000_000, Script::p2wpkh(&key.point.hash160(true)));
+        store
+            .insert(outpoint.clone(), Coin::new(tx_out, 0, false))
+            .unwrap();
+        coins.push((outpoint, 100_000_000));
+    }
+    (UtxoSet::new(store), coins)
+}
+
+#[cfg(test)]
+fn output(tx: &Tx, vout: u32) -> (OutPoint, u64) {
+    (
+        OutPoint::new(tx.hash(), vout),
+        tx.tx_outs[vout as usize].amount,
+    )
+}
+
+#[test]
+fn test_standard() {
+    let p2wpkh = Script::p2wpkh(&[1u8; 20]);
+    assert_eq!(
+        dust_threshold(&TxOut::new(0, p2wpkh.clone()), DUST_RELAY_FEE),
+        294
+    );
+    let p2pkh = Script::p2pkh(&[1u8; 20]);
+    assert_eq!(
+        dust_threshold(&TxOut::new(0, p2pkh.clone()), DUST_RELAY_FEE),
+        546
+    );
+
+    let secs: Vec<Vec<u8>> = (1..=4)
+        .map(|i| PrivateKey::new(BigInt::from(i)).unwrap().point.sec(true))
+        .collect();
+    assert!(is_standard_script(&p2wpkh));
+    assert!(is_standard_script(&Script::p2pk(&secs[0])));
+    let uncompressed = PrivateKey::new(BigInt::from(1)).unwrap().point.sec(false);
+    assert!(is_standard_script(&Script::p2pk(&uncompressed)));
+    assert!(!is_standard_script(&Script::p2pk(&secs[0][1..])));
+    assert!(is_standard_script(&Script::p2tr(&[2u8; 32])));
+    assert!(is_standard_script(&Script::multisig(1, &secs[..3])));
+    assert!(!is_standard_script(&Script::multisig(1, &secs)));
+    assert!(!is_standard_script(&Script::from_cmds(&[Cmd::num(1)])));
+    assert!(!is_standard_script(&Script::from_cmds(&[
+        Cmd::num(0),
+        Cmd::Data(vec![0; 21])
+    ])));
+    assert!(is_standard_script(&Script::op_return(&[0; 80])));
+    assert!(!is_standard_script(&Script::op_return(&[0; 81])));
+
+    let tx_in = TxIn::new([1u8; 32], 0, None, 0xffffffff);
+    let tx = |tx_outs: Vec<TxOut>| Tx::new(2, vec![tx_in.clone()], tx_outs, 0, Network::Regtest);
+    check_standard(&tx(vec![TxOut::new(294, p2wpkh.clone())])).unwrap();
+    assert!(check_standard(&tx(vec![TxOut::new(293, p2wpkh.clone())])).is_err());
+    assert!(check_standard(&tx(vec![TxOut::new(545, p2pkh)])).is_err());
+    let op_return = TxOut::new(0, Script::op_return(b"hello"));
+    check_standard(&tx(vec![op_return.clone()])).unwrap();
+    assert!(check_standard(&tx(vec![op_return.clone(), op_return])).is_err());
+    let mut v3 = tx(vec![TxOut::new(1000, p2wpkh)]);
+    v3.version = 3;
+    assert!(check_standard(&v3).is_err());
+}
+
+#[test]
+fn test_accept() {
+    let key = PrivateKey::new(BigInt::from(777)).unwrap();
+    let chain = HeaderChain::new(Network::Regtest);
+    let (utxos, coins) = test_utxos(&key);
+    let prev = PrevState {
+        chain: &chain,
+        utxos: &utxos,
+    };
+    let mut mempool = Mempool::default();
+
+    // About 110 vbytes, so 109 satoshis is below the minimum relay fee
+    let cheap = test_spend(&key, &coins[..1], &[100_000_000 - 109], 0xffffffff);
+    assert_eq!(cheap.vsize(), 110);
+    assert!(mempool.accept(cheap, &prev).is_err());
+    let parent = test_spend(&key, &coins[..1], &[50_000_000, 49_999_000], 0xffffffff);
+    assert!(mempool.accept(parent.clone(), &prev).unwrap().is_empty());
+    assert!(mempool.accept(parent.clone(), &prev).is_err());
+    let entry = mempool.get(&parent.hash()).unwrap();
+    assert_eq!(entry.fee, 1000);
+    assert_eq!(entry.fee_rate(), 1000 * 1000 / parent.vsize() as u64);
+
+    let child = test_spend(&key, &[output(&parent, 1)], &[49_990_000], 0xffffffff);
+    mempool.accept(child.clone(), &prev).unwrap();
+    let grandchild = test_spend(&key, &[output(&child, 0)], &[49_980_000], 0xffffffff);
+    mempool.accept(grandchild.clone(), &prev).unwrap();
+    assert_eq!(mempool.len(), 3);
+    assert_eq!(mempool.ancestors(&grandchild.hash()).len(), 2);
+    assert_eq!(mempool.descendants(&parent.hash()).len(), 2);
+    assert_eq!(
+        mempool.ancestor_package(&grandchild.hash()),
+        (20_000, parent.vsize() + child.vsize() + grandchild.vsize())
+    );
+    assert_eq!(
+        mempool.descendant_package(&parent.hash()),
+        mempool.ancestor_package(&grandchild.hash())
+    );
+    assert_eq!(
+        mempool.spender(&OutPoint::from_tx_in(&child.tx_ins[0])),
+        Some(&child.hash())
+    );
+
+    // Missing outputs, overspending and bad signatures
+    let missing = test_spend(
+        &key,
+        &[(OutPoint::new(parent.hash(), 2), 1000)],
+        &[1000],
+        0xffffffff,
+    );
+    assert!(mempool.accept(missing, &prev).is_err());
+    let overspend = test_spend(&key, &coins[1..2], &[100_000_001], 0xffffffff);
+    assert!(mempool.accept(overspend, &prev).is_err());
+    let mut bad = test_spend(&key, &coins[1..2], &[99_000_000], 0xffffffff);
+    bad.tx_ins[0].witness[0][10] ^= 1;
+    assert!(mempool.accept(bad, &prev).is_err());
+
+    // A block confirming the parent leaves its descendants
+    let block = Block::new(chain.tip().header.clone(), vec![parent.clone()]);
+    mempool.remove_for_block(&block);
+    assert_eq!(mempool.len(), 2);
+    assert!(mempool.get(&child.hash()).unwrap().parents.is_empty());
+    assert_eq!(
+        mempool.ancestor_package(&grandchild.hash()),
+        (19_000, child.vsize() + grandchild.vsize())
+    );
+    assert_eq!(mempool.remove(&child.hash()).len(), 2);
+    assert!(mempool.is_empty());
+    assert_eq!(mempool.total_vsize(), 0);
+
+    // A block with a conflicting spend evicts it and its descendants
+    mempool.accept(parent.clone(), &prev).unwrap();
+    mempool.accept(child, &prev).unwrap();
+    let conflict = test_spend(&key, &coins[..1], &[99_000_000], 0xffffffff);
+    mempool.remove_for_block(&Block::new(chain.tip().header.clone(), vec![conflict]));
+    assert!(mempool.is_empty());
+
+    // At most 25 transactions in a chain
+    let mut coin = coins[2].clone();
+    for i in 0..MAX_ANCESTORS {
+        let tx = test_spend(&key, &[coin], &[99_000_000 - i as u64 * 1000], 0xffffffff);
+        mempool.accept(tx.clone(), &prev).unwrap();
+        coin = output(&tx, 0);
+    }
+    let tx = test_spend(&key, &[coin], &[90_000_000], 0xffffffff);
+    assert!(mempool.accept(tx, &prev).is_err());
+}
+
+#[test]
+fn test_replace_by_fee() {
+    let key = PrivateKey::new(BigInt::from(778)).unwrap();
+    let chain = HeaderChain::new(Network::Regtest);
+    let (utxos, coins) = test_utxos(&key);
+    let prev = PrevState {
+        chain: &chain,
+        utxos: &utxos,
+    };
+    let mut mempool = Mempool::default();
+
+    let final_tx = test_spend(&key, &coins[..1], &[99_999_000], 0xffffffff);
+    mempool.accept(final_tx.clone(), &prev).unwrap();
+    let replacement = test_spend(&key, &coins[..1], &[99_000_000], 0xffffffff);
+    let err = mempool.accept(replacement, &prev).unwrap_err();
+    assert!(err.to_string().contains("non-replaceable"));
+
+    // A child inherits replaceability from its parent
+    let original = test_spend(&key, &coins[1..2], &[99_999_000], 0xfffffffd);
+    let child = test_spend(&key, &[output(&original, 0)], &[99_998_000], 0xffffffff);
+    mempool.accept(original.clone(), &prev).unwrap();
+    mempool.accept(child.clone(), &prev).unwrap();
+    assert!(mempool.signals_rbf(&child.hash()));
+
+    // It must pay for everything it evicts plus its own relay
+    let low = test_spend(&key, &coins[1..2], &[99_998_000], 0xffffffff);
+    assert!(mempool.accept(low, &prev).is_err());
+    let no_increment = test_spend(&key, &coins[1..2], &[99_998_000 - 50], 0xffffffff);
+    assert!(mempool.accept(no_increment, &prev).is_err());
+    let new_input = test_spend(
+        &key,
+        &[coins[1].clone(), output(&final_tx, 0)],
+        &[199_000_000],
+        0xffffffff,
+    );
+    let err = mempool.accept(new_input, &prev).unwrap_err();
+    assert!(err.to_string().contains("adds unconfirmed inputs"));
+    let spends_replaced = test_spend(
+        &key,
+        &[coins[1].clone(), output(&child, 0)],
+        &[199_000_000],
+        0xffffffff,
+    );
+    let err = mempool.accept(spends_replaced, &prev).unwrap_err();
+    assert!(err.to_string().contains("spends a transaction it replaces"));
+    assert_eq!(mempool.len(), 3);
+
+    let replacement = test_spend(&key, &coins[1..2], &[99_990_000], 0xffffffff);
+    let mut replaced = mempool.accept(replacement.clone(), &prev).unwrap();
+    replaced.sort();
+    let mut expected = vec![original.hash(), child.hash()];
+    expected.sort();
+    assert_eq!(replaced, expected);
+    assert_eq!(mempool.len(), 2);
+    assert!(mempool.contains(&replacement.hash()));
+    assert!(!mempool.signals_rbf(&replacement.hash()));
+
+    // A replacement evicted to make room leaves the pool as it was
+    let mut mempool = Mempool::default();
+    let original = test_spend(&key, &coins[2..3], &[99_999_000], 0xfffffffd);
+    let child = test_spend(&key, &[output(&original, 0)], &[99_998_000], 0xffffffff);
+    let rich = test_spend(&key, &coins[3..4], &[99_900_000], 0xffffffff);
+    for tx in [&original, &child, &rich].iter() {
+        mempool.accept((*tx).clone(), &prev).unwrap();
+    }
+    mempool.max_vsize = mempool.total_vsize();
+    let replacement = test_spend(&key, &coins[2..3], &[19_996_000; 5], 0xffffffff);
+    assert!(replacement.vsize() > original.vsize() + child.vsize());
+    let err = mempool.accept(replacement, &prev).unwrap_err();
+    assert_eq!(err.to_string(), "Mempool is full");
+    assert_eq!(mempool.len(), 3);
+    assert_eq!(mempool.total_vsize(), mempool.max_vsize);
+    assert_eq!(
+        mempool.descendant_package(&original.hash()),
+        (2000, original.vsize() + child.vsize())
+    );
+    assert_eq!(
+        mempool.spender(&OutPoint::from_tx_in(&child.tx_ins[0])),
+        Some(&child.hash())
+    );
+}
+
+#[test]
+fn test_package() {
+    let key = PrivateKey::new(BigInt::from(779)).unwrap();
+    let chain = HeaderChain::new(Network::Regtest);
+    let (utxos, coins) = test_utxos(&key);
+    let prev = PrevState {
+        chain: &chain,
+        utxos: &utxos,
+    };
+    let mut mempool = Mempool::default();
+
+    let parent = test_spend(&key, &coins[..1], &[100_000_000], 0xffffffff);
+    assert!(mempool.accept(parent.clone(), &prev).is_err());
+    let cheap_child = test_spend(&key, &[output(&parent, 0)], &[99_999_900], 0xffffffff);
+    assert!(mempool
+        .accept_package(vec![parent.clone(), cheap_child], &prev)
+        .is_err());
+    assert!(mempool.is_empty());
+
+    let child = test_spend(&key, &[output(&parent, 0)], &[99_999_000], 0xffffffff);
+    mempool
+        .accept_package(vec![parent.clone(), child.clone()], &prev)
+        .unwrap();
+    assert_eq!(mempool.len(), 2);
+    assert_eq!(mempool.get(&parent.hash()).unwrap().fee, 0);
+    assert_eq!(
+        mempool.ancestor_package(&child.hash()),
+        (1000, parent.vsize() + child.vsize())
+    );
+}
+
+#[test]
+fn test_next_block() {
+    let key = PrivateKey::new(BigInt::from(780)).unwrap();
+    let chain = HeaderChain::new(Network::Regtest);
+    let (utxos, coins) = test_utxos(&key);
+    let prev = PrevState {
+        chain: &chain,
+        utxos: &utxos,
+    };
+    let mut mempool = Mempool::default();
+
+    // A low fee parent with a high fee child outbids a medium fee transaction
+    let parent = test_spend(&key, &coins[..1], &[99_999_800], 0xffffffff);
+    let child = test_spend(&key, &[output(&parent, 0)], &[99_994_800], 0xffffffff);
+    let medium = test_spend(&key, &coins[1..2], &[99_999_000], 0xffffffff);
+    let cheap = test_spend(&key, &coins[2..3], &[99_999_850], 0xffffffff);
+    for tx in [&parent, &child, &medium, &cheap].iter() {
+        mempool.accept((*tx).clone(), &prev).unwrap();
+    }
+    let txids = |entries: Vec<&MempoolEntry>| -> Vec<Vec<u8>> {
+        entries.iter().map(|entry| entry.tx.hash()).collect()
+    };
+    let all = txids(mempool.next_block(4_000_000, 80_000));
+    assert_eq!(
+        all,
+        vec![parent.hash(), child.hash(), medium.hash(), cheap.hash()]
+    );
+    // The package doesn't fit, the medium fee transaction does
+    let small = txids(mempool.next_block(medium.weight() + 100, 80_000));
+    assert_eq!(small, vec![medium.hash()]);
+    assert!(mempool.next_block(4_000_000, 0).is_empty());
+
+    // Eviction drops the cheapest package first
+    mempool.max_vsize = mempool.total_vsize() - 1;
+    mempool.trim();
+    assert!(!mempool.contains(&cheap.hash()));
+    assert_eq!(mempool.len(), 3);
+    mempool.max_vsize = mempool.total_vsize() - 1;
+    mempool.trim();
+    assert!(!mempool.contains(&medium.hash()));
+    let err = mempool.accept(cheap, &prev).unwrap_err();
+    assert_eq!(err.to_string(), "Mempool is full");
+    assert_eq!(mempool.len(), 2);
+}
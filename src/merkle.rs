@@ -0,0 +1,417 @@
+use anyhow::{anyhow, Result};
+use std::io::Read;
+
+use crate::block::{Block, BlockHeader};
+use crate::helper::{encode_hex, encode_variant, merkle_parent, merkle_parent_level, read_variant};
+use crate::transaction::Tx;
+
+// A block can't hold more transactions than this
+const MAX_TRANSACTIONS: u32 = 4_000_000 / 240;
+
+// Every level of the tree, from the leaves up to the root
+pub fn merkle_tree(hashes: &[Vec<u8>]) -> Vec<Vec<Vec<u8>>> {
+    let mut levels = vec![hashes.to_vec()];
+    while levels.last().unwrap().len() > 1 {
+        let level = merkle_parent_level(levels.last().unwrap());
+        levels.push(level);
+    }
+    levels
+}
+
+// CVE-2012-2459: an odd level duplicates its last hash, so appending copies
+// of trailing transactions yields a different list with the same root. Any
+// level where a pair is made of two equal hashes marks such a mutation.
+pub fn is_mutated(hashes: &[Vec<u8>]) -> bool {
+    merkle_tree(hashes).iter().any(|level| {
+        level
+            .chunks(2)
+            .any(|pair| pair.len() == 2 && pair[0] == pair[1])
+    })
+}
+
+// A matched transaction's position in the block and its txid
+pub type Match = (u32, Vec<u8>);
+
+// BIP37: the hashes and flag bits of a depth-first walk which descends only
+// into subtrees that contain a matched transaction
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PartialMerkleTree {
+    pub total: u32,
+    pub hashes: Vec<Vec<u8>>,
+    pub flags: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    // `hashes` are txids in internal order
+    pub fn from_hashes(hashes: &[Vec<u8>], matches: &[bool]) -> Self {
+        let mut tree = Self {
+            total: hashes.len() as u32,
+            hashes: vec![],
+            flags: vec![],
+        };
+        let mut height = 0;
+        while tree.width(height) > 1 {
+            height += 1;
+        }
+        tree.build(height, 0, hashes, matches);
+        tree
+    }
+
+    fn width(&self, height: usize) -> usize {
+        (self.total as usize + (1 << height) - 1) >> height
+    }
+
+    fn calc_hash(&self, height: usize, pos: usize, hashes: &[Vec<u8>]) -> Vec<u8> {
+        if height == 0 {
+            return hashes[pos].clone();
+        }
+        let left = self.calc_hash(height - 1, pos * 2, hashes);
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.calc_hash(height - 1, pos * 2 + 1, hashes)
+        } else {
+            left.clone()
+        };
+        merkle_parent(&left, &right)
+    }
+
+    fn build(&mut self, height: usize, pos: usize, hashes: &[Vec<u8>], matches: &[bool]) {
+        let start = pos << height;
+        let end = ((pos + 1) << height).min(self.total as usize);
+        let parent_of_match = matches[start..end].iter().any(|m| *m);
+        self.flags.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            let hash = self.calc_hash(height, pos, hashes);
+            self.hashes.push(hash);
+            return;
+        }
+        self.build(height - 1, pos * 2, hashes, matches);
+        if pos * 2 + 1 < self.width(height - 1) {
+            self.build(height - 1, pos * 2 + 1, hashes, matches);
+        }
+    }
+
+    fn traverse(
+        &self,
+        height: usize,
+        pos: usize,
+        bits_used: &mut usize,
+        hashes_used: &mut usize,
+        matches: &mut Vec<Match>,
+    ) -> Result<Vec<u8>> {
+        let parent_of_match = *self
+            .flags
+            .get(*bits_used)
+            .ok_or_else(|| anyhow!("Partial merkle tree overflowed its flag bits"))?;
+        *bits_used += 1;
+        if height == 0 || !parent_of_match {
+            let hash = self
+                .hashes
+                .get(*hashes_used)
+                .ok_or_else(|| anyhow!("Partial merkle tree overflowed its hashes"))?
+                .clone();
+            *hashes_used += 1;
+            if height == 0 && parent_of_match {
+                matches.push((pos as u32, hash.clone()));
+            }
+            return Ok(hash);
+        }
+        let left = self.traverse(height - 1, pos * 2, bits_used, hashes_used, matches)?;
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            let right = self.traverse(height - 1, pos * 2 + 1, bits_used, hashes_used, matches)?;
+            // CVE-2012-2459: a real right child never equals its sibling
+            if right == left {
+                return Err(anyhow!("Partial merkle tree has a duplicated node"));
+            }
+            right
+        } else {
+            left.clone()
+        };
+        Ok(merkle_parent(&left, &right))
+    }
+
+    // Returns the merkle root and the position and txid (internal order) of
+    // every matched transaction
+    pub fn extract_matches(&self) -> Result<(Vec<u8>, Vec<Match>)> {
+        if self.total == 0 {
+            return Err(anyhow!("Partial merkle tree has no transactions"));
+        }
+        if self.total > MAX_TRANSACTIONS {
+            return Err(anyhow!("Partial merkle tree has too many transactions"));
+        }
+        if self.hashes.len() > self.total as usize {
+            return Err(anyhow!(
+                "Partial merkle tree has more hashes than transactions"
+            ));
+        }
+        if self.flags.len() < self.hashes.len() {
+            return Err(anyhow!(
+                "Partial merkle tree has fewer flag bits than hashes"
+            ));
+        }
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        let mut bits_used = 0;
+        let mut hashes_used = 0;
+        let mut matches = vec![];
+        let root = self.traverse(height, 0, &mut bits_used, &mut hashes_used, &mut matches)?;
+        // Only the padding of the last flag byte may go unused
+        if bits_used.div_ceil(8) != self.flags.len().div_ceil(8) {
+            return Err(anyhow!("Partial merkle tree has unused flag bits"));
+        }
+        if hashes_used != self.hashes.len() {
+            return Err(anyhow!("Partial merkle tree has unused hashes"));
+        }
+        Ok((root, matches))
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut total = [0u8; 4];
+        reader.read_exact(&mut total)?;
+
+        let hash_len = read_variant(reader)?;
+        let mut hashes = Vec::new();
+        for _ in 0..hash_len {
+            let mut hash = vec![0u8; 32];
+            reader.read_exact(&mut hash)?;
+            hashes.push(hash);
+        }
+
+        let flag_len = read_variant(reader)?;
+        let mut flags = Vec::new();
+        for _ in 0..flag_len {
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte)?;
+            flags.extend((0..8).map(|i| (byte[0] >> i) & 1 == 1));
+        }
+        Ok(Self {
+            total: u32::from_le_bytes(total),
+            hashes,
+            flags,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.total.to_le_bytes().to_vec();
+        result.append(&mut encode_variant(self.hashes.len() as u64));
+        for hash in &self.hashes {
+            result.extend_from_slice(hash);
+        }
+        let mut flag_bytes = vec![0u8; self.flags.len().div_ceil(8)];
+        for (i, flag) in self.flags.iter().enumerate() {
+            if *flag {
+                flag_bytes[i / 8] |= 1 << (i % 8);
+            }
+        }
+        result.append(&mut encode_variant(flag_bytes.len() as u64));
+        result.append(&mut flag_bytes);
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub tree: PartialMerkleTree,
+}
+
+impl MerkleBlock {
+    pub fn new(header: BlockHeader, tree: PartialMerkleTree) -> Self {
+        Self { header, tree }
+    }
+
+    // `txids` are in display order, as returned by `Tx::hash`
+    pub fn from_block(block: &Block, txids: &[Vec<u8>]) -> Self {
+        let matches: Vec<bool> = block
+            .txs
+            .iter()
+            .map(|tx| txids.contains(&tx.hash()))
+            .collect();
+        let tree = PartialMerkleTree::from_hashes(&block.tx_hashes(), &matches);
+        Self::new(block.header.clone(), tree)
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let header = BlockHeader::parse(reader)?;
+        let tree = PartialMerkleTree::parse(reader)?;
+        Ok(Self::new(header, tree))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.header.serialize();
+        result.append(&mut self.tree.serialize());
+        result
+    }
+
+    // Checks the proof against the header and returns the matched txids in
+    // display order
+    pub fn verify(&self) -> Result<Vec<Vec<u8>>> {
+        let (root, matches) = self.tree.extract_matches()?;
+        if root != self.header.merkle_root {
+            return Err(anyhow!(
+                "Merkle root mismatch: header {}, computed {}",
+                encode_hex(&self.header.merkle_root),
+                encode_hex(&root)
+            ));
+        }
+        Ok(matches
+            .into_iter()
+            .map(|(_, mut hash)| {
+                hash.reverse();
+                hash
+            })
+            .collect())
+    }
+
+    pub fn contains(&self, tx: &Tx) -> Result<bool> {
+        Ok(self.verify()?.contains(&tx.hash()))
+    }
+}
+
+#[cfg(test)]
+use crate::helper::{decode_hex, hash256, merkle_root};
+
+#[cfg(test)]
+fn test_hashes(ids: &[u32]) -> Vec<Vec<u8>> {
+    ids.iter().map(|i| hash256(&i.to_le_bytes())).collect()
+}
+
+#[test]
+fn test_merkle_tree() {
+    let hashes = test_hashes(&(0..7).collect::<Vec<u32>>());
+    let tree = merkle_tree(&hashes);
+    let widths: Vec<usize> = tree.iter().map(|level| level.len()).collect();
+    assert_eq!(widths, vec![7, 4, 2, 1]);
+    assert_eq!(tree[3][0], merkle_root(&hashes).unwrap());
+    assert_eq!(tree[1][3], merkle_parent(&hashes[6], &hashes[6]));
+
+    assert!(!is_mutated(&hashes));
+    let mut mutated = hashes.clone();
+    mutated.push(hashes[6].clone());
+    assert_eq!(merkle_root(&mutated), merkle_root(&hashes));
+    assert!(is_mutated(&mutated));
+
+    // Duplicating a pair one level up gives the same root too
+    let hashes = test_hashes(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    let mut mutated = hashes.clone();
+    mutated.extend_from_slice(&hashes[8..]);
+    assert_eq!(merkle_root(&mutated), merkle_root(&hashes));
+    assert!(!is_mutated(&hashes));
+    assert!(is_mutated(&mutated));
+}
+
+#[test]
+fn test_partial_merkle_tree() {
+    for total in &[1u32, 4, 7, 17, 56, 100, 127, 256, 312, 513] {
+        let hashes = test_hashes(&(0..*total).collect::<Vec<u32>>());
+        let root = merkle_root(&hashes).unwrap();
+        for step in &[1usize, 2, 3, 11, 1000] {
+            let matches: Vec<bool> = (0..*total as usize).map(|i| i % step == 0).collect();
+            let tree = PartialMerkleTree::from_hashes(&hashes, &matches);
+
+            let parsed = PartialMerkleTree::parse(&mut tree.serialize().as_slice()).unwrap();
+            let (extracted_root, extracted) = parsed.extract_matches().unwrap();
+            assert_eq!(extracted_root, root);
+            let expected: Vec<Match> = (0..*total as usize)
+                .filter(|i| matches[*i])
+                .map(|i| (i as u32, hashes[i].clone()))
+                .collect();
+            assert_eq!(extracted, expected);
+
+            let mut damaged = tree.clone();
+            damaged.hashes[0][0] ^= 1;
+            if let Ok((damaged_root, _)) = damaged.extract_matches() {
+                assert_ne!(damaged_root, root);
+            }
+        }
+    }
+
+    let hashes = test_hashes(&[1, 2, 3, 4, 5, 6, 7]);
+    let matches = [false, true, false, false, false, false, false];
+    let mut tree = PartialMerkleTree::from_hashes(&hashes, &matches);
+    tree.hashes.push(hashes[0].clone());
+    assert_eq!(
+        tree.extract_matches().unwrap_err().to_string(),
+        "Partial merkle tree has unused hashes"
+    );
+    let mut tree = PartialMerkleTree::from_hashes(&hashes, &matches);
+    tree.flags.extend_from_slice(&[false; 8]);
+    assert!(tree.extract_matches().is_err());
+    let mut tree = PartialMerkleTree::from_hashes(&hashes, &matches);
+    tree.total = 0;
+    assert!(tree.extract_matches().is_err());
+}
+
+#[test]
+fn test_partial_merkle_tree_mutated() {
+    let hashes = test_hashes(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 9, 10]);
+    let mut matches = vec![false; 12];
+    matches[9] = true;
+    matches[10] = true;
+    let tree = PartialMerkleTree::from_hashes(&hashes, &matches);
+    assert_eq!(
+        tree.extract_matches().unwrap_err().to_string(),
+        "Partial merkle tree has a duplicated node"
+    );
+}
+
+#[test]
+fn test_merkle_block() {
+    // gettxoutproof for 220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a
+    let raw = decode_hex("0100000090f0a9f110702f808219ebea1173056042a714bad51b916cb6800000000000005275289558f51c9966699404ae2294730c3c9f9bda53523ce50e9b95e558da2fdb261b4d4c86041b1ab1bf930900000005fac7708a6e81b2a986dea60db2663840ed141130848162eb1bd1dee54f309a1b2ee1e12587e497ada70d9bd10d31e83f0a924825b96cb8d04e8936d793fb60db7ad8b910d0c7ba2369bc7f18bb53d80e1869ba2c32274996cebe1ae264bc0e2289189ff0316cdc10511da71da757e553cada9f3b5b1434f3923673adb57d83caac392c38af156d6fc30b55fad4112df2b95531e68114e9ad10011e72f7b7cfdb025700").unwrap();
+    let merkle_block = MerkleBlock::parse(&mut raw.as_slice()).unwrap();
+    assert_eq!(
+        merkle_block.header.id(),
+        "0000000000013b8ab2cd513b0261a14096412195a72a0c4827d229dcc7e0f7af"
+    );
+    assert_eq!(merkle_block.tree.total, 9);
+    assert_eq!(merkle_block.serialize(), raw);
+    assert_eq!(
+        merkle_block.verify().unwrap(),
+        vec![
+            decode_hex("220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a").unwrap()
+        ]
+    );
+
+    let raw = decode_hex("01000000ba8b9cda965dd8e536670f9ddec10e53aab14b20bacad27b9137190000000000190760b278fe7b8565fda3b968b918d5fd997f993b23674c0af3b6fde300b38f33a5914ce6ed5b1b01e32f570200000002252bf9d75c4f481ebb6278d708257d1f12beb6dd30301d26c623f789b2ba6fc0e2d32adb5f8ca820731dff234a84e78ec30bce4ec69dbd562d0b2b8266bf4e5a0105").unwrap();
+    let merkle_block = MerkleBlock::parse(&mut raw.as_slice()).unwrap();
+    let (_, matches) = merkle_block.tree.extract_matches().unwrap();
+    assert_eq!(matches[0].0, 1);
+    assert_eq!(
+        merkle_block.verify().unwrap(),
+        vec![
+            decode_hex("5a4ebf66822b0b2d56bd9dc64ece0bc38ee7844a23ff1d7320a88c5fdb2ad3e2").unwrap()
+        ]
+    );
+
+    let mut forged = merkle_block;
+    forged.header.merkle_root[0] ^= 1;
+    assert!(forged
+        .verify()
+        .unwrap_err()
+        .to_string()
+        .starts_with("Merkle root mismatch"));
+}
+
+#[test]
+fn test_merkle_block_from_block() {
+    use crate::network::Network;
+
+    let raw = Network::Mainnet.genesis_block();
+    let block = Block::parse(&mut raw.as_slice(), Network::Mainnet).unwrap();
+    let coinbase = &block.txs[0];
+    let merkle_block = MerkleBlock::from_block(&block, &[coinbase.hash()]);
+    assert_eq!(merkle_block.header, block.header);
+    assert!(merkle_block.contains(coinbase).unwrap());
+
+    let merkle_block = MerkleBlock::from_block(&block, &[]);
+    assert!(merkle_block.verify().unwrap().is_empty());
+    assert!(!merkle_block.contains(coinbase).unwrap());
+}
@@ -0,0 +1,1039 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::io::Read;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use crate::block::{Block, BlockHeader};
+use crate::bloom::BloomFilter;
+use crate::helper::{encode_hex, encode_variant, hash256, read_variant};
+use crate::merkle::MerkleBlock;
+use crate::network::Network;
+use crate::transaction::Tx;
+
+pub const PROTOCOL_VERSION: u32 = 70016;
+pub const USER_AGENT: &str = "/programmingbitcoin:0.1/";
+
+pub const NODE_NETWORK: u64 = 1;
+pub const NODE_BLOOM: u64 = 1 << 2;
+pub const NODE_WITNESS: u64 = 1 << 3;
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+
+pub const MSG_TX: u32 = 1;
+pub const MSG_BLOCK: u32 = 2;
+pub const MSG_FILTERED_BLOCK: u32 = 3;
+pub const MSG_CMPCT_BLOCK: u32 = 4;
+pub const MSG_WTX: u32 = 5;
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+// Magic, command, payload length and checksum
+pub const ENVELOPE_HEADER_SIZE: usize = 24;
+pub const MAX_PAYLOAD_SIZE: u32 = 32 * 1024 * 1024;
+
+const COMMAND_SIZE: usize = 12;
+pub const MAX_HEADERS: u64 = 2000;
+const MAX_INV: u64 = 50000;
+const MAX_ADDR: u64 = 1000;
+const MAX_LOCATOR: u64 = 101;
+const MAX_ADDRV2_SIZE: usize = 512;
+// BIP37: filteradd data is limited to a script element
+const MAX_FILTERADD_SIZE: u64 = 520;
+// BIP157 limits on a single request
+pub const MAX_CFILTERS: u32 = 1000;
+pub const MAX_CFHEADERS: u32 = 2000;
+
+fn read_array<R, const N: usize>(reader: &mut R) -> Result<[u8; N]>
+where
+    R: Read,
+{
+    let mut result = [0u8; N];
+    reader.read_exact(&mut result)?;
+    Ok(result)
+}
+
+// Hashes are kept in display order and reversed on the wire
+fn read_hash<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: Read,
+{
+    let mut hash = read_array::<R, 32>(reader)?.to_vec();
+    hash.reverse();
+    Ok(hash)
+}
+
+fn serialize_hash(hash: &[u8]) -> Vec<u8> {
+    let mut result = hash.to_vec();
+    result.reverse();
+    result
+}
+
+fn read_count<R>(reader: &mut R, max: u64, name: &str) -> Result<u64>
+where
+    R: Read,
+{
+    let count = read_variant(reader)?;
+    if count > max {
+        return Err(anyhow!("Too many {} entries: {}", name, count));
+    }
+    Ok(count)
+}
+
+pub struct NetworkEnvelope {
+    pub network: Network,
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+
+impl NetworkEnvelope {
+    pub fn new(network: Network, command: &str, payload: Vec<u8>) -> Self {
+        Self {
+            network,
+            command: command.to_string(),
+            payload,
+        }
+    }
+
+    pub fn from_message(network: Network, message: &Message) -> Self {
+        Self::new(network, message.command(), message.serialize())
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let network = Network::from_magic(read_array(reader)?)?;
+
+        let command: [u8; COMMAND_SIZE] = read_array(reader)?;
+        let len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+        if command[len..].iter().any(|b| *b != 0) || !command[..len].is_ascii() {
+            return Err(anyhow!("Malformed command {}", encode_hex(&command)));
+        }
+        let command = String::from_utf8(command[..len].to_vec())?;
+
+        let length = u32::from_le_bytes(read_array(reader)?);
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(anyhow!("Payload of {} bytes is too large", length));
+        }
+        let checksum: [u8; 4] = read_array(reader)?;
+        let mut payload = vec![0u8; length as usize];
+        reader.read_exact(&mut payload)?;
+        if hash256(&payload)[..4] != checksum {
+            return Err(anyhow!("Bad checksum for {} message", command));
+        }
+        Ok(Self {
+            network,
+            command,
+            payload,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.network.magic().to_vec();
+        let mut command = self.command.as_bytes().to_vec();
+        command.resize(COMMAND_SIZE, 0);
+        result.append(&mut command);
+        result.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        result.extend_from_slice(&hash256(&self.payload)[..4]);
+        result.extend_from_slice(&self.payload);
+        result
+    }
+
+    pub fn message(&self) -> Result<Message> {
+        Message::parse(&self.command, &self.payload, self.network)
+    }
+}
+
+impl fmt::Display for NetworkEnvelope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.command, encode_hex(&self.payload))
+    }
+}
+
+// An address as it appears in version and addr messages. IPv4 addresses are
+// IPv4-mapped IPv6, and the port is big-endian.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NetAddress {
+    pub services: u64,
+    pub ip: [u8; 16],
+    pub port: u16,
+}
+
+impl NetAddress {
+    pub fn new(services: u64, addr: &SocketAddr) -> Self {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        Self {
+            services,
+            ip: ip.octets(),
+            port: addr.port(),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        let ip = Ipv6Addr::from(self.ip);
+        match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), self.port),
+            None => SocketAddr::new(IpAddr::V6(ip), self.port),
+        }
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Ok(Self {
+            services: u64::from_le_bytes(read_array(reader)?),
+            ip: read_array(reader)?,
+            port: u16::from_be_bytes(read_array(reader)?),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.services.to_le_bytes().to_vec();
+        result.extend_from_slice(&self.ip);
+        result.extend_from_slice(&self.port.to_be_bytes());
+        result
+    }
+}
+
+impl Default for NetAddress {
+    fn default() -> Self {
+        Self::new(0, &SocketAddr::from(([0, 0, 0, 0], 8333)))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct VersionMessage {
+    pub version: u32,
+    pub services: u64,
+    pub timestamp: i64,
+    pub receiver: NetAddress,
+    pub sender: NetAddress,
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+}
+
+impl VersionMessage {
+    pub fn new(timestamp: i64, nonce: u64) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            services: 0,
+            timestamp,
+            receiver: NetAddress::default(),
+            sender: NetAddress::default(),
+            nonce,
+            user_agent: USER_AGENT.to_string(),
+            start_height: 0,
+            relay: false,
+        }
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let version = u32::from_le_bytes(read_array(reader)?);
+        let services = u64::from_le_bytes(read_array(reader)?);
+        let timestamp = i64::from_le_bytes(read_array(reader)?);
+        let receiver = NetAddress::parse(reader)?;
+        let sender = NetAddress::parse(reader)?;
+        let nonce = u64::from_le_bytes(read_array(reader)?);
+        let len = read_count(reader, 256, "user agent")?;
+        let mut user_agent = vec![];
+        reader.take(len).read_to_end(&mut user_agent)?;
+        if user_agent.len() as u64 != len {
+            return Err(anyhow!("User agent is shorter than its length prefix"));
+        }
+        let start_height = i32::from_le_bytes(read_array(reader)?);
+        // BIP37: peers which predate the relay flag always relay
+        let mut byte = [0u8];
+        let relay = reader.read(&mut byte)? == 0 || byte[0] != 0;
+        Ok(Self {
+            version,
+            services,
+            timestamp,
+            receiver,
+            sender,
+            nonce,
+            user_agent: String::from_utf8(user_agent)?,
+            start_height,
+            relay,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.extend_from_slice(&self.services.to_le_bytes());
+        result.extend_from_slice(&self.timestamp.to_le_bytes());
+        result.append(&mut self.receiver.serialize());
+        result.append(&mut self.sender.serialize());
+        result.extend_from_slice(&self.nonce.to_le_bytes());
+        result.append(&mut encode_variant(self.user_agent.len() as u64));
+        result.extend_from_slice(self.user_agent.as_bytes());
+        result.extend_from_slice(&self.start_height.to_le_bytes());
+        result.push(self.relay as u8);
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GetHeadersMessage {
+    pub version: u32,
+    pub locator: Vec<Vec<u8>>,
+    pub stop: Vec<u8>,
+}
+
+impl GetHeadersMessage {
+    // A zero stop hash asks for as many headers as the peer will send
+    pub fn new(locator: Vec<Vec<u8>>, stop: Option<Vec<u8>>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            locator,
+            stop: stop.unwrap_or_else(|| vec![0u8; 32]),
+        }
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let version = u32::from_le_bytes(read_array(reader)?);
+        let count = read_count(reader, MAX_LOCATOR, "locator")?;
+        let mut locator = vec![];
+        for _ in 0..count {
+            locator.push(read_hash(reader)?);
+        }
+        let stop = read_hash(reader)?;
+        Ok(Self {
+            version,
+            locator,
+            stop,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+        result.append(&mut encode_variant(self.locator.len() as u64));
+        for hash in &self.locator {
+            result.append(&mut serialize_hash(hash));
+        }
+        result.append(&mut serialize_hash(&self.stop));
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Inventory {
+    pub kind: u32,
+    pub hash: Vec<u8>,
+}
+
+impl Inventory {
+    pub fn new(kind: u32, hash: Vec<u8>) -> Self {
+        Self { kind, hash }
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let kind = u32::from_le_bytes(read_array(reader)?);
+        let hash = read_hash(reader)?;
+        Ok(Self { kind, hash })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.kind.to_le_bytes().to_vec();
+        result.append(&mut serialize_hash(&self.hash));
+        result
+    }
+}
+
+// BIP157: getcfilters and getcfheaders ask for every block from the start
+// height up to the stop hash
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FilterRequest {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop: Vec<u8>,
+}
+
+impl FilterRequest {
+    pub fn new(filter_type: u8, start_height: u32, stop: Vec<u8>) -> Self {
+        Self {
+            filter_type,
+            start_height,
+            stop,
+        }
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let filter_type = read_array::<_, 1>(reader)?[0];
+        let start_height = u32::from_le_bytes(read_array(reader)?);
+        let stop = read_hash(reader)?;
+        Ok(Self::new(filter_type, start_height, stop))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.extend_from_slice(&self.start_height.to_le_bytes());
+        result.append(&mut serialize_hash(&self.stop));
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CFilterMessage {
+    pub filter_type: u8,
+    pub block_hash: Vec<u8>,
+    pub filter: Vec<u8>,
+}
+
+impl CFilterMessage {
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let filter_type = read_array::<_, 1>(reader)?[0];
+        let block_hash = read_hash(reader)?;
+        let len = read_count(reader, MAX_PAYLOAD_SIZE as u64, "filter")?;
+        let mut filter = vec![];
+        reader.take(len).read_to_end(&mut filter)?;
+        if filter.len() as u64 != len {
+            return Err(anyhow!("Filter is shorter than its length prefix"));
+        }
+        Ok(Self {
+            filter_type,
+            block_hash,
+            filter,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.append(&mut serialize_hash(&self.block_hash));
+        result.append(&mut encode_variant(self.filter.len() as u64));
+        result.extend_from_slice(&self.filter);
+        result
+    }
+}
+
+// The filter hashes of the requested blocks and the filter header before the
+// first one, all in display order
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CFHeadersMessage {
+    pub filter_type: u8,
+    pub stop: Vec<u8>,
+    pub prev_header: Vec<u8>,
+    pub filter_hashes: Vec<Vec<u8>>,
+}
+
+impl CFHeadersMessage {
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let filter_type = read_array::<_, 1>(reader)?[0];
+        let stop = read_hash(reader)?;
+        let prev_header = read_hash(reader)?;
+        let count = read_count(reader, MAX_CFHEADERS as u64, "cfheaders")?;
+        let mut filter_hashes = vec![];
+        for _ in 0..count {
+            filter_hashes.push(read_hash(reader)?);
+        }
+        Ok(Self {
+            filter_type,
+            stop,
+            prev_header,
+            filter_hashes,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![self.filter_type];
+        result.append(&mut serialize_hash(&self.stop));
+        result.append(&mut serialize_hash(&self.prev_header));
+        result.append(&mut encode_variant(self.filter_hashes.len() as u64));
+        for hash in &self.filter_hashes {
+            result.append(&mut serialize_hash(hash));
+        }
+        result
+    }
+}
+
+// BIP155: the address length is fixed for every known network id
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AddressV2 {
+    pub time: u32,
+    pub services: u64,
+    pub network_id: u8,
+    pub addr: Vec<u8>,
+    pub port: u16,
+}
+
+impl AddressV2 {
+    pub const IPV4: u8 = 1;
+    pub const IPV6: u8 = 2;
+    pub const TORV2: u8 = 3;
+    pub const TORV3: u8 = 4;
+    pub const I2P: u8 = 5;
+    pub const CJDNS: u8 = 6;
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let time = u32::from_le_bytes(read_array(reader)?);
+        let services = read_variant(reader)?;
+        let network_id = read_array::<R, 1>(reader)?[0];
+        let len = read_variant(reader)? as usize;
+        if len > MAX_ADDRV2_SIZE {
+            return Err(anyhow!("Address of {} bytes is too large", len));
+        }
+        let expected = match network_id {
+            Self::IPV4 => Some(4),
+            Self::IPV6 | Self::CJDNS => Some(16),
+            Self::TORV2 => Some(10),
+            Self::TORV3 | Self::I2P => Some(32),
+            _ => None,
+        };
+        if expected.is_some() && expected != Some(len) {
+            return Err(anyhow!(
+                "Address of network {} has bad length {}",
+                network_id,
+                len
+            ));
+        }
+        let mut addr = vec![0u8; len];
+        reader.read_exact(&mut addr)?;
+        let port = u16::from_be_bytes(read_array(reader)?);
+        Ok(Self {
+            time,
+            services,
+            network_id,
+            addr,
+            port,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.time.to_le_bytes().to_vec();
+        result.append(&mut encode_variant(self.services));
+        result.push(self.network_id);
+        result.append(&mut encode_variant(self.addr.len() as u64));
+        result.extend_from_slice(&self.addr);
+        result.extend_from_slice(&self.port.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Message {
+    Version(VersionMessage),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    GetHeaders(GetHeadersMessage),
+    Headers(Vec<BlockHeader>),
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    Tx(Tx),
+    Block(Block),
+    MerkleBlock(MerkleBlock),
+    Addr(Vec<(u32, NetAddress)>),
+    AddrV2(Vec<AddressV2>),
+    FeeFilter(u64),
+    SendHeaders,
+    SendCmpct(bool, u64),
+    WtxidRelay,
+    SendAddrV2,
+    FilterLoad(BloomFilter),
+    FilterAdd(Vec<u8>),
+    FilterClear,
+    GetCFilters(FilterRequest),
+    CFilter(CFilterMessage),
+    GetCFHeaders(FilterRequest),
+    CFHeaders(CFHeadersMessage),
+    Unknown(String, Vec<u8>),
+}
+
+impl Message {
+    pub fn command(&self) -> &str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::Tx(_) => "tx",
+            Message::Block(_) => "block",
+            Message::MerkleBlock(_) => "merkleblock",
+            Message::Addr(_) => "addr",
+            Message::AddrV2(_) => "addrv2",
+            Message::FeeFilter(_) => "feefilter",
+            Message::SendHeaders => "sendheaders",
+            Message::SendCmpct(_, _) => "sendcmpct",
+            Message::WtxidRelay => "wtxidrelay",
+            Message::SendAddrV2 => "sendaddrv2",
+            Message::FilterLoad(_) => "filterload",
+            Message::FilterAdd(_) => "filteradd",
+            Message::FilterClear => "filterclear",
+            Message::GetCFilters(_) => "getcfilters",
+            Message::CFilter(_) => "cfilter",
+            Message::GetCFHeaders(_) => "getcfheaders",
+            Message::CFHeaders(_) => "cfheaders",
+            Message::Unknown(command, _) => command,
+        }
+    }
+
+    // Unknown commands are kept as raw payloads
+    pub fn parse(command: &str, payload: &[u8], network: Network) -> Result<Self> {
+        let reader = &mut &payload[..];
+        let message = match command {
+            "version" => Message::Version(VersionMessage::parse(reader)?),
+            "verack" => Message::Verack,
+            "ping" => Message::Ping(u64::from_le_bytes(read_array(reader)?)),
+            "pong" => Message::Pong(u64::from_le_bytes(read_array(reader)?)),
+            "getheaders" => Message::GetHeaders(GetHeadersMessage::parse(reader)?),
+            "headers" => {
+                let count = read_count(reader, MAX_HEADERS, "headers")?;
+                let mut headers = vec![];
+                for _ in 0..count {
+                    headers.push(BlockHeader::parse(reader)?);
+                    if read_variant(reader)? != 0 {
+                        return Err(anyhow!("Headers message carries transactions"));
+                    }
+                }
+                Message::Headers(headers)
+            }
+            "inv" | "getdata" => {
+                let count = read_count(reader, MAX_INV, command)?;
+                let mut inventory = vec![];
+                for _ in 0..count {
+                    inventory.push(Inventory::parse(reader)?);
+                }
+                if command == "inv" {
+                    Message::Inv(inventory)
+                } else {
+                    Message::GetData(inventory)
+                }
+            }
+            "tx" => Message::Tx(Tx::parse(reader, network)?),
+            "block" => Message::Block(Block::parse(reader, network)?),
+            "merkleblock" => Message::MerkleBlock(MerkleBlock::parse(reader)?),
+            "addr" => {
+                let count = read_count(reader, MAX_ADDR, command)?;
+                let mut addresses = vec![];
+                for _ in 0..count {
+                    let time = u32::from_le_bytes(read_array(reader)?);
+                    addresses.push((time, NetAddress::parse(reader)?));
+                }
+                Message::Addr(addresses)
+            }
+            "addrv2" => {
+                let count = read_count(reader, MAX_ADDR, command)?;
+                let mut addresses = vec![];
+                for _ in 0..count {
+                    addresses.push(AddressV2::parse(reader)?);
+                }
+                Message::AddrV2(addresses)
+            }
+            "feefilter" => Message::FeeFilter(u64::from_le_bytes(read_array(reader)?)),
+            "sendheaders" => Message::SendHeaders,
+            "sendcmpct" => {
+                let announce = read_array::<_, 1>(reader)?[0] != 0;
+                Message::SendCmpct(announce, u64::from_le_bytes(read_array(reader)?))
+            }
+            "wtxidrelay" => Message::WtxidRelay,
+            "sendaddrv2" => Message::SendAddrV2,
+            "filterload" => Message::FilterLoad(BloomFilter::parse(reader)?),
+            "filteradd" => {
+                let len = read_count(reader, MAX_FILTERADD_SIZE, "filteradd")?;
+                let mut data = vec![0u8; len as usize];
+                reader.read_exact(&mut data)?;
+                Message::FilterAdd(data)
+            }
+            "filterclear" => Message::FilterClear,
+            "getcfilters" => Message::GetCFilters(FilterRequest::parse(reader)?),
+            "cfilter" => Message::CFilter(CFilterMessage::parse(reader)?),
+            "getcfheaders" => Message::GetCFHeaders(FilterRequest::parse(reader)?),
+            "cfheaders" => Message::CFHeaders(CFHeadersMessage::parse(reader)?),
+            _ => return Ok(Message::Unknown(command.to_string(), payload.to_vec())),
+        };
+        if !reader.is_empty() {
+            return Err(anyhow!("Trailing data in {} message", command));
+        }
+        Ok(message)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Message::Version(version) => version.serialize(),
+            Message::Verack
+            | Message::SendHeaders
+            | Message::WtxidRelay
+            | Message::SendAddrV2
+            | Message::FilterClear => vec![],
+            Message::Ping(nonce) | Message::Pong(nonce) => nonce.to_le_bytes().to_vec(),
+            Message::GetHeaders(getheaders) => getheaders.serialize(),
+            Message::Headers(headers) => {
+                let mut result = encode_variant(headers.len() as u64);
+                for header in headers {
+                    result.append(&mut header.serialize());
+                    result.push(0);
+                }
+                result
+            }
+            Message::Inv(inventory) | Message::GetData(inventory) => {
+                let mut result = encode_variant(inventory.len() as u64);
+                for item in inventory {
+                    result.append(&mut item.serialize());
+                }
+                result
+            }
+            Message::Tx(tx) => tx.serialize(),
+            Message::Block(block) => block.serialize(),
+            Message::MerkleBlock(merkle_block) => merkle_block.serialize(),
+            Message::Addr(addresses) => {
+                let mut result = encode_variant(addresses.len() as u64);
+                for (time, address) in addresses {
+                    result.extend_from_slice(&time.to_le_bytes());
+                    result.append(&mut address.serialize());
+                }
+                result
+            }
+            Message::AddrV2(addresses) => {
+                let mut result = encode_variant(addresses.len() as u64);
+                for address in addresses {
+                    result.append(&mut address.serialize());
+                }
+                result
+            }
+            Message::FeeFilter(fee_rate) => fee_rate.to_le_bytes().to_vec(),
+            Message::SendCmpct(announce, version) => {
+                let mut result = vec![*announce as u8];
+                result.extend_from_slice(&version.to_le_bytes());
+                result
+            }
+            Message::FilterLoad(filter) => filter.serialize(),
+            Message::FilterAdd(data) => {
+                let mut result = encode_variant(data.len() as u64);
+                result.extend_from_slice(data);
+                result
+            }
+            Message::GetCFilters(request) | Message::GetCFHeaders(request) => request.serialize(),
+            Message::CFilter(cfilter) => cfilter.serialize(),
+            Message::CFHeaders(cfheaders) => cfheaders.serialize(),
+            Message::Unknown(_, payload) => payload.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::helper::decode_hex;
+
+#[test]
+fn test_envelope() {
+    let raw = decode_hex("f9beb4d976657261636b000000000000000000005df6e0e2").unwrap();
+    let envelope = NetworkEnvelope::parse(&mut raw.as_slice()).unwrap();
+    assert_eq!(envelope.network, Network::Mainnet);
+    assert_eq!(envelope.command, "verack");
+    assert!(envelope.payload.is_empty());
+    assert_eq!(envelope.serialize(), raw);
+    assert_eq!(envelope.message().unwrap(), Message::Verack);
+
+    let raw = decode_hex("f9beb4d970696e6700000000000000000800000024").unwrap();
+    assert!(NetworkEnvelope::parse(&mut raw.as_slice()).is_err());
+    let raw =
+        decode_hex("f9beb4d970696e670000000000000000080000002467f11d6400000000000000").unwrap();
+    let envelope = NetworkEnvelope::parse(&mut raw.as_slice()).unwrap();
+    assert_eq!(envelope.message().unwrap(), Message::Ping(100));
+    assert_eq!(
+        NetworkEnvelope::from_message(Network::Mainnet, &Message::Ping(100)).serialize(),
+        raw
+    );
+
+    let mut bad = raw.clone();
+    bad[20] ^= 1;
+    assert_eq!(
+        NetworkEnvelope::parse(&mut bad.as_slice())
+            .err()
+            .unwrap()
+            .to_string(),
+        "Bad checksum for ping message"
+    );
+    let mut bad = raw.clone();
+    bad[0] = 0;
+    assert!(NetworkEnvelope::parse(&mut bad.as_slice()).is_err());
+    let mut bad = raw;
+    bad[9] = b'x';
+    assert!(NetworkEnvelope::parse(&mut bad.as_slice()).is_err());
+
+    let envelope = NetworkEnvelope::new(Network::Testnet3, "mempool", vec![]);
+    let raw = envelope.serialize();
+    assert_eq!(raw.len(), ENVELOPE_HEADER_SIZE);
+    assert_eq!(&raw[..4], &Network::Testnet3.magic());
+    let parsed = NetworkEnvelope::parse(&mut raw.as_slice()).unwrap();
+    assert_eq!(
+        parsed.message().unwrap(),
+        Message::Unknown("mempool".to_string(), vec![])
+    );
+}
+
+#[test]
+fn test_version() {
+    // Bitcoin Core 0.17.1
+    let raw = decode_hex("f9beb4d976657273696f6e000000000066000000be61b8277f1101000d04000000000000f00f4d5c00000000000000000000000000000000000000000000ffff5bf08c80b4bd0d04000000000000000000000000000000000000000000000000faa99559cc68a1c1102f5361746f7368693a302e31372e312f938c080001").unwrap();
+    let envelope = NetworkEnvelope::parse(&mut raw.as_slice()).unwrap();
+    let version = match envelope.message().unwrap() {
+        Message::Version(version) => version,
+        message => panic!("Unexpected {:?}", message),
+    };
+    assert_eq!(version.version, 70015);
+    assert_eq!(
+        version.services,
+        NODE_NETWORK | NODE_BLOOM | NODE_WITNESS | NODE_NETWORK_LIMITED
+    );
+    assert_eq!(version.timestamp, 1548554224);
+    assert_eq!(
+        version.receiver.socket_addr(),
+        "91.240.140.128:46269".parse().unwrap()
+    );
+    assert_eq!(version.nonce, 13952548347456104954);
+    assert_eq!(version.user_agent, "/Satoshi:0.17.1/");
+    assert_eq!(version.start_height, 560275);
+    assert!(version.relay);
+    assert_eq!(
+        NetworkEnvelope::from_message(Network::Mainnet, &Message::Version(version)).serialize(),
+        raw
+    );
+
+    // Without the BIP37 relay flag
+    let payload = decode_hex("721101000100000000000000bc8f5e5400000000010000000000000000000000000000000000ffffc61b6409208d010000000000000000000000000000000000ffffcb0071c0208d128035cbc97953f80f2f5361746f7368693a302e392e332fcf050500").unwrap();
+    let version = VersionMessage::parse(&mut payload.as_slice()).unwrap();
+    assert_eq!(version.user_agent, "/Satoshi:0.9.3/");
+    assert!(version.relay);
+
+    let version = VersionMessage::new(0, 0);
+    assert_eq!(encode_hex(&version.serialize()), "8011010000000000000000000000000000000000000000000000000000000000000000000000ffff00000000208d000000000000000000000000000000000000ffff00000000208d0000000000000000182f70726f6772616d6d696e67626974636f696e3a302e312f0000000000");
+}
+
+#[test]
+fn test_getheaders() {
+    let start =
+        decode_hex("0000000000000000001237f46acddf58578a37e213d2a6edc4884a2fcad05ba3").unwrap();
+    let mut getheaders = GetHeadersMessage::new(vec![start], None);
+    getheaders.version = 70015;
+    let payload = getheaders.serialize();
+    assert_eq!(encode_hex(&payload), "7f11010001a35bd0ca2f4a88c4eda6d213e2378a5758dfcd6af437120000000000000000000000000000000000000000000000000000000000000000000000000000000000");
+    assert_eq!(
+        Message::parse("getheaders", &payload, Network::Mainnet).unwrap(),
+        Message::GetHeaders(getheaders)
+    );
+}
+
+#[test]
+fn test_headers() {
+    let payload = decode_hex("0200000020df3b053dc46f162a9b00c7f0d5124e2676d47bbe7c5d0793a500000000000000ef445fef2ed495c275892206ca533e7411907971013ab83e3b47bd0d692d14d4dc7c835b67d8001ac157e670000000002030eb2540c41025690160a1014c577061596e32e426b712c7ca00000000000000768b89f07044e6130ead292a3f51951adbd2202df447d98789339937fd006bd44880835b67d8001ade09204600").unwrap();
+    let message = Message::parse("headers", &payload, Network::Mainnet).unwrap();
+    let headers = match &message {
+        Message::Headers(headers) => headers,
+        message => panic!("Unexpected {:?}", message),
+    };
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers[1].prev_hash(), headers[0].hash());
+    assert_eq!(message.serialize(), payload);
+
+    let mut bad = payload;
+    bad[81] = 1;
+    assert!(Message::parse("headers", &bad, Network::Mainnet).is_err());
+}
+
+#[test]
+fn test_inventory() {
+    let blocks = [
+        "00000000000000cac712b726e4326e596170574c01a16001692510c44025eb30",
+        "00000000000000beb88910c46f6b442312361c6693a7fb52065b583979844910",
+    ];
+    let inventory: Vec<Inventory> = blocks
+        .iter()
+        .map(|hash| Inventory::new(MSG_FILTERED_BLOCK, decode_hex(hash).unwrap()))
+        .collect();
+    let message = Message::GetData(inventory.clone());
+    let payload = message.serialize();
+    assert_eq!(encode_hex(&payload), "020300000030eb2540c41025690160a1014c577061596e32e426b712c7ca00000000000000030000001049847939585b0652fba793661c361223446b6fc41089b8be00000000000000");
+    assert_eq!(
+        Message::parse("getdata", &payload, Network::Mainnet).unwrap(),
+        message
+    );
+    assert_eq!(
+        Message::parse("inv", &payload, Network::Mainnet).unwrap(),
+        Message::Inv(inventory)
+    );
+    assert!(Message::parse("inv", &payload[..payload.len() - 1], Network::Mainnet).is_err());
+    assert_eq!(
+        Message::parse(
+            "inv",
+            &[payload.clone(), vec![0]].concat(),
+            Network::Mainnet
+        )
+        .unwrap_err()
+        .to_string(),
+        "Trailing data in inv message"
+    );
+    assert!(Message::parse("inv", &encode_variant(MAX_INV + 1), Network::Mainnet).is_err());
+}
+
+#[test]
+fn test_transaction_messages() {
+    let payload = decode_hex("0100000001a15d57094aa7a21a28cb20b59aab8fc7d1149a3bdbcddba9c622e4f5f6a99ece010000006c493046022100f93bb0e7d8db7bd46e40132d1f8242026e045f03a0efe71bbb8e3f475e970d790221009337cd7f1f929f00cc6ff01f03729b069a7c21b59b1736ddfee5db5946c5da8c0121033b9b137ee87d5a812d6f506efdd37f0affa7ffc310711c06c7f3e097c9447c52ffffffff0100e1f505000000001976a9140389035a9225b3839e2bbf32d826a1e222031fd888ac00000000").unwrap();
+    let message = Message::parse("tx", &payload, Network::Mainnet).unwrap();
+    assert!(matches!(message, Message::Tx(_)));
+    assert_eq!(message.serialize(), payload);
+
+    let payload = Network::Regtest.genesis_block();
+    let message = Message::parse("block", &payload, Network::Regtest).unwrap();
+    assert!(matches!(message, Message::Block(_)));
+    assert_eq!(message.serialize(), payload);
+
+    let payload = decode_hex("0100000079cda856b143d9db2c1caff01d1aecc8630d30625d10e8b4b8b0000000000000b50cc069d6a3e33e3ff84a5c41d9d3febe7c770fdcc96b2c3ff60abe184f196367291b4d4c86041b8fa45d630100000001b50cc069d6a3e33e3ff84a5c41d9d3febe7c770fdcc96b2c3ff60abe184f19630101").unwrap();
+    let message = Message::parse("merkleblock", &payload, Network::Mainnet).unwrap();
+    match &message {
+        Message::MerkleBlock(merkle_block) => assert_eq!(merkle_block.verify().unwrap().len(), 1),
+        message => panic!("Unexpected {:?}", message),
+    }
+    assert_eq!(message.serialize(), payload);
+}
+
+#[test]
+fn test_addr() {
+    let payload = decode_hex("0261bc6649019902abab208d79627683fd4804010409090909208d").unwrap();
+    let message = Message::parse("addrv2", &payload, Network::Mainnet).unwrap();
+    assert_eq!(
+        message,
+        Message::AddrV2(vec![
+            AddressV2 {
+                time: 0x4966bc61,
+                services: NODE_NETWORK,
+                network_id: 0x99,
+                addr: vec![0xab, 0xab],
+                port: 8333,
+            },
+            AddressV2 {
+                time: 0x83766279,
+                services: NODE_NETWORK_LIMITED | NODE_WITNESS | NODE_COMPACT_FILTERS,
+                network_id: AddressV2::IPV4,
+                addr: vec![9, 9, 9, 9],
+                port: 8333,
+            },
+        ])
+    );
+    assert_eq!(message.serialize(), payload);
+    assert!(
+        AddressV2::parse(&mut decode_hex("00000000010105010203040520").unwrap().as_slice())
+            .is_err()
+    );
+
+    let addr = NetAddress::new(NODE_NETWORK, &"[2001:db8::1]:18333".parse().unwrap());
+    let message = Message::Addr(vec![(1548554224, addr.clone())]);
+    let payload = message.serialize();
+    assert_eq!(payload.len(), 1 + 30);
+    assert_eq!(
+        Message::parse("addr", &payload, Network::Mainnet).unwrap(),
+        message
+    );
+    assert_eq!(addr.socket_addr(), "[2001:db8::1]:18333".parse().unwrap());
+}
+
+#[test]
+fn test_small_messages() {
+    let cases = [
+        (Message::Pong(0x1122334455667788), "8877665544332211"),
+        (Message::FeeFilter(1000), "e803000000000000"),
+        (Message::SendHeaders, ""),
+        (Message::SendCmpct(true, 2), "010200000000000000"),
+        (Message::WtxidRelay, ""),
+        (Message::SendAddrV2, ""),
+        (Message::FilterClear, ""),
+        (Message::FilterAdd(vec![0xab; 3]), "03ababab"),
+        (
+            Message::FilterLoad(BloomFilter::new(2, 5, 99, 1)),
+            "020000050000006300000001",
+        ),
+    ];
+    for (message, payload) in cases.iter() {
+        assert_eq!(encode_hex(&message.serialize()), *payload);
+        let parsed = Message::parse(
+            message.command(),
+            &decode_hex(payload).unwrap(),
+            Network::Mainnet,
+        )
+        .unwrap();
+        assert_eq!(&parsed, message);
+    }
+    assert!(Message::parse("pong", &[0; 4], Network::Mainnet).is_err());
+    assert!(Message::parse("verack", &[0], Network::Mainnet).is_err());
+    let payload = [&encode_variant(521)[..], &[0; 521]].concat();
+    assert!(Message::parse("filteradd", &payload, Network::Mainnet).is_err());
+}
+
+#[test]
+fn test_filter_messages() {
+    let genesis = Network::Mainnet.genesis_hash();
+    let request = FilterRequest::new(0, 1, genesis.clone());
+    let payload = "00010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000";
+    for message in [
+        Message::GetCFilters(request.clone()),
+        Message::GetCFHeaders(request),
+    ]
+    .iter()
+    {
+        assert_eq!(encode_hex(&message.serialize()), payload);
+        let parsed = Message::parse(
+            message.command(),
+            &decode_hex(payload).unwrap(),
+            Network::Mainnet,
+        )
+        .unwrap();
+        assert_eq!(&parsed, message);
+    }
+
+    let cfilter = Message::CFilter(CFilterMessage {
+        filter_type: 0,
+        block_hash: genesis.clone(),
+        filter: decode_hex("019dfca8").unwrap(),
+    });
+    let payload = cfilter.serialize();
+    assert_eq!(
+        encode_hex(&payload),
+        "006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d619000000000004019dfca8"
+    );
+    assert_eq!(
+        Message::parse("cfilter", &payload, Network::Mainnet).unwrap(),
+        cfilter
+    );
+    assert!(Message::parse("cfilter", &payload[..payload.len() - 1], Network::Mainnet).is_err());
+
+    let cfheaders = Message::CFHeaders(CFHeadersMessage {
+        filter_type: 0,
+        stop: genesis,
+        prev_header: vec![0u8; 32],
+        filter_hashes: vec![vec![1u8; 32], vec![2u8; 32]],
+    });
+    let payload = cfheaders.serialize();
+    assert_eq!(payload.len(), 1 + 32 + 32 + 1 + 64);
+    assert_eq!(
+        Message::parse("cfheaders", &payload, Network::Mainnet).unwrap(),
+        cfheaders
+    );
+}
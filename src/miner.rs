@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Result};
+
+use crate::block::{Block, BlockHeader, WITNESS_COMMITMENT_HEADER};
+use crate::helper::merkle_parent;
+use crate::mempool::Mempool;
+use crate::network::Network;
+use crate::script::{Cmd, Script};
+use crate::transaction::{Tx, TxIn, TxOut};
+use crate::utxo::UtxoStore;
+use crate::validation::{block_subsidy, PrevState, MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_WEIGHT};
+
+// Room left for the coinbase when picking mempool transactions
+pub const COINBASE_RESERVED_WEIGHT: usize = 4000;
+pub const COINBASE_RESERVED_SIGOPS: usize = 400;
+
+// BIP9 version bits with nothing signalled
+const TEMPLATE_VERSION: u32 = 0x20000000;
+
+// BIP34 height first, then the extra nonce
+pub fn coinbase_tx(
+    height: u32,
+    extra_nonce: u32,
+    value: u64,
+    script_pubkey: &Script,
+    network: Network,
+) -> Tx {
+    let script_sig = Script::from_cmds(&[
+        Cmd::num(height as i64),
+        Cmd::Data(extra_nonce.to_le_bytes().to_vec()),
+    ]);
+    let tx_in = TxIn::new([0u8; 32], 0xffffffff, Some(script_sig), 0xffffffff);
+    let tx_out = TxOut::new(value, script_pubkey.clone());
+    Tx::new(2, vec![tx_in], vec![tx_out], 0, network)
+}
+
+// BIP141: commits to the witness root with an all-zero reserved value
+pub fn add_witness_commitment(block: &mut Block) {
+    let reserved = vec![0u8; 32];
+    let commitment = merkle_parent(&block.witness_root().unwrap(), &reserved);
+    let script = [WITNESS_COMMITMENT_HEADER.to_vec(), commitment].concat();
+    let coinbase = &mut block.txs[0];
+    coinbase.tx_ins[0].witness = vec![reserved];
+    coinbase
+        .tx_outs
+        .push(TxOut::new(0, Script::from_bytes(script)));
+}
+
+fn update_merkle_root(block: &mut Block) {
+    let merkle_root = block.merkle_root().unwrap();
+    block.header.merkle_root.copy_from_slice(&merkle_root);
+}
+
+// A block on top of the chain's tip paying the subsidy and fees to
+// `script_pubkey`, with mempool transactions picked by ancestor feerate.
+// The nonce still has to be ground.
+pub fn build_template<S>(
+    mempool: &Mempool,
+    prev: &PrevState<S>,
+    script_pubkey: &Script,
+    timestamp: u32,
+) -> Result<Block>
+where
+    S: UtxoStore,
+{
+    let chain = prev.chain;
+    let tip = chain.tip();
+    let tip_hash = tip.header.hash();
+    let height = tip.height + 1;
+    let timestamp = timestamp.max(chain.median_time_past(&tip_hash).unwrap() + 1);
+    let bits = chain.next_bits(tip, timestamp)?;
+
+    let entries = mempool.next_block(
+        MAX_BLOCK_WEIGHT - COINBASE_RESERVED_WEIGHT,
+        MAX_BLOCK_SIGOPS_COST - COINBASE_RESERVED_SIGOPS,
+    );
+    let fees: u64 = entries.iter().map(|entry| entry.fee).sum();
+    let value = block_subsidy(height, chain.network) + fees;
+    let coinbase = coinbase_tx(height, 0, value, script_pubkey, chain.network);
+    let mut txs = vec![coinbase];
+    txs.extend(entries.into_iter().map(|entry| entry.tx.clone()));
+
+    let mut prev_block = [0u8; 32];
+    prev_block.copy_from_slice(&tip_hash);
+    prev_block.reverse();
+    let header = BlockHeader::new(TEMPLATE_VERSION, prev_block, [0u8; 32], timestamp, bits, 0);
+    let mut block = Block::new(header, txs);
+    if block.txs.iter().any(|tx| tx.is_segwit()) {
+        add_witness_commitment(&mut block);
+    }
+    update_merkle_root(&mut block);
+    Ok(block)
+}
+
+// Tries every nonce, then bumps the coinbase's extra nonce and starts over,
+// until the header meets its target or `max_tries` hashes are done
+pub fn grind(block: &mut Block, max_tries: u64) -> Result<()> {
+    let mut tries = 0;
+    loop {
+        if block.header.check_pow() {
+            return Ok(());
+        }
+        tries += 1;
+        if tries >= max_tries {
+            return Err(anyhow!("No proof of work found in {} tries", max_tries));
+        }
+        if block.header.nonce == u32::MAX {
+            bump_extra_nonce(block)?;
+        } else {
+            block.header.nonce += 1;
+        }
+    }
+}
+
+fn bump_extra_nonce(block: &mut Block) -> Result<()> {
+    let coinbase = &mut block.txs[0];
+    let mut cmds = coinbase.tx_ins[0].script_sig.cmds()?;
+    let extra_nonce = match cmds.get(1) {
+        Some(Cmd::Data(data)) if data.len() == 4 => {
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+        }
+        _ => return Err(anyhow!("Coinbase has no extra nonce")),
+    };
+    cmds[1] = Cmd::Data(extra_nonce.wrapping_add(1).to_le_bytes().to_vec());
+    coinbase.tx_ins[0].script_sig = Script::from_cmds(&cmds);
+    // The witness commitment doesn't cover the coinbase
+    update_merkle_root(block);
+    block.header.nonce = 0;
+    Ok(())
+}
+
+#[cfg(test)]
+use crate::chain::HeaderChain;
+#[cfg(test)]
+use crate::s256::PrivateKey;
+#[cfg(test)]
+use crate::testutil::{test_spend, test_submit};
+#[cfg(test)]
+use crate::utxo::{MemoryStore, OutPoint, UtxoSet};
+#[cfg(test)]
+use num_bigint::BigInt;
+
+// Builds, grinds, validates and connects a block
+#[cfg(test)]
+fn test_mine(
+    chain: &mut HeaderChain,
+    utxos: &mut UtxoSet<MemoryStore>,
+    mempool: &mut Mempool,
+    script_pubkey: &Script,
+) -> (Block, u64) {
+    let prev = PrevState { chain, utxos };
+    let timestamp = chain.tip().header.timestamp + 600;
+    let mut block = build_template(mempool, &prev, script_pubkey, timestamp).unwrap();
+    grind(&mut block, 1_000_000).unwrap();
+    let fees = test_submit(chain, utxos, &block).unwrap();
+    mempool.remove_for_block(&block);
+    (block, fees)
+}
+
+#[test]
+fn test_grind() {
+    let script_pubkey = Script::p2wpkh(&[1u8; 20]);
+    let coinbase = coinbase_tx(1, 0, 50_0000_0000, &script_pubkey, Network::Regtest);
+    let header = BlockHeader::new(TEMPLATE_VERSION, [0u8; 32], [0u8; 32], 0, 0x207fffff, 0);
+    let mut block = Block::new(header, vec![coinbase]);
+    update_merkle_root(&mut block);
+    grind(&mut block, 1000).unwrap();
+    assert!(block.header.check_pow());
+
+    let root = block.header.merkle_root;
+    bump_extra_nonce(&mut block).unwrap();
+    assert_ne!(block.header.merkle_root, root);
+    assert_eq!(block.header.nonce, 0);
+    block.validate().unwrap();
+    let cmds = block.txs[0].tx_ins[0].script_sig.cmds().unwrap();
+    assert_eq!(cmds, vec![Cmd::num(1), Cmd::Data(vec![1, 0, 0, 0])]);
+
+    // Difficulty 1 takes billions of hashes
+    block.header.bits = 0x1d00ffff;
+    assert!(grind(&mut block, 1000).is_err());
+}
+
+#[test]
+fn test_mine_regtest() {
+    let mut chain = HeaderChain::new(Network::Regtest);
+    let mut utxos = UtxoSet::new(MemoryStore::new());
+    let mut mempool = Mempool::default();
+    let key = PrivateKey::new(BigInt::from(2024)).unwrap();
+    let h160 = key.point.hash160(true);
+    let script_pubkey = Script::p2wpkh(&h160);
+
+    let (first, fees) = test_mine(&mut chain, &mut utxos, &mut mempool, &script_pubkey);
+    assert_eq!(fees, 0);
+    assert_eq!(first.txs.len(), 1);
+    assert!(first.witness_commitment().is_none());
+    for _ in 0..100 {
+        test_mine(
+            &mut chain,
+            &mut utxos,
+            &mut mempool,
+            &Script::p2wpkh(&[0u8; 20]),
+        );
+    }
+    assert_eq!(chain.height(), 101);
+
+    // Spend the now mature first coinbase through the mempool
+    let subsidy = block_subsidy(1, Network::Regtest);
+    let coin = (OutPoint::new(first.txs[0].hash(), 0), subsidy);
+    let tx = test_spend(
+        &key,
+        &[coin],
+        &[subsidy / 2, subsidy / 2 - 5000],
+        0xfffffffd,
+    );
+    let prev = PrevState {
+        chain: &chain,
+        utxos: &utxos,
+    };
+    mempool.accept(tx.clone(), &prev).unwrap();
+
+    let (block, fees) = test_mine(&mut chain, &mut utxos, &mut mempool, &script_pubkey);
+    assert_eq!(fees, 5000);
+    assert_eq!(block.txs[1], tx);
+    assert!(block.witness_commitment().is_some());
+    assert_eq!(
+        block.txs[0].tx_outs[0].amount,
+        block_subsidy(102, Network::Regtest) + 5000
+    );
+    assert!(mempool.is_empty());
+    assert!(utxos.get(&OutPoint::new(tx.hash(), 1)).is_some());
+}
@@ -0,0 +1,1436 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops;
+use std::str::FromStr;
+
+use crate::descriptor::{split_args, split_function, Context, DescriptorKey};
+use crate::helper::{decode_hex, encode_hex, hash160, hash256, ripemd160, sha256};
+use crate::interpreter::MAX_PUBKEYS_PER_MULTISIG;
+use crate::op::*;
+use crate::policy::Policy;
+use crate::script::{encode_num, Cmd, Script};
+use crate::transaction::{
+    LOCKTIME_THRESHOLD, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_MASK,
+    SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+
+const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+
+// Witness sizes including the length prefix of each element
+const SIGNATURE_SIZE: usize = 1 + 73;
+const PUBKEY_SIZE: usize = 1 + 33;
+const PREIMAGE_SIZE: usize = 1 + 32;
+
+// The correctness and malleability properties of an expression, one bit per
+// letter of the miniscript type system. B, V, K and W are the basic types;
+// g, h, i and j track the kinds of timelocks used and k is set when they are
+// not mixed.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct Type(u32);
+
+const TYPE_LETTERS: &str = "BVKWzondufesmxghijk";
+
+impl Type {
+    fn of(letters: &str) -> Self {
+        Type(letters.chars().fold(0, |acc, c| {
+            acc | 1 << TYPE_LETTERS.find(c).expect("unknown type letter")
+        }))
+    }
+
+    pub fn has(&self, letters: &str) -> bool {
+        let other = Type::of(letters);
+        self.0 & other.0 == other.0
+    }
+
+    fn when(self, condition: bool) -> Self {
+        if condition {
+            self
+        } else {
+            Type(0)
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        (self.0 & Type::of("BVKW").0).count_ones() == 1
+    }
+}
+
+impl ops::BitOr for Type {
+    type Output = Type;
+
+    fn bitor(self, other: Type) -> Type {
+        Type(self.0 | other.0)
+    }
+}
+
+impl ops::BitAnd for Type {
+    type Output = Type;
+
+    fn bitand(self, other: Type) -> Type {
+        Type(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, c) in TYPE_LETTERS.chars().enumerate() {
+            if self.0 >> i & 1 == 1 {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether two expressions combined in a conjunction can still be satisfied
+// together, i.e. they do not require both kinds of relative or absolute
+// timelocks.
+fn no_timelock_mix(x: Type, y: Type) -> bool {
+    !((x.has("g") && y.has("h"))
+        || (x.has("h") && y.has("g"))
+        || (x.has("i") && y.has("j"))
+        || (x.has("j") && y.has("i")))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fragment {
+    False,
+    True,
+    PkK(DescriptorKey),
+    PkH(DescriptorKey),
+    Older(u32),
+    After(u32),
+    Sha256(Vec<u8>),
+    Hash256(Vec<u8>),
+    Ripemd160(Vec<u8>),
+    Hash160(Vec<u8>),
+    AndOr(Box<Miniscript>, Box<Miniscript>, Box<Miniscript>),
+    AndV(Box<Miniscript>, Box<Miniscript>),
+    AndB(Box<Miniscript>, Box<Miniscript>),
+    OrB(Box<Miniscript>, Box<Miniscript>),
+    OrC(Box<Miniscript>, Box<Miniscript>),
+    OrD(Box<Miniscript>, Box<Miniscript>),
+    OrI(Box<Miniscript>, Box<Miniscript>),
+    Thresh(usize, Vec<Miniscript>),
+    Multi(usize, Vec<DescriptorKey>),
+    Alt(Box<Miniscript>),
+    Swap(Box<Miniscript>),
+    Check(Box<Miniscript>),
+    DupIf(Box<Miniscript>),
+    Verify(Box<Miniscript>),
+    NonZero(Box<Miniscript>),
+    ZeroNotEqual(Box<Miniscript>),
+}
+
+fn compute_type(node: &Fragment) -> Type {
+    let t = Type::of;
+    match node {
+        Fragment::False => t("Bzudemsxk"),
+        Fragment::True => t("Bzufmxk"),
+        Fragment::PkK(_) => t("Konudemsxk"),
+        Fragment::PkH(_) => t("Knudemsxk"),
+        Fragment::Older(n) => {
+            t("g").when(n & SEQUENCE_LOCKTIME_TYPE_FLAG != 0)
+                | t("h").when(n & SEQUENCE_LOCKTIME_TYPE_FLAG == 0)
+                | t("Bzfmxk")
+        }
+        Fragment::After(n) => {
+            t("i").when(*n >= LOCKTIME_THRESHOLD)
+                | t("j").when(*n < LOCKTIME_THRESHOLD)
+                | t("Bzfmxk")
+        }
+        Fragment::Sha256(_)
+        | Fragment::Hash256(_)
+        | Fragment::Ripemd160(_)
+        | Fragment::Hash160(_) => t("Bonudmk"),
+        Fragment::AndOr(x, y, z) => {
+            let (x, y, z) = (x.ty, y.ty, z.ty);
+            (y & z & t("BKV")).when(x.has("Bdu"))
+                | (x & y & z & t("z"))
+                | ((x | (y & z)) & t("o")).when((x | (y & z)).has("z"))
+                | (y & z & t("u"))
+                | (z & t("f")).when(x.has("s") || y.has("f"))
+                | (z & t("d"))
+                | (x & z & t("e")).when(x.has("s") || y.has("f"))
+                | (x & y & z & t("m")).when(x.has("e") && (x | y | z).has("s"))
+                | (z & (x | y) & t("s"))
+                | t("x")
+                | ((x | y | z) & t("ghij"))
+                | t("k").when((x & y & z).has("k") && no_timelock_mix(x, y))
+        }
+        Fragment::AndV(x, y) => {
+            let (x, y) = (x.ty, y.ty);
+            (y & t("KVB")).when(x.has("V"))
+                | (x & t("n"))
+                | (y & t("n")).when(x.has("z"))
+                | ((x | y) & t("o")).when((x | y).has("z"))
+                | (x & y & t("dmz"))
+                | ((x | y) & t("s"))
+                | t("f").when(y.has("f") || x.has("s"))
+                | (y & t("ux"))
+                | ((x | y) & t("ghij"))
+                | t("k").when((x & y).has("k") && no_timelock_mix(x, y))
+        }
+        Fragment::AndB(x, y) => {
+            let (x, y) = (x.ty, y.ty);
+            (x & t("B")).when(y.has("W"))
+                | ((x | y) & t("o")).when((x | y).has("z"))
+                | (x & t("n"))
+                | (y & t("n")).when(x.has("z"))
+                | (x & y & t("e")).when((x & y).has("s"))
+                | (x & y & t("dzm"))
+                | t("f").when((x & y).has("f") || x.has("sf") || y.has("sf"))
+                | ((x | y) & t("s"))
+                | t("ux")
+                | ((x | y) & t("ghij"))
+                | t("k").when((x & y).has("k") && no_timelock_mix(x, y))
+        }
+        Fragment::OrB(x, z) => {
+            let (x, z) = (x.ty, z.ty);
+            t("B").when(x.has("Bd") && z.has("Wd"))
+                | ((x | z) & t("o")).when((x | z).has("z"))
+                | (x & z & t("m")).when((x | z).has("s") && (x & z).has("e"))
+                | (x & z & t("zse"))
+                | t("dux")
+                | ((x | z) & t("ghij"))
+                | (x & z & t("k"))
+        }
+        Fragment::OrC(x, z) => {
+            let (x, z) = (x.ty, z.ty);
+            (z & t("V")).when(x.has("Bdu"))
+                | (x & t("o")).when(z.has("z"))
+                | (x & z & t("m")).when(x.has("e") && (x | z).has("s"))
+                | (x & z & t("zs"))
+                | t("fx")
+                | ((x | z) & t("ghij"))
+                | (x & z & t("k"))
+        }
+        Fragment::OrD(x, z) => {
+            let (x, z) = (x.ty, z.ty);
+            (z & t("B")).when(x.has("Bdu"))
+                | (x & t("o")).when(z.has("z"))
+                | (x & z & t("m")).when(x.has("e") && (x | z).has("s"))
+                | (x & z & t("zes"))
+                | (z & t("ufd"))
+                | t("x")
+                | ((x | z) & t("ghij"))
+                | (x & z & t("k"))
+        }
+        Fragment::OrI(x, z) => {
+            let (x, z) = (x.ty, z.ty);
+            (x & z & t("VBKufs"))
+                | t("o").when((x & z).has("z"))
+                | ((x | z) & t("e")).when((x | z).has("f"))
+                | (x & z & t("m")).when((x | z).has("s"))
+                | ((x | z) & t("d"))
+                | t("x")
+                | ((x | z) & t("ghij"))
+                | (x & z & t("k"))
+        }
+        Fragment::Thresh(k, subs) => {
+            let mut all_e = true;
+            let mut all_m = true;
+            let mut args = 0;
+            let mut num_s = 0;
+            let mut timelocks = t("k");
+            for (i, sub) in subs.iter().enumerate() {
+                let ty = sub.ty;
+                if !ty.has(if i == 0 { "Bdu" } else { "Wdu" }) {
+                    return Type(0);
+                }
+                all_e &= ty.has("e");
+                all_m &= ty.has("m");
+                if ty.has("s") {
+                    num_s += 1;
+                }
+                args += if ty.has("z") {
+                    0
+                } else if ty.has("o") {
+                    1
+                } else {
+                    2
+                };
+                timelocks = ((timelocks | ty) & t("ghij"))
+                    | t("k").when(
+                        (timelocks & ty).has("k") && (*k <= 1 || no_timelock_mix(timelocks, ty)),
+                    );
+            }
+            let n = subs.len();
+            t("Bdu")
+                | t("z").when(args == 0)
+                | t("o").when(args == 1)
+                | t("e").when(all_e && num_s == n)
+                | t("m").when(all_e && all_m && num_s + k >= n)
+                | t("s").when(num_s + k > n)
+                | timelocks
+        }
+        Fragment::Multi(_, _) => t("Budemsk"),
+        Fragment::Alt(x) => {
+            let x = x.ty;
+            t("W").when(x.has("B")) | (x & t("ghijk")) | (x & t("udfems")) | t("x")
+        }
+        Fragment::Swap(x) => {
+            let x = x.ty;
+            t("W").when(x.has("Bo")) | (x & t("ghijk")) | (x & t("udfemsx"))
+        }
+        Fragment::Check(x) => {
+            let x = x.ty;
+            t("B").when(x.has("K")) | (x & t("ghijk")) | (x & t("ondfem")) | t("us")
+        }
+        Fragment::DupIf(x) => {
+            let x = x.ty;
+            // d: is only u under tapscript, where MINIMALIF is consensus
+            t("B").when(x.has("Vz"))
+                | t("o").when(x.has("z"))
+                | t("e").when(x.has("f"))
+                | (x & t("ghijk"))
+                | (x & t("ms"))
+                | t("ndx")
+        }
+        Fragment::Verify(x) => {
+            let x = x.ty;
+            t("V").when(x.has("B")) | (x & t("ghijk")) | (x & t("zonms")) | t("fx")
+        }
+        Fragment::NonZero(x) => {
+            let x = x.ty;
+            t("B").when(x.has("Bn"))
+                | t("e").when(x.has("f"))
+                | (x & t("ghijk"))
+                | (x & t("oums"))
+                | t("ndx")
+        }
+        Fragment::ZeroNotEqual(x) => {
+            let x = x.ty;
+            (x & t("ghijk")) | (x & t("Bzondfems")) | t("ux")
+        }
+    }
+}
+
+fn num_size(n: usize) -> usize {
+    if n <= 16 {
+        1
+    } else {
+        1 + encode_num(n as i64).len()
+    }
+}
+
+fn add(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    Some(a? + b?)
+}
+
+fn max(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Miniscript {
+    pub node: Fragment,
+    pub ty: Type,
+}
+
+impl Miniscript {
+    pub fn new(node: Fragment) -> Result<Self> {
+        match &node {
+            Fragment::Older(n) | Fragment::After(n) if *n == 0 || *n >= 0x80000000 => {
+                return Err(anyhow!("Timelock {} is out of range", n));
+            }
+            Fragment::Sha256(h) | Fragment::Hash256(h) if h.len() != 32 => {
+                return Err(anyhow!("Hash must be 32 bytes"));
+            }
+            Fragment::Ripemd160(h) | Fragment::Hash160(h) if h.len() != 20 => {
+                return Err(anyhow!("Hash must be 20 bytes"));
+            }
+            Fragment::Thresh(k, subs) if *k == 0 || *k > subs.len() => {
+                return Err(anyhow!("Invalid threshold {} of {}", k, subs.len()));
+            }
+            Fragment::Multi(k, keys)
+                if *k == 0 || *k > keys.len() || keys.len() > MAX_PUBKEYS_PER_MULTISIG =>
+            {
+                return Err(anyhow!("Invalid multisig {} of {}", k, keys.len()));
+            }
+            _ => {}
+        }
+        let ty = compute_type(&node);
+        if !ty.is_valid() {
+            return Err(anyhow!("Miniscript fragment does not type check"));
+        }
+        Ok(Self { node, ty })
+    }
+
+    fn boxed(node: Fragment) -> Result<Box<Self>> {
+        Ok(Box::new(Self::new(node)?))
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let (wrappers, expr) = match (s.find(':'), s.find('(')) {
+            (Some(colon), Some(open)) if colon < open => (&s[..colon], &s[colon + 1..]),
+            (Some(colon), None) => (&s[..colon], &s[colon + 1..]),
+            _ => ("", s),
+        };
+        let mut ms = Self::parse_fragment(expr)?;
+        for wrapper in wrappers.chars().rev() {
+            let x = Box::new(ms);
+            let node = match wrapper {
+                'a' => Fragment::Alt(x),
+                's' => Fragment::Swap(x),
+                'c' => Fragment::Check(x),
+                'd' => Fragment::DupIf(x),
+                'v' => Fragment::Verify(x),
+                'j' => Fragment::NonZero(x),
+                'n' => Fragment::ZeroNotEqual(x),
+                't' => Fragment::AndV(x, Self::boxed(Fragment::True)?),
+                'l' => Fragment::OrI(Self::boxed(Fragment::False)?, x),
+                'u' => Fragment::OrI(x, Self::boxed(Fragment::False)?),
+                _ => return Err(anyhow!("Unknown wrapper {}:", wrapper)),
+            };
+            ms = Self::new(node)?;
+        }
+        Ok(ms)
+    }
+
+    fn parse_fragment(s: &str) -> Result<Self> {
+        match s {
+            "0" => return Self::new(Fragment::False),
+            "1" => return Self::new(Fragment::True),
+            _ => {}
+        }
+        let (name, inner) = split_function(s)?;
+        let args = split_args(inner)?;
+
+        let key = |i: usize| DescriptorKey::parse(args[i], Context::Wsh);
+        let sub = |i: usize| -> Result<Box<Self>> { Ok(Box::new(Self::parse(args[i])?)) };
+        let number = |i: usize| -> Result<u32> {
+            args[i]
+                .parse()
+                .map_err(|_| anyhow!("Invalid number {}", args[i]))
+        };
+        let arity = match name {
+            "pk_k" | "pk_h" | "pk" | "pkh" | "older" | "after" | "sha256" | "hash256"
+            | "ripemd160" | "hash160" => Some(1),
+            "and_v" | "and_b" | "and_n" | "or_b" | "or_c" | "or_d" | "or_i" => Some(2),
+            "andor" => Some(3),
+            _ => None,
+        };
+        if let Some(arity) = arity {
+            if args.len() != arity {
+                return Err(anyhow!("{}() takes {} arguments", name, arity));
+            }
+        }
+
+        let node = match name {
+            "pk_k" => Fragment::PkK(key(0)?),
+            "pk_h" => Fragment::PkH(key(0)?),
+            "pk" => Fragment::Check(Self::boxed(Fragment::PkK(key(0)?))?),
+            "pkh" => Fragment::Check(Self::boxed(Fragment::PkH(key(0)?))?),
+            "older" => Fragment::Older(number(0)?),
+            "after" => Fragment::After(number(0)?),
+            "sha256" => Fragment::Sha256(decode_hex(args[0])?),
+            "hash256" => Fragment::Hash256(decode_hex(args[0])?),
+            "ripemd160" => Fragment::Ripemd160(decode_hex(args[0])?),
+            "hash160" => Fragment::Hash160(decode_hex(args[0])?),
+            "andor" => Fragment::AndOr(sub(0)?, sub(1)?, sub(2)?),
+            "and_v" => Fragment::AndV(sub(0)?, sub(1)?),
+            "and_b" => Fragment::AndB(sub(0)?, sub(1)?),
+            "and_n" => Fragment::AndOr(sub(0)?, sub(1)?, Self::boxed(Fragment::False)?),
+            "or_b" => Fragment::OrB(sub(0)?, sub(1)?),
+            "or_c" => Fragment::OrC(sub(0)?, sub(1)?),
+            "or_d" => Fragment::OrD(sub(0)?, sub(1)?),
+            "or_i" => Fragment::OrI(sub(0)?, sub(1)?),
+            "thresh" | "multi" if args.len() < 2 => {
+                return Err(anyhow!("{}() needs a threshold and arguments", name))
+            }
+            "thresh" => Fragment::Thresh(
+                number(0)? as usize,
+                args[1..]
+                    .iter()
+                    .map(|s| Self::parse(s))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            "multi" => Fragment::Multi(
+                number(0)? as usize,
+                (1..args.len()).map(key).collect::<Result<Vec<_>>>()?,
+            ),
+            _ => return Err(anyhow!("Unknown miniscript fragment {}", name)),
+        };
+        Self::new(node)
+    }
+
+    fn subs(&self) -> Vec<&Miniscript> {
+        match &self.node {
+            Fragment::AndOr(x, y, z) => vec![x, y, z],
+            Fragment::AndV(x, y)
+            | Fragment::AndB(x, y)
+            | Fragment::OrB(x, y)
+            | Fragment::OrC(x, y)
+            | Fragment::OrD(x, y)
+            | Fragment::OrI(x, y) => vec![x, y],
+            Fragment::Thresh(_, subs) => subs.iter().collect(),
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::DupIf(x)
+            | Fragment::Verify(x)
+            | Fragment::NonZero(x)
+            | Fragment::ZeroNotEqual(x) => vec![x],
+            _ => vec![],
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&DescriptorKey> {
+        match &self.node {
+            Fragment::PkK(key) | Fragment::PkH(key) => vec![key],
+            Fragment::Multi(_, keys) => keys.iter().collect(),
+            _ => self.subs().into_iter().flat_map(|sub| sub.keys()).collect(),
+        }
+    }
+
+    // Rebuilds the expression with every key replaced by `f(key)`
+    pub fn translate_keys<F>(&self, f: &F) -> Result<Self>
+    where
+        F: Fn(&DescriptorKey) -> Result<DescriptorKey>,
+    {
+        let t = |x: &Miniscript| -> Result<Box<Miniscript>> { Ok(Box::new(x.translate_keys(f)?)) };
+        let node = match &self.node {
+            Fragment::PkK(key) => Fragment::PkK(f(key)?),
+            Fragment::PkH(key) => Fragment::PkH(f(key)?),
+            Fragment::Multi(k, keys) => {
+                Fragment::Multi(*k, keys.iter().map(f).collect::<Result<Vec<_>>>()?)
+            }
+            Fragment::AndOr(x, y, z) => Fragment::AndOr(t(x)?, t(y)?, t(z)?),
+            Fragment::AndV(x, y) => Fragment::AndV(t(x)?, t(y)?),
+            Fragment::AndB(x, y) => Fragment::AndB(t(x)?, t(y)?),
+            Fragment::OrB(x, y) => Fragment::OrB(t(x)?, t(y)?),
+            Fragment::OrC(x, y) => Fragment::OrC(t(x)?, t(y)?),
+            Fragment::OrD(x, y) => Fragment::OrD(t(x)?, t(y)?),
+            Fragment::OrI(x, y) => Fragment::OrI(t(x)?, t(y)?),
+            Fragment::Thresh(k, subs) => Fragment::Thresh(
+                *k,
+                subs.iter()
+                    .map(|sub| sub.translate_keys(f))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            Fragment::Alt(x) => Fragment::Alt(t(x)?),
+            Fragment::Swap(x) => Fragment::Swap(t(x)?),
+            Fragment::Check(x) => Fragment::Check(t(x)?),
+            Fragment::DupIf(x) => Fragment::DupIf(t(x)?),
+            Fragment::Verify(x) => Fragment::Verify(t(x)?),
+            Fragment::NonZero(x) => Fragment::NonZero(t(x)?),
+            Fragment::ZeroNotEqual(x) => Fragment::ZeroNotEqual(t(x)?),
+            node => node.clone(),
+        };
+        Ok(Self { node, ty: self.ty })
+    }
+
+    pub fn script_size(&self) -> usize {
+        let size = |x: &Miniscript| x.script_size();
+        match &self.node {
+            Fragment::False | Fragment::True => 1,
+            Fragment::PkK(_) => PUBKEY_SIZE,
+            Fragment::PkH(_) => 24,
+            Fragment::Older(n) | Fragment::After(n) => num_size(*n as usize) + 1,
+            Fragment::Sha256(_) | Fragment::Hash256(_) => 39,
+            Fragment::Ripemd160(_) | Fragment::Hash160(_) => 27,
+            Fragment::AndOr(x, y, z) => size(x) + size(y) + size(z) + 3,
+            Fragment::AndV(x, y) => size(x) + size(y),
+            Fragment::AndB(x, y) | Fragment::OrB(x, y) => size(x) + size(y) + 1,
+            Fragment::OrC(x, y) => size(x) + size(y) + 2,
+            Fragment::OrD(x, y) | Fragment::OrI(x, y) => size(x) + size(y) + 3,
+            Fragment::Thresh(k, subs) => {
+                subs.iter().map(size).sum::<usize>() + subs.len() - 1 + num_size(*k) + 1
+            }
+            Fragment::Multi(k, keys) => {
+                num_size(*k) + PUBKEY_SIZE * keys.len() + num_size(keys.len()) + 1
+            }
+            Fragment::Alt(x) => size(x) + 2,
+            Fragment::Swap(x) | Fragment::Check(x) | Fragment::ZeroNotEqual(x) => size(x) + 1,
+            Fragment::DupIf(x) => size(x) + 3,
+            Fragment::Verify(x) => size(x) + x.ty.has("x") as usize,
+            Fragment::NonZero(x) => size(x) + 4,
+        }
+    }
+
+    fn encode_cmds(&self, index: u32, cmds: &mut Vec<Cmd>) -> Result<()> {
+        let key_cmd = |key: &DescriptorKey| -> Result<Cmd> {
+            Ok(Cmd::Data(key.public_key(index)?.sec(true)))
+        };
+        let hash_cmds = |op: u8, h: &[u8], cmds: &mut Vec<Cmd>| {
+            cmds.extend(vec![
+                Cmd::Op(OP_SIZE),
+                Cmd::num(32),
+                Cmd::Op(OP_EQUALVERIFY),
+                Cmd::Op(op),
+                Cmd::Data(h.to_vec()),
+                Cmd::Op(OP_EQUAL),
+            ]);
+        };
+        match &self.node {
+            Fragment::False => cmds.push(Cmd::Op(OP_0)),
+            Fragment::True => cmds.push(Cmd::Op(OP_1)),
+            Fragment::PkK(key) => cmds.push(key_cmd(key)?),
+            Fragment::PkH(key) => cmds.extend(vec![
+                Cmd::Op(OP_DUP),
+                Cmd::Op(OP_HASH160),
+                Cmd::Data(key.public_key(index)?.hash160(true)),
+                Cmd::Op(OP_EQUALVERIFY),
+            ]),
+            Fragment::Older(n) => {
+                cmds.push(Cmd::num(*n as i64));
+                cmds.push(Cmd::Op(OP_CHECKSEQUENCEVERIFY));
+            }
+            Fragment::After(n) => {
+                cmds.push(Cmd::num(*n as i64));
+                cmds.push(Cmd::Op(OP_CHECKLOCKTIMEVERIFY));
+            }
+            Fragment::Sha256(h) => hash_cmds(OP_SHA256, h, cmds),
+            Fragment::Hash256(h) => hash_cmds(OP_HASH256, h, cmds),
+            Fragment::Ripemd160(h) => hash_cmds(OP_RIPEMD160, h, cmds),
+            Fragment::Hash160(h) => hash_cmds(OP_HASH160, h, cmds),
+            Fragment::AndOr(x, y, z) => {
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_NOTIF));
+                z.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ELSE));
+                y.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ENDIF));
+            }
+            Fragment::AndV(x, y) => {
+                x.encode_cmds(index, cmds)?;
+                y.encode_cmds(index, cmds)?;
+            }
+            Fragment::AndB(x, y) => {
+                x.encode_cmds(index, cmds)?;
+                y.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_BOOLAND));
+            }
+            Fragment::OrB(x, z) => {
+                x.encode_cmds(index, cmds)?;
+                z.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_BOOLOR));
+            }
+            Fragment::OrC(x, z) => {
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_NOTIF));
+                z.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ENDIF));
+            }
+            Fragment::OrD(x, z) => {
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_IFDUP));
+                cmds.push(Cmd::Op(OP_NOTIF));
+                z.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ENDIF));
+            }
+            Fragment::OrI(x, z) => {
+                cmds.push(Cmd::Op(OP_IF));
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ELSE));
+                z.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ENDIF));
+            }
+            Fragment::Thresh(k, subs) => {
+                for (i, sub) in subs.iter().enumerate() {
+                    sub.encode_cmds(index, cmds)?;
+                    if i > 0 {
+                        cmds.push(Cmd::Op(OP_ADD));
+                    }
+                }
+                cmds.push(Cmd::num(*k as i64));
+                cmds.push(Cmd::Op(OP_EQUAL));
+            }
+            Fragment::Multi(k, keys) => {
+                cmds.push(Cmd::num(*k as i64));
+                for key in keys {
+                    cmds.push(key_cmd(key)?);
+                }
+                cmds.push(Cmd::num(keys.len() as i64));
+                cmds.push(Cmd::Op(OP_CHECKMULTISIG));
+            }
+            Fragment::Alt(x) => {
+                cmds.push(Cmd::Op(OP_TOALTSTACK));
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_FROMALTSTACK));
+            }
+            Fragment::Swap(x) => {
+                cmds.push(Cmd::Op(OP_SWAP));
+                x.encode_cmds(index, cmds)?;
+            }
+            Fragment::Check(x) => {
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_CHECKSIG));
+            }
+            Fragment::DupIf(x) => {
+                cmds.push(Cmd::Op(OP_DUP));
+                cmds.push(Cmd::Op(OP_IF));
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ENDIF));
+            }
+            Fragment::Verify(x) => {
+                x.encode_cmds(index, cmds)?;
+                // fold into the VERIFY form of the last opcode where one exists
+                let verify = match cmds.last() {
+                    Some(Cmd::Op(OP_CHECKSIG)) => Some(OP_CHECKSIGVERIFY),
+                    Some(Cmd::Op(OP_CHECKMULTISIG)) => Some(OP_CHECKMULTISIGVERIFY),
+                    Some(Cmd::Op(OP_EQUAL)) => Some(OP_EQUALVERIFY),
+                    Some(Cmd::Op(OP_NUMEQUAL)) => Some(OP_NUMEQUALVERIFY),
+                    _ => None,
+                };
+                match verify {
+                    Some(op) if !x.ty.has("x") => *cmds.last_mut().unwrap() = Cmd::Op(op),
+                    _ => cmds.push(Cmd::Op(OP_VERIFY)),
+                }
+            }
+            Fragment::NonZero(x) => {
+                cmds.push(Cmd::Op(OP_SIZE));
+                cmds.push(Cmd::Op(OP_0NOTEQUAL));
+                cmds.push(Cmd::Op(OP_IF));
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_ENDIF));
+            }
+            Fragment::ZeroNotEqual(x) => {
+                x.encode_cmds(index, cmds)?;
+                cmds.push(Cmd::Op(OP_0NOTEQUAL));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, index: u32) -> Result<Script> {
+        let mut cmds = vec![];
+        self.encode_cmds(index, &mut cmds)?;
+        Ok(Script::from_cmds(&cmds))
+    }
+
+    // Largest witness (satisfaction, dissatisfaction) in bytes, counting the
+    // length prefix of every element but not the witness script itself.
+    fn max_sizes(&self) -> (Option<usize>, Option<usize>) {
+        match &self.node {
+            Fragment::False => (None, Some(0)),
+            Fragment::True => (Some(0), None),
+            Fragment::PkK(_) => (Some(SIGNATURE_SIZE), Some(1)),
+            Fragment::PkH(_) => (Some(SIGNATURE_SIZE + PUBKEY_SIZE), Some(1 + PUBKEY_SIZE)),
+            Fragment::Older(_) | Fragment::After(_) => (Some(0), None),
+            Fragment::Sha256(_)
+            | Fragment::Hash256(_)
+            | Fragment::Ripemd160(_)
+            | Fragment::Hash160(_) => (Some(PREIMAGE_SIZE), Some(PREIMAGE_SIZE)),
+            Fragment::AndOr(x, y, z) => {
+                let ((xs, xd), (ys, _), (zs, zd)) = (x.max_sizes(), y.max_sizes(), z.max_sizes());
+                (max(add(xs, ys), add(xd, zs)), add(xd, zd))
+            }
+            Fragment::AndV(x, y) => {
+                let ((xs, _), (ys, yd)) = (x.max_sizes(), y.max_sizes());
+                (add(xs, ys), add(xs, yd))
+            }
+            Fragment::AndB(x, y) => {
+                let ((xs, xd), (ys, yd)) = (x.max_sizes(), y.max_sizes());
+                (add(xs, ys), add(xd, yd))
+            }
+            Fragment::OrB(x, z) => {
+                let ((xs, xd), (zs, zd)) = (x.max_sizes(), z.max_sizes());
+                (max(add(xs, zd), add(xd, zs)), add(xd, zd))
+            }
+            Fragment::OrC(x, z) => {
+                let ((xs, xd), (zs, _)) = (x.max_sizes(), z.max_sizes());
+                (max(xs, add(xd, zs)), None)
+            }
+            Fragment::OrD(x, z) => {
+                let ((xs, xd), (zs, zd)) = (x.max_sizes(), z.max_sizes());
+                (max(xs, add(xd, zs)), add(xd, zd))
+            }
+            Fragment::OrI(x, z) => {
+                let ((xs, xd), (zs, zd)) = (x.max_sizes(), z.max_sizes());
+                (
+                    max(add(xs, Some(2)), add(zs, Some(1))),
+                    max(add(xd, Some(2)), add(zd, Some(1))),
+                )
+            }
+            Fragment::Thresh(k, subs) => {
+                // largest[j]: the largest witness satisfying exactly j subs
+                let mut largest = vec![Some(0)];
+                for sub in subs {
+                    let (s, d) = sub.max_sizes();
+                    let mut next = vec![add(largest[0], d)];
+                    for j in 1..largest.len() {
+                        next.push(max(add(largest[j], d), add(largest[j - 1], s)));
+                    }
+                    next.push(add(*largest.last().unwrap(), s));
+                    largest = next;
+                }
+                (largest[*k], largest[0])
+            }
+            Fragment::Multi(k, _) => (Some(1 + SIGNATURE_SIZE * k), Some(1 + k)),
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::ZeroNotEqual(x) => x.max_sizes(),
+            Fragment::DupIf(x) => (add(x.max_sizes().0, Some(2)), Some(1)),
+            Fragment::Verify(x) => (x.max_sizes().0, None),
+            Fragment::NonZero(x) => (x.max_sizes().0, Some(1)),
+        }
+    }
+
+    pub fn max_satisfaction_size(&self) -> Option<usize> {
+        self.max_sizes().0
+    }
+
+    fn has_duplicate_keys(&self) -> bool {
+        let mut seen = HashSet::new();
+        self.keys().iter().any(|key| !seen.insert(key.to_string()))
+    }
+
+    // A script that is valid at the top level, can only be satisfied
+    // non-malleably, always needs a signature and does not mix timelock kinds.
+    pub fn is_sane(&self) -> bool {
+        self.ty.has("Bmsk")
+            && !self.has_duplicate_keys()
+            && self.script_size() <= MAX_STANDARD_P2WSH_SCRIPT_SIZE
+    }
+
+    pub fn lift(&self) -> Policy {
+        let lift = |x: &Miniscript| x.lift();
+        match &self.node {
+            Fragment::False => Policy::Unsatisfiable,
+            Fragment::True => Policy::Trivial,
+            Fragment::PkK(key) | Fragment::PkH(key) => Policy::Key(key.clone()),
+            Fragment::Older(n) => Policy::Older(*n),
+            Fragment::After(n) => Policy::After(*n),
+            Fragment::Sha256(h) => Policy::Sha256(h.clone()),
+            Fragment::Hash256(h) => Policy::Hash256(h.clone()),
+            Fragment::Ripemd160(h) => Policy::Ripemd160(h.clone()),
+            Fragment::Hash160(h) => Policy::Hash160(h.clone()),
+            Fragment::AndOr(x, y, z) => {
+                Policy::Or(vec![(1, Policy::And(vec![lift(x), lift(y)])), (1, lift(z))])
+            }
+            Fragment::AndV(x, y) | Fragment::AndB(x, y) => Policy::And(vec![lift(x), lift(y)]),
+            Fragment::OrB(x, z)
+            | Fragment::OrC(x, z)
+            | Fragment::OrD(x, z)
+            | Fragment::OrI(x, z) => Policy::Or(vec![(1, lift(x)), (1, lift(z))]),
+            Fragment::Thresh(k, subs) => Policy::Thresh(*k, subs.iter().map(lift).collect()),
+            Fragment::Multi(k, keys) => Policy::Thresh(
+                *k,
+                keys.iter().map(|key| Policy::Key(key.clone())).collect(),
+            ),
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::DupIf(x)
+            | Fragment::Verify(x)
+            | Fragment::NonZero(x)
+            | Fragment::ZeroNotEqual(x) => lift(x),
+        }
+    }
+
+    fn produce(&self, satisfier: &Satisfier, index: u32) -> Result<(InputStack, InputStack)> {
+        let (zero, one) = (InputStack::push(vec![]), InputStack::push(vec![1]));
+        let invalid = InputStack::invalid;
+        let empty = InputStack::empty;
+        Ok(match &self.node {
+            Fragment::False => (empty(), invalid()),
+            Fragment::True => (invalid(), empty()),
+            Fragment::PkK(key) => {
+                let sec = key.public_key(index)?.sec(true);
+                (zero, InputStack::signature(satisfier.signatures.get(&sec)))
+            }
+            Fragment::PkH(key) => {
+                let sec = key.public_key(index)?.sec(true);
+                let key = InputStack::push(sec.clone());
+                (
+                    zero + key.clone(),
+                    InputStack::signature(satisfier.signatures.get(&sec)) + key,
+                )
+            }
+            Fragment::Older(n) => match satisfier.check_older(*n) {
+                true => (invalid(), empty()),
+                false => (invalid(), invalid()),
+            },
+            Fragment::After(n) => match satisfier.check_after(*n) {
+                true => (invalid(), empty()),
+                false => (invalid(), invalid()),
+            },
+            Fragment::Sha256(h)
+            | Fragment::Hash256(h)
+            | Fragment::Ripemd160(h)
+            | Fragment::Hash160(h) => {
+                let preimage = match satisfier.preimages.get(h) {
+                    Some(preimage) => InputStack::push(preimage.clone()),
+                    None => invalid(),
+                };
+                // any other 32 bytes dissatisfy, so a third party can swap them
+                (InputStack::push(vec![0; 32]).malleable(true), preimage)
+            }
+            Fragment::AndOr(x, y, z) => {
+                let ((xd, xs), (yd, ys), (zd, zs)) = (
+                    x.produce(satisfier, index)?,
+                    y.produce(satisfier, index)?,
+                    z.produce(satisfier, index)?,
+                );
+                (
+                    (zd + xd.clone()) | (yd + xs.clone()).malleable(true).non_canon(),
+                    (ys + xs) | (zs + xd),
+                )
+            }
+            Fragment::AndV(x, y) => {
+                let ((_, xs), (yd, ys)) =
+                    (x.produce(satisfier, index)?, y.produce(satisfier, index)?);
+                ((yd + xs.clone()).non_canon(), ys + xs)
+            }
+            Fragment::AndB(x, y) => {
+                let ((xd, xs), (yd, ys)) =
+                    (x.produce(satisfier, index)?, y.produce(satisfier, index)?);
+                (
+                    (yd.clone() + xd.clone())
+                        | (ys.clone() + xd).malleable(true).non_canon()
+                        | (yd + xs.clone()).malleable(true).non_canon(),
+                    ys + xs,
+                )
+            }
+            Fragment::OrB(x, z) => {
+                let ((xd, xs), (zd, zs)) =
+                    (x.produce(satisfier, index)?, z.produce(satisfier, index)?);
+                (
+                    zd.clone() + xd.clone(),
+                    (zd + xs.clone()) | (zs.clone() + xd) | (zs + xs).malleable(true).non_canon(),
+                )
+            }
+            Fragment::OrC(x, z) => {
+                let ((xd, xs), (_, zs)) =
+                    (x.produce(satisfier, index)?, z.produce(satisfier, index)?);
+                (invalid(), xs | (zs + xd))
+            }
+            Fragment::OrD(x, z) => {
+                let ((xd, xs), (zd, zs)) =
+                    (x.produce(satisfier, index)?, z.produce(satisfier, index)?);
+                (zd + xd.clone(), xs | (zs + xd))
+            }
+            Fragment::OrI(x, z) => {
+                let ((xd, xs), (zd, zs)) =
+                    (x.produce(satisfier, index)?, z.produce(satisfier, index)?);
+                (
+                    (xd + one.clone()) | (zd + zero.clone()),
+                    (xs + one) | (zs + zero),
+                )
+            }
+            Fragment::Thresh(k, subs) => {
+                // sats[j]: the best way to satisfy exactly j of the subs seen so
+                // far. Later subs are consumed first so they go deeper.
+                let mut sats = vec![empty()];
+                for sub in subs.iter().rev() {
+                    let (d, s) = sub.produce(satisfier, index)?;
+                    let mut next = vec![sats[0].clone() + d.clone()];
+                    for j in 1..sats.len() {
+                        next.push(
+                            (sats[j].clone() + d.clone()) | (sats[j - 1].clone() + s.clone()),
+                        );
+                    }
+                    next.push(sats.last().unwrap().clone() + s);
+                    sats = next;
+                }
+                let mut dissat = invalid();
+                for (j, stack) in sats.iter().enumerate() {
+                    if j != *k {
+                        let stack = if j == 0 {
+                            stack.clone()
+                        } else {
+                            stack.clone().malleable(true).non_canon()
+                        };
+                        dissat = dissat | stack;
+                    }
+                }
+                (dissat, sats[*k].clone())
+            }
+            Fragment::Multi(k, keys) => {
+                let mut sats = vec![zero.clone()];
+                for key in keys {
+                    let sec = key.public_key(index)?.sec(true);
+                    let sig = InputStack::signature(satisfier.signatures.get(&sec));
+                    let mut next = vec![sats[0].clone()];
+                    for j in 1..sats.len() {
+                        next.push(sats[j].clone() | (sats[j - 1].clone() + sig.clone()));
+                    }
+                    next.push(sats.last().unwrap().clone() + sig);
+                    sats = next;
+                }
+                let mut dissat = zero.clone();
+                for _ in 0..*k {
+                    dissat = dissat + zero.clone();
+                }
+                (dissat, sats[*k].clone())
+            }
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::ZeroNotEqual(x) => x.produce(satisfier, index)?,
+            Fragment::DupIf(x) => (zero, x.produce(satisfier, index)?.1 + one),
+            Fragment::Verify(x) => (invalid(), x.produce(satisfier, index)?.1),
+            Fragment::NonZero(x) => (zero, x.produce(satisfier, index)?.1),
+        })
+    }
+
+    // The witness stack, bottom first, that satisfies the script with the
+    // given signatures, preimages and timelocks. Satisfactions that a third
+    // party could alter are refused.
+    pub fn satisfy(&self, satisfier: &Satisfier, index: u32) -> Result<Vec<Vec<u8>>> {
+        let (_, sat) = self.produce(satisfier, index)?;
+        if !sat.available {
+            return Err(anyhow!("Not enough signatures, preimages or timelocks"));
+        }
+        if sat.malleable {
+            return Err(anyhow!("Only malleable satisfactions are available"));
+        }
+        Ok(sat.stack)
+    }
+}
+
+impl fmt::Display for Miniscript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut wrappers = String::new();
+        let mut node = self;
+        loop {
+            let (letter, inner) = match &node.node {
+                Fragment::Alt(x) => ('a', x),
+                Fragment::Swap(x) => ('s', x),
+                Fragment::Check(x) if !matches!(x.node, Fragment::PkK(_) | Fragment::PkH(_)) => {
+                    ('c', x)
+                }
+                Fragment::DupIf(x) => ('d', x),
+                Fragment::Verify(x) => ('v', x),
+                Fragment::NonZero(x) => ('j', x),
+                Fragment::ZeroNotEqual(x) => ('n', x),
+                Fragment::AndV(x, y) if y.node == Fragment::True => ('t', x),
+                Fragment::OrI(x, z) if x.node == Fragment::False => ('l', z),
+                Fragment::OrI(x, z) if z.node == Fragment::False => ('u', x),
+                _ => break,
+            };
+            wrappers.push(letter);
+            node = inner;
+        }
+        if !wrappers.is_empty() {
+            write!(f, "{}:", wrappers)?;
+        }
+
+        let list = |f: &mut fmt::Formatter, name: &str, subs: &[&Miniscript]| {
+            write!(f, "{}(", name)?;
+            for (i, sub) in subs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{}", sub)?;
+            }
+            write!(f, ")")
+        };
+        match &node.node {
+            Fragment::False => write!(f, "0"),
+            Fragment::True => write!(f, "1"),
+            Fragment::Check(x) => match &x.node {
+                Fragment::PkK(key) => write!(f, "pk({})", key),
+                Fragment::PkH(key) => write!(f, "pkh({})", key),
+                _ => unreachable!(),
+            },
+            Fragment::PkK(key) => write!(f, "pk_k({})", key),
+            Fragment::PkH(key) => write!(f, "pk_h({})", key),
+            Fragment::Older(n) => write!(f, "older({})", n),
+            Fragment::After(n) => write!(f, "after({})", n),
+            Fragment::Sha256(h) => write!(f, "sha256({})", encode_hex(h)),
+            Fragment::Hash256(h) => write!(f, "hash256({})", encode_hex(h)),
+            Fragment::Ripemd160(h) => write!(f, "ripemd160({})", encode_hex(h)),
+            Fragment::Hash160(h) => write!(f, "hash160({})", encode_hex(h)),
+            Fragment::AndOr(x, y, z) if z.node == Fragment::False => list(f, "and_n", &[x, y]),
+            Fragment::AndOr(x, y, z) => list(f, "andor", &[x, y, z]),
+            Fragment::AndV(x, y) => list(f, "and_v", &[x, y]),
+            Fragment::AndB(x, y) => list(f, "and_b", &[x, y]),
+            Fragment::OrB(x, z) => list(f, "or_b", &[x, z]),
+            Fragment::OrC(x, z) => list(f, "or_c", &[x, z]),
+            Fragment::OrD(x, z) => list(f, "or_d", &[x, z]),
+            Fragment::OrI(x, z) => list(f, "or_i", &[x, z]),
+            Fragment::Thresh(k, subs) => {
+                write!(f, "thresh({}", k)?;
+                for sub in subs {
+                    write!(f, ",{}", sub)?;
+                }
+                write!(f, ")")
+            }
+            Fragment::Multi(k, keys) => {
+                write!(f, "multi({}", k)?;
+                for key in keys {
+                    write!(f, ",{}", key)?;
+                }
+                write!(f, ")")
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl FromStr for Miniscript {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+// What is available to satisfy a script: signatures by serialized public key,
+// preimages by hash, and the spending input's nSequence and the transaction's
+// nLockTime.
+#[derive(Debug, Clone, Default)]
+pub struct Satisfier {
+    pub signatures: HashMap<Vec<u8>, Vec<u8>>,
+    pub preimages: HashMap<Vec<u8>, Vec<u8>>,
+    pub sequence: Option<u32>,
+    pub lock_time: Option<u32>,
+}
+
+impl Satisfier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_signature(&mut self, sec: Vec<u8>, signature: Vec<u8>) {
+        self.signatures.insert(sec, signature);
+    }
+
+    pub fn add_preimage(&mut self, preimage: Vec<u8>) {
+        for hash in [
+            sha256(&preimage),
+            hash256(&preimage),
+            ripemd160(&preimage),
+            hash160(&preimage),
+        ] {
+            self.preimages.insert(hash, preimage.clone());
+        }
+    }
+
+    // BIP68/112: same kind of lock and at least as long
+    fn check_older(&self, n: u32) -> bool {
+        let sequence = match self.sequence {
+            Some(sequence) => sequence,
+            None => return false,
+        };
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return false;
+        }
+        let mask = SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK;
+        let (sequence, n) = (sequence & mask, n & mask);
+        (sequence < SEQUENCE_LOCKTIME_TYPE_FLAG) == (n < SEQUENCE_LOCKTIME_TYPE_FLAG)
+            && sequence >= n
+    }
+
+    // BIP65: same kind of lock and at least as late
+    fn check_after(&self, n: u32) -> bool {
+        match self.lock_time {
+            Some(lock_time) => {
+                (lock_time < LOCKTIME_THRESHOLD) == (n < LOCKTIME_THRESHOLD) && lock_time >= n
+            }
+            None => false,
+        }
+    }
+}
+
+// A candidate witness with the properties needed to pick between
+// alternatives without introducing malleability.
+#[derive(Debug, Clone)]
+struct InputStack {
+    available: bool,
+    has_sig: bool,
+    malleable: bool,
+    non_canon: bool,
+    size: usize,
+    stack: Vec<Vec<u8>>,
+}
+
+impl InputStack {
+    fn empty() -> Self {
+        Self {
+            available: true,
+            has_sig: false,
+            malleable: false,
+            non_canon: false,
+            size: 0,
+            stack: vec![],
+        }
+    }
+
+    fn invalid() -> Self {
+        Self {
+            available: false,
+            ..Self::empty()
+        }
+    }
+
+    fn push(element: Vec<u8>) -> Self {
+        Self {
+            size: element.len() + 1,
+            stack: vec![element],
+            ..Self::empty()
+        }
+    }
+
+    fn signature(signature: Option<&Vec<u8>>) -> Self {
+        match signature {
+            Some(signature) => Self {
+                has_sig: true,
+                ..Self::push(signature.clone())
+            },
+            None => Self {
+                has_sig: true,
+                ..Self::invalid()
+            },
+        }
+    }
+
+    fn malleable(mut self, malleable: bool) -> Self {
+        self.malleable |= malleable;
+        self
+    }
+
+    fn non_canon(mut self) -> Self {
+        self.non_canon = true;
+        self
+    }
+}
+
+// Stacks `other` on top of `self`
+impl ops::Add for InputStack {
+    type Output = InputStack;
+
+    fn add(mut self, mut other: InputStack) -> InputStack {
+        self.available &= other.available;
+        self.has_sig |= other.has_sig;
+        self.malleable |= other.malleable;
+        self.non_canon |= other.non_canon;
+        self.size += other.size;
+        self.stack.append(&mut other.stack);
+        self
+    }
+}
+
+// Picks one of two alternative witnesses
+impl ops::BitOr for InputStack {
+    type Output = InputStack;
+
+    fn bitor(mut self, mut other: InputStack) -> InputStack {
+        if !self.available {
+            return other;
+        }
+        if !other.available {
+            return self;
+        }
+        // a third party could always swap a signed witness for an unsigned one
+        if !self.has_sig && other.has_sig {
+            return self;
+        }
+        if self.has_sig && !other.has_sig {
+            return other;
+        }
+        if !self.has_sig && !other.has_sig {
+            self.malleable = true;
+            other.malleable = true;
+        } else {
+            if other.malleable && !self.malleable {
+                return self;
+            }
+            if self.malleable && !other.malleable {
+                return other;
+            }
+        }
+        if self.non_canon != other.non_canon {
+            return if self.non_canon { other } else { self };
+        }
+        if self.size <= other.size {
+            self
+        } else {
+            other
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_key(n: u32) -> String {
+    use crate::s256::PrivateKey;
+
+    encode_hex(&PrivateKey::new(n.into()).unwrap().point.sec(true))
+}
+
+#[test]
+fn test_parse_and_encode() {
+    let (a, b, c) = (test_key(1), test_key(2), test_key(3));
+    let cases = [
+        (
+            format!("and_v(v:pk({}),older(12960))", a),
+            format!("21{}ad02a032b2", a),
+        ),
+        (
+            format!("or_d(pk({}),older(12960))", a),
+            format!("21{}ac736402a032b268", a),
+        ),
+        (
+            format!("multi(2,{},{},{})", a, b, c),
+            format!("5221{}21{}21{}53ae", a, b, c),
+        ),
+        (
+            format!("and_n(pkh({}),after(100))", a),
+            format!(
+                "76a914{}88ac6400670164b168",
+                encode_hex(&hash160(&decode_hex(&a).unwrap()))
+            ),
+        ),
+        (
+            format!("thresh(2,pk({}),s:pk({}),sln:older(144))", a, b),
+            format!("21{}ac7c21{}ac937c630067029000b29268935287", a, b),
+        ),
+        (
+            format!(
+                "andor(pk({}),sha256(9267d3dbed802941483f1afa2a6bc68de5f653128aca9bf1461c5d0a3ad36ed2),after(500001))",
+                a
+            ),
+            format!(
+                "21{}ac640321a107b16782012088a8209267d3dbed802941483f1afa2a6bc68de5f653128aca9bf1461c5d0a3ad36ed28768",
+                a
+            ),
+        ),
+    ];
+    for (s, script) in cases.iter() {
+        let ms: Miniscript = s.parse().unwrap();
+        assert_eq!(&ms.to_string(), s);
+        assert_eq!(&encode_hex(ms.encode(0).unwrap().as_bytes()), script);
+        assert_eq!(ms.script_size(), script.len() / 2, "{}", s);
+    }
+}
+
+#[test]
+fn test_type_check() {
+    let (a, b) = (test_key(1), test_key(2));
+    let valid = [
+        (format!("pk({})", a), "Bondu"),
+        (format!("v:pk({})", a), "Von"),
+        (format!("pk_k({})", a), "Kondu"),
+        ("older(144)".to_string(), "Bz"),
+        (format!("s:pk({})", a), "Wdu"),
+        (format!("or_b(pk({}),s:pk({}))", a, b), "Bdu"),
+        ("and_b(1,a:1)".to_string(), "Bu"),
+    ];
+    for (s, ty) in valid.iter() {
+        let ms: Miniscript = s.parse().unwrap();
+        assert!(ms.ty.has(ty), "{} is {}", s, ms.ty);
+    }
+
+    for s in &[
+        format!("pk_k({}", a),
+        format!("and_v(pk({}),pk({}))", a, b),
+        format!("or_b(pk({}),pk({}))", a, b),
+        format!("c:pk({})", a),
+        format!("or_d(older(1),pk({}))", a),
+        format!("thresh(3,pk({}),s:pk({}))", a, b),
+        "older(0)".to_string(),
+        "sha256(00)".to_string(),
+        format!("x:pk({})", a),
+    ] {
+        assert!(s.parse::<Miniscript>().is_err(), "{}", s);
+    }
+}
+
+#[test]
+fn test_sanity() {
+    let (a, b) = (test_key(1), test_key(2));
+    let sane: Miniscript = format!("and_v(v:pk({}),or_d(pk({}),older(12960)))", a, b)
+        .parse()
+        .unwrap();
+    assert!(sane.is_sane());
+    assert_eq!(sane.max_satisfaction_size(), Some(SIGNATURE_SIZE * 2));
+
+    // no signature needed
+    assert!(!"older(144)".parse::<Miniscript>().unwrap().is_sane());
+    // height and time locks that cannot both be met
+    let mixed: Miniscript = format!("and_v(v:pk({}),and_v(v:after(100),after(500000001)))", a)
+        .parse()
+        .unwrap();
+    assert!(!mixed.is_sane());
+    // the same key twice
+    let duplicate: Miniscript = format!("and_v(v:pk({}),pk({}))", a, a).parse().unwrap();
+    assert!(!duplicate.is_sane());
+    // a third party can satisfy the second branch
+    let malleable: Miniscript = format!(
+        "or_i(pk({}),sha256(9267d3dbed802941483f1afa2a6bc68de5f653128aca9bf1461c5d0a3ad36ed2))",
+        a
+    )
+    .parse()
+    .unwrap();
+    assert!(malleable.ty.has("m"));
+    assert!(!malleable.ty.has("s"));
+}
+
+#[test]
+fn test_satisfy() {
+    use crate::s256::PrivateKey;
+
+    let keys: Vec<Vec<u8>> = (1..=4)
+        .map(|n| PrivateKey::new(n.into()).unwrap().point.sec(true))
+        .collect();
+    let sig = |n: u8| vec![0x30, n, 0x01];
+    let ms: Miniscript = format!(
+        "and_v(v:pk({}),or_d(multi(2,{},{}),and_v(v:pkh({}),older(144))))",
+        encode_hex(&keys[0]),
+        encode_hex(&keys[1]),
+        encode_hex(&keys[2]),
+        encode_hex(&keys[3]),
+    )
+    .parse()
+    .unwrap();
+    assert!(ms.is_sane());
+
+    let mut satisfier = Satisfier::new();
+    satisfier.add_signature(keys[0].clone(), sig(0));
+    assert!(ms.satisfy(&satisfier, 0).is_err());
+
+    // the recovery branch once the timelock has passed
+    satisfier.add_signature(keys[3].clone(), sig(3));
+    satisfier.sequence = Some(144);
+    assert_eq!(
+        ms.satisfy(&satisfier, 0).unwrap(),
+        vec![sig(3), keys[3].clone(), vec![], vec![], vec![], sig(0)]
+    );
+    satisfier.sequence = Some(143);
+    assert!(ms.satisfy(&satisfier, 0).is_err());
+
+    // the multisig branch
+    satisfier.add_signature(keys[1].clone(), sig(1));
+    satisfier.add_signature(keys[2].clone(), sig(2));
+    assert_eq!(
+        ms.satisfy(&satisfier, 0).unwrap(),
+        vec![vec![], sig(1), sig(2), sig(0)]
+    );
+
+    // hash locks
+    let preimage = vec![0x42; 32];
+    let ms: Miniscript = format!(
+        "and_v(v:pk({}),sha256({}))",
+        encode_hex(&keys[0]),
+        encode_hex(&sha256(&preimage))
+    )
+    .parse()
+    .unwrap();
+    assert!(ms.satisfy(&satisfier, 0).is_err());
+    satisfier.add_preimage(preimage.clone());
+    assert_eq!(ms.satisfy(&satisfier, 0).unwrap(), vec![preimage, sig(0)]);
+}
+
+#[test]
+fn test_timelocks() {
+    let mut satisfier = Satisfier::new();
+    satisfier.sequence = Some(SEQUENCE_LOCKTIME_TYPE_FLAG | 10);
+    assert!(satisfier.check_older(SEQUENCE_LOCKTIME_TYPE_FLAG | 10));
+    assert!(!satisfier.check_older(10));
+    satisfier.sequence = Some(SEQUENCE_LOCKTIME_DISABLE_FLAG | 10);
+    assert!(!satisfier.check_older(1));
+
+    satisfier.lock_time = Some(700_000);
+    assert!(satisfier.check_after(700_000));
+    assert!(!satisfier.check_after(700_001));
+    assert!(!satisfier.check_after(LOCKTIME_THRESHOLD));
+}
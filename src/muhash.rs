@@ -0,0 +1,145 @@
+use num_bigint::BigUint;
+use once_cell::sync::Lazy;
+use std::convert::TryInto;
+
+use crate::helper::sha256;
+
+const NUM_BYTES: usize = 384;
+
+// 2^3072 - 1103717, the largest 3072-bit safe prime
+static PRIME: Lazy<BigUint> =
+    Lazy::new(|| (BigUint::from(1u32) << (NUM_BYTES * 8)) - BigUint::from(1103717u32));
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// ChaCha20 keystream with a zero nonce
+fn chacha20_keystream(key: &[u8], len: usize) -> Vec<u8> {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (i, word) in key.chunks(4).enumerate() {
+        initial[4 + i] = u32::from_le_bytes(word.try_into().unwrap());
+    }
+    let mut result = vec![];
+    let mut counter = 0u32;
+    while result.len() < len {
+        initial[12] = counter;
+        let mut state = initial;
+        for _ in 0..10 {
+            quarter_round(&mut state, 0, 4, 8, 12);
+            quarter_round(&mut state, 1, 5, 9, 13);
+            quarter_round(&mut state, 2, 6, 10, 14);
+            quarter_round(&mut state, 3, 7, 11, 15);
+            quarter_round(&mut state, 0, 5, 10, 15);
+            quarter_round(&mut state, 1, 6, 11, 12);
+            quarter_round(&mut state, 2, 7, 8, 13);
+            quarter_round(&mut state, 3, 4, 9, 14);
+        }
+        for (word, initial) in state.iter().zip(initial.iter()) {
+            result.extend_from_slice(&word.wrapping_add(*initial).to_le_bytes());
+        }
+        counter += 1;
+    }
+    result.truncate(len);
+    result
+}
+
+// Each element becomes a 3072-bit number through SHA256 and ChaCha20
+fn to_num(data: &[u8]) -> BigUint {
+    BigUint::from_bytes_le(&chacha20_keystream(&sha256(data), NUM_BYTES)) % &*PRIME
+}
+
+// A rolling hash of a set, as used by Bitcoin Core for the UTXO set.
+// Elements can be added and removed in any order, and equal sets always hash
+// the same.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MuHash {
+    numerator: BigUint,
+    denominator: BigUint,
+}
+
+impl MuHash {
+    pub fn new() -> Self {
+        Self {
+            numerator: BigUint::from(1u32),
+            denominator: BigUint::from(1u32),
+        }
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = &self.numerator * to_num(data) % &*PRIME;
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = &self.denominator * to_num(data) % &*PRIME;
+    }
+
+    // In display order, like the muhash of gettxoutsetinfo
+    pub fn finalize(&self) -> Vec<u8> {
+        let exponent = &*PRIME - BigUint::from(2u32);
+        let inverse = self.denominator.modpow(&exponent, &PRIME);
+        let mut bytes = (&self.numerator * inverse % &*PRIME).to_bytes_le();
+        bytes.resize(NUM_BYTES, 0);
+        let mut result = sha256(&bytes);
+        result.reverse();
+        result
+    }
+}
+
+impl Default for MuHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+use crate::helper::{decode_hex, encode_hex};
+
+#[cfg(test)]
+fn from_int(i: u8) -> Vec<u8> {
+    let mut result = vec![0u8; 32];
+    result[0] = i;
+    result
+}
+
+#[test]
+fn test_chacha20() {
+    // RFC 7539 appendix A.1, test vector 1
+    let key =
+        decode_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+    assert_eq!(
+        encode_hex(&chacha20_keystream(&key, 64)),
+        "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+    );
+}
+
+#[test]
+fn test_muhash() {
+    // Bitcoin Core's muhash_tests
+    let mut acc = MuHash::new();
+    acc.insert(&from_int(0));
+    acc.insert(&from_int(1));
+    acc.remove(&from_int(2));
+    assert_eq!(
+        encode_hex(&acc.finalize()),
+        "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+    );
+
+    // Order doesn't matter and removal cancels insertion
+    let mut other = MuHash::new();
+    other.remove(&from_int(2));
+    other.insert(&from_int(3));
+    other.insert(&from_int(1));
+    other.insert(&from_int(0));
+    other.remove(&from_int(3));
+    assert_eq!(other.finalize(), acc.finalize());
+    assert_ne!(MuHash::new().finalize(), acc.finalize());
+}
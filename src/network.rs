@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::helper::{decode_hex, hash256};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Network {
+    Mainnet,
+    Testnet3,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+// The coinbase shared by the mainnet, testnet3, signet and regtest genesis blocks.
+static GENESIS_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+static TESTNET4_GENESIS_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff5504ffff001d01044c4c30332f4d61792f323032342030303030303030303030303030303030303030303165626435386332343439373062336161396437383362623030313031316662653865613865393865303065ffffffff0100f2052a010000002321000000000000000000000000000000000000000000000000000000000000000000ac00000000";
+
+impl Network {
+    pub const ALL: [Network; 5] = [
+        Network::Mainnet,
+        Network::Testnet3,
+        Network::Testnet4,
+        Network::Signet,
+        Network::Regtest,
+    ];
+
+    pub fn is_mainnet(&self) -> bool {
+        self == &Network::Mainnet
+    }
+
+    pub fn p2pkh_prefix(&self) -> u8 {
+        if self.is_mainnet() {
+            0x00
+        } else {
+            0x6f
+        }
+    }
+
+    pub fn p2sh_prefix(&self) -> u8 {
+        if self.is_mainnet() {
+            0x05
+        } else {
+            0xc4
+        }
+    }
+
+    pub fn wif_prefix(&self) -> u8 {
+        if self.is_mainnet() {
+            0x80
+        } else {
+            0xef
+        }
+    }
+
+    pub fn bech32_hrp(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "bc",
+            Network::Testnet3 | Network::Testnet4 | Network::Signet => "tb",
+            Network::Regtest => "bcrt",
+        }
+    }
+
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet3 => [0x0b, 0x11, 0x09, 0x07],
+            Network::Testnet4 => [0x1c, 0x16, 0x3f, 0x28],
+            Network::Signet => [0x0a, 0x03, 0xcf, 0x40],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+
+    pub fn from_magic(magic: [u8; 4]) -> Result<Self> {
+        Network::ALL
+            .iter()
+            .find(|n| n.magic() == magic)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown network magic {:02x?}", magic))
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 8333,
+            Network::Testnet3 => 18333,
+            Network::Testnet4 => 48333,
+            Network::Signet => 38333,
+            Network::Regtest => 18444,
+        }
+    }
+
+    pub fn xprv_version(&self) -> [u8; 4] {
+        if self.is_mainnet() {
+            [0x04, 0x88, 0xad, 0xe4]
+        } else {
+            [0x04, 0x35, 0x83, 0x94]
+        }
+    }
+
+    pub fn xpub_version(&self) -> [u8; 4] {
+        if self.is_mainnet() {
+            [0x04, 0x88, 0xb2, 0x1e]
+        } else {
+            [0x04, 0x35, 0x87, 0xcf]
+        }
+    }
+
+    // The easiest target a header may have
+    pub fn pow_limit_bits(&self) -> u32 {
+        match self {
+            Network::Mainnet | Network::Testnet3 | Network::Testnet4 => 0x1d00ffff,
+            Network::Signet => 0x1e0377ae,
+            Network::Regtest => 0x207fffff,
+        }
+    }
+
+    // A block more than 20 minutes after its parent may use the pow limit
+    pub fn allows_min_difficulty_blocks(&self) -> bool {
+        matches!(
+            self,
+            Network::Testnet3 | Network::Testnet4 | Network::Regtest
+        )
+    }
+
+    pub fn no_retargeting(&self) -> bool {
+        self == &Network::Regtest
+    }
+
+    // BIP94: testnet4's timewarp fix and retarget base
+    pub fn enforces_bip94(&self) -> bool {
+        self == &Network::Testnet4
+    }
+
+    pub fn subsidy_halving_interval(&self) -> u32 {
+        match self {
+            Network::Regtest => 150,
+            _ => 210_000,
+        }
+    }
+
+    // Soft fork activation heights as (BIP34, BIP65, BIP66, CSV, segwit)
+    fn activation_heights(&self) -> (u32, u32, u32, u32, u32) {
+        match self {
+            Network::Mainnet => (227931, 388381, 363725, 419328, 481824),
+            Network::Testnet3 => (21111, 581885, 330776, 770112, 834624),
+            Network::Testnet4 | Network::Signet => (1, 1, 1, 1, 1),
+            Network::Regtest => (1, 1, 1, 1, 0),
+        }
+    }
+
+    pub fn bip34_height(&self) -> u32 {
+        self.activation_heights().0
+    }
+
+    pub fn bip65_height(&self) -> u32 {
+        self.activation_heights().1
+    }
+
+    pub fn bip66_height(&self) -> u32 {
+        self.activation_heights().2
+    }
+
+    pub fn csv_height(&self) -> u32 {
+        self.activation_heights().3
+    }
+
+    pub fn segwit_height(&self) -> u32 {
+        self.activation_heights().4
+    }
+
+    // (time, bits, nonce) of the genesis header
+    fn genesis_params(&self) -> (u32, u32, u32) {
+        match self {
+            Network::Mainnet => (1231006505, 0x1d00ffff, 2083236893),
+            Network::Testnet3 => (1296688602, 0x1d00ffff, 414098458),
+            Network::Testnet4 => (1714777860, 0x1d00ffff, 393743547),
+            Network::Signet => (1598918400, 0x1e0377ae, 52613770),
+            Network::Regtest => (1296688602, 0x207fffff, 2),
+        }
+    }
+
+    pub fn genesis_block(&self) -> Vec<u8> {
+        let coinbase = match self {
+            Network::Testnet4 => decode_hex(TESTNET4_GENESIS_COINBASE),
+            _ => decode_hex(GENESIS_COINBASE),
+        }
+        .unwrap();
+        let (time, bits, nonce) = self.genesis_params();
+
+        let mut result = 1u32.to_le_bytes().to_vec();
+        result.append(&mut vec![0; 32]);
+        result.append(&mut hash256(&coinbase));
+        result.append(&mut time.to_le_bytes().to_vec());
+        result.append(&mut bits.to_le_bytes().to_vec());
+        result.append(&mut nonce.to_le_bytes().to_vec());
+        result.push(0x01);
+        result.append(&mut coinbase.to_vec());
+        result
+    }
+
+    pub fn genesis_hash(&self) -> Vec<u8> {
+        let mut hash = hash256(&self.genesis_block()[..80]);
+        hash.reverse();
+        hash
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Network::Mainnet => "main",
+            Network::Testnet3 => "test",
+            Network::Testnet4 => "testnet4",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "main" | "mainnet" | "bitcoin" => Ok(Network::Mainnet),
+            "test" | "testnet" | "testnet3" => Ok(Network::Testnet3),
+            "testnet4" => Ok(Network::Testnet4),
+            "signet" => Ok(Network::Signet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(anyhow!("Unknown network {}", s)),
+        }
+    }
+}
+
+#[test]
+fn test_genesis_hash() {
+    use crate::helper::encode_hex;
+
+    let expected = [
+        "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+        "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043",
+        "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+        "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+    ];
+    for (network, hash) in Network::ALL.iter().zip(expected.iter()) {
+        assert_eq!(encode_hex(&network.genesis_hash()), hash.to_string());
+    }
+}
+
+#[test]
+fn test_network_name() {
+    for network in Network::ALL.iter() {
+        assert_eq!(&network.to_string().parse::<Network>().unwrap(), network);
+        assert_eq!(&Network::from_magic(network.magic()).unwrap(), network);
+    }
+    assert!("foo".parse::<Network>().is_err());
+}
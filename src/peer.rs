@@ -0,0 +1,550 @@
+use anyhow::{anyhow, Result};
+use futures_util::stream::{self, Stream};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::message::{
+    Message, NetworkEnvelope, VersionMessage, ENVELOPE_HEADER_SIZE, MAX_PAYLOAD_SIZE,
+};
+use crate::network::Network;
+
+// BIP130 sendheaders is the oldest feature a header-first client relies on
+pub const MIN_PEER_VERSION: u32 = 70012;
+
+// BIP339 wtxidrelay and BIP155 sendaddrv2 are negotiated from this version
+const WTXID_RELAY_VERSION: u32 = 70016;
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+// The handshake and feature negotiation without any I/O. Every incoming
+// message yields the replies to send and possibly a message for the caller.
+#[derive(Debug, Clone)]
+pub struct PeerState {
+    pub network: Network,
+    pub version: VersionMessage,
+    pub peer_version: Option<VersionMessage>,
+    pub verack: bool,
+    pub wtxid_relay: bool,
+    pub addrv2: bool,
+    pub send_headers: bool,
+    pub fee_filter: u64,
+}
+
+impl PeerState {
+    pub fn new(network: Network, version: VersionMessage) -> Self {
+        Self {
+            network,
+            version,
+            peer_version: None,
+            verack: false,
+            wtxid_relay: false,
+            addrv2: false,
+            send_headers: false,
+            fee_filter: 0,
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.peer_version.is_some() && self.verack
+    }
+
+    fn common_version(&self) -> u32 {
+        match &self.peer_version {
+            Some(peer_version) => peer_version.version.min(self.version.version),
+            None => 0,
+        }
+    }
+
+    pub fn receive(&mut self, message: Message) -> Result<(Vec<Message>, Option<Message>)> {
+        match message {
+            Message::Version(version) => {
+                if self.peer_version.is_some() {
+                    return Err(anyhow!("Duplicate version message"));
+                }
+                if version.version < MIN_PEER_VERSION {
+                    return Err(anyhow!(
+                        "Peer protocol version {} is too old",
+                        version.version
+                    ));
+                }
+                if version.nonce == self.version.nonce {
+                    return Err(anyhow!("Connected to ourselves"));
+                }
+                self.peer_version = Some(version);
+                let mut replies = vec![];
+                if self.common_version() >= WTXID_RELAY_VERSION {
+                    replies.push(Message::WtxidRelay);
+                    replies.push(Message::SendAddrV2);
+                }
+                replies.push(Message::Verack);
+                Ok((replies, None))
+            }
+            _ if self.peer_version.is_none() => {
+                Err(anyhow!("Received {} before version", message.command()))
+            }
+            Message::Verack => {
+                if self.verack {
+                    return Err(anyhow!("Duplicate verack message"));
+                }
+                self.verack = true;
+                Ok((vec![], None))
+            }
+            Message::WtxidRelay | Message::SendAddrV2 => {
+                if self.verack {
+                    return Err(anyhow!("Received {} after verack", message.command()));
+                }
+                if message == Message::WtxidRelay {
+                    self.wtxid_relay = self.common_version() >= WTXID_RELAY_VERSION;
+                } else {
+                    self.addrv2 = true;
+                }
+                Ok((vec![], None))
+            }
+            // Bitcoin Core ignores anything else until the handshake is done
+            _ if !self.verack => Ok((vec![], None)),
+            Message::Ping(nonce) => Ok((vec![Message::Pong(nonce)], None)),
+            Message::SendHeaders => {
+                self.send_headers = true;
+                Ok((vec![], None))
+            }
+            Message::FeeFilter(fee_rate) => {
+                self.fee_filter = fee_rate;
+                Ok((vec![], None))
+            }
+            message => Ok((vec![], Some(message))),
+        }
+    }
+}
+
+pub async fn read_envelope<R>(reader: &mut R) -> Result<NetworkEnvelope>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut raw = vec![0u8; ENVELOPE_HEADER_SIZE];
+    reader.read_exact(&mut raw).await?;
+    let length = u32::from_le_bytes(raw[16..20].try_into().unwrap());
+    if length > MAX_PAYLOAD_SIZE {
+        return Err(anyhow!("Payload of {} bytes is too large", length));
+    }
+    raw.resize(ENVELOPE_HEADER_SIZE + length as usize, 0);
+    reader.read_exact(&mut raw[ENVELOPE_HEADER_SIZE..]).await?;
+    NetworkEnvelope::parse(&mut raw.as_slice())
+}
+
+fn check_network(envelope: &NetworkEnvelope, network: Network) -> Result<()> {
+    if envelope.network != network {
+        return Err(anyhow!(
+            "Peer is on {}, expected {}",
+            envelope.network,
+            network
+        ));
+    }
+    Ok(())
+}
+
+pub struct Peer<S> {
+    stream: S,
+    pub state: PeerState,
+    pub handshake_timeout: Duration,
+    pub idle_timeout: Duration,
+}
+
+impl<S> Peer<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S, network: Network, version: VersionMessage) -> Self {
+        Self {
+            stream,
+            state: PeerState::new(network, version),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<()> {
+        let raw = NetworkEnvelope::from_message(self.state.network, message).serialize();
+        self.stream.write_all(&raw).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read_next(&mut self) -> Result<Option<Message>> {
+        let envelope = timeout(self.idle_timeout, read_envelope(&mut self.stream))
+            .await
+            .map_err(|_| anyhow!("Peer timed out"))??;
+        check_network(&envelope, self.state.network)?;
+        let (replies, message) = self.state.receive(envelope.message()?)?;
+        for reply in &replies {
+            self.send(reply).await?;
+        }
+        Ok(message)
+    }
+
+    pub async fn handshake(&mut self) -> Result<()> {
+        self.send(&Message::Version(self.state.version.clone()))
+            .await?;
+        let limit = self.handshake_timeout;
+        timeout(limit, async {
+            while !self.state.is_established() {
+                self.read_next().await?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|_| anyhow!("Handshake timed out"))?
+    }
+
+    // Waits for the next message which isn't handled by the peer itself
+    pub async fn recv(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.read_next().await? {
+                return Ok(message);
+            }
+        }
+    }
+
+    // Incoming messages until the first error, which ends the stream
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message>> {
+        stream::unfold(Some(self), |peer| async move {
+            let mut peer = peer?;
+            match peer.recv().await {
+                Ok(message) => Some((Ok(message), Some(peer))),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
+
+// The same peer over a blocking stream. Reads can't be interrupted, so
+// idle timeouts are left to the stream, e.g. `TcpStream::set_read_timeout`.
+pub struct BlockingPeer<S> {
+    stream: S,
+    pub state: PeerState,
+    pub handshake_timeout: Duration,
+    closed: bool,
+}
+
+impl<S> BlockingPeer<S>
+where
+    S: Read + Write,
+{
+    pub fn new(stream: S, network: Network, version: VersionMessage) -> Self {
+        Self {
+            stream,
+            state: PeerState::new(network, version),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            closed: false,
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<()> {
+        let raw = NetworkEnvelope::from_message(self.state.network, message).serialize();
+        self.stream.write_all(&raw)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn read_next(&mut self) -> Result<Option<Message>> {
+        let envelope = NetworkEnvelope::parse(&mut self.stream)?;
+        check_network(&envelope, self.state.network)?;
+        let (replies, message) = self.state.receive(envelope.message()?)?;
+        for reply in &replies {
+            self.send(reply)?;
+        }
+        Ok(message)
+    }
+
+    pub fn handshake(&mut self) -> Result<()> {
+        self.send(&Message::Version(self.state.version.clone()))?;
+        let deadline = Instant::now() + self.handshake_timeout;
+        while !self.state.is_established() {
+            self.read_next()?;
+            if Instant::now() > deadline {
+                return Err(anyhow!("Handshake timed out"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn recv(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.read_next()? {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+impl<S> Iterator for BlockingPeer<S>
+where
+    S: Read + Write,
+{
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.closed {
+            return None;
+        }
+        let result = self.recv();
+        self.closed = result.is_err();
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+use crate::message::{Inventory, MSG_BLOCK, PROTOCOL_VERSION};
+
+// A version message from a peer speaking the given protocol version
+#[cfg(test)]
+fn test_version(version: u32, nonce: u64) -> VersionMessage {
+    let mut result = VersionMessage::new(0, nonce);
+    result.version = version;
+    result
+}
+
+// Plays the remote side of the handshake and returns what the client sent
+// back after our version
+#[cfg(test)]
+async fn fake_node_handshake<S>(node: &mut S, version: u32) -> Vec<Message>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let envelope = read_envelope(node).await.unwrap();
+    assert!(matches!(envelope.message().unwrap(), Message::Version(_)));
+    let mut messages = vec![Message::Version(test_version(version, 2))];
+    if version >= WTXID_RELAY_VERSION {
+        messages.push(Message::WtxidRelay);
+        messages.push(Message::SendAddrV2);
+    }
+    messages.push(Message::Verack);
+    fake_node_send(node, &messages).await;
+
+    let mut replies = vec![];
+    while replies.last() != Some(&Message::Verack) {
+        let envelope = read_envelope(node).await.unwrap();
+        replies.push(envelope.message().unwrap());
+    }
+    replies
+}
+
+#[cfg(test)]
+async fn fake_node_send<S>(node: &mut S, messages: &[Message])
+where
+    S: AsyncWrite + Unpin,
+{
+    for message in messages {
+        let raw = NetworkEnvelope::from_message(Network::Regtest, message).serialize();
+        node.write_all(&raw).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_handshake() {
+    let (client, mut node) = tokio::io::duplex(1 << 16);
+    let mut peer = Peer::new(client, Network::Regtest, VersionMessage::new(0, 1));
+    let (result, replies) = tokio::join!(
+        peer.handshake(),
+        fake_node_handshake(&mut node, PROTOCOL_VERSION)
+    );
+    result.unwrap();
+    assert_eq!(
+        replies,
+        vec![Message::WtxidRelay, Message::SendAddrV2, Message::Verack]
+    );
+    assert!(peer.state.is_established());
+    assert!(peer.state.wtxid_relay);
+    assert!(peer.state.addrv2);
+
+    // Pings are answered and feature messages recorded without reaching the
+    // caller
+    let header = Network::Regtest.genesis_block()[..80].to_vec();
+    let headers = Message::parse(
+        "headers",
+        &[&[1][..], &header, &[0]].concat(),
+        Network::Regtest,
+    )
+    .unwrap();
+    fake_node_send(
+        &mut node,
+        &[
+            Message::Ping(7),
+            Message::SendHeaders,
+            Message::FeeFilter(1000),
+            headers.clone(),
+        ],
+    )
+    .await;
+    assert_eq!(peer.recv().await.unwrap(), headers);
+    assert_eq!(
+        read_envelope(&mut node).await.unwrap().message().unwrap(),
+        Message::Pong(7)
+    );
+    assert!(peer.state.send_headers);
+    assert_eq!(peer.state.fee_filter, 1000);
+
+    let getdata = Message::GetData(vec![Inventory::new(MSG_BLOCK, vec![0u8; 32])]);
+    peer.send(&getdata).await.unwrap();
+    assert_eq!(
+        read_envelope(&mut node).await.unwrap().message().unwrap(),
+        getdata
+    );
+
+    // The stream ends after the node hangs up
+    use futures_util::StreamExt;
+    let inv = Message::Inv(vec![Inventory::new(MSG_BLOCK, vec![1u8; 32])]);
+    fake_node_send(&mut node, std::slice::from_ref(&inv)).await;
+    drop(node);
+    let messages: Vec<Result<Message>> = peer.into_stream().collect().await;
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].as_ref().unwrap(), &inv);
+    assert!(messages[1].is_err());
+}
+
+#[tokio::test]
+async fn test_handshake_old_peer() {
+    let (client, mut node) = tokio::io::duplex(1 << 16);
+    let mut peer = Peer::new(client, Network::Regtest, VersionMessage::new(0, 1));
+    let (result, replies) = tokio::join!(peer.handshake(), fake_node_handshake(&mut node, 70015));
+    result.unwrap();
+    assert_eq!(replies, vec![Message::Verack]);
+    assert!(!peer.state.wtxid_relay);
+    assert!(!peer.state.addrv2);
+}
+
+#[tokio::test]
+async fn test_timeouts() {
+    let (client, _node) = tokio::io::duplex(1 << 16);
+    let mut peer = Peer::new(client, Network::Regtest, VersionMessage::new(0, 1));
+    peer.handshake_timeout = Duration::from_millis(50);
+    assert_eq!(
+        peer.handshake().await.unwrap_err().to_string(),
+        "Handshake timed out"
+    );
+
+    let (client, mut node) = tokio::io::duplex(1 << 16);
+    let mut peer = Peer::new(client, Network::Regtest, VersionMessage::new(0, 1));
+    let (result, _) = tokio::join!(
+        peer.handshake(),
+        fake_node_handshake(&mut node, PROTOCOL_VERSION)
+    );
+    result.unwrap();
+    peer.idle_timeout = Duration::from_millis(50);
+    assert_eq!(peer.recv().await.unwrap_err().to_string(), "Peer timed out");
+}
+
+#[tokio::test]
+async fn test_wrong_network() {
+    let (client, mut node) = tokio::io::duplex(1 << 16);
+    let mut peer = Peer::new(client, Network::Mainnet, VersionMessage::new(0, 1));
+    let (result, _) = tokio::join!(peer.handshake(), async {
+        read_envelope(&mut node).await.unwrap();
+        fake_node_send(&mut node, &[Message::Version(VersionMessage::new(0, 2))]).await;
+    });
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Peer is on regtest, expected main"
+    );
+}
+
+#[test]
+fn test_peer_state() {
+    let mut state = PeerState::new(Network::Regtest, VersionMessage::new(0, 1));
+    assert_eq!(
+        state.receive(Message::Verack).unwrap_err().to_string(),
+        "Received verack before version"
+    );
+    assert!(state
+        .receive(Message::Version(test_version(70001, 2)))
+        .is_err());
+    assert_eq!(
+        state
+            .receive(Message::Version(VersionMessage::new(0, 1)))
+            .unwrap_err()
+            .to_string(),
+        "Connected to ourselves"
+    );
+
+    let (replies, _) = state
+        .receive(Message::Version(VersionMessage::new(0, 2)))
+        .unwrap();
+    assert_eq!(replies.last(), Some(&Message::Verack));
+    assert!(state
+        .receive(Message::Version(VersionMessage::new(0, 2)))
+        .is_err());
+    // Ignored until verack
+    assert_eq!(state.receive(Message::Ping(1)).unwrap(), (vec![], None));
+    state.receive(Message::Verack).unwrap();
+    assert!(state.is_established());
+    assert!(state.receive(Message::WtxidRelay).is_err());
+    assert!(state.receive(Message::Verack).is_err());
+    assert_eq!(
+        state.receive(Message::Ping(1)).unwrap(),
+        (vec![Message::Pong(1)], None)
+    );
+    assert_eq!(
+        state.receive(Message::Pong(1)).unwrap(),
+        (vec![], Some(Message::Pong(1)))
+    );
+}
+
+#[test]
+fn test_blocking_peer() {
+    use std::os::unix::net::UnixStream;
+
+    let (client, mut node) = UnixStream::pair().unwrap();
+    let handle = std::thread::spawn(move || {
+        let send = |node: &mut UnixStream, message: Message| {
+            let raw = NetworkEnvelope::from_message(Network::Regtest, &message).serialize();
+            node.write_all(&raw).unwrap();
+        };
+        let envelope = NetworkEnvelope::parse(&mut node).unwrap();
+        assert!(matches!(envelope.message().unwrap(), Message::Version(_)));
+        send(&mut node, Message::Version(VersionMessage::new(0, 2)));
+        send(&mut node, Message::WtxidRelay);
+        send(&mut node, Message::Verack);
+        let mut replies = vec![];
+        for _ in 0..3 {
+            replies.push(
+                NetworkEnvelope::parse(&mut node)
+                    .unwrap()
+                    .message()
+                    .unwrap(),
+            );
+        }
+        send(&mut node, Message::Ping(9));
+        send(&mut node, Message::FeeFilter(5));
+        send(&mut node, Message::Pong(3));
+        replies.push(
+            NetworkEnvelope::parse(&mut node)
+                .unwrap()
+                .message()
+                .unwrap(),
+        );
+        replies
+    });
+
+    let mut peer = BlockingPeer::new(client, Network::Regtest, VersionMessage::new(0, 1));
+    peer.handshake().unwrap();
+    assert!(peer.state.wtxid_relay);
+    assert!(!peer.state.addrv2);
+    assert_eq!(peer.next().unwrap().unwrap(), Message::Pong(3));
+    assert_eq!(peer.state.fee_filter, 5);
+
+    let replies = handle.join().unwrap();
+    assert_eq!(
+        replies,
+        vec![
+            Message::WtxidRelay,
+            Message::SendAddrV2,
+            Message::Verack,
+            Message::Pong(9)
+        ]
+    );
+    assert!(peer.next().unwrap().is_err());
+    assert!(peer.next().is_none());
+}
@@ -18,7 +18,7 @@ pub enum Point<F> {
     Actual(ActualPoint<F>),
 }
 
-impl<'a, F> Point<F> {
+impl<F> Point<F> {
     fn new(x: F, y: F) -> Point<F> {
         Point::Actual(ActualPoint { x, y })
     }
@@ -52,7 +52,7 @@ where
         Curve { a, b }
     }
 
-    pub fn point_from_field_element(&self, x: F, y: F) -> Result<CurvePoint<F>> {
+    pub fn point_from_field_element(&self, x: F, y: F) -> Result<CurvePoint<'_, F>> {
         if y.as_ref().pow(&2.into())
             != (&(&x.as_ref().pow(&3.into()) + &(self.a.as_ref() * x.as_ref())?)?
                 + self.b.as_ref())?
@@ -65,7 +65,7 @@ where
         })
     }
 
-    pub fn point<A, B>(&self, x: A, y: B) -> Result<CurvePoint<F>>
+    pub fn point<A, B>(&self, x: A, y: B) -> Result<CurvePoint<'_, F>>
     where
         A: Into<BigInt>,
         B: Into<BigInt>,
@@ -76,7 +76,7 @@ where
         )
     }
 
-    pub fn inf(&self) -> CurvePoint<F> {
+    pub fn inf(&self) -> CurvePoint<'_, F> {
         CurvePoint {
             c: self,
             p: Point::Inf,
@@ -90,7 +90,7 @@ pub struct CurvePoint<'a, F> {
     pub p: Point<F>,
 }
 
-impl<'a, 'b, 'c, F> ops::Add<&'c CurvePoint<'a, F>> for &'b CurvePoint<'a, F>
+impl<'a, 'c, F> ops::Add<&'c CurvePoint<'a, F>> for &CurvePoint<'a, F>
 where
     F: AsRef<FieldElement<'a>> + From<FieldElement<'a>> + Debug + PartialEq + Clone,
 {
@@ -0,0 +1,631 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::descriptor::{split_args, split_function, Context, DescriptorKey};
+use crate::helper::{decode_hex, encode_hex};
+use crate::interpreter::MAX_PUBKEYS_PER_MULTISIG;
+use crate::miniscript::{Fragment, Miniscript, Type};
+
+// Expected witness sizes used by the compiler, including length prefixes
+const SIGNATURE_COST: f64 = 74.0;
+const PUBKEY_COST: f64 = 34.0;
+const PREIMAGE_COST: f64 = 33.0;
+const INFINITE: f64 = f64::INFINITY;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Policy {
+    Unsatisfiable,
+    Trivial,
+    Key(DescriptorKey),
+    After(u32),
+    Older(u32),
+    Sha256(Vec<u8>),
+    Hash256(Vec<u8>),
+    Ripemd160(Vec<u8>),
+    Hash160(Vec<u8>),
+    And(Vec<Policy>),
+    // Branches with their relative probabilities of being used
+    Or(Vec<(usize, Policy)>),
+    Thresh(usize, Vec<Policy>),
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "UNSATISFIABLE" => return Ok(Policy::Unsatisfiable),
+            "TRIVIAL" => return Ok(Policy::Trivial),
+            _ => {}
+        }
+        let (name, inner) = split_function(s)?;
+        let args = split_args(inner)?;
+        let single = || {
+            if args.len() != 1 {
+                return Err(anyhow!("{}() takes a single argument", name));
+            }
+            Ok(args[0])
+        };
+        let timelock = |s: &str| -> Result<u32> {
+            match s.parse() {
+                Ok(n) if n > 0 && n < 0x80000000 => Ok(n),
+                _ => Err(anyhow!("Invalid timelock {}", s)),
+            }
+        };
+        let hash = |s: &str, len: usize| -> Result<Vec<u8>> {
+            let h = decode_hex(s)?;
+            if h.len() != len {
+                return Err(anyhow!("{}() takes a {} byte hash", name, len));
+            }
+            Ok(h)
+        };
+        let policy = match name {
+            "pk" => Policy::Key(DescriptorKey::parse(single()?, Context::Wsh)?),
+            "after" => Policy::After(timelock(single()?)?),
+            "older" => Policy::Older(timelock(single()?)?),
+            "sha256" => Policy::Sha256(hash(single()?, 32)?),
+            "hash256" => Policy::Hash256(hash(single()?, 32)?),
+            "ripemd160" => Policy::Ripemd160(hash(single()?, 20)?),
+            "hash160" => Policy::Hash160(hash(single()?, 20)?),
+            "and" if args.len() == 2 => Policy::And(
+                args.iter()
+                    .map(|s| Self::parse(s))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            "or" if args.len() == 2 => Policy::Or(
+                args.iter()
+                    .map(|s| Self::parse_weighted(s))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            "and" | "or" => return Err(anyhow!("{}() takes two arguments", name)),
+            "thresh" => {
+                if args.len() < 2 {
+                    return Err(anyhow!("thresh() needs a threshold and arguments"));
+                }
+                let k: usize = args[0]
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid threshold {}", args[0]))?;
+                if k == 0 || k > args.len() - 1 {
+                    return Err(anyhow!("Invalid threshold {} of {}", k, args.len() - 1));
+                }
+                Policy::Thresh(
+                    k,
+                    args[1..]
+                        .iter()
+                        .map(|s| Self::parse(s))
+                        .collect::<Result<Vec<_>>>()?,
+                )
+            }
+            _ => return Err(anyhow!("Unknown policy {}", name)),
+        };
+        Ok(policy)
+    }
+
+    fn parse_weighted(s: &str) -> Result<(usize, Self)> {
+        match s.find('@') {
+            Some(i) => {
+                let weight = s[..i]
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid weight {}", &s[..i]))?;
+                if weight == 0 {
+                    return Err(anyhow!("Weights must be positive"));
+                }
+                Ok((weight, Self::parse(&s[i + 1..])?))
+            }
+            None => Ok((1, Self::parse(s)?)),
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&DescriptorKey> {
+        match self {
+            Policy::Key(key) => vec![key],
+            Policy::And(subs) | Policy::Thresh(_, subs) => {
+                subs.iter().flat_map(|sub| sub.keys()).collect()
+            }
+            Policy::Or(subs) => subs.iter().flat_map(|(_, sub)| sub.keys()).collect(),
+            _ => vec![],
+        }
+    }
+
+    // The cheapest non-malleable miniscript, by script size plus the
+    // expected witness size given the branch weights
+    pub fn compile(&self) -> Result<Miniscript> {
+        let mut compiler = Compiler::default();
+        let candidates = compiler.compile(self, 1.0, 0.0);
+        let best = candidates
+            .into_iter()
+            .filter(|c| c.ms.ty.has("Bm"))
+            .min_by(|a, b| {
+                let rank = |c: &Candidate| (!c.ms.ty.has("s"), !c.ms.ty.has("k"));
+                rank(a)
+                    .cmp(&rank(b))
+                    .then(a.cost(1.0, 0.0).partial_cmp(&b.cost(1.0, 0.0)).unwrap())
+            })
+            .ok_or_else(|| anyhow!("{} has no non-malleable miniscript", self))?;
+        Ok(best.ms)
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Policy::Unsatisfiable => write!(f, "UNSATISFIABLE"),
+            Policy::Trivial => write!(f, "TRIVIAL"),
+            Policy::Key(key) => write!(f, "pk({})", key),
+            Policy::After(n) => write!(f, "after({})", n),
+            Policy::Older(n) => write!(f, "older({})", n),
+            Policy::Sha256(h) => write!(f, "sha256({})", encode_hex(h)),
+            Policy::Hash256(h) => write!(f, "hash256({})", encode_hex(h)),
+            Policy::Ripemd160(h) => write!(f, "ripemd160({})", encode_hex(h)),
+            Policy::Hash160(h) => write!(f, "hash160({})", encode_hex(h)),
+            Policy::And(subs) => {
+                write!(f, "and(")?;
+                for (i, sub) in subs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", sub)?;
+                }
+                write!(f, ")")
+            }
+            Policy::Or(subs) => {
+                write!(f, "or(")?;
+                for (i, (weight, sub)) in subs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    if *weight != 1 {
+                        write!(f, "{}@", weight)?;
+                    }
+                    write!(f, "{}", sub)?;
+                }
+                write!(f, ")")
+            }
+            Policy::Thresh(k, subs) => {
+                write!(f, "thresh({}", k)?;
+                for sub in subs {
+                    write!(f, ",{}", sub)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+// A compiled expression with its expected satisfaction and dissatisfaction
+// witness sizes
+#[derive(Debug, Clone)]
+struct Candidate {
+    ms: Miniscript,
+    size: usize,
+    sat: f64,
+    dissat: f64,
+}
+
+impl Candidate {
+    fn new(node: Fragment, sat: f64, dissat: f64) -> Option<Self> {
+        let ms = Miniscript::new(node).ok()?;
+        Some(Self {
+            size: ms.script_size(),
+            ms,
+            sat,
+            dissat,
+        })
+    }
+
+    fn cost(&self, sat_prob: f64, dissat_prob: f64) -> f64 {
+        let mut cost = self.size as f64;
+        if sat_prob > 0.0 {
+            cost += sat_prob * self.sat;
+        }
+        if dissat_prob > 0.0 {
+            cost += dissat_prob * self.dissat;
+        }
+        cost
+    }
+
+    fn boxed(&self) -> Box<Miniscript> {
+        Box::new(self.ms.clone())
+    }
+
+    fn wrappings(&self) -> Vec<Option<Candidate>> {
+        let (sat, dissat) = (self.sat, self.dissat);
+        let constant = |node| Box::new(Miniscript::new(node).unwrap());
+        vec![
+            Self::new(Fragment::Alt(self.boxed()), sat, dissat),
+            Self::new(Fragment::Swap(self.boxed()), sat, dissat),
+            Self::new(Fragment::Check(self.boxed()), sat, dissat),
+            Self::new(Fragment::DupIf(self.boxed()), sat + 2.0, 1.0),
+            Self::new(Fragment::Verify(self.boxed()), sat, INFINITE),
+            Self::new(Fragment::NonZero(self.boxed()), sat, 1.0),
+            Self::new(Fragment::ZeroNotEqual(self.boxed()), sat, dissat),
+            Self::new(
+                Fragment::AndV(self.boxed(), constant(Fragment::True)),
+                sat,
+                INFINITE,
+            ),
+            Self::new(
+                Fragment::OrI(constant(Fragment::False), self.boxed()),
+                sat + 1.0,
+                (dissat + 1.0).min(2.0),
+            ),
+            Self::new(
+                Fragment::OrI(self.boxed(), constant(Fragment::False)),
+                sat + 2.0,
+                (dissat + 2.0).min(1.0),
+            ),
+        ]
+    }
+}
+
+// The cheapest candidate found so far for each type, since an expression of
+// one type cannot stand in for another.
+struct Candidates {
+    best: BTreeMap<Type, Candidate>,
+    sat_prob: f64,
+    dissat_prob: f64,
+}
+
+impl Candidates {
+    fn new(sat_prob: f64, dissat_prob: f64) -> Self {
+        Self {
+            best: BTreeMap::new(),
+            sat_prob,
+            dissat_prob,
+        }
+    }
+
+    fn insert(&mut self, candidate: Option<Candidate>) -> bool {
+        let candidate = match candidate {
+            Some(candidate) => candidate,
+            None => return false,
+        };
+        let cost = candidate.cost(self.sat_prob, self.dissat_prob);
+        match self.best.get(&candidate.ms.ty) {
+            Some(existing) if existing.cost(self.sat_prob, self.dissat_prob) <= cost => false,
+            _ => {
+                self.best.insert(candidate.ms.ty, candidate);
+                true
+            }
+        }
+    }
+
+    fn add_wrappings(&mut self) {
+        for _ in 0..4 {
+            let current: Vec<Candidate> = self.best.values().cloned().collect();
+            let mut changed = false;
+            for candidate in current {
+                for wrapped in candidate.wrappings() {
+                    changed |= self.insert(wrapped);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    fn cheapest(
+        candidates: &[Candidate],
+        ty: &str,
+        sat_prob: f64,
+        dissat_prob: f64,
+    ) -> Option<Candidate> {
+        candidates
+            .iter()
+            .filter(|c| c.ms.ty.has(ty))
+            .min_by(|a, b| {
+                a.cost(sat_prob, dissat_prob)
+                    .partial_cmp(&b.cost(sat_prob, dissat_prob))
+                    .unwrap()
+            })
+            .cloned()
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    cache: HashMap<(String, u64, u64), Vec<Candidate>>,
+}
+
+impl Compiler {
+    fn compile(&mut self, policy: &Policy, sat_prob: f64, dissat_prob: f64) -> Vec<Candidate> {
+        let key = (
+            policy.to_string(),
+            sat_prob.to_bits(),
+            dissat_prob.to_bits(),
+        );
+        if let Some(result) = self.cache.get(&key) {
+            return result.clone();
+        }
+        let mut candidates = Candidates::new(sat_prob, dissat_prob);
+        match policy {
+            Policy::Unsatisfiable => {
+                candidates.insert(Candidate::new(Fragment::False, INFINITE, 0.0));
+            }
+            Policy::Trivial => {
+                candidates.insert(Candidate::new(Fragment::True, 0.0, INFINITE));
+            }
+            Policy::Key(key) => {
+                candidates.insert(Candidate::new(
+                    Fragment::PkK(key.clone()),
+                    SIGNATURE_COST,
+                    1.0,
+                ));
+                candidates.insert(Candidate::new(
+                    Fragment::PkH(key.clone()),
+                    SIGNATURE_COST + PUBKEY_COST,
+                    1.0 + PUBKEY_COST,
+                ));
+            }
+            Policy::After(n) => {
+                candidates.insert(Candidate::new(Fragment::After(*n), 0.0, INFINITE));
+            }
+            Policy::Older(n) => {
+                candidates.insert(Candidate::new(Fragment::Older(*n), 0.0, INFINITE));
+            }
+            Policy::Sha256(h) | Policy::Hash256(h) | Policy::Ripemd160(h) | Policy::Hash160(h) => {
+                let node = match policy {
+                    Policy::Sha256(_) => Fragment::Sha256(h.clone()),
+                    Policy::Hash256(_) => Fragment::Hash256(h.clone()),
+                    Policy::Ripemd160(_) => Fragment::Ripemd160(h.clone()),
+                    _ => Fragment::Hash160(h.clone()),
+                };
+                candidates.insert(Candidate::new(node, PREIMAGE_COST, PREIMAGE_COST));
+            }
+            Policy::And(subs) if subs.len() > 2 => {
+                let rest = Policy::And(subs[1..].to_vec());
+                let nested = Policy::And(vec![subs[0].clone(), rest]);
+                for candidate in self.compile(&nested, sat_prob, dissat_prob) {
+                    candidates.insert(Some(candidate));
+                }
+            }
+            Policy::And(subs) => {
+                let left = self.compile(&subs[0], sat_prob, dissat_prob);
+                let right = self.compile(&subs[1], sat_prob, dissat_prob);
+                for (xs, ys) in &[(&left, &right), (&right, &left)] {
+                    for x in xs.iter() {
+                        for y in ys.iter() {
+                            self.add_and(&mut candidates, x, y);
+                        }
+                    }
+                }
+            }
+            Policy::Or(subs) if subs.len() > 2 => {
+                let rest_weight = subs[1..].iter().map(|(w, _)| w).sum();
+                let rest = Policy::Or(subs[1..].to_vec());
+                let nested = Policy::Or(vec![subs[0].clone(), (rest_weight, rest)]);
+                for candidate in self.compile(&nested, sat_prob, dissat_prob) {
+                    candidates.insert(Some(candidate));
+                }
+            }
+            Policy::Or(subs) => {
+                let total = (subs[0].0 + subs[1].0) as f64;
+                let (l, r) = (subs[0].0 as f64 / total, subs[1].0 as f64 / total);
+                let left = self.compile(&subs[0].1, sat_prob * l, dissat_prob + sat_prob * r);
+                let right = self.compile(&subs[1].1, sat_prob * r, dissat_prob + sat_prob * l);
+                for (xs, zs, px, pz) in &[(&left, &right, l, r), (&right, &left, r, l)] {
+                    for x in xs.iter() {
+                        for z in zs.iter() {
+                            self.add_or(&mut candidates, x, z, *px, *pz);
+                        }
+                    }
+                }
+            }
+            Policy::Thresh(k, subs) => {
+                let n = subs.len();
+                if *k == n {
+                    for candidate in self.compile(&Policy::And(subs.clone()), sat_prob, dissat_prob)
+                    {
+                        candidates.insert(Some(candidate));
+                    }
+                }
+                if *k == 1 {
+                    let or = Policy::Or(subs.iter().map(|sub| (1, sub.clone())).collect());
+                    for candidate in self.compile(&or, sat_prob, dissat_prob) {
+                        candidates.insert(Some(candidate));
+                    }
+                }
+                let keys: Vec<DescriptorKey> = subs
+                    .iter()
+                    .filter_map(|sub| match sub {
+                        Policy::Key(key) => Some(key.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                if keys.len() == n && n <= MAX_PUBKEYS_PER_MULTISIG {
+                    candidates.insert(Candidate::new(
+                        Fragment::Multi(*k, keys),
+                        1.0 + SIGNATURE_COST * *k as f64,
+                        1.0 + *k as f64,
+                    ));
+                }
+                self.add_thresh(&mut candidates, *k, subs);
+            }
+        }
+        candidates.add_wrappings();
+        let result: Vec<Candidate> = candidates.best.into_values().collect();
+        self.cache.insert(key, result.clone());
+        result
+    }
+
+    fn add_and(&self, candidates: &mut Candidates, x: &Candidate, y: &Candidate) {
+        let sat = x.sat + y.sat;
+        if x.ms.ty.has("V") {
+            candidates.insert(Candidate::new(
+                Fragment::AndV(x.boxed(), y.boxed()),
+                sat,
+                INFINITE,
+            ));
+        }
+        if x.ms.ty.has("B") && y.ms.ty.has("W") {
+            candidates.insert(Candidate::new(
+                Fragment::AndB(x.boxed(), y.boxed()),
+                sat,
+                x.dissat + y.dissat,
+            ));
+        }
+        if x.ms.ty.has("Bdu") {
+            let zero = Box::new(Miniscript::new(Fragment::False).unwrap());
+            candidates.insert(Candidate::new(
+                Fragment::AndOr(x.boxed(), y.boxed(), zero),
+                sat,
+                x.dissat,
+            ));
+        }
+    }
+
+    fn add_or(&self, candidates: &mut Candidates, x: &Candidate, z: &Candidate, px: f64, pz: f64) {
+        let dissat = x.dissat + z.dissat;
+        if x.ms.ty.has("Bd") && z.ms.ty.has("Wd") {
+            candidates.insert(Candidate::new(
+                Fragment::OrB(x.boxed(), z.boxed()),
+                px * (x.sat + z.dissat) + pz * (x.dissat + z.sat),
+                dissat,
+            ));
+        }
+        if x.ms.ty.has("Bdu") {
+            let sat = px * x.sat + pz * (x.dissat + z.sat);
+            if z.ms.ty.has("B") {
+                candidates.insert(Candidate::new(
+                    Fragment::OrD(x.boxed(), z.boxed()),
+                    sat,
+                    dissat,
+                ));
+            }
+            if z.ms.ty.has("V") {
+                candidates.insert(Candidate::new(
+                    Fragment::OrC(x.boxed(), z.boxed()),
+                    sat,
+                    INFINITE,
+                ));
+            }
+        }
+        candidates.insert(Candidate::new(
+            Fragment::OrI(x.boxed(), z.boxed()),
+            px * (x.sat + 2.0) + pz * (z.sat + 1.0),
+            (x.dissat + 2.0).min(z.dissat + 1.0),
+        ));
+    }
+
+    // thresh() needs its first argument to be Bdu and the rest Wdu, so put
+    // first whichever argument is cheapest in that position
+    fn add_thresh(&mut self, candidates: &mut Candidates, k: usize, subs: &[Policy]) {
+        let n = subs.len();
+        let ratio = k as f64 / n as f64;
+        let (sat_prob, dissat_prob) = (
+            candidates.sat_prob * ratio,
+            candidates.dissat_prob + candidates.sat_prob * (1.0 - ratio),
+        );
+        let mut firsts = vec![];
+        let mut rests = vec![];
+        for sub in subs {
+            let compiled = self.compile(sub, sat_prob, dissat_prob);
+            let first = Candidates::cheapest(&compiled, "Bdu", sat_prob, dissat_prob);
+            let rest = Candidates::cheapest(&compiled, "Wdu", sat_prob, dissat_prob);
+            match (first, rest) {
+                (Some(first), Some(rest)) => {
+                    firsts.push(first);
+                    rests.push(rest);
+                }
+                _ => return,
+            }
+        }
+        let first = (0..n)
+            .min_by(|&a, &b| {
+                let saving = |i: usize| {
+                    firsts[i].cost(sat_prob, dissat_prob) - rests[i].cost(sat_prob, dissat_prob)
+                };
+                saving(a).partial_cmp(&saving(b)).unwrap()
+            })
+            .unwrap();
+        let mut args = vec![firsts[first].clone()];
+        args.extend(
+            rests
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| *i != first)
+                .map(|(_, c)| c.clone()),
+        );
+        let sat = args
+            .iter()
+            .map(|c| ratio * c.sat + (1.0 - ratio) * c.dissat)
+            .sum();
+        let dissat = args.iter().map(|c| c.dissat).sum();
+        candidates.insert(Candidate::new(
+            Fragment::Thresh(k, args.into_iter().map(|c| c.ms).collect()),
+            sat,
+            dissat,
+        ));
+    }
+}
+
+#[cfg(test)]
+fn test_key(n: u32) -> String {
+    use crate::s256::PrivateKey;
+
+    encode_hex(&PrivateKey::new(n.into()).unwrap().point.sec(true))
+}
+
+#[test]
+fn test_parse_policy() {
+    let a = test_key(1);
+    let s = format!(
+        "or(99@thresh(2,pk({}),older(144),after(700000)),sha256(9267d3dbed802941483f1afa2a6bc68de5f653128aca9bf1461c5d0a3ad36ed2))",
+        a
+    );
+    let policy: Policy = s.parse().unwrap();
+    assert_eq!(policy.to_string(), s);
+    assert_eq!(policy.keys().len(), 1);
+
+    for s in &[
+        "and(older(1))".to_string(),
+        "older(0)".to_string(),
+        "thresh(3,older(1),older(2))".to_string(),
+        "or(0@older(1),older(2))".to_string(),
+        "sha256(00)".to_string(),
+        format!("multi(1,{})", a),
+    ] {
+        assert!(s.parse::<Policy>().is_err(), "{}", s);
+    }
+}
+
+#[test]
+fn test_compile() {
+    let (a, b, c) = (test_key(1), test_key(2), test_key(3));
+    let cases = [
+        (
+            format!("and(pk({}),or(99@pk({}),older(12960)))", a, b),
+            format!("and_v(or_c(pk({}),v:older(12960)),pk({}))", b, a),
+        ),
+        (
+            format!("or(pk({}),pk({}))", a, b),
+            format!("or_b(pk({}),s:pk({}))", a, b),
+        ),
+        (
+            format!("thresh(2,pk({}),pk({}),pk({}))", a, b, c),
+            format!("multi(2,{},{},{})", a, b, c),
+        ),
+        (
+            format!("thresh(3,pk({}),pk({}),pk({}),older(12960))", a, b, c),
+            format!(
+                "thresh(3,pk({}),s:pk({}),s:pk({}),sln:older(12960))",
+                a, b, c
+            ),
+        ),
+    ];
+    for (policy, expected) in cases.iter() {
+        let policy: Policy = policy.parse().unwrap();
+        let ms = policy.compile().unwrap();
+        assert_eq!(&ms.to_string(), expected);
+        assert!(ms.is_sane());
+    }
+}
@@ -0,0 +1,1668 @@
+use anyhow::{anyhow, Result};
+use num_bigint::{BigInt, Sign};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::Read;
+
+use crate::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint};
+use crate::descriptor::Descriptor;
+use crate::helper::{encode_variant, hash160, hash256, read_variant, ripemd160, sha256};
+use crate::network::Network;
+use crate::op::*;
+use crate::s256::{PrivateKey, S256Point, Signature};
+use crate::script::{Cmd, Script};
+use crate::transaction::{
+    Tx, TxIn, TxOut, LOCKTIME_THRESHOLD, SIGHASH_ALL, SIGHASH_ANYONECANPAY, SIGHASH_NONE,
+    SIGHASH_SINGLE,
+};
+
+const MAGIC: &[u8] = b"psbt\xff";
+
+const PSBT_GLOBAL_UNSIGNED_TX: u64 = 0x00;
+const PSBT_GLOBAL_XPUB: u64 = 0x01;
+const PSBT_GLOBAL_TX_VERSION: u64 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u64 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u64 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u64 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u64 = 0x06;
+const PSBT_GLOBAL_VERSION: u64 = 0xfb;
+const PSBT_GLOBAL_PROPRIETARY: u64 = 0xfc;
+
+const PSBT_IN_NON_WITNESS_UTXO: u64 = 0x00;
+const PSBT_IN_WITNESS_UTXO: u64 = 0x01;
+const PSBT_IN_PARTIAL_SIG: u64 = 0x02;
+const PSBT_IN_SIGHASH_TYPE: u64 = 0x03;
+const PSBT_IN_REDEEM_SCRIPT: u64 = 0x04;
+const PSBT_IN_WITNESS_SCRIPT: u64 = 0x05;
+const PSBT_IN_BIP32_DERIVATION: u64 = 0x06;
+const PSBT_IN_FINAL_SCRIPTSIG: u64 = 0x07;
+const PSBT_IN_FINAL_SCRIPTWITNESS: u64 = 0x08;
+const PSBT_IN_RIPEMD160: u64 = 0x0a;
+const PSBT_IN_SHA256: u64 = 0x0b;
+const PSBT_IN_HASH160: u64 = 0x0c;
+const PSBT_IN_HASH256: u64 = 0x0d;
+const PSBT_IN_PREVIOUS_TXID: u64 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u64 = 0x0f;
+const PSBT_IN_SEQUENCE: u64 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u64 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u64 = 0x12;
+const PSBT_IN_PROPRIETARY: u64 = 0xfc;
+
+const PSBT_OUT_REDEEM_SCRIPT: u64 = 0x00;
+const PSBT_OUT_WITNESS_SCRIPT: u64 = 0x01;
+const PSBT_OUT_BIP32_DERIVATION: u64 = 0x02;
+const PSBT_OUT_AMOUNT: u64 = 0x03;
+const PSBT_OUT_SCRIPT: u64 = 0x04;
+const PSBT_OUT_PROPRIETARY: u64 = 0xfc;
+
+// BIP370 PSBT_GLOBAL_TX_MODIFIABLE flags
+pub const INPUTS_MODIFIABLE: u8 = 0x01;
+pub const OUTPUTS_MODIFIABLE: u8 = 0x02;
+pub const HAS_SIGHASH_SINGLE: u8 = 0x04;
+
+// The master key fingerprint and path a key was derived with
+pub type KeySource = (Fingerprint, DerivationPath);
+
+// A pair read from one of the maps, split into its type and the rest of the key
+struct Pair {
+    key_type: u64,
+    key_data: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Pair {
+    fn key(&self) -> Vec<u8> {
+        let mut key = encode_variant(self.key_type);
+        key.extend_from_slice(&self.key_data);
+        key
+    }
+
+    fn expect_empty_key(&self) -> Result<()> {
+        if !self.key_data.is_empty() {
+            return Err(anyhow!("Key type {:02x} takes no key data", self.key_type));
+        }
+        Ok(())
+    }
+
+    fn value_u32(&self) -> Result<u32> {
+        let mut b = [0u8; 4];
+        if self.value.len() != 4 {
+            return Err(anyhow!(
+                "Key type {:02x} needs a 4 byte value",
+                self.key_type
+            ));
+        }
+        b.copy_from_slice(&self.value);
+        Ok(u32::from_le_bytes(b))
+    }
+}
+
+// Reads one map up to its separator, rejecting repeated keys
+fn read_map<R>(reader: &mut R) -> Result<Vec<Pair>>
+where
+    R: Read,
+{
+    let mut result = vec![];
+    let mut seen = HashSet::new();
+    loop {
+        let key = read_bytes(reader)?;
+        if key.is_empty() {
+            return Ok(result);
+        }
+        let mut key_reader = key.as_slice();
+        let key_type = read_variant(&mut key_reader)?;
+        let pair = Pair {
+            key_type,
+            key_data: key_reader.to_vec(),
+            value: read_bytes(reader)?,
+        };
+        if !seen.insert(pair.key()) {
+            return Err(anyhow!("Duplicate key {:02x?} in PSBT map", pair.key()));
+        }
+        result.push(pair);
+    }
+}
+
+fn read_bytes<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: Read,
+{
+    let len = read_variant(reader)?;
+    let mut result = vec![];
+    reader.take(len).read_to_end(&mut result)?;
+    if result.len() as u64 != len {
+        return Err(anyhow!("PSBT ends in the middle of a key or value"));
+    }
+    Ok(result)
+}
+
+fn write_pair(result: &mut Vec<u8>, key_type: u64, key_data: &[u8], value: &[u8]) {
+    let mut key = encode_variant(key_type);
+    key.extend_from_slice(key_data);
+    write_raw_pair(result, &key, value);
+}
+
+fn write_raw_pair(result: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    result.append(&mut encode_variant(key.len() as u64));
+    result.extend_from_slice(key);
+    result.append(&mut encode_variant(value.len() as u64));
+    result.extend_from_slice(value);
+}
+
+fn parse_tx(b: &[u8], network: Network) -> Result<Tx> {
+    let mut reader = b;
+    let tx = Tx::parse(&mut reader, network)?;
+    if !reader.is_empty() {
+        return Err(anyhow!("Trailing bytes after transaction"));
+    }
+    Ok(tx)
+}
+
+fn parse_pubkey(b: &[u8]) -> Result<Vec<u8>> {
+    let valid = match b.first() {
+        Some(0x02) | Some(0x03) => b.len() == 33,
+        Some(0x04) => b.len() == 65,
+        _ => false,
+    };
+    if !valid {
+        return Err(anyhow!("Invalid public key {:02x?}", b));
+    }
+    S256Point::parse(b)?;
+    Ok(b.to_vec())
+}
+
+fn parse_key_source(b: &[u8]) -> Result<KeySource> {
+    if b.len() < 4 || !b.len().is_multiple_of(4) {
+        return Err(anyhow!("Invalid key origin of {} bytes", b.len()));
+    }
+    let mut fingerprint = [0u8; 4];
+    fingerprint.copy_from_slice(&b[..4]);
+    let path = b[4..]
+        .chunks(4)
+        .map(|c| ChildNumber(u32::from_le_bytes([c[0], c[1], c[2], c[3]])))
+        .collect();
+    Ok((Fingerprint(fingerprint), DerivationPath(path)))
+}
+
+fn serialize_key_source(source: &KeySource) -> Vec<u8> {
+    let mut result = source.0 .0.to_vec();
+    for child in &(source.1).0 {
+        result.extend_from_slice(&child.0.to_le_bytes());
+    }
+    result
+}
+
+fn parse_witness(b: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut reader = b;
+    let items = read_variant(&mut reader)?;
+    let mut result = vec![];
+    for _ in 0..items {
+        result.push(read_bytes(&mut reader)?);
+    }
+    if !reader.is_empty() {
+        return Err(anyhow!("Trailing bytes after witness"));
+    }
+    Ok(result)
+}
+
+fn serialize_witness(witness: &[Vec<u8>]) -> Vec<u8> {
+    let mut result = encode_variant(witness.len() as u64);
+    for item in witness {
+        result.append(&mut encode_variant(item.len() as u64));
+        result.extend_from_slice(item);
+    }
+    result
+}
+
+// k and the keys of a bare k-of-n CHECKMULTISIG script
+fn parse_multisig(script: &Script) -> Option<(usize, Vec<Vec<u8>>)> {
+    let cmds = script.cmds().ok()?;
+    let small_int = |cmd: &Cmd| match cmd {
+        Cmd::Op(op) if (OP_1..=OP_16).contains(op) => Some((op - OP_1 + 1) as usize),
+        _ => None,
+    };
+    if cmds.len() < 4 || cmds[cmds.len() - 1] != Cmd::Op(OP_CHECKMULTISIG) {
+        return None;
+    }
+    let k = small_int(&cmds[0])?;
+    let n = small_int(&cmds[cmds.len() - 2])?;
+    let keys = cmds[1..cmds.len() - 2]
+        .iter()
+        .map(|cmd| match cmd {
+            Cmd::Data(key) => Some(key.clone()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if keys.len() != n || k > n {
+        return None;
+    }
+    Some((k, keys))
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PsbtInput {
+    pub non_witness_utxo: Option<Tx>,
+    pub witness_utxo: Option<TxOut>,
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub sighash_type: Option<u32>,
+    pub redeem_script: Option<Script>,
+    pub witness_script: Option<Script>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub final_script_sig: Option<Script>,
+    pub final_script_witness: Option<Vec<Vec<u8>>>,
+    pub ripemd160_preimages: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub sha256_preimages: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub hash160_preimages: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub hash256_preimages: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub required_time_locktime: Option<u32>,
+    pub required_height_locktime: Option<u32>,
+    pub proprietary: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl PsbtInput {
+    // Version 2 maps also carry the outpoint and sequence, which are returned
+    // as a TxIn
+    fn parse<R>(reader: &mut R, version: u32, network: Network) -> Result<(Self, Option<TxIn>)>
+    where
+        R: Read,
+    {
+        let mut input = Self::default();
+        let mut prev_tx = None;
+        let mut prev_index = None;
+        let mut sequence = None;
+        for pair in read_map(reader)? {
+            let v2_only = matches!(
+                pair.key_type,
+                PSBT_IN_PREVIOUS_TXID
+                    | PSBT_IN_OUTPUT_INDEX
+                    | PSBT_IN_SEQUENCE
+                    | PSBT_IN_REQUIRED_TIME_LOCKTIME
+                    | PSBT_IN_REQUIRED_HEIGHT_LOCKTIME
+            );
+            if v2_only && version < 2 {
+                if pair.key_data.is_empty() {
+                    return Err(anyhow!(
+                        "Input key type {:02x} is not allowed in PSBT version 0",
+                        pair.key_type
+                    ));
+                }
+                input.unknown.insert(pair.key(), pair.value);
+                continue;
+            }
+            match pair.key_type {
+                PSBT_IN_NON_WITNESS_UTXO => {
+                    pair.expect_empty_key()?;
+                    input.non_witness_utxo = Some(parse_tx(&pair.value, network)?);
+                }
+                PSBT_IN_WITNESS_UTXO => {
+                    pair.expect_empty_key()?;
+                    let mut reader = pair.value.as_slice();
+                    input.witness_utxo = Some(TxOut::parse(&mut reader)?);
+                    if !reader.is_empty() {
+                        return Err(anyhow!("Trailing bytes after witness UTXO"));
+                    }
+                }
+                PSBT_IN_PARTIAL_SIG => {
+                    let key = parse_pubkey(&pair.key_data)?;
+                    if pair.value.is_empty() {
+                        return Err(anyhow!("Empty partial signature"));
+                    }
+                    Signature::parse(&pair.value[..pair.value.len() - 1])?;
+                    input.partial_sigs.insert(key, pair.value);
+                }
+                PSBT_IN_SIGHASH_TYPE => {
+                    pair.expect_empty_key()?;
+                    input.sighash_type = Some(pair.value_u32()?);
+                }
+                PSBT_IN_REDEEM_SCRIPT => {
+                    pair.expect_empty_key()?;
+                    input.redeem_script = Some(Script::from_bytes(pair.value));
+                }
+                PSBT_IN_WITNESS_SCRIPT => {
+                    pair.expect_empty_key()?;
+                    input.witness_script = Some(Script::from_bytes(pair.value));
+                }
+                PSBT_IN_BIP32_DERIVATION => {
+                    let key = parse_pubkey(&pair.key_data)?;
+                    input
+                        .bip32_derivation
+                        .insert(key, parse_key_source(&pair.value)?);
+                }
+                PSBT_IN_FINAL_SCRIPTSIG => {
+                    pair.expect_empty_key()?;
+                    input.final_script_sig = Some(Script::from_bytes(pair.value));
+                }
+                PSBT_IN_FINAL_SCRIPTWITNESS => {
+                    pair.expect_empty_key()?;
+                    input.final_script_witness = Some(parse_witness(&pair.value)?);
+                }
+                PSBT_IN_RIPEMD160 | PSBT_IN_SHA256 | PSBT_IN_HASH160 | PSBT_IN_HASH256 => {
+                    let (hash, map) = match pair.key_type {
+                        PSBT_IN_RIPEMD160 => {
+                            (ripemd160(&pair.value), &mut input.ripemd160_preimages)
+                        }
+                        PSBT_IN_SHA256 => (sha256(&pair.value), &mut input.sha256_preimages),
+                        PSBT_IN_HASH160 => (hash160(&pair.value), &mut input.hash160_preimages),
+                        _ => (hash256(&pair.value), &mut input.hash256_preimages),
+                    };
+                    if hash != pair.key_data {
+                        return Err(anyhow!("Preimage does not match its hash"));
+                    }
+                    map.insert(pair.key_data, pair.value);
+                }
+                PSBT_IN_PREVIOUS_TXID => {
+                    pair.expect_empty_key()?;
+                    if pair.value.len() != 32 {
+                        return Err(anyhow!("Previous txid must be 32 bytes"));
+                    }
+                    let mut txid = [0u8; 32];
+                    txid.copy_from_slice(&pair.value);
+                    prev_tx = Some(txid);
+                }
+                PSBT_IN_OUTPUT_INDEX => {
+                    pair.expect_empty_key()?;
+                    prev_index = Some(pair.value_u32()?);
+                }
+                PSBT_IN_SEQUENCE => {
+                    pair.expect_empty_key()?;
+                    sequence = Some(pair.value_u32()?);
+                }
+                PSBT_IN_REQUIRED_TIME_LOCKTIME => {
+                    pair.expect_empty_key()?;
+                    let locktime = pair.value_u32()?;
+                    if locktime < LOCKTIME_THRESHOLD {
+                        return Err(anyhow!("Required time locktime {} is a height", locktime));
+                    }
+                    input.required_time_locktime = Some(locktime);
+                }
+                PSBT_IN_REQUIRED_HEIGHT_LOCKTIME => {
+                    pair.expect_empty_key()?;
+                    let locktime = pair.value_u32()?;
+                    if locktime == 0 || locktime >= LOCKTIME_THRESHOLD {
+                        return Err(anyhow!(
+                            "Required height locktime {} is not a height",
+                            locktime
+                        ));
+                    }
+                    input.required_height_locktime = Some(locktime);
+                }
+                PSBT_IN_PROPRIETARY => {
+                    input.proprietary.insert(pair.key(), pair.value);
+                }
+                _ => {
+                    input.unknown.insert(pair.key(), pair.value);
+                }
+            }
+        }
+
+        if version < 2 {
+            return Ok((input, None));
+        }
+        let prev_tx = prev_tx.ok_or_else(|| anyhow!("Input is missing its previous txid"))?;
+        let prev_index = prev_index.ok_or_else(|| anyhow!("Input is missing its output index"))?;
+        let tx_in = TxIn::new(prev_tx, prev_index, None, sequence.unwrap_or(0xffffffff));
+        Ok((input, Some(tx_in)))
+    }
+
+    fn serialize(&self, version: u32, tx_in: &TxIn) -> Vec<u8> {
+        let mut result = vec![];
+        if let Some(tx) = &self.non_witness_utxo {
+            write_pair(&mut result, PSBT_IN_NON_WITNESS_UTXO, &[], &tx.serialize());
+        }
+        if let Some(tx_out) = &self.witness_utxo {
+            write_pair(&mut result, PSBT_IN_WITNESS_UTXO, &[], &tx_out.serialize());
+        }
+        for (key, sig) in &self.partial_sigs {
+            write_pair(&mut result, PSBT_IN_PARTIAL_SIG, key, sig);
+        }
+        if let Some(sighash_type) = self.sighash_type {
+            write_pair(
+                &mut result,
+                PSBT_IN_SIGHASH_TYPE,
+                &[],
+                &sighash_type.to_le_bytes(),
+            );
+        }
+        if let Some(script) = &self.redeem_script {
+            write_pair(&mut result, PSBT_IN_REDEEM_SCRIPT, &[], script.as_bytes());
+        }
+        if let Some(script) = &self.witness_script {
+            write_pair(&mut result, PSBT_IN_WITNESS_SCRIPT, &[], script.as_bytes());
+        }
+        for (key, source) in &self.bip32_derivation {
+            write_pair(
+                &mut result,
+                PSBT_IN_BIP32_DERIVATION,
+                key,
+                &serialize_key_source(source),
+            );
+        }
+        if let Some(script) = &self.final_script_sig {
+            write_pair(&mut result, PSBT_IN_FINAL_SCRIPTSIG, &[], script.as_bytes());
+        }
+        if let Some(witness) = &self.final_script_witness {
+            write_pair(
+                &mut result,
+                PSBT_IN_FINAL_SCRIPTWITNESS,
+                &[],
+                &serialize_witness(witness),
+            );
+        }
+        for (key_type, map) in &[
+            (PSBT_IN_RIPEMD160, &self.ripemd160_preimages),
+            (PSBT_IN_SHA256, &self.sha256_preimages),
+            (PSBT_IN_HASH160, &self.hash160_preimages),
+            (PSBT_IN_HASH256, &self.hash256_preimages),
+        ] {
+            for (hash, preimage) in map.iter() {
+                write_pair(&mut result, *key_type, hash, preimage);
+            }
+        }
+        if version >= 2 {
+            write_pair(&mut result, PSBT_IN_PREVIOUS_TXID, &[], &tx_in.prev_tx);
+            write_pair(
+                &mut result,
+                PSBT_IN_OUTPUT_INDEX,
+                &[],
+                &tx_in.prev_index.to_le_bytes(),
+            );
+            write_pair(
+                &mut result,
+                PSBT_IN_SEQUENCE,
+                &[],
+                &tx_in.sequence.to_le_bytes(),
+            );
+            if let Some(locktime) = self.required_time_locktime {
+                write_pair(
+                    &mut result,
+                    PSBT_IN_REQUIRED_TIME_LOCKTIME,
+                    &[],
+                    &locktime.to_le_bytes(),
+                );
+            }
+            if let Some(locktime) = self.required_height_locktime {
+                write_pair(
+                    &mut result,
+                    PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+                    &[],
+                    &locktime.to_le_bytes(),
+                );
+            }
+        }
+        for (key, value) in self.proprietary.iter().chain(self.unknown.iter()) {
+            write_raw_pair(&mut result, key, value);
+        }
+        result.push(0x00);
+        result
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.final_script_sig.is_some() || self.final_script_witness.is_some()
+    }
+
+    // Fields are merged, keeping our own value wherever both sides set one
+    fn combine(&mut self, other: &PsbtInput) {
+        if self.non_witness_utxo.is_none() {
+            self.non_witness_utxo = other.non_witness_utxo.clone();
+        }
+        if self.witness_utxo.is_none() {
+            self.witness_utxo = other.witness_utxo.clone();
+        }
+        if self.sighash_type.is_none() {
+            self.sighash_type = other.sighash_type;
+        }
+        if self.redeem_script.is_none() {
+            self.redeem_script = other.redeem_script.clone();
+        }
+        if self.witness_script.is_none() {
+            self.witness_script = other.witness_script.clone();
+        }
+        if self.final_script_sig.is_none() {
+            self.final_script_sig = other.final_script_sig.clone();
+        }
+        if self.final_script_witness.is_none() {
+            self.final_script_witness = other.final_script_witness.clone();
+        }
+        if self.required_time_locktime.is_none() {
+            self.required_time_locktime = other.required_time_locktime;
+        }
+        if self.required_height_locktime.is_none() {
+            self.required_height_locktime = other.required_height_locktime;
+        }
+        merge(&mut self.partial_sigs, &other.partial_sigs);
+        merge(&mut self.bip32_derivation, &other.bip32_derivation);
+        merge(&mut self.ripemd160_preimages, &other.ripemd160_preimages);
+        merge(&mut self.sha256_preimages, &other.sha256_preimages);
+        merge(&mut self.hash160_preimages, &other.hash160_preimages);
+        merge(&mut self.hash256_preimages, &other.hash256_preimages);
+        merge(&mut self.proprietary, &other.proprietary);
+        merge(&mut self.unknown, &other.unknown);
+    }
+}
+
+fn merge<V: Clone>(map: &mut BTreeMap<Vec<u8>, V>, other: &BTreeMap<Vec<u8>, V>) {
+    for (key, value) in other {
+        map.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PsbtOutput {
+    pub redeem_script: Option<Script>,
+    pub witness_script: Option<Script>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub proprietary: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl PsbtOutput {
+    fn parse<R>(reader: &mut R, version: u32) -> Result<(Self, Option<TxOut>)>
+    where
+        R: Read,
+    {
+        let mut output = Self::default();
+        let mut amount = None;
+        let mut script = None;
+        for pair in read_map(reader)? {
+            let v2_only = matches!(pair.key_type, PSBT_OUT_AMOUNT | PSBT_OUT_SCRIPT);
+            if v2_only && version < 2 {
+                if pair.key_data.is_empty() {
+                    return Err(anyhow!(
+                        "Output key type {:02x} is not allowed in PSBT version 0",
+                        pair.key_type
+                    ));
+                }
+                output.unknown.insert(pair.key(), pair.value);
+                continue;
+            }
+            match pair.key_type {
+                PSBT_OUT_REDEEM_SCRIPT => {
+                    pair.expect_empty_key()?;
+                    output.redeem_script = Some(Script::from_bytes(pair.value));
+                }
+                PSBT_OUT_WITNESS_SCRIPT => {
+                    pair.expect_empty_key()?;
+                    output.witness_script = Some(Script::from_bytes(pair.value));
+                }
+                PSBT_OUT_BIP32_DERIVATION => {
+                    let key = parse_pubkey(&pair.key_data)?;
+                    output
+                        .bip32_derivation
+                        .insert(key, parse_key_source(&pair.value)?);
+                }
+                PSBT_OUT_AMOUNT => {
+                    pair.expect_empty_key()?;
+                    if pair.value.len() != 8 {
+                        return Err(anyhow!("Output amount must be 8 bytes"));
+                    }
+                    let mut b = [0u8; 8];
+                    b.copy_from_slice(&pair.value);
+                    amount = Some(u64::from_le_bytes(b));
+                }
+                PSBT_OUT_SCRIPT => {
+                    pair.expect_empty_key()?;
+                    script = Some(Script::from_bytes(pair.value));
+                }
+                PSBT_OUT_PROPRIETARY => {
+                    output.proprietary.insert(pair.key(), pair.value);
+                }
+                _ => {
+                    output.unknown.insert(pair.key(), pair.value);
+                }
+            }
+        }
+
+        if version < 2 {
+            return Ok((output, None));
+        }
+        let amount = amount.ok_or_else(|| anyhow!("Output is missing its amount"))?;
+        let script = script.ok_or_else(|| anyhow!("Output is missing its script"))?;
+        Ok((output, Some(TxOut::new(amount, script))))
+    }
+
+    fn serialize(&self, version: u32, tx_out: &TxOut) -> Vec<u8> {
+        let mut result = vec![];
+        if let Some(script) = &self.redeem_script {
+            write_pair(&mut result, PSBT_OUT_REDEEM_SCRIPT, &[], script.as_bytes());
+        }
+        if let Some(script) = &self.witness_script {
+            write_pair(&mut result, PSBT_OUT_WITNESS_SCRIPT, &[], script.as_bytes());
+        }
+        for (key, source) in &self.bip32_derivation {
+            write_pair(
+                &mut result,
+                PSBT_OUT_BIP32_DERIVATION,
+                key,
+                &serialize_key_source(source),
+            );
+        }
+        if version >= 2 {
+            write_pair(
+                &mut result,
+                PSBT_OUT_AMOUNT,
+                &[],
+                &tx_out.amount.to_le_bytes(),
+            );
+            write_pair(
+                &mut result,
+                PSBT_OUT_SCRIPT,
+                &[],
+                tx_out.script_pubkey.as_bytes(),
+            );
+        }
+        for (key, value) in self.proprietary.iter().chain(self.unknown.iter()) {
+            write_raw_pair(&mut result, key, value);
+        }
+        result.push(0x00);
+        result
+    }
+
+    fn combine(&mut self, other: &PsbtOutput) {
+        if self.redeem_script.is_none() {
+            self.redeem_script = other.redeem_script.clone();
+        }
+        if self.witness_script.is_none() {
+            self.witness_script = other.witness_script.clone();
+        }
+        merge(&mut self.bip32_derivation, &other.bip32_derivation);
+        merge(&mut self.proprietary, &other.proprietary);
+        merge(&mut self.unknown, &other.unknown);
+    }
+}
+
+// BIP174 (version 0) and BIP370 (version 2) partially signed transactions.
+// Both versions keep the transaction in `tx`; version 2 spreads it over the
+// per-input and per-output maps when serialized.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Psbt {
+    pub version: u32,
+    pub tx: Tx,
+    pub xpubs: BTreeMap<Vec<u8>, KeySource>,
+    pub fallback_locktime: Option<u32>,
+    pub tx_modifiable: Option<u8>,
+    pub proprietary: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub inputs: Vec<PsbtInput>,
+    pub outputs: Vec<PsbtOutput>,
+}
+
+impl Psbt {
+    // Creator: wraps a transaction that carries no signatures yet
+    pub fn from_tx(tx: Tx) -> Result<Self> {
+        if tx
+            .tx_ins
+            .iter()
+            .any(|tx_in| !tx_in.script_sig.is_empty() || !tx_in.witness.is_empty())
+        {
+            return Err(anyhow!("Unsigned transaction has scriptSigs or witnesses"));
+        }
+        Ok(Self {
+            version: 0,
+            inputs: vec![PsbtInput::default(); tx.tx_ins.len()],
+            outputs: vec![PsbtOutput::default(); tx.tx_outs.len()],
+            tx,
+            xpubs: BTreeMap::new(),
+            fallback_locktime: None,
+            tx_modifiable: None,
+            proprietary: BTreeMap::new(),
+            unknown: BTreeMap::new(),
+        })
+    }
+
+    pub fn parse<R>(reader: &mut R, network: Network) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut magic = [0u8; 5];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(anyhow!("Invalid PSBT magic"));
+        }
+
+        let mut unsigned_tx = None;
+        let mut version = 0;
+        let mut tx_version = None;
+        let mut fallback_locktime = None;
+        let mut input_count = None;
+        let mut output_count = None;
+        let mut tx_modifiable = None;
+        let mut xpubs = BTreeMap::new();
+        let mut proprietary = BTreeMap::new();
+        let mut unknown = BTreeMap::new();
+        let read_count = |pair: &Pair| -> Result<u64> {
+            pair.expect_empty_key()?;
+            let mut reader = pair.value.as_slice();
+            let count = read_variant(&mut reader)?;
+            if !reader.is_empty() {
+                return Err(anyhow!("Trailing bytes after count"));
+            }
+            Ok(count)
+        };
+        for pair in read_map(reader)? {
+            match pair.key_type {
+                PSBT_GLOBAL_UNSIGNED_TX => {
+                    pair.expect_empty_key()?;
+                    let tx = parse_tx(&pair.value, network)?;
+                    unsigned_tx = Some(tx);
+                }
+                PSBT_GLOBAL_XPUB => {
+                    ExtendedPubKey::parse(&pair.key_data)?;
+                    xpubs.insert(pair.key_data.clone(), parse_key_source(&pair.value)?);
+                }
+                PSBT_GLOBAL_TX_VERSION => {
+                    pair.expect_empty_key()?;
+                    tx_version = Some(pair.value_u32()?);
+                }
+                PSBT_GLOBAL_FALLBACK_LOCKTIME => {
+                    pair.expect_empty_key()?;
+                    fallback_locktime = Some(pair.value_u32()?);
+                }
+                PSBT_GLOBAL_INPUT_COUNT => input_count = Some(read_count(&pair)?),
+                PSBT_GLOBAL_OUTPUT_COUNT => output_count = Some(read_count(&pair)?),
+                PSBT_GLOBAL_TX_MODIFIABLE => {
+                    pair.expect_empty_key()?;
+                    if pair.value.len() != 1 {
+                        return Err(anyhow!("Modifiable flags must be 1 byte"));
+                    }
+                    tx_modifiable = Some(pair.value[0]);
+                }
+                PSBT_GLOBAL_VERSION => {
+                    pair.expect_empty_key()?;
+                    version = pair.value_u32()?;
+                }
+                PSBT_GLOBAL_PROPRIETARY => {
+                    proprietary.insert(pair.key(), pair.value);
+                }
+                _ => {
+                    unknown.insert(pair.key(), pair.value);
+                }
+            }
+        }
+
+        let (tx, input_count, output_count) = match version {
+            0 => {
+                if tx_version.is_some()
+                    || fallback_locktime.is_some()
+                    || input_count.is_some()
+                    || output_count.is_some()
+                    || tx_modifiable.is_some()
+                {
+                    return Err(anyhow!("PSBT version 0 has version 2 global fields"));
+                }
+                let tx = unsigned_tx.ok_or_else(|| anyhow!("PSBT has no unsigned transaction"))?;
+                if tx
+                    .tx_ins
+                    .iter()
+                    .any(|tx_in| !tx_in.script_sig.is_empty() || !tx_in.witness.is_empty())
+                {
+                    return Err(anyhow!("Unsigned transaction has scriptSigs or witnesses"));
+                }
+                let (inputs, outputs) = (tx.tx_ins.len() as u64, tx.tx_outs.len() as u64);
+                (tx, inputs, outputs)
+            }
+            2 => {
+                if unsigned_tx.is_some() {
+                    return Err(anyhow!("PSBT version 2 has an unsigned transaction"));
+                }
+                let tx_version =
+                    tx_version.ok_or_else(|| anyhow!("PSBT has no transaction version"))?;
+                let tx = Tx::new(tx_version, vec![], vec![], 0, network);
+                (
+                    tx,
+                    input_count.ok_or_else(|| anyhow!("PSBT has no input count"))?,
+                    output_count.ok_or_else(|| anyhow!("PSBT has no output count"))?,
+                )
+            }
+            _ => return Err(anyhow!("Unsupported PSBT version {}", version)),
+        };
+
+        let mut psbt = Self {
+            version,
+            tx,
+            xpubs,
+            fallback_locktime,
+            tx_modifiable,
+            proprietary,
+            unknown,
+            inputs: vec![],
+            outputs: vec![],
+        };
+        for i in 0..input_count {
+            let (input, tx_in) = PsbtInput::parse(reader, version, network)?;
+            if let Some(tx_in) = tx_in {
+                psbt.tx.tx_ins.push(tx_in);
+            }
+            if let Some(utxo) = &input.non_witness_utxo {
+                let tx_in = &psbt.tx.tx_ins[i as usize];
+                if utxo.hash().iter().rev().ne(tx_in.prev_tx.iter()) {
+                    return Err(anyhow!("Input {} has the wrong non-witness UTXO", i));
+                }
+            }
+            psbt.inputs.push(input);
+        }
+        for _ in 0..output_count {
+            let (output, tx_out) = PsbtOutput::parse(reader, version)?;
+            if let Some(tx_out) = tx_out {
+                psbt.tx.tx_outs.push(tx_out);
+            }
+            psbt.outputs.push(output);
+        }
+
+        let mut rest = [0u8; 1];
+        if reader.read(&mut rest)? != 0 {
+            return Err(anyhow!("Trailing bytes after PSBT"));
+        }
+        if version >= 2 {
+            psbt.tx.locktime = psbt.locktime()?;
+        }
+        Ok(psbt)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = MAGIC.to_vec();
+        if self.version < 2 {
+            write_pair(
+                &mut result,
+                PSBT_GLOBAL_UNSIGNED_TX,
+                &[],
+                &self.tx.serialize_legacy(),
+            );
+        }
+        for (xpub, source) in &self.xpubs {
+            write_pair(
+                &mut result,
+                PSBT_GLOBAL_XPUB,
+                xpub,
+                &serialize_key_source(source),
+            );
+        }
+        if self.version >= 2 {
+            write_pair(
+                &mut result,
+                PSBT_GLOBAL_TX_VERSION,
+                &[],
+                &self.tx.version.to_le_bytes(),
+            );
+            if let Some(locktime) = self.fallback_locktime {
+                write_pair(
+                    &mut result,
+                    PSBT_GLOBAL_FALLBACK_LOCKTIME,
+                    &[],
+                    &locktime.to_le_bytes(),
+                );
+            }
+            write_pair(
+                &mut result,
+                PSBT_GLOBAL_INPUT_COUNT,
+                &[],
+                &encode_variant(self.inputs.len() as u64),
+            );
+            write_pair(
+                &mut result,
+                PSBT_GLOBAL_OUTPUT_COUNT,
+                &[],
+                &encode_variant(self.outputs.len() as u64),
+            );
+            if let Some(flags) = self.tx_modifiable {
+                write_pair(&mut result, PSBT_GLOBAL_TX_MODIFIABLE, &[], &[flags]);
+            }
+        }
+        if self.version > 0 {
+            write_pair(
+                &mut result,
+                PSBT_GLOBAL_VERSION,
+                &[],
+                &self.version.to_le_bytes(),
+            );
+        }
+        for (key, value) in self.proprietary.iter().chain(self.unknown.iter()) {
+            write_raw_pair(&mut result, key, value);
+        }
+        result.push(0x00);
+
+        for (input, tx_in) in self.inputs.iter().zip(&self.tx.tx_ins) {
+            result.append(&mut input.serialize(self.version, tx_in));
+        }
+        for (output, tx_out) in self.outputs.iter().zip(&self.tx.tx_outs) {
+            result.append(&mut output.serialize(self.version, tx_out));
+        }
+        result
+    }
+
+    pub fn parse_base64(s: &str, network: Network) -> Result<Self> {
+        let b = base64::decode(s.trim())?;
+        Self::parse(&mut b.as_slice(), network)
+    }
+
+    pub fn serialize_base64(&self) -> String {
+        base64::encode(self.serialize())
+    }
+
+    // BIP370 lock time determination. Heights win when every input that
+    // asks for a lock time can take one.
+    pub fn locktime(&self) -> Result<u32> {
+        let constrained = self
+            .inputs
+            .iter()
+            .filter(|input| {
+                input.required_time_locktime.is_some() || input.required_height_locktime.is_some()
+            })
+            .collect::<Vec<_>>();
+        if constrained.is_empty() {
+            return Ok(self.fallback_locktime.unwrap_or(0));
+        }
+        if constrained
+            .iter()
+            .all(|input| input.required_height_locktime.is_some())
+        {
+            return Ok(constrained
+                .iter()
+                .filter_map(|input| input.required_height_locktime)
+                .max()
+                .unwrap_or(0));
+        }
+        if constrained
+            .iter()
+            .all(|input| input.required_time_locktime.is_some())
+        {
+            return Ok(constrained
+                .iter()
+                .filter_map(|input| input.required_time_locktime)
+                .max()
+                .unwrap_or(0));
+        }
+        Err(anyhow!("Inputs require incompatible lock time types"))
+    }
+
+    pub fn to_v2(&self) -> Result<Self> {
+        let mut psbt = self.clone();
+        if psbt.version < 2 {
+            psbt.version = 2;
+            psbt.fallback_locktime = Some(self.tx.locktime);
+            psbt.tx_modifiable = Some(0);
+        }
+        Ok(psbt)
+    }
+
+    // Version 0 has no room for per-input lock time requirements, so they are
+    // resolved into the transaction
+    pub fn to_v0(&self) -> Result<Self> {
+        let mut psbt = self.clone();
+        if psbt.version >= 2 {
+            psbt.tx.locktime = self.locktime()?;
+            psbt.version = 0;
+            psbt.fallback_locktime = None;
+            psbt.tx_modifiable = None;
+            for input in psbt.inputs.iter_mut() {
+                input.required_time_locktime = None;
+                input.required_height_locktime = None;
+            }
+        }
+        Ok(psbt)
+    }
+
+    // Combiner
+    pub fn combine(&mut self, other: &Psbt) -> Result<()> {
+        if self.version != other.version {
+            return Err(anyhow!("Cannot combine PSBTs of different versions"));
+        }
+        if self.tx.hash() != other.tx.hash()
+            || self.inputs.len() != other.inputs.len()
+            || self.outputs.len() != other.outputs.len()
+        {
+            return Err(anyhow!("Cannot combine PSBTs for different transactions"));
+        }
+        if self.fallback_locktime.is_none() {
+            self.fallback_locktime = other.fallback_locktime;
+        }
+        if let Some(flags) = other.tx_modifiable {
+            // A combined PSBT is only as modifiable as both halves
+            self.tx_modifiable = Some(self.tx_modifiable.unwrap_or(flags) & flags);
+        }
+        merge(&mut self.xpubs, &other.xpubs);
+        merge(&mut self.proprietary, &other.proprietary);
+        merge(&mut self.unknown, &other.unknown);
+        for (input, other) in self.inputs.iter_mut().zip(&other.inputs) {
+            input.combine(other);
+        }
+        for (output, other) in self.outputs.iter_mut().zip(&other.outputs) {
+            output.combine(other);
+        }
+        Ok(())
+    }
+
+    // Updater: fills in the scripts and key origins a descriptor produces at
+    // `index` for the given input
+    pub fn update_input_with_descriptor(
+        &mut self,
+        input_index: usize,
+        descriptor: &Descriptor,
+        index: u32,
+    ) -> Result<()> {
+        if let Descriptor::Tr(..) = descriptor {
+            return Err(anyhow!("Taproot PSBT fields are not supported"));
+        }
+        let input = self
+            .inputs
+            .get_mut(input_index)
+            .ok_or_else(|| anyhow!("Input {} is out of range", input_index))?;
+        input.redeem_script = descriptor.redeem_script(index)?;
+        input.witness_script = descriptor.witness_script(index)?;
+        for key in descriptor.keys() {
+            let sec = key.public_key(index)?.sec(key.is_compressed());
+            input
+                .bip32_derivation
+                .insert(sec, key.full_derivation(index)?);
+        }
+        Ok(())
+    }
+
+    pub fn update_output_with_descriptor(
+        &mut self,
+        output_index: usize,
+        descriptor: &Descriptor,
+        index: u32,
+    ) -> Result<()> {
+        if let Descriptor::Tr(..) = descriptor {
+            return Err(anyhow!("Taproot PSBT fields are not supported"));
+        }
+        if self.tx.tx_outs.get(output_index).map(|o| &o.script_pubkey)
+            != Some(&descriptor.script_pubkey(index)?)
+        {
+            return Err(anyhow!(
+                "Output {} does not pay to the descriptor",
+                output_index
+            ));
+        }
+        let output = &mut self.outputs[output_index];
+        output.redeem_script = descriptor.redeem_script(index)?;
+        output.witness_script = descriptor.witness_script(index)?;
+        for key in descriptor.keys() {
+            let sec = key.public_key(index)?.sec(key.is_compressed());
+            output
+                .bip32_derivation
+                .insert(sec, key.full_derivation(index)?);
+        }
+        Ok(())
+    }
+
+    // The output being spent by an input
+    pub fn utxo(&self, input_index: usize) -> Result<TxOut> {
+        let input = self
+            .inputs
+            .get(input_index)
+            .ok_or_else(|| anyhow!("Input {} is out of range", input_index))?;
+        if let Some(tx_out) = &input.witness_utxo {
+            return Ok(tx_out.clone());
+        }
+        let tx_in = &self.tx.tx_ins[input_index];
+        let tx = input
+            .non_witness_utxo
+            .as_ref()
+            .ok_or_else(|| anyhow!("Input {} has no UTXO", input_index))?;
+        if tx.hash().iter().rev().ne(tx_in.prev_tx.iter()) {
+            return Err(anyhow!(
+                "Input {} has the wrong non-witness UTXO",
+                input_index
+            ));
+        }
+        tx.tx_outs
+            .get(tx_in.prev_index as usize)
+            .cloned()
+            .ok_or_else(|| anyhow!("Input {} spends a missing output", input_index))
+    }
+
+    // The script run by the input's signature checks, and the amount spent
+    // if it is a segwit v0 input
+    fn script_code(&self, input_index: usize) -> Result<(Script, Option<u64>)> {
+        let input = &self.inputs[input_index];
+        let utxo = self.utxo(input_index)?;
+        let mut script = utxo.script_pubkey;
+        if script.is_p2sh() {
+            let redeem_script = input
+                .redeem_script
+                .clone()
+                .ok_or_else(|| anyhow!("Input {} has no redeem script", input_index))?;
+            if Script::p2sh(&hash160(redeem_script.as_bytes())) != script {
+                return Err(anyhow!("Input {} has the wrong redeem script", input_index));
+            }
+            script = redeem_script;
+        }
+        match script.witness_program() {
+            Some((0, program)) if program.len() == 20 => {
+                Ok((Script::p2pkh(program), Some(utxo.amount)))
+            }
+            Some((0, program)) if program.len() == 32 => {
+                let witness_script = input
+                    .witness_script
+                    .clone()
+                    .ok_or_else(|| anyhow!("Input {} has no witness script", input_index))?;
+                if sha256(witness_script.as_bytes()) != program {
+                    return Err(anyhow!(
+                        "Input {} has the wrong witness script",
+                        input_index
+                    ));
+                }
+                Ok((witness_script, Some(utxo.amount)))
+            }
+            Some((version, _)) => Err(anyhow!(
+                "Input {} is witness version {}, which cannot be signed",
+                input_index,
+                version
+            )),
+            None => Ok((script, None)),
+        }
+    }
+
+    // Signer: adds a signature from `key` to the input if its script uses
+    // the key. Returns whether a signature was added.
+    pub fn sign_input(&mut self, input_index: usize, key: &PrivateKey) -> Result<bool> {
+        if self
+            .inputs
+            .get(input_index)
+            .ok_or_else(|| anyhow!("Input {} is out of range", input_index))?
+            .is_finalized()
+        {
+            return Ok(false);
+        }
+        let (script_code, amount) = self.script_code(input_index)?;
+        let cmds = script_code.cmds()?;
+        let compressions: &[bool] = if amount.is_some() {
+            &[true]
+        } else {
+            &[true, false]
+        };
+        let sec = compressions.iter().map(|c| key.point.sec(*c)).find(|sec| {
+            cmds.contains(&Cmd::Data(sec.clone())) || cmds.contains(&Cmd::Data(hash160(sec)))
+        });
+        let sec = match sec {
+            Some(sec) => sec,
+            None => return Ok(false),
+        };
+
+        let sighash_type = self.inputs[input_index].sighash_type.unwrap_or(SIGHASH_ALL);
+        let z = match amount {
+            Some(amount) => {
+                self.tx
+                    .sig_hash_bip143(input_index, &script_code, amount, sighash_type)?
+            }
+            None => self.tx.sig_hash(input_index, &script_code, sighash_type)?,
+        };
+        let mut sig = key.sign(BigInt::from_bytes_be(Sign::Plus, &z)).der();
+        sig.push(sighash_type as u8);
+        self.inputs[input_index].partial_sigs.insert(sec, sig);
+
+        // BIP370: signatures pin down whatever parts of the transaction they commit to
+        if let Some(flags) = self.tx_modifiable.as_mut() {
+            if sighash_type & SIGHASH_ANYONECANPAY == 0 {
+                *flags &= !INPUTS_MODIFIABLE;
+            }
+            match sighash_type & 0x1f {
+                SIGHASH_NONE => {}
+                SIGHASH_SINGLE => *flags |= HAS_SIGHASH_SINGLE,
+                _ => *flags &= !OUTPUTS_MODIFIABLE,
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn sign(&mut self, key: &PrivateKey) -> Result<usize> {
+        let mut signed = 0;
+        for i in 0..self.inputs.len() {
+            if self.sign_input(i, key)? {
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+
+    // Signs with every key whose origin names this master key, the way a
+    // hardware wallet handles a PSBT from a watch-only coordinator
+    pub fn sign_with_xprv(&mut self, master: &ExtendedPrivKey) -> Result<usize> {
+        let fingerprint = master.fingerprint();
+        let mut signed = 0;
+        for i in 0..self.inputs.len() {
+            let sources = self.inputs[i]
+                .bip32_derivation
+                .iter()
+                .filter(|(_, (fp, _))| fp == &fingerprint)
+                .map(|(sec, (_, path))| (sec.clone(), path.clone()))
+                .collect::<Vec<_>>();
+            for (sec, path) in sources {
+                let key = master.derive_path(&path)?.private_key;
+                if key.point.sec(sec.len() == 33) != sec {
+                    continue;
+                }
+                if self.sign_input(i, &key)? {
+                    signed += 1;
+                }
+            }
+        }
+        Ok(signed)
+    }
+
+    // Finalizer: builds the scriptSig and witness for inputs spending single
+    // key or multisig scripts, bare or wrapped in P2SH and P2WSH
+    pub fn finalize_input(&mut self, input_index: usize) -> Result<()> {
+        if self.inputs[input_index].is_finalized() {
+            return Ok(());
+        }
+        let (script_code, amount) = self.script_code(input_index)?;
+        let input = &self.inputs[input_index];
+        let sig_for = |sec: &[u8]| input.partial_sigs.get(sec).cloned();
+
+        let mut stack = if let Some((k, keys)) = parse_multisig(&script_code) {
+            let sigs = keys
+                .iter()
+                .filter_map(|sec| sig_for(sec))
+                .collect::<Vec<_>>();
+            if sigs.len() < k {
+                return Err(anyhow!(
+                    "Input {} has {} of {} signatures",
+                    input_index,
+                    sigs.len(),
+                    k
+                ));
+            }
+            let mut stack = vec![vec![]];
+            stack.extend(sigs.into_iter().take(k));
+            stack
+        } else if script_code.is_p2pkh() {
+            let h160 = &script_code.as_bytes()[3..23];
+            let (sec, sig) = input
+                .partial_sigs
+                .iter()
+                .find(|(sec, _)| hash160(sec) == h160)
+                .ok_or_else(|| anyhow!("Input {} has no signature", input_index))?;
+            vec![sig.clone(), sec.clone()]
+        } else {
+            match script_code.cmds()?.as_slice() {
+                [Cmd::Data(sec), Cmd::Op(OP_CHECKSIG)] => vec![sig_for(sec)
+                    .ok_or_else(|| anyhow!("Input {} has no signature", input_index))?],
+                _ => {
+                    return Err(anyhow!(
+                        "Input {} has a script that cannot be finalized",
+                        input_index
+                    ))
+                }
+            }
+        };
+
+        let push_all = |items: &[Vec<u8>]| {
+            Script::from_cmds(&items.iter().cloned().map(Cmd::Data).collect::<Vec<_>>())
+        };
+        let redeem_script = input.redeem_script.as_ref().map(|s| s.as_bytes().to_vec());
+        let input = &mut self.inputs[input_index];
+        if amount.is_some() {
+            if input.witness_script.is_some() {
+                stack.push(script_code.as_bytes().to_vec());
+            }
+            input.final_script_witness = Some(stack);
+            // Native segwit spends leave the scriptSig empty
+            input.final_script_sig = redeem_script.map(|s| push_all(&[s]));
+        } else {
+            stack.extend(redeem_script);
+            input.final_script_sig = Some(push_all(&stack));
+        }
+
+        // Everything but the UTXOs and the final fields is dropped once final
+        input.partial_sigs.clear();
+        input.sighash_type = None;
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation.clear();
+        input.ripemd160_preimages.clear();
+        input.sha256_preimages.clear();
+        input.hash160_preimages.clear();
+        input.hash256_preimages.clear();
+        Ok(())
+    }
+
+    pub fn finalize(&mut self) -> Result<()> {
+        for i in 0..self.inputs.len() {
+            self.finalize_input(i)?;
+        }
+        Ok(())
+    }
+
+    // Extractor
+    pub fn extract(&self) -> Result<Tx> {
+        let mut tx = self.tx.clone();
+        if self.version >= 2 {
+            tx.locktime = self.locktime()?;
+        }
+        for (i, (tx_in, input)) in tx.tx_ins.iter_mut().zip(&self.inputs).enumerate() {
+            if !input.is_finalized() {
+                return Err(anyhow!("Input {} is not finalized", i));
+            }
+            tx_in.script_sig = input.final_script_sig.clone().unwrap_or_default();
+            tx_in.witness = input.final_script_witness.clone().unwrap_or_default();
+        }
+        Ok(tx)
+    }
+}
+
+impl fmt::Display for Psbt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.serialize_base64())
+    }
+}
+
+// BIP174 signer 1 output and the combined PSBT for the same transaction
+#[cfg(test)]
+const SIGNER1_PSBT: &str = "70736274ff01009a020000000258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd750000000000ffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d0100000000ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f00000000000100bb0200000001aad73931018bd25f84ae400b68848be09db706eac2ac18298babee71ab656f8b0000000048473044022058f6fc7c6a33e1b31548d481c826c015bd30135aad42cd67790dab66d2ad243b02204a1ced2604c6735b6393e5b41691dd78b00f0c5942fb9f751856faa938157dba01feffffff0280f0fa020000000017a9140fb9463421696b82c833af241c78c17ddbde493487d0f20a270100000017a91429ca74f8a08f81999428185c97b5d852e4063f6187650000002202029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f473044022074018ad4180097b873323c0015720b3684cc8123891048e7dbcd9b55ad679c99022073d369b740e3eb53dcefa33823c8070514ca55a7dd9544f157c167913261118c01010304010000000104475221029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f2102dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d752ae2206029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f10d90c6a4f000000800000008000000080220602dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d710d90c6a4f0000008000000080010000800001012000c2eb0b0000000017a914b7f5faf40e3d40a5a459b1db3535f2b72fa921e887220203089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc473044022062eb7a556107a7c73f45ac4ab5a1dddf6f7075fb1275969a7f383efff784bcb202200c05dbb7470dbf2f08557dd356c7325c1ed30913e996cd3840945db12228da5f010103040100000001042200208c2353173743b595dfb4a07b72ba8e42e3797da74e87fe7d9d7497e3b2028903010547522103089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc21023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7352ae2206023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7310d90c6a4f000000800000008003000080220603089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc10d90c6a4f00000080000000800200008000220203a9a4c37f5996d3aa25dbac6b570af0650394492942460b354753ed9eeca5877110d90c6a4f000000800000008004000080002202027f6399757d2eff55a136ad02c684b1838b6556e5f1b6b34282a94b6b5005109610d90c6a4f00000080000000800500008000";
+#[cfg(test)]
+const COMBINED_PSBT: &str = "70736274ff01009a020000000258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd750000000000ffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d0100000000ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f00000000000100bb0200000001aad73931018bd25f84ae400b68848be09db706eac2ac18298babee71ab656f8b0000000048473044022058f6fc7c6a33e1b31548d481c826c015bd30135aad42cd67790dab66d2ad243b02204a1ced2604c6735b6393e5b41691dd78b00f0c5942fb9f751856faa938157dba01feffffff0280f0fa020000000017a9140fb9463421696b82c833af241c78c17ddbde493487d0f20a270100000017a91429ca74f8a08f81999428185c97b5d852e4063f6187650000002202029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f473044022074018ad4180097b873323c0015720b3684cc8123891048e7dbcd9b55ad679c99022073d369b740e3eb53dcefa33823c8070514ca55a7dd9544f157c167913261118c01220202dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d7483045022100f61038b308dc1da865a34852746f015772934208c6d24454393cd99bdf2217770220056e675a675a6d0a02b85b14e5e29074d8a25a9b5760bea2816f661910a006ea01010304010000000104475221029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f2102dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d752ae2206029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f10d90c6a4f000000800000008000000080220602dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d710d90c6a4f0000008000000080010000800001012000c2eb0b0000000017a914b7f5faf40e3d40a5a459b1db3535f2b72fa921e887220203089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc473044022062eb7a556107a7c73f45ac4ab5a1dddf6f7075fb1275969a7f383efff784bcb202200c05dbb7470dbf2f08557dd356c7325c1ed30913e996cd3840945db12228da5f012202023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e73473044022065f45ba5998b59a27ffe1a7bed016af1f1f90d54b3aa8f7450aa5f56a25103bd02207f724703ad1edb96680b284b56d4ffcb88f7fb759eabbe08aa30f29b851383d2010103040100000001042200208c2353173743b595dfb4a07b72ba8e42e3797da74e87fe7d9d7497e3b2028903010547522103089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc21023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7352ae2206023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7310d90c6a4f000000800000008003000080220603089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc10d90c6a4f00000080000000800200008000220203a9a4c37f5996d3aa25dbac6b570af0650394492942460b354753ed9eeca5877110d90c6a4f000000800000008004000080002202027f6399757d2eff55a136ad02c684b1838b6556e5f1b6b34282a94b6b5005109610d90c6a4f00000080000000800500008000";
+
+#[cfg(test)]
+fn test_psbt(hex: &str) -> Psbt {
+    use crate::helper::decode_hex;
+
+    Psbt::parse(&mut decode_hex(hex).unwrap().as_slice(), Network::Testnet3).unwrap()
+}
+
+#[cfg(test)]
+fn test_master() -> ExtendedPrivKey {
+    "tprv8ZgxMBicQKsPd9TeAdPADNnSyH9SSUUbTVeFszDE23Ki6TBB5nCefAdHkK8Fm3qMQR6sHwA56zqRmKmxnHk37JkiFzvncDqoKmPWubu7hDF"
+        .parse()
+        .unwrap()
+}
+
+#[cfg(test)]
+fn strip_signatures(psbt: &Psbt) -> Psbt {
+    let mut psbt = psbt.clone();
+    for input in psbt.inputs.iter_mut() {
+        input.partial_sigs.clear();
+    }
+    psbt
+}
+
+#[test]
+fn test_valid_vectors() {
+    use crate::helper::decode_hex;
+
+    let vectors = [
+        "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab300000000000000",
+        "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac000000000001076a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000",
+        "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000001030401000000000000",
+        "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac00000000000100df0200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf6000000006a473044022070b2245123e6bf474d60c5b50c043d4c691a5d2435f09a34a7662a9dc251790a022001329ca9dacf280bdf30740ec0390422422c81cb45839457aeb76fc12edd95b3012102657d118d3357b8e0f4c2cd46db7b39f6d9c38d9a70abcb9b2de5dc8dbfe4ce31feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e13000001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb8230800220202ead596687ca806043edc3de116cdf29d5e9257c196cd055cf698c8d02bf24e9910b4a6ba670000008000000080020000800022020394f62be9df19952c5587768aeb7698061ad2c4a25c894f47d8c162b4d7213d0510b4a6ba6700000080010000800200008000",
+        "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+        "70736274ff01003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000a0f0102030405060708090f0102030405060708090a0b0c0d0e0f0000",
+    ];
+    let psbts = vectors.iter().map(|hex| test_psbt(hex)).collect::<Vec<_>>();
+    for (psbt, hex) in psbts.iter().zip(&vectors) {
+        assert_eq!(psbt.serialize(), decode_hex(hex).unwrap());
+    }
+
+    assert_eq!(
+        psbts[0].serialize_base64(),
+        "cHNidP8BAHUCAAAAASaBcTce3/KF6Tet7qSze3gADAVmy7OtZGQXE8pCFxv2AAAAAAD+////AtPf9QUAAAAAGXapFNDFmQPFusKGh2DpD9UhpGZap2UgiKwA4fUFAAAAABepFDVF5uM7gyxHBQ8k0+65PJwDlIvHh7MuEwAAAQD9pQEBAAAAAAECiaPHHqtNIOA3G7ukzGmPopXJRjr6Ljl/hTPMti+VZ+UBAAAAFxYAFL4Y0VKpsBIDna89p95PUzSe7LmF/////4b4qkOnHf8USIk6UwpyN+9rRgi7st0tAXHmOuxqSJC0AQAAABcWABT+Pp7xp0XpdNkCxDVZQ6vLNL1TU/////8CAMLrCwAAAAAZdqkUhc/xCX/Z4Ai7NK9wnGIZeziXikiIrHL++E4sAAAAF6kUM5cluiHv1irHU6m80GfWx6ajnQWHAkcwRAIgJxK+IuAnDzlPVoMR3HyppolwuAJf3TskAinwf4pfOiQCIAGLONfc0xTnNMkna9b7QPZzMlvEuqFEyADS8vAtsnZcASED0uFWdJQbrUqZY3LLh+GFbTZSYG2YVi/jnF6efkE/IQUCSDBFAiEA0SuFLYXc2WHS9fSrZgZU327tzHlMDDPOXMMJ/7X85Y0CIGczio4OFyXBl/saiK9Z9R5E5CVbIBZ8hoQDHAXR8lkqASECI7cr7vCWXRC+B3jv7NYfysb3mk6haTkzgHNEZPhPKrMAAAAAAAAA"
+    );
+    assert_eq!(
+        Psbt::parse_base64(&psbts[0].to_string(), Network::Testnet3).unwrap(),
+        psbts[0]
+    );
+    assert_eq!(
+        psbts[0].inputs[0].non_witness_utxo.as_ref().unwrap().id(),
+        "f61b1742ca13176464adb3cb66050c00787bb3a4eead37e985f2df1e37718126"
+    );
+
+    assert!(psbts[1].inputs[0].final_script_sig.is_some());
+    let redeem_script = psbts[1].inputs[1].redeem_script.as_ref().unwrap();
+    assert!(redeem_script.is_p2wpkh());
+    assert_eq!(
+        Script::p2sh(&hash160(redeem_script.as_bytes())),
+        psbts[1].inputs[1]
+            .witness_utxo
+            .as_ref()
+            .unwrap()
+            .script_pubkey
+    );
+
+    assert_eq!(psbts[2].inputs[0].sighash_type, Some(SIGHASH_ALL));
+    assert!(psbts[2].utxo(0).unwrap().script_pubkey.is_p2pkh());
+
+    assert_eq!(psbts[3].outputs[0].bip32_derivation.len(), 1);
+    assert!(psbts[4].inputs[0]
+        .redeem_script
+        .as_ref()
+        .unwrap()
+        .is_p2wsh());
+
+    assert_eq!(
+        psbts[5].tx.id(),
+        "75c5c9665a570569ad77dd1279e6fd4628a093c4dcbf8d41532614044c14c115"
+    );
+    assert_eq!(
+        psbts[5].inputs[0]
+            .unknown
+            .get(&decode_hex("0f010203040506070809").unwrap()),
+        Some(&decode_hex("0102030405060708090a0b0c0d0e0f").unwrap())
+    );
+}
+
+#[test]
+fn test_invalid_vectors() {
+    use crate::helper::decode_hex;
+
+    for hex in &[
+        // Network transaction, not a PSBT
+        "0200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf6000000006a473044022070b2245123e6bf474d60c5b50c043d4c691a5d2435f09a34a7662a9dc251790a022001329ca9dacf280bdf30740ec0390422422c81cb45839457aeb76fc12edd95b3012102657d118d3357b8e0f4c2cd46db7b39f6d9c38d9a70abcb9b2de5dc8dbfe4ce31feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300",
+        // Missing output maps
+        "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000000",
+        // Unsigned transaction with a scriptSig
+        "70736274ff0100fd0a010200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be4000000006a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa88292feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac00000000000001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000",
+        // No unsigned transaction
+        "70736274ff000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000000",
+        // Duplicate keys in an input map
+        "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000001003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000000",
+    ] {
+        let b = decode_hex(hex).unwrap();
+        assert!(Psbt::parse(&mut b.as_slice(), Network::Testnet3).is_err());
+    }
+}
+
+#[test]
+fn test_sign() {
+    use crate::helper::decode_hex;
+
+    let signer1 = test_psbt(SIGNER1_PSBT);
+    let combined = test_psbt(COMBINED_PSBT);
+    let master = test_master();
+    let key = |path: &str| {
+        master
+            .derive_path(&path.parse().unwrap())
+            .unwrap()
+            .private_key
+    };
+
+    let mut psbt = strip_signatures(&signer1);
+    assert_eq!(psbt.sign(&key("m/0'/0'/0'")).unwrap(), 1);
+    assert_eq!(psbt.sign(&key("m/0'/0'/2'")).unwrap(), 1);
+    assert_eq!(psbt.sign(&key("m/0'/0'/4'")).unwrap(), 0);
+    assert_eq!(psbt.serialize(), decode_hex(SIGNER1_PSBT).unwrap());
+
+    // The second signer in BIP174 does not grind for a low R, so only its
+    // 72 byte signature differs from ours
+    let mut psbt = strip_signatures(&combined);
+    assert_eq!(psbt.sign_with_xprv(&master).unwrap(), 4);
+    let high_r =
+        decode_hex("02dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d7").unwrap();
+    for (input, expected) in psbt.inputs.iter().zip(&combined.inputs) {
+        assert_eq!(input.partial_sigs.len(), 2);
+        for (key, sig) in &input.partial_sigs {
+            if key == &high_r {
+                assert_eq!((sig.len(), expected.partial_sigs[key].len()), (71, 72));
+            } else {
+                assert_eq!(sig, &expected.partial_sigs[key]);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_combine() {
+    let signer1 = test_psbt(SIGNER1_PSBT);
+    let combined = test_psbt(COMBINED_PSBT);
+    let mut signer2 = combined.clone();
+    for (input, signed) in signer2.inputs.iter_mut().zip(&signer1.inputs) {
+        for key in signed.partial_sigs.keys() {
+            input.partial_sigs.remove(key);
+        }
+    }
+
+    let mut psbt = signer1.clone();
+    psbt.combine(&signer2).unwrap();
+    assert_eq!(psbt, combined);
+    let mut psbt = signer2.clone();
+    psbt.combine(&signer1).unwrap();
+    assert_eq!(psbt, combined);
+
+    let other = test_psbt("70736274ff01003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000a0f0102030405060708090f0102030405060708090a0b0c0d0e0f0000");
+    assert!(psbt.combine(&other).is_err());
+}
+
+#[test]
+fn test_finalize_and_extract() {
+    let mut psbt = test_psbt(COMBINED_PSBT);
+    assert!(psbt.extract().is_err());
+    psbt.finalize().unwrap();
+    assert!(psbt.inputs.iter().all(|input| input.partial_sigs.is_empty()
+        && input.bip32_derivation.is_empty()
+        && input.redeem_script.is_none()));
+    let tx = psbt.extract().unwrap();
+
+    // P2SH 2-of-2: OP_0, both signatures in key order, then the redeem script
+    let combined = test_psbt(COMBINED_PSBT);
+    let input = &combined.inputs[0];
+    let redeem_script = input.redeem_script.clone().unwrap();
+    let (_, keys) = parse_multisig(&redeem_script).unwrap();
+    let mut expected = vec![Cmd::Op(OP_0)];
+    for key in &keys {
+        expected.push(Cmd::Data(input.partial_sigs[key].clone()));
+    }
+    expected.push(Cmd::Data(redeem_script.as_bytes().to_vec()));
+    assert_eq!(tx.tx_ins[0].script_sig.cmds().unwrap(), expected);
+    assert!(tx.tx_ins[0].witness.is_empty());
+
+    // P2SH-P2WSH 2-of-2: the scriptSig only pushes the P2WSH program
+    let input = &combined.inputs[1];
+    let witness_script = input.witness_script.clone().unwrap();
+    assert_eq!(
+        tx.tx_ins[1].script_sig.cmds().unwrap(),
+        vec![Cmd::Data(
+            input.redeem_script.as_ref().unwrap().as_bytes().to_vec()
+        )]
+    );
+    let (_, keys) = parse_multisig(&witness_script).unwrap();
+    let mut expected = vec![vec![]];
+    for key in &keys {
+        expected.push(input.partial_sigs[key].clone());
+    }
+    expected.push(witness_script.as_bytes().to_vec());
+    assert_eq!(tx.tx_ins[1].witness, expected);
+    assert_eq!(
+        Tx::parse(&mut tx.serialize().as_slice(), Network::Testnet3).unwrap(),
+        tx
+    );
+
+    let mut psbt = test_psbt(SIGNER1_PSBT);
+    assert!(psbt.finalize().is_err());
+}
+
+#[test]
+fn test_descriptor_round_trip() {
+    let master = test_master();
+    let descriptor = Descriptor::parse(&format!(
+        "wpkh([{}/84'/1'/0']{}/0/*)",
+        master.fingerprint(),
+        master
+            .derive_path(&"m/84'/1'/0'".parse().unwrap())
+            .unwrap()
+            .to_pub()
+    ))
+    .unwrap();
+    let change = Descriptor::parse(
+        "sh(wpkh(02dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d7))",
+    )
+    .unwrap();
+
+    let utxo = TxOut::new(100_000, descriptor.script_pubkey(5).unwrap());
+    let tx = Tx::new(
+        2,
+        vec![TxIn::new([0x11; 32], 1, None, 0xfffffffd)],
+        vec![
+            TxOut::new(50_000, Script::p2wpkh(&[0x22; 20])),
+            TxOut::new(49_000, change.script_pubkey(0).unwrap()),
+        ],
+        0,
+        Network::Testnet3,
+    );
+    let mut psbt = Psbt::from_tx(tx).unwrap();
+    psbt.inputs[0].witness_utxo = Some(utxo);
+    psbt.update_input_with_descriptor(0, &descriptor, 5)
+        .unwrap();
+    psbt.update_output_with_descriptor(1, &change, 0).unwrap();
+    assert!(psbt.update_output_with_descriptor(0, &change, 0).is_err());
+    assert_eq!(
+        psbt.outputs[1].redeem_script,
+        change.redeem_script(0).unwrap()
+    );
+
+    // The air-gapped signer only sees the base64 form
+    let mut offline = Psbt::parse_base64(&psbt.to_string(), Network::Testnet3).unwrap();
+    assert_eq!(offline.sign_with_xprv(&master).unwrap(), 1);
+    psbt.combine(&offline).unwrap();
+    psbt.finalize().unwrap();
+    let tx = psbt.extract().unwrap();
+
+    let key = master
+        .derive_path(&"m/84'/1'/0'/0/5".parse().unwrap())
+        .unwrap()
+        .private_key;
+    let sec = key.point.sec(true);
+    assert_eq!(tx.tx_ins[0].witness.len(), 2);
+    assert_eq!(tx.tx_ins[0].witness[1], sec);
+    assert!(tx.tx_ins[0].script_sig.is_empty());
+    let sig = &tx.tx_ins[0].witness[0];
+    let z = tx
+        .sig_hash_bip143(0, &Script::p2pkh(&hash160(&sec)), 100_000, SIGHASH_ALL)
+        .unwrap();
+    assert!(key.point.verify(
+        BigInt::from_bytes_be(Sign::Plus, &z),
+        Signature::parse(&sig[..sig.len() - 1]).unwrap()
+    ));
+}
+
+#[test]
+fn test_version_2() {
+    let v0 = test_psbt(COMBINED_PSBT);
+    let v2 = v0.to_v2().unwrap();
+    let parsed = Psbt::parse(&mut v2.serialize().as_slice(), Network::Testnet3).unwrap();
+    assert_eq!(parsed, v2);
+    assert_eq!(parsed.to_v0().unwrap(), v0);
+    assert_eq!(
+        Psbt::parse_base64(&v2.to_string(), Network::Testnet3).unwrap(),
+        v2
+    );
+
+    let mut psbt = v2;
+    psbt.inputs[0].required_height_locktime = Some(100);
+    psbt.inputs[1].required_height_locktime = Some(200);
+    psbt.inputs[1].required_time_locktime = Some(LOCKTIME_THRESHOLD + 1);
+    assert_eq!(psbt.locktime().unwrap(), 200);
+    psbt.inputs[0].required_height_locktime = None;
+    psbt.inputs[0].required_time_locktime = Some(LOCKTIME_THRESHOLD + 5);
+    assert_eq!(psbt.locktime().unwrap(), LOCKTIME_THRESHOLD + 5);
+    psbt.inputs[1].required_time_locktime = None;
+    assert!(psbt.locktime().is_err());
+    assert!(psbt.to_v0().is_err());
+
+    // Version 2 fields are rejected in version 0 PSBTs. The transaction
+    // version pair goes right after the 0x9a byte unsigned transaction.
+    let mut b = v0.serialize();
+    let at = MAGIC.len() + 3 + 0x9a;
+    b.splice(at..at, vec![0x01, 0x02, 0x04, 0x02, 0x00, 0x00, 0x00]);
+    assert!(Psbt::parse(&mut b.as_slice(), Network::Testnet3).is_err());
+}
@@ -1,12 +1,16 @@
 use crate::field_element::{FieldElement, Prime};
-use crate::helper::{encode_base58_checksum, hash160};
-use crate::point::{Curve, CurvePoint};
-use anyhow::Result;
+use crate::helper::{
+    decode_base58_checksum, encode_base58_checksum, hash160, hmac_sha256, tagged_hash,
+};
+use crate::network::Network;
+use crate::point::{Curve, CurvePoint, Point};
+use anyhow::{anyhow, Result};
 use num_bigint::{BigInt, Sign};
 use num_traits::Pow;
 use once_cell::sync::Lazy;
-use rand::seq::IteratorRandom;
+use std::fmt;
 use std::ops;
+use zeroize::Zeroizing;
 
 static A: Lazy<BigInt> = Lazy::new(|| BigInt::from(0));
 static B: Lazy<BigInt> = Lazy::new(|| BigInt::from(7));
@@ -19,14 +23,14 @@ static C: Lazy<Curve<S256Field>> = Lazy::new(|| {
     )
 });
 
-static N: Lazy<BigInt> = Lazy::new(|| {
+pub static N: Lazy<BigInt> = Lazy::new(|| {
     BigInt::parse_bytes(
         b"fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141",
         16,
     )
     .unwrap()
 });
-static G: Lazy<S256Point> = Lazy::new(|| {
+pub static G: Lazy<S256Point> = Lazy::new(|| {
     S256Point::new(
         BigInt::parse_bytes(
             b"79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
@@ -53,7 +57,7 @@ impl<'a> S256Field<'a> {
     }
 
     fn sqrt(&self) -> S256Field<'a> {
-        self.pow(&((&(*P).0 + 1) / 4)).into()
+        self.pow(&((&P.0 + 1) / 4)).into()
     }
 }
 
@@ -77,6 +81,13 @@ impl<'a> From<FieldElement<'a>> for S256Field<'a> {
     }
 }
 
+pub fn to_32_bytes(n: &BigInt) -> Vec<u8> {
+    let mut bytes = n.to_bytes_be().1;
+    let mut result = vec![0; 32 - bytes.len()];
+    result.append(&mut bytes);
+    result
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct S256Point<'a> {
     pub cp: CurvePoint<'a, S256Field<'a>>,
@@ -95,9 +106,9 @@ impl<'a> S256Point<'a> {
             (&S256Field::from(x.pow(&3.into())).inner + &S256Field::new(B.clone()).inner)?.into();
         let beta = alpha.sqrt();
         let (even_beta, odd_beta) = if &beta.num % 2 == BigInt::from(0) {
-            (beta.clone(), S256Field::new(&(*P).0 - &beta.num))
+            (beta.clone(), S256Field::new(&P.0 - &beta.num))
         } else {
-            (S256Field::new(&(*P).0 - &beta.num), beta)
+            (S256Field::new(&P.0 - &beta.num), beta)
         };
 
         if is_even {
@@ -119,43 +130,138 @@ impl<'a> S256Point<'a> {
     }
 
     pub fn verify(&self, z: BigInt, sig: Signature) -> bool {
-        let s_inv = sig.s.modpow(&(&*N - 2), &*N);
+        let zero = BigInt::from(0);
+        if sig.r <= zero || sig.r >= *N || sig.s <= zero || sig.s >= *N {
+            return false;
+        }
+        let s_inv = sig.s.modpow(&(&*N - 2), &N);
         let u = z * &s_inv % &*N;
         let v = &sig.r * s_inv % &*N;
         let total = (&*(u * G.clone()).unwrap() + &*(v * self.clone()).unwrap()).unwrap();
-        let r = &total.p.as_actual().x.num;
-        r == &sig.r
+        match &total.p {
+            Point::Actual(p) => &p.x.num % &*N == sig.r,
+            Point::Inf => false,
+        }
+    }
+
+    // BIP340: checks a 64-byte signature against the x-only key of this point
+    pub fn verify_schnorr(&self, msg: &[u8], sig: &[u8]) -> bool {
+        if sig.len() != 64 || self.cp.p == Point::Inf {
+            return false;
+        }
+        let r = BigInt::from_bytes_be(Sign::Plus, &sig[..32]);
+        let s = BigInt::from_bytes_be(Sign::Plus, &sig[32..]);
+        if r >= P.0 || s >= *N {
+            return false;
+        }
+        let point = match S256Point::lift_x(&self.xonly()) {
+            Ok(point) => point,
+            Err(_) => return false,
+        };
+        let challenge = tagged_hash(
+            "BIP0340/challenge",
+            &[&sig[..32], &point.xonly(), msg].concat(),
+        );
+        let e = BigInt::from_bytes_be(Sign::Plus, &challenge) % &*N;
+        let total = match (s * G.clone(), (&*N - e) * point) {
+            (Ok(sg), Ok(ep)) => &*sg + &*ep,
+            _ => return false,
+        };
+        match total.map(|total| total.p) {
+            Ok(Point::Actual(p)) => &p.y.num % 2 == BigInt::from(0) && p.x.num == r,
+            _ => false,
+        }
     }
 
     pub fn sec(&self, compressed: bool) -> Vec<u8> {
         if compressed {
             if &self.cp.p.as_actual().y.num % 2 == BigInt::from(0) {
                 let mut result = vec![0x02];
-                result.append(&mut self.cp.p.as_actual().x.num.to_bytes_be().1);
+                result.append(&mut to_32_bytes(&self.cp.p.as_actual().x.num));
                 return result;
             }
             let mut result = vec![0x03];
-            result.append(&mut self.cp.p.as_actual().x.num.to_bytes_be().1);
+            result.append(&mut to_32_bytes(&self.cp.p.as_actual().x.num));
             return result;
         }
 
         let mut result = vec![0x04];
-        result.append(&mut self.cp.p.as_actual().x.num.to_bytes_be().1);
-        result.append(&mut self.cp.p.as_actual().y.num.to_bytes_be().1);
+        result.append(&mut to_32_bytes(&self.cp.p.as_actual().x.num));
+        result.append(&mut to_32_bytes(&self.cp.p.as_actual().y.num));
 
         result
     }
 
-    fn hash160(&self, compressed: bool) -> Vec<u8> {
+    pub fn hash160(&self, compressed: bool) -> Vec<u8> {
         hash160(&self.sec(compressed))
     }
 
-    fn address(&self, compressed: bool, testnet: bool) -> String {
+    pub fn address(&self, compressed: bool, network: Network) -> String {
         let mut h160 = self.hash160(compressed);
-        let prefix: u8 = if testnet { 0x6f } else { 0x00 };
-        h160.insert(0, prefix);
+        h160.insert(0, network.p2pkh_prefix());
         encode_base58_checksum(&h160)
     }
+
+    pub fn xonly(&self) -> Vec<u8> {
+        to_32_bytes(&self.cp.p.as_actual().x.num)
+    }
+
+    pub fn has_even_y(&self) -> bool {
+        &self.cp.p.as_actual().y.num % 2 == BigInt::from(0)
+    }
+
+    // BIP340: the point with the given x coordinate and an even y
+    pub fn lift_x(xonly: &[u8]) -> Result<S256Point<'a>> {
+        if xonly.len() != 32 {
+            return Err(anyhow!("x-only key must be 32 bytes"));
+        }
+        let x = BigInt::from_bytes_be(Sign::Plus, xonly);
+        if x >= P.0 {
+            return Err(anyhow!("x-only key is not a field element"));
+        }
+        let mut sec = vec![0x02];
+        sec.extend_from_slice(xonly);
+        S256Point::parse(&sec)
+    }
+
+    // The public key that made `sig` over `z`. The recovery id is the parity
+    // of R's y coordinate; ids for r >= n - p are not supported.
+    pub fn recover(z: BigInt, sig: &Signature, recovery_id: u8) -> Result<S256Point<'static>> {
+        let n = &*N;
+        let zero = BigInt::from(0);
+        if recovery_id > 1 || sig.r <= zero || sig.r >= *n || sig.s <= zero || sig.s >= *n {
+            return Err(anyhow!("Invalid signature for key recovery"));
+        }
+        let r_point =
+            S256Point::parse(&[&[0x02 | recovery_id][..], &to_32_bytes(&sig.r)].concat())?;
+        let r_inv = sig.r.modpow(&(n - 2), n);
+        let u1 = (n - z % n) * &r_inv % n;
+        let u2 = &sig.s * r_inv % n;
+        let q: S256Point = (&*(u1 * G.clone())? + &*(u2 * r_point)?)?.into();
+        if q.cp.p == Point::Inf {
+            return Err(anyhow!("Recovered key is infinity"));
+        }
+        Ok(q)
+    }
+
+    // BIP341: the output key committing to this internal key and an optional
+    // script tree
+    pub fn tap_tweak(&self, merkle_root: Option<&[u8]>) -> Result<S256Point<'a>> {
+        let internal = S256Point::lift_x(&self.xonly())?;
+        let mut msg = internal.xonly();
+        if let Some(root) = merkle_root {
+            msg.extend_from_slice(root);
+        }
+        let t = BigInt::from_bytes_be(Sign::Plus, &tagged_hash("TapTweak", &msg));
+        if t >= *N {
+            return Err(anyhow!("Taproot tweak is out of range"));
+        }
+        let q: S256Point = (&*(t * G.clone())? + &*internal)?.into();
+        if q.cp.p == Point::Inf {
+            return Err(anyhow!("Taproot output key is infinity"));
+        }
+        Ok(q)
+    }
 }
 
 impl<'a> ops::Deref for S256Point<'a> {
@@ -175,6 +281,7 @@ impl<'a> From<CurvePoint<'a, S256Field<'a>>> for S256Point<'a> {
 impl<'a> ops::Mul<S256Point<'a>> for BigInt {
     type Output = Result<S256Point<'a>>;
 
+    #[allow(clippy::suspicious_arithmetic_impl)]
     fn mul(self, other: S256Point<'a>) -> Result<S256Point<'a>> {
         let coef = self % &*N;
         coef.mul(other.cp).map(|cp| cp.into())
@@ -192,7 +299,51 @@ impl Signature {
         Self { r, s }
     }
 
-    fn der(&self) -> Vec<u8> {
+    pub fn parse(der: &[u8]) -> Result<Self> {
+        if der.len() < 8 || der[0] != 0x30 || der[1] as usize != der.len() - 2 {
+            return Err(anyhow!("Invalid DER signature framing"));
+        }
+        let (r, rest) = parse_der_integer(&der[2..])?;
+        let (s, rest) = parse_der_integer(rest)?;
+        if !rest.is_empty() {
+            return Err(anyhow!("Trailing bytes after DER signature"));
+        }
+        Ok(Self::new(r, s))
+    }
+
+    // Bitcoin Core's lax DER parsing, which accepts the signatures mined
+    // before BIP66. Integers too large for the curve give a signature that
+    // never verifies.
+    pub fn parse_der_lax(der: &[u8]) -> Result<Self> {
+        let mut pos = 0;
+        if der.first() != Some(&0x30) {
+            return Err(anyhow!("Invalid DER signature framing"));
+        }
+        pos += 1;
+        let len = *der
+            .get(pos)
+            .ok_or_else(|| anyhow!("Truncated DER signature"))? as usize;
+        pos += 1;
+        if len & 0x80 != 0 {
+            // The sequence length is skipped, whatever it says
+            let len = len - 0x80;
+            if len > der.len() - pos {
+                return Err(anyhow!("Truncated DER signature"));
+            }
+            pos += len;
+        }
+        let (r, rest) = parse_der_integer_lax(&der[pos..])?;
+        let (s, _) = parse_der_integer_lax(rest)?;
+        if r.len() > 32 || s.len() > 32 {
+            return Ok(Self::new(BigInt::from(0), BigInt::from(0)));
+        }
+        Ok(Self::new(
+            BigInt::from_bytes_be(Sign::Plus, r),
+            BigInt::from_bytes_be(Sign::Plus, s),
+        ))
+    }
+
+    pub fn der(&self) -> Vec<u8> {
         let rbin = self.r.to_bytes_be().1;
         let mut rbin: Vec<u8> = rbin.into_iter().skip_while(|b| b == &0x00).collect();
         if rbin[0] & 0x80 > 0 {
@@ -215,26 +366,124 @@ impl Signature {
         b.append(&mut result);
         b
     }
+
+    // 32-byte r followed by 32-byte s
+    pub fn parse_compact(b: &[u8]) -> Result<Self> {
+        if b.len() != 64 {
+            return Err(anyhow!("Compact signature must be 64 bytes"));
+        }
+        Ok(Self::new(
+            BigInt::from_bytes_be(Sign::Plus, &b[..32]),
+            BigInt::from_bytes_be(Sign::Plus, &b[32..]),
+        ))
+    }
+
+    pub fn compact(&self) -> Vec<u8> {
+        [to_32_bytes(&self.r), to_32_bytes(&self.s)].concat()
+    }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// Returns the integer's bytes without leading zeros, and what follows it
+fn parse_der_integer_lax(b: &[u8]) -> Result<(&[u8], &[u8])> {
+    if b.len() < 2 || b[0] != 0x02 {
+        return Err(anyhow!("Expected a DER integer"));
+    }
+    let mut pos = 2;
+    let mut len = b[1] as usize;
+    if len & 0x80 != 0 {
+        let mut len_bytes = &b[pos..];
+        if len - 0x80 > len_bytes.len() {
+            return Err(anyhow!("Truncated DER integer"));
+        }
+        len_bytes = &len_bytes[..len - 0x80];
+        pos += len_bytes.len();
+        while len_bytes.first() == Some(&0) {
+            len_bytes = &len_bytes[1..];
+        }
+        if len_bytes.len() >= std::mem::size_of::<usize>() {
+            return Err(anyhow!("DER integer length too large"));
+        }
+        len = len_bytes.iter().fold(0, |acc, b| (acc << 8) + *b as usize);
+    }
+    if len > b.len() - pos {
+        return Err(anyhow!("Truncated DER integer"));
+    }
+    let mut n = &b[pos..pos + len];
+    while n.first() == Some(&0) {
+        n = &n[1..];
+    }
+    Ok((n, &b[pos + len..]))
+}
+
+fn parse_der_integer(b: &[u8]) -> Result<(BigInt, &[u8])> {
+    if b.len() < 3 || b[0] != 0x02 {
+        return Err(anyhow!("Expected a DER integer"));
+    }
+    let len = b[1] as usize;
+    if len == 0 || len > 33 || b.len() < 2 + len {
+        return Err(anyhow!("Invalid DER integer length"));
+    }
+    let n = &b[2..2 + len];
+    if n[0] & 0x80 != 0 || (len > 1 && n[0] == 0x00 && n[1] & 0x80 == 0) {
+        return Err(anyhow!("DER integer is negative or not minimally encoded"));
+    }
+    Ok((BigInt::from_bytes_be(Sign::Plus, n), &b[2 + len..]))
+}
+
+// The secret is kept as bytes which are wiped on drop. Arithmetic works on
+// a BigInt built for the occasion, which is not wiped.
+#[derive(PartialEq)]
 pub struct PrivateKey<'a> {
-    pub secret: BigInt,
+    secret: Zeroizing<[u8; 32]>,
     pub point: S256Point<'a>,
 }
 
 impl<'a> PrivateKey<'a> {
-    fn new(secret: BigInt) -> Self {
-        let point = (secret.clone() * G.clone()).unwrap();
-        Self { secret, point }
+    pub fn new(secret: BigInt) -> Result<Self> {
+        if secret < BigInt::from(1) || secret >= *N {
+            return Err(anyhow!("Private key out of range"));
+        }
+        let point = (secret.clone() * G.clone())?;
+        let bytes = Zeroizing::new(secret.to_bytes_be().1);
+        let mut result = Zeroizing::new([0u8; 32]);
+        result[32 - bytes.len()..].copy_from_slice(&bytes);
+        Ok(Self {
+            secret: result,
+            point,
+        })
+    }
+
+    // Big endian, borrowed so that no unwiped copy is made here
+    pub fn secret_bytes(&self) -> &[u8; 32] {
+        &self.secret
+    }
+
+    fn scalar(&self) -> BigInt {
+        BigInt::from_bytes_be(Sign::Plus, &self.secret[..])
     }
 
+    // Grinds RFC6979 nonces for a low R as Bitcoin Core does, which keeps
+    // the DER encoding at 71 bytes or less
     pub fn sign(&self, z: BigInt) -> Signature {
-        let mut rng = rand::thread_rng();
+        let mut counter = 0u32;
+        loop {
+            let sig = if counter == 0 {
+                self.sign_with_entropy(&z, None)
+            } else {
+                let mut extra = [0u8; 32];
+                extra[..4].copy_from_slice(&counter.to_le_bytes());
+                self.sign_with_entropy(&z, Some(&extra))
+            };
+            if sig.r.bits() < 256 {
+                return sig;
+            }
+            counter += 1;
+        }
+    }
 
+    fn sign_with_entropy(&self, z: &BigInt, extra: Option<&[u8]>) -> Signature {
         let n = &*N;
-        let range = num_iter::range(BigInt::from(0), n.clone());
-        let k = range.choose(&mut rng).unwrap();
+        let k = self.deterministic_k(z, extra);
         let r = (k.clone() * G.clone())
             .unwrap()
             .cp
@@ -243,25 +492,89 @@ impl<'a> PrivateKey<'a> {
             .x
             .num
             .clone();
-        let k_inv = k.modpow(&(n - 2), &n);
-        let mut s = (z + &r * &self.secret) * k_inv % n;
+        let k_inv = k.modpow(&(n - 2), n);
+        let mut s = (z + &r * self.scalar()) * k_inv % n;
         if s > n / 2 {
             s = n - s;
         }
         Signature::new(r, s)
     }
 
-    pub fn wif(&self, compressed: bool, testnet: bool) -> String {
-        let mut secret_bytes_right = self.secret.to_bytes_be().1;
-        let l = 32 - secret_bytes_right.len();
-        let mut secret_bytes = Vec::with_capacity(l);
-        for _ in 0..l {
-            secret_bytes.push(0);
+    // RFC6979 section 3.2, with extra entropy appended to the seed the way
+    // libsecp256k1 does
+    fn deterministic_k(&self, z: &BigInt, extra: Option<&[u8]>) -> BigInt {
+        let mut k = vec![0u8; 32];
+        let mut v = vec![1u8; 32];
+        let mut seed = Zeroizing::new(self.secret.to_vec());
+        seed.append(&mut to_32_bytes(&(z % &*N)));
+        if let Some(extra) = extra {
+            seed.extend_from_slice(extra);
+        }
+
+        k = hmac_sha256(&k, &[&v[..], &[0x00], &seed].concat());
+        v = hmac_sha256(&k, &v);
+        k = hmac_sha256(&k, &[&v[..], &[0x01], &seed].concat());
+        v = hmac_sha256(&k, &v);
+        loop {
+            v = hmac_sha256(&k, &v);
+            let candidate = BigInt::from_bytes_be(Sign::Plus, &v);
+            if candidate > 0.into() && candidate < *N {
+                return candidate;
+            }
+            k = hmac_sha256(&k, &[&v[..], &[0x00]].concat());
+            v = hmac_sha256(&k, &v);
         }
-        secret_bytes.append(&mut secret_bytes_right);
-        let mut result = if testnet { vec![0xef] } else { vec![0x80] };
+    }
 
-        result.append(&mut secret_bytes.to_vec());
+    // BIP340, with 32 bytes of auxiliary randomness mixed into the nonce
+    pub fn sign_schnorr(&self, msg: &[u8], aux: &[u8]) -> Vec<u8> {
+        let n = &*N;
+        let d = if self.point.has_even_y() {
+            self.scalar()
+        } else {
+            n - self.scalar()
+        };
+        let xonly = self.point.xonly();
+        let masked: Vec<u8> = to_32_bytes(&d)
+            .iter()
+            .zip(tagged_hash("BIP0340/aux", aux))
+            .map(|(a, b)| a ^ b)
+            .collect();
+        let nonce = tagged_hash("BIP0340/nonce", &[&masked[..], &xonly, msg].concat());
+        let k = BigInt::from_bytes_be(Sign::Plus, &nonce) % n;
+        let r_point = (k.clone() * G.clone()).unwrap();
+        let k = if r_point.has_even_y() { k } else { n - k };
+        let r = r_point.xonly();
+        let challenge = tagged_hash("BIP0340/challenge", &[&r[..], &xonly, msg].concat());
+        let e = BigInt::from_bytes_be(Sign::Plus, &challenge) % n;
+        let s = (k + e * d) % n;
+        [r, to_32_bytes(&s)].concat()
+    }
+
+    // Returns the key, whether its public key is compressed, and the network
+    // (mainnet or testnet3) the prefix belongs to.
+    pub fn parse_wif(s: &str) -> Result<(Self, bool, Network)> {
+        let b = decode_base58_checksum(s)?;
+        let network = match b.first() {
+            Some(0x80) => Network::Mainnet,
+            Some(0xef) => Network::Testnet3,
+            _ => return Err(anyhow!("Invalid WIF prefix for {}", s)),
+        };
+        let compressed = match b.len() {
+            33 => false,
+            34 if b[33] == 0x01 => true,
+            _ => return Err(anyhow!("Invalid WIF length for {}", s)),
+        };
+        let secret = BigInt::from_bytes_be(Sign::Plus, &b[1..33]);
+        if secret == BigInt::from(0) || secret >= *N {
+            return Err(anyhow!("WIF secret is out of range"));
+        }
+        Ok((Self::new(secret)?, compressed, network))
+    }
+
+    pub fn wif(&self, compressed: bool, network: Network) -> String {
+        let mut result = Zeroizing::new(vec![network.wif_prefix()]);
+        result.extend_from_slice(&self.secret[..]);
 
         if compressed {
             result.push(0x01);
@@ -270,6 +583,26 @@ impl<'a> PrivateKey<'a> {
     }
 }
 
+// Keeps the secret out of logs and panic messages
+impl<'a> fmt::Debug for PrivateKey<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PrivateKey")
+            .field("point", &self.point)
+            .finish_non_exhaustive()
+    }
+}
+
+// Written out so that a copy gets its own buffer, wiped on drop like the
+// original's
+impl<'a> Clone for PrivateKey<'a> {
+    fn clone(&self) -> Self {
+        Self {
+            secret: Zeroizing::new(*self.secret),
+            point: self.point.clone(),
+        }
+    }
+}
+
 #[test]
 fn test_3_9() {
     assert_eq!((N.clone() * G.clone()).unwrap(), C.inf().into());
@@ -304,7 +637,7 @@ fn test_3_11_3() {
     .unwrap();
 
     let point = S256Point::new(px, py).unwrap();
-    let s_inv = s.modpow(&(&*N - 2), &*N);
+    let s_inv = s.modpow(&(&*N - 2), &N);
     let u = z * &s_inv % &*N;
     let v = &r * s_inv % &*N;
     assert_eq!(
@@ -353,7 +686,7 @@ fn test_3_11_4() {
 
 #[test]
 fn test_exam_4_1() {
-    let key = PrivateKey::new(BigInt::from(5000));
+    let key = PrivateKey::new(BigInt::from(5000)).unwrap();
     assert_eq!(
         key.point.sec(false).iter().map(|n| format!("{:02x}", n)).collect::<String>(),
         "04ffe558e388852f0120e46af2d1b370f85854a8eb0841811ece0e3e03d282d57c315dc72890a4f10a1481c031b03b351b0dc79901ca18a00cf009dbdb157a1d10".to_string());
@@ -369,7 +702,7 @@ fn test_exam_4_1() {
 
 #[test]
 fn test_exam_4_2() {
-    let key = PrivateKey::new(BigInt::from(5001));
+    let key = PrivateKey::new(BigInt::from(5001)).unwrap();
     assert_eq!(
         key.point
             .sec(true)
@@ -413,20 +746,151 @@ fn test_exam_4_3() {
 
 #[test]
 fn test_exam_4_5() {
-    let p = PrivateKey::new(BigInt::from(5002));
-    let address = p.point.address(false, true);
+    let p = PrivateKey::new(BigInt::from(5002)).unwrap();
+    let address = p.point.address(false, Network::Testnet3);
     assert_eq!(address, "mmTPbXQFxboEtNRkwfh6K51jvdtHLxGeMA".to_string());
 
-    let p = PrivateKey::new(BigInt::from(2020).pow(&5_u8));
-    let address = p.point.address(true, true);
+    let p = PrivateKey::new(BigInt::from(2020).pow(&5_u8)).unwrap();
+    let address = p.point.address(true, Network::Testnet3);
     assert_eq!(address, "mopVkxp8UhXqRYbCYJsbeE1h1fiF64jcoH".to_string());
+
+    let p = PrivateKey::new(BigInt::parse_bytes(b"12345deadbeef", 16).unwrap()).unwrap();
+    let address = p.point.address(true, Network::Mainnet);
+    assert_eq!(address, "1F1Pn2y6pDb68E5nYJJeba4TLg2U7B6KF1".to_string());
 }
 
 #[test]
 fn test_exam_4_6() {
-    let p = PrivateKey::new(BigInt::parse_bytes(b"54321deadbeef", 16).unwrap());
+    let p = PrivateKey::new(BigInt::parse_bytes(b"54321deadbeef", 16).unwrap()).unwrap();
     assert_eq!(
-        p.wif(true, true),
+        p.wif(true, Network::Testnet3),
         "cMahea7zqjxrtgAbB7LSGbcQUr1uX1ojuat9qKrpR8M8odsZpvec".to_string()
     );
+    assert_eq!(
+        p.wif(true, Network::Mainnet),
+        "KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgiuQJv1h8Ytr2S53a".to_string()
+    );
+
+    let (parsed, compressed, network) =
+        PrivateKey::parse_wif("cMahea7zqjxrtgAbB7LSGbcQUr1uX1ojuat9qKrpR8M8odsZpvec").unwrap();
+    assert_eq!(parsed, p);
+    assert!(compressed);
+    assert_eq!(network, Network::Testnet3);
+    assert!(PrivateKey::parse_wif("cMahea7zqjxrtgAbB7LSGbcQUr1uX1ojuat9qKrpR8M8odsZpveC").is_err());
+}
+
+#[test]
+fn test_tap_tweak() {
+    use crate::helper::{decode_hex, encode_hex};
+
+    // BIP341 wallet test vectors
+    let internal = S256Point::lift_x(
+        &decode_hex("d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d").unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        encode_hex(&internal.tap_tweak(None).unwrap().xonly()),
+        "53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343"
+    );
+
+    let internal = S256Point::lift_x(
+        &decode_hex("187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27").unwrap(),
+    )
+    .unwrap();
+    let root =
+        decode_hex("5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21").unwrap();
+    assert_eq!(
+        encode_hex(&internal.tap_tweak(Some(&root)).unwrap().xonly()),
+        "147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3"
+    );
+}
+
+#[test]
+fn test_deterministic_sign() {
+    use crate::helper::{decode_hex, encode_hex, sha256};
+
+    assert!(PrivateKey::new(BigInt::from(0)).is_err());
+    assert!(PrivateKey::new(N.clone()).is_err());
+    assert!(PrivateKey::new(BigInt::from(1) << 256).is_err());
+    assert!(PrivateKey::new(&*N - 1).is_ok());
+
+    let key = PrivateKey::new(BigInt::from(1)).unwrap();
+    let z = BigInt::from_bytes_be(Sign::Plus, &sha256(b"Satoshi Nakamoto"));
+    assert_eq!(
+        key.deterministic_k(&z, None),
+        BigInt::parse_bytes(
+            b"8f8a276c19f4149656b280621e358cce24f5f52542772691ee69063b74f15d15",
+            16
+        )
+        .unwrap()
+    );
+    let sig = key.sign_with_entropy(&z, None);
+    assert_eq!(sig.der(), decode_hex("3045022100934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d802202442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5").unwrap());
+    assert_eq!(Signature::parse(&sig.der()).unwrap(), sig);
+
+    let sig = key.sign(z.clone());
+    assert!(sig.der().len() <= 71);
+    assert_eq!(key.sign(z.clone()), sig);
+    assert!(key.point.verify(z, sig));
+
+    assert!(Signature::parse(&decode_hex("3006020180020101").unwrap()).is_err());
+    assert!(Signature::parse(&decode_hex("300602010102010100").unwrap()).is_err());
+
+    // Lax parsing takes negative, padded and long form integers, a wrong
+    // sequence length and trailing bytes
+    let lax = |hex: &str| Signature::parse_der_lax(&decode_hex(hex).unwrap());
+    let expected = Signature::new(BigInt::from(0x80), BigInt::from(1));
+    assert_eq!(lax("3006020180020101").unwrap(), expected);
+    assert_eq!(lax("300a0203000080020200010000").unwrap(), expected);
+    assert_eq!(lax("30810002810180028200010100").unwrap(), expected);
+    assert_eq!(lax(&encode_hex(&expected.der())).unwrap(), expected);
+    let big = format!("3026022101{}020101", "00".repeat(32));
+    assert_eq!(lax(&big).unwrap().r, BigInt::from(0));
+    assert!(lax("3006020180").is_err());
+    assert!(lax("3006020480020101").is_err());
+    assert!(lax("3106020180020101").is_err());
+}
+
+#[test]
+fn test_schnorr() {
+    use crate::helper::{decode_hex, encode_hex};
+
+    // BIP340 test vectors 0 and 1
+    let cases = [
+        (
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca821525f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0",
+        ),
+        (
+            "b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+            "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a",
+        ),
+    ];
+    for (secret, aux, msg, expected) in cases.iter() {
+        let key = PrivateKey::new(BigInt::from_bytes_be(
+            Sign::Plus,
+            &decode_hex(secret).unwrap(),
+        ))
+        .unwrap();
+        let msg = decode_hex(msg).unwrap();
+        let sig = key.sign_schnorr(&msg, &decode_hex(aux).unwrap());
+        assert_eq!(encode_hex(&sig), *expected);
+        assert!(key.point.verify_schnorr(&msg, &sig));
+
+        let mut bad = sig.clone();
+        bad[63] ^= 1;
+        assert!(!key.point.verify_schnorr(&msg, &bad));
+        assert!(!key.point.verify_schnorr(&[0xffu8; 32], &sig));
+        assert!(!key.point.verify_schnorr(&msg, &sig[..63]));
+    }
+
+    // The signature is for the x-only key, whatever the parity of the point
+    let key = PrivateKey::new(BigInt::from(3)).unwrap();
+    let negated = PrivateKey::new(&*N - 3).unwrap();
+    let sig = negated.sign_schnorr(&[1u8; 32], &[0u8; 32]);
+    assert!(key.point.verify_schnorr(&[1u8; 32], &sig));
 }
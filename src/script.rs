@@ -0,0 +1,542 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::io::Read;
+
+use crate::helper::{
+    decode_base58_checksum, decode_segwit_address, encode_base58_checksum, encode_hex,
+    encode_segwit_address, encode_variant, read_variant,
+};
+use crate::network::Network;
+use crate::op::*;
+
+pub const MAX_SCRIPT_SIZE: usize = 10000;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Cmd {
+    Op(u8),
+    Data(Vec<u8>),
+}
+
+impl Cmd {
+    // Minimal push of a script number
+    pub fn num(n: i64) -> Self {
+        match n {
+            0 => Cmd::Op(OP_0),
+            -1 => Cmd::Op(OP_1NEGATE),
+            1..=16 => Cmd::Op(OP_1 + n as u8 - 1),
+            _ => Cmd::Data(encode_num(n)),
+        }
+    }
+}
+
+pub fn encode_num(n: i64) -> Vec<u8> {
+    if n == 0 {
+        return vec![];
+    }
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    let mut result = vec![];
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if result.last().unwrap() & 0x80 != 0 {
+        result.push(if negative { 0x80 } else { 0x00 });
+    } else if negative {
+        *result.last_mut().unwrap() |= 0x80;
+    }
+    result
+}
+
+pub fn decode_num(b: &[u8]) -> Result<i64> {
+    if b.len() > 8 {
+        return Err(anyhow!("Script number is longer than 8 bytes"));
+    }
+    if b.is_empty() {
+        return Ok(0);
+    }
+    let mut result: i64 = 0;
+    for (i, byte) in b.iter().enumerate() {
+        result |= (*byte as i64) << (8 * i);
+    }
+    let sign_bit = 0x80_i64 << (8 * (b.len() - 1));
+    if result & sign_bit != 0 {
+        Ok(-(result & !sign_bit))
+    } else {
+        Ok(result)
+    }
+}
+
+// The instruction starting at `i` and the position after it
+pub fn read_instruction(raw: &[u8], i: usize) -> Result<(u8, Cmd, usize)> {
+    let op = raw[i];
+    let mut i = i + 1;
+    let len = match op {
+        1..=0x4b => op as usize,
+        OP_PUSHDATA1 => {
+            let b = raw.get(i).ok_or_else(|| anyhow!("Truncated push"))?;
+            i += 1;
+            *b as usize
+        }
+        OP_PUSHDATA2 => {
+            let b = raw.get(i..i + 2).ok_or_else(|| anyhow!("Truncated push"))?;
+            i += 2;
+            u16::from_le_bytes([b[0], b[1]]) as usize
+        }
+        OP_PUSHDATA4 => {
+            let b = raw.get(i..i + 4).ok_or_else(|| anyhow!("Truncated push"))?;
+            i += 4;
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize
+        }
+        _ => return Ok((op, Cmd::Op(op), i)),
+    };
+    let data = raw
+        .get(i..i + len)
+        .ok_or_else(|| anyhow!("Truncated push"))?;
+    Ok((op, Cmd::Data(data.to_vec()), i + len))
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default, PartialOrd, Ord)]
+pub struct Script {
+    raw: Vec<u8>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { raw: vec![] }
+    }
+
+    pub fn from_bytes(raw: Vec<u8>) -> Self {
+        Self { raw }
+    }
+
+    pub fn from_cmds(cmds: &[Cmd]) -> Self {
+        let mut raw = vec![];
+        for cmd in cmds {
+            match cmd {
+                Cmd::Op(op) => raw.push(*op),
+                Cmd::Data(data) => {
+                    let len = data.len();
+                    if len < OP_PUSHDATA1 as usize {
+                        raw.push(len as u8);
+                    } else if len <= 0xff {
+                        raw.push(OP_PUSHDATA1);
+                        raw.push(len as u8);
+                    } else if len <= 0xffff {
+                        raw.push(OP_PUSHDATA2);
+                        raw.append(&mut (len as u16).to_le_bytes().to_vec());
+                    } else {
+                        raw.push(OP_PUSHDATA4);
+                        raw.append(&mut (len as u32).to_le_bytes().to_vec());
+                    }
+                    raw.extend_from_slice(data);
+                }
+            }
+        }
+        Self { raw }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    // Each instruction with the opcode that produced it, so that callers can
+    // tell how a push was encoded.
+    pub fn instructions(&self) -> Result<Vec<(u8, Cmd)>> {
+        let mut result = vec![];
+        let mut i = 0;
+        while i < self.raw.len() {
+            let (op, cmd, next) = read_instruction(&self.raw, i)?;
+            result.push((op, cmd));
+            i = next;
+        }
+        Ok(result)
+    }
+
+    pub fn cmds(&self) -> Result<Vec<Cmd>> {
+        Ok(self
+            .instructions()?
+            .into_iter()
+            .map(|(_, cmd)| cmd)
+            .collect())
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let len = read_variant(reader)?;
+        let mut raw = vec![];
+        reader.take(len).read_to_end(&mut raw)?;
+        if raw.len() as u64 != len {
+            return Err(anyhow!("Script is shorter than its length prefix"));
+        }
+        Ok(Self { raw })
+    }
+
+    pub fn raw_serialize(&self) -> Vec<u8> {
+        self.raw.clone()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = encode_variant(self.raw.len() as u64);
+        result.extend_from_slice(&self.raw);
+        result
+    }
+
+    pub fn p2pk(sec: &[u8]) -> Self {
+        Self::from_cmds(&[Cmd::Data(sec.to_vec()), Cmd::Op(OP_CHECKSIG)])
+    }
+
+    pub fn p2pkh(h160: &[u8]) -> Self {
+        Self::from_cmds(&[
+            Cmd::Op(OP_DUP),
+            Cmd::Op(OP_HASH160),
+            Cmd::Data(h160.to_vec()),
+            Cmd::Op(OP_EQUALVERIFY),
+            Cmd::Op(OP_CHECKSIG),
+        ])
+    }
+
+    pub fn p2sh(h160: &[u8]) -> Self {
+        Self::from_cmds(&[
+            Cmd::Op(OP_HASH160),
+            Cmd::Data(h160.to_vec()),
+            Cmd::Op(OP_EQUAL),
+        ])
+    }
+
+    pub fn witness_v0(program: &[u8]) -> Self {
+        Self::from_cmds(&[Cmd::Op(OP_0), Cmd::Data(program.to_vec())])
+    }
+
+    pub fn p2wpkh(h160: &[u8]) -> Self {
+        Self::witness_v0(h160)
+    }
+
+    pub fn p2wsh(s256: &[u8]) -> Self {
+        Self::witness_v0(s256)
+    }
+
+    pub fn p2tr(xonly: &[u8]) -> Self {
+        Self::from_cmds(&[Cmd::Op(OP_1), Cmd::Data(xonly.to_vec())])
+    }
+
+    pub fn multisig(k: usize, secs: &[Vec<u8>]) -> Self {
+        let mut cmds = vec![Cmd::num(k as i64)];
+        cmds.extend(secs.iter().map(|sec| Cmd::Data(sec.clone())));
+        cmds.push(Cmd::num(secs.len() as i64));
+        cmds.push(Cmd::Op(OP_CHECKMULTISIG));
+        Self::from_cmds(&cmds)
+    }
+
+    pub fn op_return(data: &[u8]) -> Self {
+        Self::from_cmds(&[Cmd::Op(OP_RETURN), Cmd::Data(data.to_vec())])
+    }
+
+    // A compressed or uncompressed key and OP_CHECKSIG, with the key's
+    // prefix matching its length as Bitcoin Core's solver requires
+    pub fn is_p2pk(&self) -> bool {
+        match self.raw.as_slice() {
+            [33, 0x02..=0x03, .., OP_CHECKSIG] => self.raw.len() == 35,
+            [65, 0x04 | 0x06 | 0x07, .., OP_CHECKSIG] => self.raw.len() == 67,
+            _ => false,
+        }
+    }
+
+    pub fn is_p2pkh(&self) -> bool {
+        self.raw.len() == 25
+            && self.raw[0] == OP_DUP
+            && self.raw[1] == OP_HASH160
+            && self.raw[2] == 20
+            && self.raw[23] == OP_EQUALVERIFY
+            && self.raw[24] == OP_CHECKSIG
+    }
+
+    pub fn is_p2sh(&self) -> bool {
+        self.raw.len() == 23
+            && self.raw[0] == OP_HASH160
+            && self.raw[1] == 20
+            && self.raw[22] == OP_EQUAL
+    }
+
+    // BIP141: a version byte push followed by a 2 to 40 byte program push
+    pub fn witness_program(&self) -> Option<(u8, &[u8])> {
+        if self.raw.len() < 4 || self.raw.len() > 42 {
+            return None;
+        }
+        let version = match self.raw[0] {
+            OP_0 => 0,
+            OP_1..=OP_16 => self.raw[0] - OP_1 + 1,
+            _ => return None,
+        };
+        if self.raw[1] as usize + 2 != self.raw.len() {
+            return None;
+        }
+        Some((version, &self.raw[2..]))
+    }
+
+    pub fn is_p2wpkh(&self) -> bool {
+        matches!(self.witness_program(), Some((0, p)) if p.len() == 20)
+    }
+
+    pub fn is_p2wsh(&self) -> bool {
+        matches!(self.witness_program(), Some((0, p)) if p.len() == 32)
+    }
+
+    pub fn is_p2tr(&self) -> bool {
+        matches!(self.witness_program(), Some((1, p)) if p.len() == 32)
+    }
+
+    pub fn is_op_return(&self) -> bool {
+        self.raw.first() == Some(&OP_RETURN)
+    }
+
+    // Outputs which can never be spent are left out of the UTXO set
+    pub fn is_unspendable(&self) -> bool {
+        self.is_op_return() || self.raw.len() > MAX_SCRIPT_SIZE
+    }
+
+    // Opcodes up to OP_16 only, as P2SH requires of the scriptSig
+    pub fn is_push_only(&self) -> bool {
+        match self.instructions() {
+            Ok(instructions) => instructions.iter().all(|(op, _)| *op <= OP_16),
+            Err(_) => false,
+        }
+    }
+
+    // Signature operations as consensus counts them. A multisig counts as 20
+    // unless `accurate` is set and it follows its key count. Counting stops
+    // at a truncated push.
+    pub fn sigop_count(&self, accurate: bool) -> usize {
+        let mut count = 0;
+        let mut last = OP_INVALIDOPCODE;
+        let mut i = 0;
+        while i < self.raw.len() {
+            let (op, _, next) = match read_instruction(&self.raw, i) {
+                Ok(instruction) => instruction,
+                Err(_) => break,
+            };
+            match op {
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    count += if accurate && (OP_1..=OP_16).contains(&last) {
+                        (last - OP_1 + 1) as usize
+                    } else {
+                        20
+                    }
+                }
+                _ => {}
+            }
+            last = op;
+            i = next;
+        }
+        count
+    }
+
+    pub fn address(&self, network: Network) -> Option<String> {
+        if self.is_p2pkh() {
+            let mut b = vec![network.p2pkh_prefix()];
+            b.extend_from_slice(&self.raw[3..23]);
+            return Some(encode_base58_checksum(&b));
+        }
+        if self.is_p2sh() {
+            let mut b = vec![network.p2sh_prefix()];
+            b.extend_from_slice(&self.raw[2..22]);
+            return Some(encode_base58_checksum(&b));
+        }
+        if let Some((version, program)) = self.witness_program() {
+            return Some(encode_segwit_address(
+                network.bech32_hrp(),
+                version,
+                program,
+            ));
+        }
+        None
+    }
+
+    // Returns the script paying to `address` along with the networks that share
+    // its prefix.
+    pub fn from_address(address: &str) -> Result<(Self, Vec<Network>)> {
+        if let Ok((hrp, version, program)) = decode_segwit_address(address) {
+            let networks: Vec<Network> = Network::ALL
+                .iter()
+                .filter(|n| n.bech32_hrp() == hrp)
+                .copied()
+                .collect();
+            if networks.is_empty() {
+                return Err(anyhow!("Unknown address prefix {}", hrp));
+            }
+            let op = if version == 0 {
+                OP_0
+            } else {
+                OP_1 + version - 1
+            };
+            return Ok((
+                Self::from_cmds(&[Cmd::Op(op), Cmd::Data(program)]),
+                networks,
+            ));
+        }
+
+        let b = decode_base58_checksum(address)?;
+        if b.len() != 21 {
+            return Err(anyhow!("Invalid address {}", address));
+        }
+        for prefix_is_p2pkh in &[true, false] {
+            let networks: Vec<Network> = Network::ALL
+                .iter()
+                .filter(|n| {
+                    let prefix = if *prefix_is_p2pkh {
+                        n.p2pkh_prefix()
+                    } else {
+                        n.p2sh_prefix()
+                    };
+                    prefix == b[0]
+                })
+                .copied()
+                .collect();
+            if !networks.is_empty() {
+                let script = if *prefix_is_p2pkh {
+                    Self::p2pkh(&b[1..])
+                } else {
+                    Self::p2sh(&b[1..])
+                };
+                return Ok((script, networks));
+            }
+        }
+        Err(anyhow!("Unknown address version {}", b[0]))
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instructions = match self.instructions() {
+            Ok(instructions) => instructions,
+            Err(_) => return write!(f, "[error]"),
+        };
+        let s: Vec<String> = instructions
+            .into_iter()
+            .map(|(_, cmd)| match cmd {
+                Cmd::Op(op) => op_name(op),
+                Cmd::Data(data) => encode_hex(&data),
+            })
+            .collect();
+        write!(f, "{}", s.join(" "))
+    }
+}
+
+#[test]
+fn test_parse() {
+    use crate::helper::decode_hex;
+
+    let b = decode_hex("6a47304402207899531a52d59a6de200179928ca900254a36b8dff8bb75f5f5d71b1cdc26125022008b422690b8461cb52c3cc30330b23d574351872b7c361e9aae3649071c1a7160121035d5c93d9ac96881f19ba1f686f15f009ded7c62efe85a872e6a19b43c15a2937").unwrap();
+    let script = Script::parse(&mut b.as_slice()).unwrap();
+    let cmds = script.cmds().unwrap();
+    assert_eq!(
+        cmds[0],
+        Cmd::Data(decode_hex("304402207899531a52d59a6de200179928ca900254a36b8dff8bb75f5f5d71b1cdc26125022008b422690b8461cb52c3cc30330b23d574351872b7c361e9aae3649071c1a71601").unwrap())
+    );
+    assert_eq!(
+        cmds[1],
+        Cmd::Data(
+            decode_hex("035d5c93d9ac96881f19ba1f686f15f009ded7c62efe85a872e6a19b43c15a2937")
+                .unwrap()
+        )
+    );
+    assert_eq!(script.serialize(), b);
+    assert_eq!(Script::from_cmds(&cmds), script);
+
+    // truncated pushes survive a round trip but cannot be interpreted
+    let script = Script::from_bytes(vec![0x4c, 0x05, 0x01]);
+    assert!(script.cmds().is_err());
+    assert_eq!(
+        Script::parse(&mut script.serialize().as_slice()).unwrap(),
+        script
+    );
+    assert!(Script::parse(&mut [0x05, 0x01].as_ref()).is_err());
+}
+
+#[test]
+fn test_num() {
+    let cases: [(i64, &[u8]); 7] = [
+        (0, &[]),
+        (1, &[0x01]),
+        (-1, &[0x81]),
+        (127, &[0x7f]),
+        (128, &[0x80, 0x00]),
+        (-128, &[0x80, 0x80]),
+        (500000, &[0x20, 0xa1, 0x07]),
+    ];
+    for (n, b) in cases.iter() {
+        assert_eq!(encode_num(*n), b.to_vec());
+        assert_eq!(decode_num(b).unwrap(), *n);
+    }
+    assert_eq!(decode_num(&[0xff; 8]).unwrap(), -i64::MAX);
+    assert!(decode_num(&[0x01; 9]).is_err());
+    assert_eq!(Cmd::num(16), Cmd::Op(OP_16));
+    assert_eq!(Cmd::num(17), Cmd::Data(vec![17]));
+}
+
+#[test]
+fn test_address() {
+    use crate::helper::decode_hex;
+
+    let h160 = decode_hex("74d691da1574e6b3c192ecfb52cc8984ee7b6c56").unwrap();
+    let script = Script::p2pkh(&h160);
+    assert_eq!(
+        script.address(Network::Mainnet).unwrap(),
+        "1BenRpVUFK65JFWcQSuHnJKzc4M8ZP8Eqa"
+    );
+    assert_eq!(
+        script.address(Network::Testnet3).unwrap(),
+        "mrAjisaT4LXL5MzE81sfcDYKU3wqWSvf9q"
+    );
+    let script = Script::p2sh(&h160);
+    assert_eq!(
+        script.address(Network::Mainnet).unwrap(),
+        "3CLoMMyuoDQTPRD3XYZtCvgvkadrAdvdXh"
+    );
+    assert_eq!(
+        script.address(Network::Testnet3).unwrap(),
+        "2N3u1R6uwQfuobCqbCgBkpsgBxvr1tZpe7B"
+    );
+
+    for address in &[
+        "1BenRpVUFK65JFWcQSuHnJKzc4M8ZP8Eqa",
+        "3CLoMMyuoDQTPRD3XYZtCvgvkadrAdvdXh",
+        "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+        "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+    ] {
+        let (script, networks) = Script::from_address(address).unwrap();
+        assert_eq!(networks, vec![Network::Mainnet]);
+        assert_eq!(&script.address(Network::Mainnet).unwrap(), address);
+    }
+    let (script, networks) =
+        Script::from_address("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7")
+            .unwrap();
+    assert!(script.is_p2wsh());
+    assert_eq!(networks.len(), 3);
+
+    assert!(Script::op_return(b"hello")
+        .address(Network::Mainnet)
+        .is_none());
+}
+
+#[test]
+fn test_display() {
+    use crate::helper::decode_hex;
+
+    let h160 = decode_hex("74d691da1574e6b3c192ecfb52cc8984ee7b6c56").unwrap();
+    assert_eq!(
+        Script::p2pkh(&h160).to_string(),
+        "OP_DUP OP_HASH160 74d691da1574e6b3c192ecfb52cc8984ee7b6c56 OP_EQUALVERIFY OP_CHECKSIG"
+    );
+}
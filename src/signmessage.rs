@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+use num_bigint::{BigInt, Sign};
+
+use crate::helper::{encode_variant, hash160, hash256};
+use crate::s256::{PrivateKey, S256Point, Signature};
+use crate::script::Script;
+
+const MAGIC: &str = "Bitcoin Signed Message:\n";
+
+pub fn message_hash(message: &str) -> Vec<u8> {
+    let mut b = encode_variant(MAGIC.len() as u64);
+    b.extend_from_slice(MAGIC.as_bytes());
+    b.append(&mut encode_variant(message.len() as u64));
+    b.extend_from_slice(message.as_bytes());
+    hash256(&b)
+}
+
+// Bitcoin Core's signmessage format: a header byte of 27 + recovery id,
+// plus 4 for a compressed key, then r and s, all base64 encoded
+pub fn sign_message(key: &PrivateKey, compressed: bool, message: &str) -> String {
+    let z = BigInt::from_bytes_be(Sign::Plus, &message_hash(message));
+    let sig = key.sign(z.clone());
+    let recovery_id = (0..2)
+        .find(|id| S256Point::recover(z.clone(), &sig, *id).is_ok_and(|point| point == key.point))
+        .expect("Signature should recover to the signing key");
+    let mut b = vec![27 + recovery_id + if compressed { 4 } else { 0 }];
+    b.append(&mut sig.compact());
+    base64::encode(b)
+}
+
+// Accepts P2PKH addresses, and for compressed keys the P2SH-P2WPKH and
+// P2WPKH forms as BIP137 and Electrum do. Headers 35 to 42 are treated as
+// compressed keys too.
+pub fn verify_message(address: &str, signature: &str, message: &str) -> Result<bool> {
+    let (script, _) = Script::from_address(address)?;
+    let b = base64::decode(signature.trim())?;
+    if b.len() != 65 || b[0] < 27 || b[0] > 42 {
+        return Err(anyhow!("Invalid message signature {}", signature));
+    }
+    let header = b[0] - 27;
+    let compressed = header >= 4;
+    let sig = Signature::parse_compact(&b[1..])?;
+    let z = BigInt::from_bytes_be(Sign::Plus, &message_hash(message));
+    let point = match S256Point::recover(z, &sig, header % 4) {
+        Ok(point) => point,
+        Err(_) => return Ok(false),
+    };
+    let h160 = hash160(&point.sec(compressed));
+    let mut candidates = vec![Script::p2pkh(&h160)];
+    if compressed {
+        let p2wpkh = Script::p2wpkh(&h160);
+        candidates.push(Script::p2sh(&hash160(&p2wpkh.raw_serialize())));
+        candidates.push(p2wpkh);
+    }
+    Ok(candidates.contains(&script))
+}
+
+#[cfg(test)]
+use crate::network::Network;
+
+#[test]
+fn test_verify_message() {
+    // From Bitcoin Core's rpc_signmessage.py
+    let address = "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB";
+    let signature =
+        "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0=";
+    let message = "This is just a test message";
+    assert!(verify_message(address, signature, message).unwrap());
+    assert!(!verify_message(address, signature, "This is just a test messag").unwrap());
+    let other = PrivateKey::new(BigInt::from(2))
+        .unwrap()
+        .point
+        .address(true, Network::Testnet3);
+    assert!(!verify_message(&other, signature, message).unwrap());
+    assert!(verify_message(address, "INbVnW4e6PeRmsv2", message).is_err());
+
+    let (key, compressed, network) =
+        PrivateKey::parse_wif("cUeKHd5orzT3mz8P9pxyREHfsWtVfgsfDjiZZBcjUBAaGk1BTj7N").unwrap();
+    assert_eq!(key.point.address(compressed, network), address);
+    // Our nonces are ground for a low R, which Core's compact signing skips
+    let ours = sign_message(&key, compressed, message);
+    assert!(verify_message(address, &ours, message).unwrap());
+}
+
+#[test]
+fn test_sign_message() {
+    let key = PrivateKey::new(BigInt::from(0xdead_beefu64)).unwrap();
+    for compressed in [true, false].iter() {
+        let signature = sign_message(&key, *compressed, "hello");
+        let address = key.point.address(*compressed, Network::Mainnet);
+        assert!(verify_message(&address, &signature, "hello").unwrap());
+        assert!(!verify_message(&address, &signature, "hello!").unwrap());
+    }
+
+    // Segwit addresses of the same compressed key
+    let signature = sign_message(&key, true, "hello");
+    let h160 = key.point.hash160(true);
+    let p2wpkh = Script::p2wpkh(&h160);
+    let p2sh = Script::p2sh(&hash160(&p2wpkh.raw_serialize()));
+    for script in [p2wpkh, p2sh].iter() {
+        let address = script.address(Network::Mainnet).unwrap();
+        assert!(verify_message(&address, &signature, "hello").unwrap());
+    }
+}
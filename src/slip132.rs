@@ -0,0 +1,308 @@
+use anyhow::{anyhow, Result};
+
+use crate::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+use crate::helper::{decode_base58_checksum, encode_base58_checksum};
+use crate::network::Network;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum ScriptType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+    P2shMulti,
+    P2shP2wshMulti,
+    P2wshMulti,
+}
+
+impl ScriptType {
+    pub fn is_multisig(&self) -> bool {
+        matches!(
+            self,
+            ScriptType::P2shMulti | ScriptType::P2shP2wshMulti | ScriptType::P2wshMulti
+        )
+    }
+
+    pub fn purpose(&self) -> u32 {
+        match self {
+            ScriptType::P2pkh => 44,
+            ScriptType::P2shP2wpkh => 49,
+            ScriptType::P2wpkh => 84,
+            ScriptType::P2tr => 86,
+            ScriptType::P2shMulti => 45,
+            ScriptType::P2shP2wshMulti | ScriptType::P2wshMulti => 48,
+        }
+    }
+
+    // m/purpose'/coin_type'/account' for single key wallets (BIP44/49/84/86) and
+    // m/48'/coin_type'/account'/script_type' for BIP48 multisig wallets.
+    pub fn account_path(&self, network: Network, account: u32) -> Result<DerivationPath> {
+        if self == &ScriptType::P2shMulti {
+            return Err(anyhow!("BIP45 wallets have no account level"));
+        }
+        let coin_type = if network.is_mainnet() { 0 } else { 1 };
+        let mut path = DerivationPath::master()
+            .child(ChildNumber::hardened(self.purpose())?)
+            .child(ChildNumber::hardened(coin_type)?)
+            .child(ChildNumber::hardened(account)?);
+        match self {
+            ScriptType::P2shP2wshMulti => path = path.child(ChildNumber::hardened(1)?),
+            ScriptType::P2wshMulti => path = path.child(ChildNumber::hardened(2)?),
+            _ => {}
+        }
+        Ok(path)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct KeyVersion {
+    pub private: [u8; 4],
+    pub public: [u8; 4],
+    pub mainnet: bool,
+    pub script_type: ScriptType,
+}
+
+// Ordered so that the first match for a script type is the preferred
+// encoding. P2TR and bare multisig have no SLIP-132 prefix of their own and
+// share xpub/tpub with P2PKH.
+static VERSIONS: [KeyVersion; 14] = [
+    KeyVersion {
+        private: [0x04, 0x88, 0xad, 0xe4],
+        public: [0x04, 0x88, 0xb2, 0x1e],
+        mainnet: true,
+        script_type: ScriptType::P2pkh,
+    },
+    KeyVersion {
+        private: [0x04, 0x88, 0xad, 0xe4],
+        public: [0x04, 0x88, 0xb2, 0x1e],
+        mainnet: true,
+        script_type: ScriptType::P2tr,
+    },
+    KeyVersion {
+        private: [0x04, 0x88, 0xad, 0xe4],
+        public: [0x04, 0x88, 0xb2, 0x1e],
+        mainnet: true,
+        script_type: ScriptType::P2shMulti,
+    },
+    KeyVersion {
+        private: [0x04, 0x9d, 0x78, 0x78],
+        public: [0x04, 0x9d, 0x7c, 0xb2],
+        mainnet: true,
+        script_type: ScriptType::P2shP2wpkh,
+    },
+    KeyVersion {
+        private: [0x02, 0x95, 0xb0, 0x05],
+        public: [0x02, 0x95, 0xb4, 0x3f],
+        mainnet: true,
+        script_type: ScriptType::P2shP2wshMulti,
+    },
+    KeyVersion {
+        private: [0x04, 0xb2, 0x43, 0x0c],
+        public: [0x04, 0xb2, 0x47, 0x46],
+        mainnet: true,
+        script_type: ScriptType::P2wpkh,
+    },
+    KeyVersion {
+        private: [0x02, 0xaa, 0x7a, 0x99],
+        public: [0x02, 0xaa, 0x7e, 0xd3],
+        mainnet: true,
+        script_type: ScriptType::P2wshMulti,
+    },
+    KeyVersion {
+        private: [0x04, 0x35, 0x83, 0x94],
+        public: [0x04, 0x35, 0x87, 0xcf],
+        mainnet: false,
+        script_type: ScriptType::P2pkh,
+    },
+    KeyVersion {
+        private: [0x04, 0x35, 0x83, 0x94],
+        public: [0x04, 0x35, 0x87, 0xcf],
+        mainnet: false,
+        script_type: ScriptType::P2tr,
+    },
+    KeyVersion {
+        private: [0x04, 0x35, 0x83, 0x94],
+        public: [0x04, 0x35, 0x87, 0xcf],
+        mainnet: false,
+        script_type: ScriptType::P2shMulti,
+    },
+    KeyVersion {
+        private: [0x04, 0x4a, 0x4e, 0x28],
+        public: [0x04, 0x4a, 0x52, 0x62],
+        mainnet: false,
+        script_type: ScriptType::P2shP2wpkh,
+    },
+    KeyVersion {
+        private: [0x02, 0x42, 0x85, 0xb5],
+        public: [0x02, 0x42, 0x89, 0xef],
+        mainnet: false,
+        script_type: ScriptType::P2shP2wshMulti,
+    },
+    KeyVersion {
+        private: [0x04, 0x5f, 0x18, 0xbc],
+        public: [0x04, 0x5f, 0x1c, 0xf6],
+        mainnet: false,
+        script_type: ScriptType::P2wpkh,
+    },
+    KeyVersion {
+        private: [0x02, 0x57, 0x50, 0x48],
+        public: [0x02, 0x57, 0x54, 0x83],
+        mainnet: false,
+        script_type: ScriptType::P2wshMulti,
+    },
+];
+
+impl KeyVersion {
+    pub fn lookup(network: Network, script_type: ScriptType) -> &'static KeyVersion {
+        VERSIONS
+            .iter()
+            .find(|v| v.mainnet == network.is_mainnet() && v.script_type == script_type)
+            .unwrap()
+    }
+
+    pub fn from_bytes(version: [u8; 4]) -> Result<&'static KeyVersion> {
+        VERSIONS
+            .iter()
+            .find(|v| v.private == version || v.public == version)
+            .ok_or_else(|| anyhow!("Unknown extended key version {:02x?}", version))
+    }
+
+    pub fn network(&self) -> Network {
+        if self.mainnet {
+            Network::Mainnet
+        } else {
+            Network::Testnet3
+        }
+    }
+}
+
+fn decode_version(s: &str) -> Result<(Vec<u8>, &'static KeyVersion)> {
+    let b = decode_base58_checksum(s)?;
+    if b.len() < 4 {
+        return Err(anyhow!("Extended key is too short"));
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&b[..4]);
+    Ok((b, KeyVersion::from_bytes(version)?))
+}
+
+impl ExtendedPubKey {
+    pub fn to_slip132_string(&self, script_type: ScriptType) -> String {
+        let version = KeyVersion::lookup(self.network, script_type);
+        encode_base58_checksum(&self.serialize_with_version(version.public))
+    }
+
+    // Returns the key along with the script type its prefix stands for. xpub
+    // and tpub map to P2PKH.
+    pub fn from_slip132_str(s: &str) -> Result<(Self, ScriptType)> {
+        let (b, version) = decode_version(s)?;
+        if b[..4] != version.public {
+            return Err(anyhow!("{} is not an extended public key", s));
+        }
+        let key = ExtendedPubKey::parse_with_network(&b, version.network())?;
+        Ok((key, version.script_type))
+    }
+}
+
+impl ExtendedPrivKey {
+    pub fn to_slip132_string(&self, script_type: ScriptType) -> String {
+        let version = KeyVersion::lookup(self.network, script_type);
+        encode_base58_checksum(&self.serialize_with_version(version.private))
+    }
+
+    pub fn from_slip132_str(s: &str) -> Result<(Self, ScriptType)> {
+        let (b, version) = decode_version(s)?;
+        if b[..4] != version.private {
+            return Err(anyhow!("{} is not an extended private key", s));
+        }
+        let key = ExtendedPrivKey::parse_with_network(&b, version.network())?;
+        Ok((key, version.script_type))
+    }
+}
+
+#[cfg(test)]
+fn abandon_about_master(network: Network) -> ExtendedPrivKey {
+    use crate::helper::decode_hex;
+
+    // seed of "abandon abandon ... about" with an empty passphrase
+    let seed = decode_hex("5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4").unwrap();
+    ExtendedPrivKey::new_master(&seed, network).unwrap()
+}
+
+#[test]
+fn test_bip84_account() {
+    let master = abandon_about_master(Network::Mainnet);
+    let path = ScriptType::P2wpkh
+        .account_path(Network::Mainnet, 0)
+        .unwrap();
+    assert_eq!(path.to_string(), "m/84'/0'/0'");
+
+    let account = master.derive_path(&path).unwrap();
+    let zprv = account.to_slip132_string(ScriptType::P2wpkh);
+    let zpub = account.to_pub().to_slip132_string(ScriptType::P2wpkh);
+    assert_eq!(zprv, "zprvAdG4iTXWBoARxkkzNpNh8r6Qag3irQB8PzEMkAFeTRXxHpbF9z4QgEvBRmfvqWvGp42t42nvgGpNgYSJA9iefm1yYNZKEm7z6qUWCroSQnE");
+    assert_eq!(zpub, "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs");
+
+    assert_eq!(
+        ExtendedPrivKey::from_slip132_str(&zprv).unwrap(),
+        (account.clone(), ScriptType::P2wpkh)
+    );
+    assert_eq!(
+        ExtendedPubKey::from_slip132_str(&zpub).unwrap(),
+        (account.to_pub(), ScriptType::P2wpkh)
+    );
+    assert!(ExtendedPubKey::from_slip132_str(&zprv).is_err());
+}
+
+#[test]
+fn test_bip49_account() {
+    let master = abandon_about_master(Network::Mainnet);
+    let path = ScriptType::P2shP2wpkh
+        .account_path(Network::Mainnet, 0)
+        .unwrap();
+    let account = master.derive_path(&path).unwrap();
+    let ypub = account.to_pub().to_slip132_string(ScriptType::P2shP2wpkh);
+    assert_eq!(ypub, "ypub6Ww3ibxVfGzLrAH1PNcjyAWenMTbbAosGNB6VvmSEgytSER9azLDWCxoJwW7Ke7icmizBMXrzBx9979FfaHxHcrArf3zbeJJJUZPf663zsP");
+}
+
+#[test]
+fn test_slip132_prefixes() {
+    let master = abandon_about_master(Network::Testnet3);
+    let xpub = master.to_pub();
+    let cases = [
+        (ScriptType::P2pkh, "tpub"),
+        (ScriptType::P2shP2wpkh, "upub"),
+        (ScriptType::P2wpkh, "vpub"),
+        (ScriptType::P2shP2wshMulti, "Upub"),
+        (ScriptType::P2wshMulti, "Vpub"),
+    ];
+    for (script_type, prefix) in cases.iter() {
+        let s = xpub.to_slip132_string(*script_type);
+        assert!(s.starts_with(prefix));
+        assert_eq!(
+            ExtendedPubKey::from_slip132_str(&s).unwrap(),
+            (xpub.clone(), *script_type)
+        );
+    }
+
+    let xpub = abandon_about_master(Network::Mainnet).to_pub();
+    let cases = [
+        (ScriptType::P2pkh, "xpub"),
+        (ScriptType::P2shP2wpkh, "ypub"),
+        (ScriptType::P2wpkh, "zpub"),
+        (ScriptType::P2shP2wshMulti, "Ypub"),
+        (ScriptType::P2wshMulti, "Zpub"),
+    ];
+    for (script_type, prefix) in cases.iter() {
+        assert!(xpub.to_slip132_string(*script_type).starts_with(prefix));
+    }
+    assert_eq!(xpub.to_slip132_string(ScriptType::P2tr), xpub.to_string());
+
+    assert_eq!(
+        ScriptType::P2wshMulti
+            .account_path(Network::Testnet3, 1)
+            .unwrap()
+            .to_string(),
+        "m/48'/1'/1'/2'"
+    );
+}
@@ -0,0 +1,544 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::block::{Block, BlockHeader};
+use crate::chain::HeaderChain;
+use crate::gcs::{filter_header, BlockFilter, BASIC_FILTER_TYPE};
+use crate::helper::{encode_hex, encode_variant, read_variant};
+use crate::message::{
+    FilterRequest, GetHeadersMessage, Inventory, Message, MAX_CFHEADERS, MAX_CFILTERS, MAX_HEADERS,
+    MSG_BLOCK, MSG_WITNESS_FLAG, NODE_COMPACT_FILTERS,
+};
+use crate::network::Network;
+use crate::peer::Peer;
+use crate::script::Script;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Utxo {
+    pub txid: Vec<u8>,
+    pub index: u32,
+    pub amount: u64,
+    pub script_pubkey: Script,
+    pub height: u32,
+}
+
+// A BIP157 light client: headers first, then filter headers and filters,
+// and only the blocks whose filters match the wallet's scripts. Peers never
+// learn which scripts belong to us, and a filter which doesn't commit to
+// its filter header chain is rejected. The filter headers themselves are
+// only as honest as the one peer serving them, which could leave
+// transactions out; they aren't cross-checked against other peers.
+pub struct LightClient {
+    pub chain: HeaderChain,
+    scripts: Vec<Vec<u8>>,
+    // (block hash, filter header) by height on the active chain
+    filter_headers: Vec<(Vec<u8>, Vec<u8>)>,
+    // The next height whose filter hasn't been checked
+    scan_height: u32,
+    utxos: HashMap<(Vec<u8>, u32), Utxo>,
+}
+
+impl LightClient {
+    pub fn new(network: Network, scripts: &[Script]) -> Self {
+        Self {
+            chain: HeaderChain::new(network),
+            scripts: scripts.iter().map(|s| s.raw_serialize()).collect(),
+            filter_headers: vec![],
+            scan_height: 0,
+            utxos: HashMap::new(),
+        }
+    }
+
+    pub fn filter_header(&self, height: u32) -> Option<&[u8]> {
+        let (_, header) = self.filter_headers.get(height as usize)?;
+        Some(header)
+    }
+
+    pub fn scan_height(&self) -> u32 {
+        self.scan_height
+    }
+
+    pub fn utxos(&self) -> Vec<&Utxo> {
+        let mut result: Vec<&Utxo> = self.utxos.values().collect();
+        result.sort_by_key(|utxo| (utxo.height, utxo.txid.clone(), utxo.index));
+        result
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.utxos.values().map(|utxo| utxo.amount).sum()
+    }
+
+    pub async fn sync<S>(&mut self, peer: &mut Peer<S>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.sync_headers(peer).await?;
+        self.sync_filter_headers(peer).await?;
+        self.sync_filters(peer).await
+    }
+
+    pub async fn sync_headers<S>(&mut self, peer: &mut Peer<S>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let getheaders = GetHeadersMessage::new(self.chain.locator(), None);
+            peer.send(&Message::GetHeaders(getheaders)).await?;
+            let headers = loop {
+                if let Message::Headers(headers) = peer.recv().await? {
+                    break headers;
+                }
+            };
+            self.chain.add_headers(&headers)?;
+            if (headers.len() as u64) < MAX_HEADERS {
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn sync_filter_headers<S>(&mut self, peer: &mut Peer<S>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let services = peer.state.peer_version.as_ref().map_or(0, |v| v.services);
+        if services & NODE_COMPACT_FILTERS == 0 {
+            return Err(anyhow!("Peer doesn't serve compact block filters"));
+        }
+        self.rewind();
+        let tip = self.chain.height();
+        while self.filter_headers.len() as u32 <= tip {
+            let start = self.filter_headers.len() as u32;
+            let stop_height = (start + MAX_CFHEADERS - 1).min(tip);
+            let stop = self.chain.header_at(stop_height).unwrap().hash();
+            let request = FilterRequest::new(BASIC_FILTER_TYPE, start, stop.clone());
+            peer.send(&Message::GetCFHeaders(request)).await?;
+            let cfheaders = loop {
+                if let Message::CFHeaders(cfheaders) = peer.recv().await? {
+                    break cfheaders;
+                }
+            };
+            if cfheaders.filter_type != BASIC_FILTER_TYPE
+                || cfheaders.stop != stop
+                || cfheaders.filter_hashes.len() as u32 != stop_height - start + 1
+            {
+                return Err(anyhow!("Unexpected cfheaders for {}", encode_hex(&stop)));
+            }
+            let mut prev = self.prev_filter_header(start);
+            if cfheaders.prev_header != prev {
+                return Err(anyhow!("Filter headers don't connect at height {}", start));
+            }
+            for (height, filter_hash) in (start..).zip(cfheaders.filter_hashes.iter()) {
+                let header = filter_header(filter_hash, &prev);
+                let block_hash = self.chain.header_at(height).unwrap().hash();
+                self.filter_headers.push((block_hash, header.clone()));
+                prev = header;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn sync_filters<S>(&mut self, peer: &mut Peer<S>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let end = self.filter_headers.len() as u32;
+        while self.scan_height < end {
+            let start = self.scan_height;
+            let stop_height = (start + MAX_CFILTERS - 1).min(end - 1);
+            let stop = self.filter_headers[stop_height as usize].0.clone();
+            let request = FilterRequest::new(BASIC_FILTER_TYPE, start, stop);
+            peer.send(&Message::GetCFilters(request)).await?;
+            let mut matched = vec![];
+            for height in start..=stop_height {
+                let cfilter = loop {
+                    if let Message::CFilter(cfilter) = peer.recv().await? {
+                        break cfilter;
+                    }
+                };
+                let (block_hash, expected) = &self.filter_headers[height as usize];
+                if cfilter.filter_type != BASIC_FILTER_TYPE || &cfilter.block_hash != block_hash {
+                    return Err(anyhow!(
+                        "Unexpected filter for block {}",
+                        encode_hex(&cfilter.block_hash)
+                    ));
+                }
+                let filter = BlockFilter::new(cfilter.filter);
+                if &filter.header(&self.prev_filter_header(height)) != expected {
+                    return Err(anyhow!(
+                        "Filter for block {} doesn't match its filter header",
+                        encode_hex(block_hash)
+                    ));
+                }
+                if filter.match_any(block_hash, &self.scripts)? {
+                    matched.push(height);
+                }
+            }
+            // Blocks are applied in order, so that spends find their outputs
+            for height in matched {
+                let block = self.fetch_block(peer, height).await?;
+                self.scan_block(&block, height);
+            }
+            self.scan_height = stop_height + 1;
+        }
+        Ok(())
+    }
+
+    async fn fetch_block<S>(&mut self, peer: &mut Peer<S>, height: u32) -> Result<Block>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let hash = self.filter_headers[height as usize].0.clone();
+        let inventory = Inventory::new(MSG_BLOCK | MSG_WITNESS_FLAG, hash.clone());
+        peer.send(&Message::GetData(vec![inventory])).await?;
+        let block = loop {
+            if let Message::Block(block) = peer.recv().await? {
+                if block.hash() == hash {
+                    break block;
+                }
+            }
+        };
+        // The header is already part of the chain, so this ties the
+        // transactions to the proof of work
+        block.validate()?;
+        Ok(block)
+    }
+
+    fn scan_block(&mut self, block: &Block, height: u32) {
+        for tx in &block.txs {
+            for tx_in in &tx.tx_ins {
+                let mut prev_tx = tx_in.prev_tx.to_vec();
+                prev_tx.reverse();
+                self.utxos.remove(&(prev_tx, tx_in.prev_index));
+            }
+            let txid = tx.hash();
+            for (index, tx_out) in tx.tx_outs.iter().enumerate() {
+                if !self.scripts.contains(&tx_out.script_pubkey.raw_serialize()) {
+                    continue;
+                }
+                let utxo = Utxo {
+                    txid: txid.clone(),
+                    index: index as u32,
+                    amount: tx_out.amount,
+                    script_pubkey: tx_out.script_pubkey.clone(),
+                    height,
+                };
+                self.utxos.insert((txid.clone(), index as u32), utxo);
+            }
+        }
+    }
+
+    fn prev_filter_header(&self, height: u32) -> Vec<u8> {
+        match height {
+            0 => vec![0u8; 32],
+            _ => self.filter_headers[height as usize - 1].1.clone(),
+        }
+    }
+
+    // Drops filter headers for blocks a reorg took off the active chain. A
+    // reorg below the scanned height rescans the wallet from scratch, since
+    // spent outputs can't be restored otherwise.
+    fn rewind(&mut self) {
+        let mut keep = 0;
+        while keep < self.filter_headers.len() {
+            match self.chain.header_at(keep as u32) {
+                Some(header) if header.hash() == self.filter_headers[keep].0 => keep += 1,
+                _ => break,
+            }
+        }
+        self.filter_headers.truncate(keep);
+        if self.scan_height as usize > keep {
+            self.scan_height = 0;
+            self.utxos.clear();
+        }
+    }
+
+    // The active headers and filter headers, so a restart only syncs what's
+    // new. The wallet itself is rebuilt by scanning the filters again.
+    pub fn save<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let height = self.chain.height();
+        writer.write_all(&encode_variant(height as u64))?;
+        for h in 1..=height {
+            writer.write_all(&self.chain.header_at(h).unwrap().serialize())?;
+        }
+        writer.write_all(&encode_variant(self.filter_headers.len() as u64))?;
+        for (_, header) in &self.filter_headers {
+            writer.write_all(header)?;
+        }
+        Ok(())
+    }
+
+    pub fn load<R>(reader: &mut R, network: Network, scripts: &[Script]) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut client = Self::new(network, scripts);
+        let count = read_variant(reader)?;
+        for _ in 0..count {
+            client.chain.add_header(BlockHeader::parse(reader)?)?;
+        }
+        let count = read_variant(reader)?;
+        if count > client.chain.height() as u64 + 1 {
+            return Err(anyhow!("More filter headers than headers"));
+        }
+        for height in 0..count as u32 {
+            let mut header = vec![0u8; 32];
+            reader.read_exact(&mut header)?;
+            let block_hash = client.chain.header_at(height).unwrap().hash();
+            client.filter_headers.push((block_hash, header));
+        }
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+use crate::message::{VersionMessage, NODE_NETWORK, NODE_WITNESS};
+#[cfg(test)]
+use crate::peer::read_envelope;
+#[cfg(test)]
+use crate::testutil::{test_coinbase, test_mine, test_unsigned_spend};
+#[cfg(test)]
+use crate::transaction::TxOut;
+#[cfg(test)]
+use crate::utxo::OutPoint;
+
+// Basic filters as a full node builds them, looking up spent outputs in
+// the earlier blocks
+#[cfg(test)]
+fn test_filters(blocks: &[Block]) -> Vec<BlockFilter> {
+    let mut outputs: HashMap<Vec<u8>, Script> = HashMap::new();
+    let mut result = vec![];
+    for block in blocks {
+        let mut prevout_scripts = vec![];
+        for tx in &block.txs {
+            for tx_in in tx.tx_ins.iter().filter(|_| !tx.is_coinbase()) {
+                prevout_scripts.push(outputs[&tx_in.outpoint()].clone());
+            }
+            for (index, tx_out) in tx.tx_outs.iter().enumerate() {
+                let mut outpoint = tx.hash();
+                outpoint.reverse();
+                outpoint.extend_from_slice(&(index as u32).to_le_bytes());
+                outputs.insert(outpoint, tx_out.script_pubkey.clone());
+            }
+        }
+        result.push(BlockFilter::basic(block, &prevout_scripts).unwrap());
+    }
+    result
+}
+
+// Serves the chain from an in-process node, optionally lying about one
+// block's filter. Returns how many blocks were downloaded.
+#[cfg(test)]
+async fn fake_node(
+    mut stream: tokio::io::DuplexStream,
+    blocks: Vec<Block>,
+    bad_filter: Option<usize>,
+) -> usize {
+    use tokio::io::AsyncWriteExt;
+
+    let filters = test_filters(&blocks);
+    let mut filter_headers = vec![];
+    let mut prev = vec![0u8; 32];
+    for filter in &filters {
+        prev = filter.header(&prev);
+        filter_headers.push(prev.clone());
+    }
+    let position = |hash: &[u8]| blocks.iter().position(|block| block.hash() == hash);
+
+    let mut served = 0;
+    while let Ok(envelope) = read_envelope(&mut stream).await {
+        let replies = match envelope.message().unwrap() {
+            Message::Version(_) => {
+                let mut version = VersionMessage::new(0, 2);
+                version.services = NODE_NETWORK | NODE_WITNESS | NODE_COMPACT_FILTERS;
+                vec![Message::Version(version), Message::Verack]
+            }
+            Message::GetHeaders(getheaders) => {
+                let start = getheaders
+                    .locator
+                    .iter()
+                    .find_map(|hash| position(hash))
+                    .unwrap_or(0);
+                let headers = blocks[start + 1..]
+                    .iter()
+                    .take(MAX_HEADERS as usize)
+                    .map(|block| block.header.clone())
+                    .collect();
+                vec![Message::Headers(headers)]
+            }
+            Message::GetCFHeaders(request) => {
+                let start = request.start_height as usize;
+                let stop = position(&request.stop).unwrap();
+                let prev_header = match start {
+                    0 => vec![0u8; 32],
+                    _ => filter_headers[start - 1].clone(),
+                };
+                let cfheaders = crate::message::CFHeadersMessage {
+                    filter_type: BASIC_FILTER_TYPE,
+                    stop: request.stop,
+                    prev_header,
+                    filter_hashes: filters[start..=stop].iter().map(|f| f.hash()).collect(),
+                };
+                vec![Message::CFHeaders(cfheaders)]
+            }
+            Message::GetCFilters(request) => {
+                let stop = position(&request.stop).unwrap();
+                (request.start_height as usize..=stop)
+                    .map(|height| {
+                        let filter = match bad_filter {
+                            Some(bad) if bad == height => vec![0],
+                            _ => filters[height].content.clone(),
+                        };
+                        Message::CFilter(crate::message::CFilterMessage {
+                            filter_type: BASIC_FILTER_TYPE,
+                            block_hash: blocks[height].hash(),
+                            filter,
+                        })
+                    })
+                    .collect()
+            }
+            Message::GetData(inventory) => inventory
+                .iter()
+                .map(|item| {
+                    served += 1;
+                    Message::Block(blocks[position(&item.hash).unwrap()].clone())
+                })
+                .collect(),
+            _ => vec![],
+        };
+        for reply in replies {
+            let raw =
+                crate::message::NetworkEnvelope::from_message(Network::Regtest, &reply).serialize();
+            if stream.write_all(&raw).await.is_err() {
+                return served;
+            }
+        }
+    }
+    served
+}
+
+#[cfg(test)]
+async fn test_connect(
+    blocks: &[Block],
+    bad_filter: Option<usize>,
+) -> (
+    Peer<tokio::io::DuplexStream>,
+    tokio::task::JoinHandle<usize>,
+) {
+    let (client, node) = tokio::io::duplex(1 << 20);
+    let handle = tokio::spawn(fake_node(node, blocks.to_vec(), bad_filter));
+    let mut peer = Peer::new(client, Network::Regtest, VersionMessage::new(0, 1));
+    peer.handshake().await.unwrap();
+    (peer, handle)
+}
+
+#[cfg(test)]
+fn test_chain(ours: &Script, theirs: &Script) -> Vec<Block> {
+    let genesis = Network::Regtest.genesis_block();
+    let mut blocks = vec![Block::parse(&mut genesis.as_slice(), Network::Regtest).unwrap()];
+    blocks.push(test_mine(
+        &blocks[0].header,
+        test_coinbase(1, 50_0000_0000, ours),
+        vec![],
+    ));
+    let funding = blocks[1].txs[0].clone();
+    blocks.push(test_mine(
+        &blocks[1].header,
+        test_coinbase(2, 50_0000_0000, theirs),
+        vec![],
+    ));
+    let spend = test_unsigned_spend(
+        &[OutPoint::new(funding.hash(), 0)],
+        vec![
+            TxOut::new(30_0000_0000, theirs.clone()),
+            TxOut::new(19_9999_0000, ours.clone()),
+        ],
+    );
+    blocks.push(test_mine(
+        &blocks[2].header,
+        test_coinbase(3, 50_0000_0000, theirs),
+        vec![spend],
+    ));
+    blocks.push(test_mine(
+        &blocks[3].header,
+        test_coinbase(4, 50_0000_0000, theirs),
+        vec![],
+    ));
+    blocks
+}
+
+#[tokio::test]
+async fn test_light_client() {
+    let ours = Script::p2wpkh(&[1u8; 20]);
+    let theirs = Script::p2wpkh(&[2u8; 20]);
+    let mut blocks = test_chain(&ours, &theirs);
+
+    let mut client = LightClient::new(Network::Regtest, std::slice::from_ref(&ours));
+    let (mut peer, handle) = test_connect(&blocks, None).await;
+    client.sync(&mut peer).await.unwrap();
+    drop(peer);
+    // Only the blocks paying to or spending from the wallet
+    assert_eq!(handle.await.unwrap(), 2);
+    assert_eq!(client.chain.height(), 4);
+    assert_eq!(client.scan_height(), 5);
+    assert_eq!(client.balance(), 19_9999_0000);
+    let utxos = client.utxos();
+    assert_eq!(utxos.len(), 1);
+    assert_eq!(utxos[0].txid, blocks[3].txs[1].hash());
+    assert_eq!(utxos[0].index, 1);
+    assert_eq!(utxos[0].height, 3);
+
+    // Storing and reloading keeps the headers; the wallet needs a rescan
+    let mut stored = vec![];
+    client.save(&mut stored).unwrap();
+    let mut reloaded = LightClient::load(
+        &mut stored.as_slice(),
+        Network::Regtest,
+        std::slice::from_ref(&ours),
+    )
+    .unwrap();
+    assert_eq!(reloaded.chain.tip(), client.chain.tip());
+    assert_eq!(reloaded.filter_header(4), client.filter_header(4));
+    assert_eq!(reloaded.scan_height(), 0);
+
+    // A later sync only fetches what's new
+    blocks.push(test_mine(
+        &blocks[4].header,
+        test_coinbase(5, 50_0000_0000, &ours),
+        vec![],
+    ));
+    let (mut peer, handle) = test_connect(&blocks, None).await;
+    client.sync(&mut peer).await.unwrap();
+    drop(peer);
+    assert_eq!(handle.await.unwrap(), 1);
+    assert_eq!(client.balance(), 69_9999_0000);
+
+    let (mut peer, handle) = test_connect(&blocks, None).await;
+    reloaded.sync(&mut peer).await.unwrap();
+    drop(peer);
+    assert_eq!(handle.await.unwrap(), 3);
+    assert_eq!(reloaded.utxos(), client.utxos());
+}
+
+#[tokio::test]
+async fn test_light_client_bad_filter() {
+    let ours = Script::p2wpkh(&[1u8; 20]);
+    let theirs = Script::p2wpkh(&[2u8; 20]);
+    let blocks = test_chain(&ours, &theirs);
+
+    // Hiding the spend behind an empty filter breaks the filter header chain
+    let mut client = LightClient::new(Network::Regtest, &[ours]);
+    let (mut peer, _) = test_connect(&blocks, Some(3)).await;
+    assert_eq!(
+        client.sync(&mut peer).await.unwrap_err().to_string(),
+        format!(
+            "Filter for block {} doesn't match its filter header",
+            blocks[3].id()
+        )
+    );
+    assert_eq!(client.scan_height(), 0);
+}
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+
+use crate::helper::tagged_hash;
+use crate::s256::S256Point;
+use crate::script::Script;
+
+pub const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+
+pub fn tap_leaf_hash(leaf_version: u8, script: &Script) -> Vec<u8> {
+    let mut msg = vec![leaf_version];
+    msg.append(&mut script.serialize());
+    tagged_hash("TapLeaf", &msg)
+}
+
+pub fn tap_branch_hash(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let msg = if a <= b {
+        [a, b].concat()
+    } else {
+        [b, a].concat()
+    };
+    tagged_hash("TapBranch", &msg)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TapTree {
+    Leaf(u8, Script),
+    Branch(Box<TapTree>, Box<TapTree>),
+}
+
+impl TapTree {
+    pub fn leaf(script: Script) -> Self {
+        TapTree::Leaf(TAPSCRIPT_LEAF_VERSION, script)
+    }
+
+    pub fn branch(left: TapTree, right: TapTree) -> Self {
+        TapTree::Branch(Box::new(left), Box::new(right))
+    }
+
+    pub fn merkle_root(&self) -> Vec<u8> {
+        match self {
+            TapTree::Leaf(version, script) => tap_leaf_hash(*version, script),
+            TapTree::Branch(left, right) => {
+                tap_branch_hash(&left.merkle_root(), &right.merkle_root())
+            }
+        }
+    }
+
+    // Every leaf with the sibling hashes from the leaf up to the root
+    pub fn leaves(&self) -> Vec<(u8, Script, Vec<Vec<u8>>)> {
+        match self {
+            TapTree::Leaf(version, script) => vec![(*version, script.clone(), vec![])],
+            TapTree::Branch(left, right) => {
+                let mut result = vec![];
+                for (child, sibling) in &[(left, right), (right, left)] {
+                    let sibling_hash = sibling.merkle_root();
+                    for (version, script, mut path) in child.leaves() {
+                        path.push(sibling_hash.clone());
+                        result.push((version, script, path));
+                    }
+                }
+                result
+            }
+        }
+    }
+
+    pub fn control_block(
+        &self,
+        internal_key: &S256Point,
+        leaf_version: u8,
+        script: &Script,
+    ) -> Result<Vec<u8>> {
+        let output_key = internal_key.tap_tweak(Some(&self.merkle_root()))?;
+        let (_, _, path) = self
+            .leaves()
+            .into_iter()
+            .find(|(v, s, _)| v == &leaf_version && s == script)
+            .ok_or_else(|| anyhow!("Script is not a leaf of the tree"))?;
+
+        let parity = if output_key.has_even_y() { 0 } else { 1 };
+        let mut result = vec![leaf_version | parity];
+        result.append(&mut internal_key.xonly());
+        for hash in path {
+            result.extend_from_slice(&hash);
+        }
+        Ok(result)
+    }
+}
+
+// The merkle root committed to by a control block and leaf script
+pub fn control_block_root(control_block: &[u8], script: &Script) -> Result<Vec<u8>> {
+    if control_block.len() < 33 || !(control_block.len() - 33).is_multiple_of(32) {
+        return Err(anyhow!(
+            "Invalid control block length {}",
+            control_block.len()
+        ));
+    }
+    let mut hash = tap_leaf_hash(control_block[0] & 0xfe, script);
+    for node in control_block[33..].chunks(32) {
+        hash = tap_branch_hash(&hash, node);
+    }
+    Ok(hash)
+}
+
+#[test]
+fn test_script_tree() {
+    use crate::helper::{decode_hex, encode_hex};
+
+    // BIP341 wallet test vectors
+    let internal = S256Point::lift_x(
+        &decode_hex("ee4fe085983462a184015d1f782d6a5f8b9c2b60130aff050ce221ecf3786592").unwrap(),
+    )
+    .unwrap();
+    let a = Script::from_bytes(
+        decode_hex("20387671353e273264c495656e27e39ba899ea8fee3bb69fb2a680e22093447d48ac").unwrap(),
+    );
+    let b = Script::from_bytes(decode_hex("06424950333431").unwrap());
+    let tree = TapTree::branch(TapTree::leaf(a.clone()), TapTree::Leaf(0xfa, b.clone()));
+
+    assert_eq!(
+        encode_hex(&tap_leaf_hash(TAPSCRIPT_LEAF_VERSION, &a)),
+        "8ad69ec7cf41c2a4001fd1f738bf1e505ce2277acdcaa63fe4765192497f47a7"
+    );
+    assert_eq!(
+        encode_hex(&tree.merkle_root()),
+        "6c2dc106ab816b73f9d07e3cd1ef2c8c1256f519748e0813e4edd2405d277bef"
+    );
+    assert_eq!(
+        encode_hex(
+            &internal
+                .tap_tweak(Some(&tree.merkle_root()))
+                .unwrap()
+                .xonly()
+        ),
+        "712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5"
+    );
+
+    let control_block = tree
+        .control_block(&internal, TAPSCRIPT_LEAF_VERSION, &a)
+        .unwrap();
+    assert_eq!(
+        encode_hex(&control_block),
+        "c0ee4fe085983462a184015d1f782d6a5f8b9c2b60130aff050ce221ecf3786592f224a923cd0021ab202ab139cc56802ddb92dcfc172b9212261a539df79a112a"
+    );
+    assert_eq!(
+        control_block_root(&control_block, &a).unwrap(),
+        tree.merkle_root()
+    );
+    assert_eq!(
+        encode_hex(&tree.control_block(&internal, 0xfa, &b).unwrap()),
+        "faee4fe085983462a184015d1f782d6a5f8b9c2b60130aff050ce221ecf37865928ad69ec7cf41c2a4001fd1f738bf1e505ce2277acdcaa63fe4765192497f47a7"
+    );
+    assert!(tree
+        .control_block(&internal, TAPSCRIPT_LEAF_VERSION, &b)
+        .is_err());
+}
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use num_bigint::{BigInt, Sign};
+
+use crate::block::{Block, BlockHeader};
+use crate::chain::HeaderChain;
+use crate::miner::{add_witness_commitment, coinbase_tx};
+use crate::network::Network;
+use crate::s256::PrivateKey;
+use crate::script::Script;
+use crate::transaction::{Tx, TxIn, TxOut, SIGHASH_ALL};
+use crate::utxo::{MemoryStore, OutPoint, UtxoSet};
+use crate::validation::{validate_block, PrevState};
+
+pub fn test_coinbase(height: u32, value: u64, script_pubkey: &Script) -> Tx {
+    coinbase_tx(height, 0, value, script_pubkey, Network::Regtest)
+}
+
+// Spends the outpoints without signing anything
+pub fn test_unsigned_spend(outpoints: &[OutPoint], tx_outs: Vec<TxOut>) -> Tx {
+    let tx_ins = outpoints
+        .iter()
+        .map(|outpoint| {
+            let mut prev_tx = [0u8; 32];
+            prev_tx.copy_from_slice(&outpoint.serialize()[..32]);
+            TxIn::new(prev_tx, outpoint.vout, None, 0xffffffff)
+        })
+        .collect();
+    Tx::new(2, tx_ins, tx_outs, 0, Network::Regtest)
+}
+
+// Spends P2WPKH outputs of the key back to it
+pub fn test_spend(
+    key: &PrivateKey,
+    coins: &[(OutPoint, u64)],
+    amounts: &[u64],
+    sequence: u32,
+) -> Tx {
+    let outpoints: Vec<OutPoint> = coins.iter().map(|(outpoint, _)| outpoint.clone()).collect();
+    let h160 = key.point.hash160(true);
+    let tx_outs = amounts
+        .iter()
+        .map(|amount| TxOut::new(*amount, Script::p2wpkh(&h160)))
+        .collect();
+    let mut tx = test_unsigned_spend(&outpoints, tx_outs);
+    for tx_in in tx.tx_ins.iter_mut() {
+        tx_in.sequence = sequence;
+    }
+    for (i, (_, value)) in coins.iter().enumerate() {
+        let z = tx
+            .sig_hash_bip143(i, &Script::p2pkh(&h160), *value, SIGHASH_ALL)
+            .unwrap();
+        let mut sig = key.sign(BigInt::from_bytes_be(Sign::Plus, &z)).der();
+        sig.push(SIGHASH_ALL as u8);
+        tx.tx_ins[i].witness = vec![sig, key.point.sec(true)];
+    }
+    tx
+}
+
+// A block on top of `prev` ten minutes later, with a witness commitment
+// when needed, mined at regtest difficulty
+pub fn test_mine(prev: &BlockHeader, coinbase: Tx, txs: Vec<Tx>) -> Block {
+    let mut prev_block = [0u8; 32];
+    prev_block.copy_from_slice(&prev.hash());
+    prev_block.reverse();
+    let header = BlockHeader::new(
+        0x20000000,
+        prev_block,
+        [0u8; 32],
+        prev.timestamp + 600,
+        prev.bits,
+        0,
+    );
+    let mut block = Block::new(header, [vec![coinbase], txs].concat());
+    if block.txs.iter().any(|tx| tx.is_segwit()) {
+        add_witness_commitment(&mut block);
+    }
+    let merkle_root = block.merkle_root().unwrap();
+    block.header.merkle_root.copy_from_slice(&merkle_root);
+    while !block.header.check_pow() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+// Validates and connects a block on top of the chain's tip. Returns the fees
+// it collects.
+pub fn test_submit(
+    chain: &mut HeaderChain,
+    utxos: &mut UtxoSet<MemoryStore>,
+    block: &Block,
+) -> Result<u64> {
+    let fees = validate_block(block, &PrevState { chain, utxos })?;
+    utxos.connect_block(block, chain.height() + 1, chain.network)?;
+    chain.add_header(block.header.clone())?;
+    Ok(fees)
+}
@@ -1,56 +1,183 @@
-use crate::helper::{hash256, read_variant, encode_variant};
-use num_bigint::{BigInt, Sign};
+use anyhow::{anyhow, Result};
 use std::io::Read;
 
-#[derive(Debug, Clone)]
+use crate::helper::{
+    encode_hex, encode_variant, hash256, read_variant, read_variant_with_prefix, sha256,
+    tagged_hash,
+};
+use crate::network::Network;
+use crate::script::Script;
+
+pub const SIGHASH_DEFAULT: u32 = 0x00;
+pub const SIGHASH_ALL: u32 = 0x01;
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+
+// Locktimes below the threshold are heights, the rest are timestamps
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+// BIP68 relative locktimes in the sequence field
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Tx {
-    version: u32,
-    tx_ins: Vec<TxIn>,
-    tx_outs: Vec<TxOut>,
-    locktime: u32,
-    testnet: bool,
+    pub version: u32,
+    pub tx_ins: Vec<TxIn>,
+    pub tx_outs: Vec<TxOut>,
+    pub locktime: u32,
+    pub network: Network,
 }
 
 impl Tx {
-    fn parse<R: Read>(reader: &mut R, testnet: bool) -> Self
+    pub fn new(
+        version: u32,
+        tx_ins: Vec<TxIn>,
+        tx_outs: Vec<TxOut>,
+        locktime: u32,
+        network: Network,
+    ) -> Self {
+        Self {
+            version,
+            tx_ins,
+            tx_outs,
+            locktime,
+            network,
+        }
+    }
+
+    pub fn parse<R>(reader: &mut R, network: Network) -> Result<Self>
     where
         R: Read,
     {
         let mut version = [0u8; 4];
-        reader.read_exact(&mut version).unwrap();
+        reader.read_exact(&mut version)?;
 
-        let input_len = read_variant(reader);
+        // BIP144: a zero input count is the segwit marker, followed by the flag
+        let mut prefix = [0u8; 1];
+        reader.read_exact(&mut prefix)?;
+        let segwit = prefix[0] == 0x00;
+        let input_len = if segwit {
+            reader.read_exact(&mut prefix)?;
+            if prefix[0] != 0x01 {
+                return Err(anyhow!("Unknown segwit flag {:02x}", prefix[0]));
+            }
+            read_variant(reader)?
+        } else {
+            read_variant_with_prefix(reader, prefix[0])?
+        };
         let mut tx_ins = Vec::new();
         for _ in 0..input_len {
-            tx_ins.push(TxIn::parse(reader));
+            tx_ins.push(TxIn::parse(reader)?);
         }
 
-        let output_len = read_variant(reader);
+        let output_len = read_variant(reader)?;
         let mut tx_outs = Vec::new();
         for _ in 0..output_len {
-            tx_outs.push(TxOut::parse(reader));
+            tx_outs.push(TxOut::parse(reader)?);
+        }
+
+        if segwit {
+            for tx_in in tx_ins.iter_mut() {
+                let items = read_variant(reader)?;
+                for _ in 0..items {
+                    tx_in.witness.push(read_bytes(reader)?);
+                }
+            }
+            if tx_ins.iter().all(|tx_in| tx_in.witness.is_empty()) {
+                return Err(anyhow!("Segwit transaction has no witness data"));
+            }
         }
 
         let mut locktime = [0u8; 4];
-        reader.read_exact(&mut locktime).unwrap();
-        Self {
-            version: u32::from_le_bytes(version),
+        reader.read_exact(&mut locktime)?;
+        Ok(Self::new(
+            u32::from_le_bytes(version),
             tx_ins,
             tx_outs,
-            locktime: u32::from_le_bytes(locktime),
-            testnet,
+            u32::from_le_bytes(locktime),
+            network,
+        ))
+    }
+
+    pub fn is_segwit(&self) -> bool {
+        self.tx_ins.iter().any(|tx_in| !tx_in.witness.is_empty())
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.tx_ins.len() == 1
+            && self.tx_ins[0].prev_tx == [0u8; 32]
+            && self.tx_ins[0].prev_index == 0xffffffff
+    }
+
+    // Whether the locktime lets the transaction into a block at this height
+    // and time. Inputs with final sequences disable the locktime.
+    pub fn is_final(&self, height: u32, time: u32) -> bool {
+        if self.locktime == 0 {
+            return true;
         }
+        let cutoff = if self.locktime < LOCKTIME_THRESHOLD {
+            height
+        } else {
+            time
+        };
+        self.locktime < cutoff
+            || self
+                .tx_ins
+                .iter()
+                .all(|tx_in| tx_in.sequence == SEQUENCE_FINAL)
+    }
+
+    pub fn id(&self) -> String {
+        encode_hex(&self.hash())
+    }
+
+    pub fn wtxid(&self) -> String {
+        encode_hex(&self.witness_hash())
+    }
+
+    // The txid in display (big-endian) order
+    pub fn hash(&self) -> Vec<u8> {
+        let mut h = hash256(&self.serialize_legacy());
+        h.reverse();
+        h
     }
 
-    fn id(&self) -> String {
-        self.hash()
-            .iter()
-            .map(|n| format!("{:02x}", n))
-            .collect::<String>()
+    pub fn witness_hash(&self) -> Vec<u8> {
+        let mut h = hash256(&self.serialize());
+        h.reverse();
+        h
+    }
+
+    pub fn serialize_legacy(&self) -> Vec<u8> {
+        let mut result = self.version.to_le_bytes().to_vec();
+
+        result.append(&mut encode_variant(self.tx_ins.len() as u64));
+        for tx_in in &self.tx_ins {
+            result.append(&mut tx_in.serialize());
+        }
+
+        result.append(&mut encode_variant(self.tx_outs.len() as u64));
+        for tx_out in &self.tx_outs {
+            result.append(&mut tx_out.serialize());
+        }
+
+        result.extend_from_slice(&self.locktime.to_le_bytes());
+        result
     }
 
     pub fn serialize(&self) -> Vec<u8> {
+        if !self.is_segwit() {
+            return self.serialize_legacy();
+        }
         let mut result = self.version.to_le_bytes().to_vec();
+        result.extend_from_slice(&[0x00, 0x01]);
 
         result.append(&mut encode_variant(self.tx_ins.len() as u64));
         for tx_in in &self.tx_ins {
@@ -62,21 +189,245 @@ impl Tx {
             result.append(&mut tx_out.serialize());
         }
 
-        result.append(&mut self.locktime.to_le_bytes().to_vec());
+        for tx_in in &self.tx_ins {
+            result.append(&mut tx_in.serialize_witness());
+        }
+
+        result.extend_from_slice(&self.locktime.to_le_bytes());
         result
     }
 
-    fn hash(&self) -> Vec<u8> {
-        hash256(&self.serialize())
+    pub fn weight(&self) -> usize {
+        self.serialize_legacy().len() * (WITNESS_SCALE_FACTOR - 1) + self.serialize().len()
+    }
+
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(WITNESS_SCALE_FACTOR)
+    }
+
+    // The legacy signature hash, with script_code standing in for the
+    // scriptSig of the input being signed
+    pub fn sig_hash(
+        &self,
+        input_index: usize,
+        script_code: &Script,
+        sighash_type: u32,
+    ) -> Result<Vec<u8>> {
+        if input_index >= self.tx_ins.len() {
+            return Err(anyhow!("Input {} is out of range", input_index));
+        }
+        let base_type = sighash_type & 0x1f;
+        // Consensus quirk: SINGLE without a matching output signs the number one
+        if base_type == SIGHASH_SINGLE && input_index >= self.tx_outs.len() {
+            let mut one = vec![0u8; 32];
+            one[0] = 0x01;
+            return Ok(one);
+        }
+
+        let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+        let mut tx_ins = vec![];
+        for (i, tx_in) in self.tx_ins.iter().enumerate() {
+            if anyone_can_pay && i != input_index {
+                continue;
+            }
+            let mut tx_in = tx_in.clone();
+            tx_in.witness = vec![];
+            if i == input_index {
+                tx_in.script_sig = script_code.clone();
+            } else {
+                tx_in.script_sig = Script::new();
+                if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+                    tx_in.sequence = 0;
+                }
+            }
+            tx_ins.push(tx_in);
+        }
+
+        let tx_outs = match base_type {
+            SIGHASH_NONE => vec![],
+            SIGHASH_SINGLE => {
+                let mut tx_outs = vec![TxOut::new(u64::MAX, Script::new()); input_index];
+                tx_outs.push(self.tx_outs[input_index].clone());
+                tx_outs
+            }
+            _ => self.tx_outs.clone(),
+        };
+
+        let tx = Tx::new(self.version, tx_ins, tx_outs, self.locktime, self.network);
+        let mut s = tx.serialize_legacy();
+        s.extend_from_slice(&sighash_type.to_le_bytes());
+        Ok(hash256(&s))
+    }
+
+    // BIP143: the signature hash for segwit v0 inputs
+    pub fn sig_hash_bip143(
+        &self,
+        input_index: usize,
+        script_code: &Script,
+        amount: u64,
+        sighash_type: u32,
+    ) -> Result<Vec<u8>> {
+        let tx_in = self
+            .tx_ins
+            .get(input_index)
+            .ok_or_else(|| anyhow!("Input {} is out of range", input_index))?;
+        let base_type = sighash_type & 0x1f;
+        let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+
+        let hash_prevouts = if anyone_can_pay {
+            vec![0u8; 32]
+        } else {
+            let mut s = vec![];
+            for tx_in in &self.tx_ins {
+                s.append(&mut tx_in.outpoint());
+            }
+            hash256(&s)
+        };
+
+        let hash_sequence =
+            if anyone_can_pay || base_type == SIGHASH_SINGLE || base_type == SIGHASH_NONE {
+                vec![0u8; 32]
+            } else {
+                let mut s = vec![];
+                for tx_in in &self.tx_ins {
+                    s.extend_from_slice(&tx_in.sequence.to_le_bytes());
+                }
+                hash256(&s)
+            };
+
+        let hash_outputs = if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+            let mut s = vec![];
+            for tx_out in &self.tx_outs {
+                s.append(&mut tx_out.serialize());
+            }
+            hash256(&s)
+        } else if base_type == SIGHASH_SINGLE && input_index < self.tx_outs.len() {
+            hash256(&self.tx_outs[input_index].serialize())
+        } else {
+            vec![0u8; 32]
+        };
+
+        let mut s = self.version.to_le_bytes().to_vec();
+        s.extend_from_slice(&hash_prevouts);
+        s.extend_from_slice(&hash_sequence);
+        s.append(&mut tx_in.outpoint());
+        s.append(&mut script_code.serialize());
+        s.extend_from_slice(&amount.to_le_bytes());
+        s.extend_from_slice(&tx_in.sequence.to_le_bytes());
+        s.extend_from_slice(&hash_outputs);
+        s.extend_from_slice(&self.locktime.to_le_bytes());
+        s.extend_from_slice(&sighash_type.to_le_bytes());
+        Ok(hash256(&s))
+    }
+
+    // BIP341: the signature hash for taproot inputs, which commits to the
+    // outputs spent by every input. Script path spends pass the tapleaf hash
+    // and the position of the last executed OP_CODESEPARATOR.
+    pub fn sig_hash_bip341(
+        &self,
+        input_index: usize,
+        prevouts: &[TxOut],
+        sighash_type: u32,
+        annex: Option<&[u8]>,
+        leaf: Option<(&[u8], u32)>,
+    ) -> Result<Vec<u8>> {
+        let tx_in = self
+            .tx_ins
+            .get(input_index)
+            .ok_or_else(|| anyhow!("Input {} is out of range", input_index))?;
+        if prevouts.len() != self.tx_ins.len() {
+            return Err(anyhow!(
+                "Expected {} spent outputs, got {}",
+                self.tx_ins.len(),
+                prevouts.len()
+            ));
+        }
+        if !matches!(sighash_type, 0x00..=0x03 | 0x81..=0x83) {
+            return Err(anyhow!("Invalid taproot sighash type {:02x}", sighash_type));
+        }
+        let base_type = sighash_type & 0x03;
+        let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+
+        // Epoch zero
+        let mut s = vec![0x00, sighash_type as u8];
+        s.extend_from_slice(&self.version.to_le_bytes());
+        s.extend_from_slice(&self.locktime.to_le_bytes());
+        if !anyone_can_pay {
+            let mut outpoints = vec![];
+            let mut amounts = vec![];
+            let mut script_pubkeys = vec![];
+            let mut sequences = vec![];
+            for (tx_in, prevout) in self.tx_ins.iter().zip(prevouts) {
+                outpoints.append(&mut tx_in.outpoint());
+                amounts.extend_from_slice(&prevout.amount.to_le_bytes());
+                script_pubkeys.append(&mut prevout.script_pubkey.serialize());
+                sequences.extend_from_slice(&tx_in.sequence.to_le_bytes());
+            }
+            s.append(&mut sha256(&outpoints));
+            s.append(&mut sha256(&amounts));
+            s.append(&mut sha256(&script_pubkeys));
+            s.append(&mut sha256(&sequences));
+        }
+        if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+            let mut outputs = vec![];
+            for tx_out in &self.tx_outs {
+                outputs.append(&mut tx_out.serialize());
+            }
+            s.append(&mut sha256(&outputs));
+        }
+
+        s.push(leaf.is_some() as u8 * 2 + annex.is_some() as u8);
+        if anyone_can_pay {
+            let prevout = &prevouts[input_index];
+            s.append(&mut tx_in.outpoint());
+            s.extend_from_slice(&prevout.amount.to_le_bytes());
+            s.append(&mut prevout.script_pubkey.serialize());
+            s.extend_from_slice(&tx_in.sequence.to_le_bytes());
+        } else {
+            s.extend_from_slice(&(input_index as u32).to_le_bytes());
+        }
+        if let Some(annex) = annex {
+            let mut data = encode_variant(annex.len() as u64);
+            data.extend_from_slice(annex);
+            s.append(&mut sha256(&data));
+        }
+        if base_type == SIGHASH_SINGLE {
+            let tx_out = self
+                .tx_outs
+                .get(input_index)
+                .ok_or_else(|| anyhow!("No output matches input {}", input_index))?;
+            s.append(&mut sha256(&tx_out.serialize()));
+        }
+        if let Some((leaf_hash, codesep_pos)) = leaf {
+            s.extend_from_slice(leaf_hash);
+            // Key version
+            s.push(0x00);
+            s.extend_from_slice(&codesep_pos.to_le_bytes());
+        }
+        Ok(tagged_hash("TapSighash", &s))
     }
 }
 
-#[derive(Debug, Clone)]
-struct TxIn {
-    prev_tx: [u8; 32],
-    prev_index: u32,
-    script_sig: Script,
-    sequence: u32,
+fn read_bytes<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: Read,
+{
+    let len = read_variant(reader)?;
+    let mut result = vec![];
+    reader.take(len).read_to_end(&mut result)?;
+    if result.len() as u64 != len {
+        return Err(anyhow!("Data is shorter than its length prefix"));
+    }
+    Ok(result)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TxIn {
+    pub prev_tx: [u8; 32],
+    pub prev_index: u32,
+    pub script_sig: Script,
+    pub sequence: u32,
+    pub witness: Vec<Vec<u8>>,
 }
 
 impl TxIn {
@@ -89,64 +440,86 @@ impl TxIn {
         Self {
             prev_tx,
             prev_index,
-            script_sig: script_sig.unwrap_or(Script::new()),
+            script_sig: script_sig.unwrap_or_default(),
             sequence,
+            witness: vec![],
         }
     }
 
-    pub fn parse<R>(reader: &mut R) -> Self
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
     where
         R: Read,
     {
         let mut prev_tx = [0u8; 32];
-        reader.read_exact(&mut prev_tx).unwrap();
+        reader.read_exact(&mut prev_tx)?;
 
         let mut prev_index = [0u8; 4];
-        reader.read_exact(&mut prev_index).unwrap();
+        reader.read_exact(&mut prev_index)?;
 
-        let script_sig = Some(Script::parse(reader));
+        let script_sig = Some(Script::parse(reader)?);
 
         let mut sequence = [0u8; 4];
-        reader.read_exact(&mut sequence).unwrap();
-        Self::new(
+        reader.read_exact(&mut sequence)?;
+        Ok(Self::new(
             prev_tx,
             u32::from_le_bytes(prev_index),
             script_sig,
             u32::from_le_bytes(sequence),
-        )
+        ))
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
+    // The previous transaction id in display order
+    pub fn prev_tx_id(&self) -> String {
+        let mut h = self.prev_tx.to_vec();
+        h.reverse();
+        encode_hex(&h)
+    }
+
+    pub fn outpoint(&self) -> Vec<u8> {
         let mut result = self.prev_tx.to_vec();
-        result.append(&mut self.prev_index.to_le_bytes().to_vec());
+        result.extend_from_slice(&self.prev_index.to_le_bytes());
+        result
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.outpoint();
         result.append(&mut self.script_sig.serialize());
-        result.append(&mut self.sequence.to_le_bytes().to_vec());
+        result.extend_from_slice(&self.sequence.to_le_bytes());
+        result
+    }
+
+    pub fn serialize_witness(&self) -> Vec<u8> {
+        let mut result = encode_variant(self.witness.len() as u64);
+        for item in &self.witness {
+            result.append(&mut encode_variant(item.len() as u64));
+            result.extend_from_slice(item);
+        }
         result
     }
 }
 
-#[derive(Debug, Clone)]
-struct TxOut {
-    amount: u32,
-    script_pubkey: ScriptPubKey,
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TxOut {
+    pub amount: u64,
+    pub script_pubkey: Script,
 }
 
 impl TxOut {
-    pub fn new(amount: u32, script_pubkey: ScriptPubKey) -> Self {
+    pub fn new(amount: u64, script_pubkey: Script) -> Self {
         Self {
             amount,
             script_pubkey,
         }
     }
 
-    pub fn parse<R>(reader: &mut R) -> Self
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
     where
         R: Read,
     {
-        let mut amount = [0u8; 4];
-        reader.read_exact(&mut amount).unwrap();
-        let script_pubkey = ScriptPubKey::parse(reader);
-        Self::new(u32::from_le_bytes(amount), script_pubkey)
+        let mut amount = [0u8; 8];
+        reader.read_exact(&mut amount)?;
+        let script_pubkey = Script::parse(reader)?;
+        Ok(Self::new(u64::from_le_bytes(amount), script_pubkey))
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -156,42 +529,169 @@ impl TxOut {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Script;
+#[cfg(test)]
+use crate::helper::decode_hex;
 
-impl Script {
-    pub fn new() -> Self {
-        Self
-    }
+#[test]
+fn test_parse_legacy() {
+    let raw = decode_hex("0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600").unwrap();
+    let tx = Tx::parse(&mut raw.as_slice(), Network::Mainnet).unwrap();
+    assert_eq!(tx.version, 1);
+    assert_eq!(tx.tx_ins.len(), 1);
+    assert_eq!(
+        tx.tx_ins[0].prev_tx_id(),
+        "d1c789a9c60383bf715f3f6ad9d14b91fe55f3deb369fe5d9280cb1a01793f81"
+    );
+    assert_eq!(tx.tx_ins[0].sequence, 0xfffffffe);
+    assert_eq!(tx.tx_outs[0].amount, 32454049);
+    assert_eq!(tx.tx_outs[1].amount, 10011545);
+    assert_eq!(tx.locktime, 410393);
+    assert!(!tx.is_segwit());
+    assert_eq!(tx.serialize(), raw);
+    assert_eq!(
+        tx.id(),
+        "452c629d67e41baec3ac6f04fe744b4b9617f8f859c63b3002f8684e7a4fee03"
+    );
+}
 
-    pub fn parse<R>(reader: &mut R) -> Self
-    where
-        R: Read,
-    {
-        Script::new()
-    }
+#[test]
+fn test_parse_segwit() {
+    let raw = decode_hex("02000000000101595895ea20179de87052b4046dfe6fd515860505d6511a9004cf12a1f93cac7c0100000000ffffffff01deb807000000000017a9140f3444e271620c736808aa7b33e370bd87cb5a078702483045022100fb60dad8df4af2841adc0346638c16d0b8035f5e3f3753b88db122e70c79f9370220756e6633b17fd2710e626347d28d60b0a2d6cbb41de51740644b9fb3ba7751040121028fa937ca8cba2197a37c007176ed8941055d3bcb8627d085e94553e62f057dcc00000000").unwrap();
+    let tx = Tx::parse(&mut raw.as_slice(), Network::Mainnet).unwrap();
+    assert!(tx.is_segwit());
+    assert_eq!(tx.tx_ins[0].witness.len(), 2);
+    assert_eq!(tx.serialize(), raw);
+    assert_eq!(
+        tx.id(),
+        "f5864806e3565c34d1b41e716f72609d00b55ea5eac5b924c9719a842ef42206"
+    );
+    assert_eq!(
+        tx.wtxid(),
+        "80b7d8a82d5d5bf92905b06f2014dd699e03837ca172e3a59d51426ebbe3e7f5"
+    );
+    assert_eq!(tx.weight(), 442);
+    assert_eq!(tx.vsize(), 111);
 
-    pub fn serialize(&self) -> Vec<u8> {
-        vec![]
-    }
+    assert!(Tx::parse(&mut &raw[..raw.len() - 1], Network::Mainnet).is_err());
 }
 
-#[derive(Debug, Clone)]
-struct ScriptPubKey;
+#[test]
+fn test_sig_hash() {
+    use crate::helper::hash160;
+    use crate::s256::{S256Point, Signature};
+    use crate::script::Cmd;
+    use num_bigint::{BigInt, Sign};
 
-impl ScriptPubKey {
-    pub fn new() -> Self {
-        Self
-    }
+    let raw = decode_hex("0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600").unwrap();
+    let tx = Tx::parse(&mut raw.as_slice(), Network::Mainnet).unwrap();
+    let cmds = tx.tx_ins[0].script_sig.cmds().unwrap();
+    let (sig, sec) = match (&cmds[0], &cmds[1]) {
+        (Cmd::Data(sig), Cmd::Data(sec)) => (sig.clone(), sec.clone()),
+        _ => panic!("unexpected scriptSig"),
+    };
+    let script_code = Script::p2pkh(&hash160(&sec));
+    let z = tx.sig_hash(0, &script_code, SIGHASH_ALL).unwrap();
+    assert_eq!(
+        encode_hex(&z),
+        "27e0c5994dec7824e56dec6b2fcb342eb7cdb0d0957c2fce9882f715e85d81a6"
+    );
+    let point = S256Point::parse(&sec).unwrap();
+    let sig = Signature::parse(&sig[..sig.len() - 1]).unwrap();
+    assert!(point.verify(BigInt::from_bytes_be(Sign::Plus, &z), sig));
 
-    pub fn parse<R>(reader: &mut R) -> Self
-    where
-        R: Read,
-    {
-        ScriptPubKey::new()
-    }
+    let z = tx.sig_hash(0, &script_code, SIGHASH_SINGLE).unwrap();
+    assert_ne!(z[0], 0x01);
+    let mut tx = tx;
+    tx.tx_ins.push(tx.tx_ins[0].clone());
+    tx.tx_ins.push(tx.tx_ins[0].clone());
+    let z = tx.sig_hash(2, &script_code, SIGHASH_SINGLE).unwrap();
+    assert_eq!(encode_hex(&z), format!("01{}", "00".repeat(31)));
+}
 
-    pub fn serialize(&self) -> Vec<u8> {
-        vec![]
+#[test]
+fn test_sig_hash_bip143() {
+    let raw = decode_hex("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000").unwrap();
+    let tx = Tx::parse(&mut raw.as_slice(), Network::Mainnet).unwrap();
+    let script_code =
+        Script::p2pkh(&decode_hex("1d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap());
+    let z = tx
+        .sig_hash_bip143(1, &script_code, 600_000_000, SIGHASH_ALL)
+        .unwrap();
+    assert_eq!(
+        encode_hex(&z),
+        "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+    );
+}
+
+#[test]
+fn test_sig_hash_bip341() {
+    let vectors: serde_json::Value =
+        serde_json::from_str(include_str!("bip341_vectors.json")).unwrap();
+    let vector = &vectors["keyPathSpending"][0];
+    let given = &vector["given"];
+    let raw = decode_hex(given["rawUnsignedTx"].as_str().unwrap()).unwrap();
+    let tx = Tx::parse(&mut raw.as_slice(), Network::Mainnet).unwrap();
+    let prevouts: Vec<TxOut> = given["utxosSpent"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|utxo| {
+            let script = decode_hex(utxo["scriptPubKey"].as_str().unwrap()).unwrap();
+            TxOut::new(
+                utxo["amountSats"].as_u64().unwrap(),
+                Script::from_bytes(script),
+            )
+        })
+        .collect();
+    for input in vector["inputSpending"].as_array().unwrap() {
+        let index = input["given"]["txinIndex"].as_u64().unwrap() as usize;
+        let sighash_type = input["given"]["hashType"].as_u64().unwrap() as u32;
+        let z = tx
+            .sig_hash_bip341(index, &prevouts, sighash_type, None, None)
+            .unwrap();
+        assert_eq!(
+            encode_hex(&z),
+            input["intermediary"]["sigHash"].as_str().unwrap()
+        );
     }
+
+    assert!(tx
+        .sig_hash_bip341(0, &prevouts[1..], SIGHASH_ALL, None, None)
+        .is_err());
+    assert!(tx.sig_hash_bip341(0, &prevouts, 0x04, None, None).is_err());
+    assert!(tx
+        .sig_hash_bip341(3, &prevouts, SIGHASH_SINGLE, None, None)
+        .is_err());
+    // The annex and the leaf are committed to
+    let z = tx
+        .sig_hash_bip341(0, &prevouts, SIGHASH_DEFAULT, None, None)
+        .unwrap();
+    let annexed = tx
+        .sig_hash_bip341(0, &prevouts, SIGHASH_DEFAULT, Some(&[0x50]), None)
+        .unwrap();
+    let leaf = tx
+        .sig_hash_bip341(0, &prevouts, SIGHASH_DEFAULT, None, Some((&[0u8; 32], 0)))
+        .unwrap();
+    assert_ne!(z, annexed);
+    assert_ne!(z, leaf);
+}
+
+#[test]
+fn test_is_final() {
+    let mut tx = Tx::new(
+        2,
+        vec![TxIn::new([1u8; 32], 0, None, 0xfffffffe)],
+        vec![TxOut::new(1000, Script::new())],
+        0,
+        Network::Regtest,
+    );
+    assert!(tx.is_final(0, 0));
+    tx.locktime = 100;
+    assert!(!tx.is_final(100, 0));
+    assert!(tx.is_final(101, 0));
+    tx.locktime = 1_600_000_000;
+    assert!(!tx.is_final(1_000_000, 1_600_000_000));
+    assert!(tx.is_final(0, 1_600_000_001));
+    tx.tx_ins[0].sequence = SEQUENCE_FINAL;
+    assert!(tx.is_final(0, 0));
 }
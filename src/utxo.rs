@@ -0,0 +1,780 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::block::Block;
+use crate::helper::{encode_hex, encode_variant, hash256, read_variant};
+use crate::muhash::MuHash;
+use crate::network::Network;
+use crate::transaction::{TxIn, TxOut};
+use crate::validation::enforce_bip30;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Vec<u8>,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    // The txid is in display order, as returned by `Tx::hash`
+    pub fn new(txid: Vec<u8>, vout: u32) -> Self {
+        Self { txid, vout }
+    }
+
+    pub fn from_tx_in(tx_in: &TxIn) -> Self {
+        let mut txid = tx_in.prev_tx.to_vec();
+        txid.reverse();
+        Self::new(txid, tx_in.prev_index)
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut txid = vec![0u8; 32];
+        reader.read_exact(&mut txid)?;
+        txid.reverse();
+        let mut vout = [0u8; 4];
+        reader.read_exact(&mut vout)?;
+        Ok(Self::new(txid, u32::from_le_bytes(vout)))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = self.txid.clone();
+        result.reverse();
+        result.extend_from_slice(&self.vout.to_le_bytes());
+        result
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", encode_hex(&self.txid), self.vout)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Coin {
+    pub tx_out: TxOut,
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+impl Coin {
+    pub fn new(tx_out: TxOut, height: u32, is_coinbase: bool) -> Self {
+        Self {
+            tx_out,
+            height,
+            is_coinbase,
+        }
+    }
+
+    pub fn parse<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut code = [0u8; 4];
+        reader.read_exact(&mut code)?;
+        let code = u32::from_le_bytes(code);
+        let tx_out = TxOut::parse(reader)?;
+        Ok(Self::new(tx_out, code >> 1, code & 1 == 1))
+    }
+
+    // The height and coinbase flag share a field, as in Bitcoin Core
+    pub fn serialize(&self) -> Vec<u8> {
+        let code = (self.height << 1) | self.is_coinbase as u32;
+        let mut result = code.to_le_bytes().to_vec();
+        result.append(&mut self.tx_out.serialize());
+        result
+    }
+}
+
+// The coins a block spent, in input order
+pub type BlockUndo = Vec<Coin>;
+
+fn serialize_undo(undo: &[Coin]) -> Vec<u8> {
+    let mut result = encode_variant(undo.len() as u64);
+    for coin in undo {
+        result.append(&mut coin.serialize());
+    }
+    result
+}
+
+fn parse_undo<R>(reader: &mut R) -> Result<BlockUndo>
+where
+    R: Read,
+{
+    let count = read_variant(reader)?;
+    let mut result = vec![];
+    for _ in 0..count {
+        result.push(Coin::parse(reader)?);
+    }
+    Ok(result)
+}
+
+pub trait UtxoStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<&Coin>;
+    fn insert(&mut self, outpoint: OutPoint, coin: Coin) -> Result<()>;
+    fn remove(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>>;
+    fn coins(&self) -> Box<dyn Iterator<Item = (&OutPoint, &Coin)> + '_>;
+    // Block hashes are in display order
+    fn get_undo(&self, block_hash: &[u8]) -> Option<&BlockUndo>;
+    fn insert_undo(&mut self, block_hash: &[u8], undo: BlockUndo) -> Result<()>;
+    fn remove_undo(&mut self, block_hash: &[u8]) -> Result<Option<BlockUndo>>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    coins: HashMap<OutPoint, Coin>,
+    undo: HashMap<Vec<u8>, BlockUndo>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UtxoStore for MemoryStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<&Coin> {
+        self.coins.get(outpoint)
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, coin: Coin) -> Result<()> {
+        self.coins.insert(outpoint, coin);
+        Ok(())
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>> {
+        Ok(self.coins.remove(outpoint))
+    }
+
+    fn coins(&self) -> Box<dyn Iterator<Item = (&OutPoint, &Coin)> + '_> {
+        Box::new(self.coins.iter())
+    }
+
+    fn get_undo(&self, block_hash: &[u8]) -> Option<&BlockUndo> {
+        self.undo.get(block_hash)
+    }
+
+    fn insert_undo(&mut self, block_hash: &[u8], undo: BlockUndo) -> Result<()> {
+        self.undo.insert(block_hash.to_vec(), undo);
+        Ok(())
+    }
+
+    fn remove_undo(&mut self, block_hash: &[u8]) -> Result<Option<BlockUndo>> {
+        Ok(self.undo.remove(block_hash))
+    }
+}
+
+const RECORD_COIN: u8 = 1;
+const RECORD_SPEND: u8 = 2;
+const RECORD_UNDO: u8 = 3;
+const RECORD_DROP_UNDO: u8 = 4;
+
+// An append-only log of changes, replayed into memory on open. The changes
+// between flushes, a block's worth, are written as one frame with its
+// length and checksum, so a crash mid-write leaves a torn last frame which
+// is dropped on the next open.
+pub struct FileStore {
+    path: PathBuf,
+    memory: MemoryStore,
+    log: BufWriter<File>,
+    pending: Vec<u8>,
+}
+
+impl FileStore {
+    pub fn open<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let mut memory = MemoryStore::new();
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let valid = Self::replay(&mut reader, &mut memory)?;
+            let file = OpenOptions::new().write(true).open(&path)?;
+            if file.metadata()?.len() > valid {
+                file.set_len(valid)?;
+                file.sync_all()?;
+            }
+        }
+        let log = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        Ok(Self {
+            path,
+            memory,
+            log,
+            pending: vec![],
+        })
+    }
+
+    // Applies every complete frame and returns the length they take up
+    fn replay<R>(reader: &mut R, memory: &mut MemoryStore) -> Result<u64>
+    where
+        R: Read,
+    {
+        let mut valid = 0;
+        loop {
+            let mut header = [0u8; 8];
+            let read = read_full(reader, &mut header)?;
+            if read < header.len() {
+                return Ok(valid);
+            }
+            let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let mut payload = vec![];
+            reader.take(len as u64).read_to_end(&mut payload)?;
+            if payload.len() < len {
+                return Ok(valid);
+            }
+            if hash256(&payload)[..4] != header[4..] {
+                // Only the last frame can be torn
+                if read_full(reader, &mut [0u8; 1])? == 0 {
+                    return Ok(valid);
+                }
+                return Err(anyhow!("Bad checksum in UTXO log at {}", valid));
+            }
+            Self::apply(&mut payload.as_slice(), memory)?;
+            valid += (header.len() + len) as u64;
+        }
+    }
+
+    fn apply<R>(reader: &mut R, memory: &mut MemoryStore) -> Result<()>
+    where
+        R: Read,
+    {
+        let mut kind = [0u8; 1];
+        while reader.read(&mut kind)? != 0 {
+            match kind[0] {
+                RECORD_COIN => {
+                    let outpoint = OutPoint::parse(reader)?;
+                    memory.insert(outpoint, Coin::parse(reader)?)?;
+                }
+                RECORD_SPEND => {
+                    memory.remove(&OutPoint::parse(reader)?)?;
+                }
+                RECORD_UNDO | RECORD_DROP_UNDO => {
+                    let mut block_hash = [0u8; 32];
+                    reader.read_exact(&mut block_hash)?;
+                    if kind[0] == RECORD_UNDO {
+                        memory.insert_undo(&block_hash, parse_undo(reader)?)?;
+                    } else {
+                        memory.remove_undo(&block_hash)?;
+                    }
+                }
+                kind => return Err(anyhow!("Unknown UTXO log record {}", kind)),
+            }
+        }
+        Ok(())
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut result = (payload.len() as u32).to_le_bytes().to_vec();
+        result.extend_from_slice(&hash256(payload)[..4]);
+        result.extend_from_slice(payload);
+        result
+    }
+
+    fn coin_record(outpoint: &OutPoint, coin: &Coin) -> Vec<u8> {
+        let mut result = vec![RECORD_COIN];
+        result.append(&mut outpoint.serialize());
+        result.append(&mut coin.serialize());
+        result
+    }
+
+    fn undo_record(block_hash: &[u8], undo: &[Coin]) -> Vec<u8> {
+        let mut result = vec![RECORD_UNDO];
+        result.extend_from_slice(block_hash);
+        result.append(&mut serialize_undo(undo));
+        result
+    }
+
+    // Rewrites the log with only the current state
+    pub fn compact(&mut self) -> Result<()> {
+        self.flush()?;
+        let tmp = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for (outpoint, coin) in self.memory.coins() {
+            writer.write_all(&Self::frame(&Self::coin_record(outpoint, coin)))?;
+        }
+        for (block_hash, undo) in self.memory.undo.iter() {
+            writer.write_all(&Self::frame(&Self::undo_record(block_hash, undo)))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.log = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+}
+
+impl UtxoStore for FileStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<&Coin> {
+        self.memory.get(outpoint)
+    }
+
+    fn insert(&mut self, outpoint: OutPoint, coin: Coin) -> Result<()> {
+        self.pending
+            .append(&mut Self::coin_record(&outpoint, &coin));
+        self.memory.insert(outpoint, coin)
+    }
+
+    fn remove(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>> {
+        self.pending.push(RECORD_SPEND);
+        self.pending.append(&mut outpoint.serialize());
+        self.memory.remove(outpoint)
+    }
+
+    fn coins(&self) -> Box<dyn Iterator<Item = (&OutPoint, &Coin)> + '_> {
+        self.memory.coins()
+    }
+
+    fn get_undo(&self, block_hash: &[u8]) -> Option<&BlockUndo> {
+        self.memory.get_undo(block_hash)
+    }
+
+    fn insert_undo(&mut self, block_hash: &[u8], undo: BlockUndo) -> Result<()> {
+        self.pending
+            .append(&mut Self::undo_record(block_hash, &undo));
+        self.memory.insert_undo(block_hash, undo)
+    }
+
+    fn remove_undo(&mut self, block_hash: &[u8]) -> Result<Option<BlockUndo>> {
+        self.pending.push(RECORD_DROP_UNDO);
+        self.pending.extend_from_slice(block_hash);
+        self.memory.remove_undo(block_hash)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.log.write_all(&Self::frame(&self.pending))?;
+            self.pending.clear();
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+// Reads until the buffer is full or the reader ends, returning how much was
+// read
+fn read_full<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize>
+where
+    R: Read,
+{
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+// What MuHash commits to for each coin, as in Bitcoin Core's
+// gettxoutsetinfo
+fn muhash_element(outpoint: &OutPoint, coin: &Coin) -> Vec<u8> {
+    [outpoint.serialize(), coin.serialize()].concat()
+}
+
+pub struct UtxoSet<S> {
+    store: S,
+    muhash: MuHash,
+}
+
+impl<S> UtxoSet<S>
+where
+    S: UtxoStore,
+{
+    pub fn new(store: S) -> Self {
+        let mut muhash = MuHash::new();
+        for (outpoint, coin) in store.coins() {
+            muhash.insert(&muhash_element(outpoint, coin));
+        }
+        Self { store, muhash }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Coin> {
+        self.store.get(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.coins().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn muhash(&self) -> Vec<u8> {
+        self.muhash.finalize()
+    }
+
+    // Spends the inputs and adds the outputs of every transaction, keeping
+    // the spent coins as undo data. Nothing changes if an input is missing.
+    // Where BIP30 isn't enforced an output may replace an unspent coin,
+    // which is then lost, as in Bitcoin Core.
+    pub fn connect_block(&mut self, block: &Block, height: u32, network: Network) -> Result<()> {
+        let block_id = block.id();
+        let bip30 = enforce_bip30(network, height, &block.hash());
+        let mut created = HashSet::new();
+        let mut spent = HashSet::new();
+        for tx in &block.txs {
+            if !tx.is_coinbase() {
+                for tx_in in &tx.tx_ins {
+                    let outpoint = OutPoint::from_tx_in(tx_in);
+                    let exists = created.contains(&outpoint) || self.get(&outpoint).is_some();
+                    if !exists || !spent.insert(outpoint.clone()) {
+                        return Err(anyhow!(
+                            "Block {} spends missing output {}",
+                            block_id,
+                            outpoint
+                        ));
+                    }
+                }
+            }
+            let txid = tx.hash();
+            for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+                let outpoint = OutPoint::new(txid.clone(), vout as u32);
+                if tx_out.script_pubkey.is_unspendable() {
+                    continue;
+                }
+                let unspent = self.get(&outpoint).is_some() && !spent.contains(&outpoint);
+                if (bip30 && unspent) || !created.insert(outpoint.clone()) {
+                    return Err(anyhow!(
+                        "Block {} overwrites unspent output {}",
+                        block_id,
+                        outpoint
+                    ));
+                }
+            }
+        }
+
+        let mut undo = vec![];
+        for tx in &block.txs {
+            if !tx.is_coinbase() {
+                for tx_in in &tx.tx_ins {
+                    let outpoint = OutPoint::from_tx_in(tx_in);
+                    let coin = self.store.remove(&outpoint)?.unwrap();
+                    self.muhash.remove(&muhash_element(&outpoint, &coin));
+                    undo.push(coin);
+                }
+            }
+            let txid = tx.hash();
+            for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+                if tx_out.script_pubkey.is_unspendable() {
+                    continue;
+                }
+                let outpoint = OutPoint::new(txid.clone(), vout as u32);
+                if let Some(old) = self.get(&outpoint) {
+                    self.muhash.remove(&muhash_element(&outpoint, old));
+                }
+                let coin = Coin::new(tx_out.clone(), height, tx.is_coinbase());
+                self.muhash.insert(&muhash_element(&outpoint, &coin));
+                self.store.insert(outpoint, coin)?;
+            }
+        }
+        self.store.insert_undo(&block.hash(), undo)?;
+        self.store.flush()
+    }
+
+    // Reverts the last connected block using its undo data
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<()> {
+        let block_hash = block.hash();
+        let mut undo = self
+            .store
+            .get_undo(&block_hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("No undo data for block {}", block.id()))?;
+        let spends: Vec<OutPoint> = block
+            .txs
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.tx_ins.iter().map(OutPoint::from_tx_in))
+            .collect();
+        if spends.len() != undo.len() {
+            return Err(anyhow!("Undo data doesn't match block {}", block.id()));
+        }
+        for tx in &block.txs {
+            let txid = tx.hash();
+            for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+                let outpoint = OutPoint::new(txid.clone(), vout as u32);
+                if !tx_out.script_pubkey.is_unspendable()
+                    && self.get(&outpoint).is_none()
+                    && !spends.contains(&outpoint)
+                {
+                    return Err(anyhow!(
+                        "Output {} of block {} is spent",
+                        outpoint,
+                        block.id()
+                    ));
+                }
+            }
+        }
+
+        for tx in block.txs.iter().rev() {
+            let txid = tx.hash();
+            for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+                if tx_out.script_pubkey.is_unspendable() {
+                    continue;
+                }
+                let outpoint = OutPoint::new(txid.clone(), vout as u32);
+                let coin = self.store.remove(&outpoint)?.unwrap();
+                self.muhash.remove(&muhash_element(&outpoint, &coin));
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for tx_in in tx.tx_ins.iter().rev() {
+                let outpoint = OutPoint::from_tx_in(tx_in);
+                let coin = undo.pop().unwrap();
+                self.muhash.insert(&muhash_element(&outpoint, &coin));
+                self.store.insert(outpoint, coin)?;
+            }
+        }
+        self.store.remove_undo(&block_hash)?;
+        self.store.flush()
+    }
+}
+
+#[cfg(test)]
+use crate::block::BlockHeader;
+#[cfg(test)]
+use crate::script::Script;
+#[cfg(test)]
+use crate::testutil::{test_coinbase, test_unsigned_spend};
+#[cfg(test)]
+use crate::transaction::Tx;
+
+#[cfg(test)]
+fn test_block(height: u32, txs: Vec<Tx>) -> Block {
+    let header = BlockHeader::new(1, [0u8; 32], [0u8; 32], height, 0x207fffff, 0);
+    let mut coinbase = test_coinbase(height, 50_0000_0000, &Script::p2wpkh(&[height as u8; 20]));
+    coinbase
+        .tx_outs
+        .push(TxOut::new(0, Script::op_return(b"commitment")));
+    let mut block = Block::new(header, [vec![coinbase], txs].concat());
+    let merkle_root = block.merkle_root().unwrap();
+    block.header.merkle_root.copy_from_slice(&merkle_root);
+    block
+}
+
+// Pays each amount to the same script
+#[cfg(test)]
+fn test_payments(amounts: &[u64]) -> Vec<TxOut> {
+    amounts
+        .iter()
+        .map(|amount| TxOut::new(*amount, Script::p2wpkh(&[0xaa; 20])))
+        .collect()
+}
+
+// Connects three blocks, the last one spending an output created in the same
+// block, then disconnects them again
+#[cfg(test)]
+fn check_connect_disconnect<S>(store: S) -> UtxoSet<S>
+where
+    S: UtxoStore,
+{
+    let mut utxos = UtxoSet::new(store);
+    let empty = utxos.muhash();
+
+    let block1 = test_block(1, vec![]);
+    let coinbase1 = OutPoint::new(block1.txs[0].hash(), 0);
+    utxos.connect_block(&block1, 1, Network::Regtest).unwrap();
+    assert_eq!(utxos.len(), 1);
+    let coin = utxos.get(&coinbase1).unwrap();
+    assert_eq!((coin.height, coin.is_coinbase), (1, true));
+    assert!(utxos.get(&OutPoint::new(block1.txs[0].hash(), 1)).is_none());
+    let after1 = utxos.muhash();
+
+    let spend = test_unsigned_spend(
+        std::slice::from_ref(&coinbase1),
+        test_payments(&[10_0000_0000, 39_0000_0000]),
+    );
+    let block2 = test_block(2, vec![spend.clone()]);
+    utxos.connect_block(&block2, 2, Network::Regtest).unwrap();
+    assert!(utxos.get(&coinbase1).is_none());
+    assert_eq!(utxos.len(), 3);
+    let after2 = utxos.muhash();
+
+    let chained = test_unsigned_spend(
+        &[OutPoint::new(spend.hash(), 0)],
+        test_payments(&[9_0000_0000]),
+    );
+    let spend_chained = test_unsigned_spend(
+        &[OutPoint::new(chained.hash(), 0)],
+        test_payments(&[8_0000_0000]),
+    );
+    let block3 = test_block(3, vec![chained, spend_chained.clone()]);
+    utxos.connect_block(&block3, 3, Network::Regtest).unwrap();
+    assert_eq!(utxos.len(), 4);
+    let coin = utxos.get(&OutPoint::new(spend_chained.hash(), 0)).unwrap();
+    assert_eq!((coin.height, coin.is_coinbase), (3, false));
+
+    // Double spends and missing outputs leave the set alone
+    let before = utxos.muhash();
+    let double = test_block(
+        4,
+        vec![test_unsigned_spend(
+            std::slice::from_ref(&coinbase1),
+            test_payments(&[1]),
+        )],
+    );
+    assert!(utxos.connect_block(&double, 4, Network::Regtest).is_err());
+    assert!(utxos.connect_block(&block3, 4, Network::Regtest).is_err());
+    assert_eq!(utxos.muhash(), before);
+    assert!(utxos.disconnect_block(&double).is_err());
+
+    utxos.disconnect_block(&block3).unwrap();
+    assert_eq!(utxos.muhash(), after2);
+    assert!(utxos.disconnect_block(&block3).is_err());
+    utxos.disconnect_block(&block2).unwrap();
+    assert_eq!(utxos.muhash(), after1);
+    assert_eq!(utxos.get(&coinbase1).unwrap().height, 1);
+
+    // Reconnecting gives the same set
+    utxos.connect_block(&block2, 2, Network::Regtest).unwrap();
+    assert_eq!(utxos.muhash(), after2);
+    utxos.disconnect_block(&block2).unwrap();
+    utxos.disconnect_block(&block1).unwrap();
+    assert_eq!(utxos.muhash(), empty);
+    assert!(utxos.is_empty());
+
+    utxos.connect_block(&block1, 1, Network::Regtest).unwrap();
+    utxos.connect_block(&block2, 2, Network::Regtest).unwrap();
+    utxos
+}
+
+#[test]
+fn test_coin() {
+    let coin = Coin::new(TxOut::new(1000, Script::p2wpkh(&[1u8; 20])), 700000, true);
+    let raw = coin.serialize();
+    assert_eq!(&raw[..4], &(700000u32 * 2 + 1).to_le_bytes());
+    assert_eq!(Coin::parse(&mut raw.as_slice()).unwrap(), coin);
+
+    let outpoint = OutPoint::new(vec![0xab; 32], 3);
+    let raw = outpoint.serialize();
+    assert_eq!(raw.len(), 36);
+    assert_eq!(OutPoint::parse(&mut raw.as_slice()).unwrap(), outpoint);
+}
+
+#[test]
+fn test_memory_store() {
+    let utxos = check_connect_disconnect(MemoryStore::new());
+    assert_eq!(utxos.len(), 3);
+}
+
+#[test]
+fn test_duplicate_coinbase() {
+    let mut utxos = UtxoSet::new(MemoryStore::new());
+    let block = test_block(1, vec![]);
+    let outpoint = OutPoint::new(block.txs[0].hash(), 0);
+    utxos.connect_block(&block, 1, Network::Mainnet).unwrap();
+    let before = utxos.muhash();
+    assert!(utxos.connect_block(&block, 2, Network::Mainnet).is_err());
+    assert_eq!(utxos.muhash(), before);
+
+    // Once BIP34 is active the duplicate replaces the coin
+    utxos
+        .connect_block(&block, 300_000, Network::Mainnet)
+        .unwrap();
+    assert_eq!(utxos.len(), 1);
+    assert_eq!(utxos.get(&outpoint).unwrap().height, 300_000);
+    let mut expected = MuHash::new();
+    expected.insert(&muhash_element(&outpoint, utxos.get(&outpoint).unwrap()));
+    assert_eq!(utxos.muhash(), expected.finalize());
+}
+
+#[test]
+fn test_file_store() {
+    let path = std::env::temp_dir().join(format!("utxo-test-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let utxos = check_connect_disconnect(FileStore::open(&path).unwrap());
+    let muhash = utxos.muhash();
+    let coins: HashMap<OutPoint, Coin> = utxos
+        .store()
+        .coins()
+        .map(|(outpoint, coin)| (outpoint.clone(), coin.clone()))
+        .collect();
+    drop(utxos);
+
+    // The log replays to the same set, and so does the compacted log
+    let mut store = FileStore::open(&path).unwrap();
+    for (outpoint, coin) in coins.iter() {
+        assert_eq!(store.get(outpoint), Some(coin));
+    }
+    let size = fs::metadata(&path).unwrap().len();
+    store.compact().unwrap();
+    assert!(fs::metadata(&path).unwrap().len() < size);
+    drop(store);
+
+    let mut utxos = UtxoSet::new(FileStore::open(&path).unwrap());
+    assert_eq!(utxos.muhash(), muhash);
+    let block2 = test_block(2, vec![]);
+    assert!(utxos.disconnect_block(&block2).is_err());
+    let spend = test_unsigned_spend(
+        &[OutPoint::new(test_block(1, vec![]).txs[0].hash(), 0)],
+        test_payments(&[10_0000_0000, 39_0000_0000]),
+    );
+    utxos.disconnect_block(&test_block(2, vec![spend])).unwrap();
+    assert_eq!(utxos.len(), 1);
+    drop(utxos);
+    assert_eq!(UtxoSet::new(FileStore::open(&path).unwrap()).len(), 1);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_torn_log() {
+    let path = std::env::temp_dir().join(format!("utxo-torn-{}.log", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut utxos = UtxoSet::new(FileStore::open(&path).unwrap());
+    utxos
+        .connect_block(&test_block(1, vec![]), 1, Network::Regtest)
+        .unwrap();
+    let muhash = utxos.muhash();
+    let size = fs::metadata(&path).unwrap().len();
+    utxos
+        .connect_block(&test_block(2, vec![]), 2, Network::Regtest)
+        .unwrap();
+    drop(utxos);
+
+    // Each block is one frame, and a partly written last one is dropped
+    let full = fs::read(&path).unwrap();
+    for len in [size as usize + 5, full.len() - 1].iter() {
+        fs::write(&path, &full[..*len]).unwrap();
+        let utxos = UtxoSet::new(FileStore::open(&path).unwrap());
+        assert_eq!(utxos.muhash(), muhash);
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+    }
+    let mut torn = full.clone();
+    *torn.last_mut().unwrap() ^= 1;
+    fs::write(&path, &torn).unwrap();
+    assert_eq!(
+        UtxoSet::new(FileStore::open(&path).unwrap()).muhash(),
+        muhash
+    );
+
+    // Corruption before the end is an error
+    let mut corrupt = full;
+    corrupt[10] ^= 1;
+    fs::write(&path, &corrupt).unwrap();
+    assert!(FileStore::open(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
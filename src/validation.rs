@@ -0,0 +1,682 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+
+use crate::block::Block;
+use crate::chain::HeaderChain;
+use crate::helper::encode_hex;
+use crate::interpreter::{
+    verify_input, VERIFY_CHECKLOCKTIMEVERIFY, VERIFY_CHECKSEQUENCEVERIFY, VERIFY_DERSIG,
+    VERIFY_NONE, VERIFY_NULLDUMMY, VERIFY_P2SH, VERIFY_TAPROOT, VERIFY_WITNESS,
+};
+use crate::network::Network;
+use crate::script::{Cmd, Script};
+use crate::transaction::{
+    Tx, TxOut, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY,
+    SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG, WITNESS_SCALE_FACTOR,
+};
+use crate::utxo::{Coin, OutPoint, UtxoSet, UtxoStore};
+
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+pub const COINBASE_MATURITY: u32 = 100;
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+// Blocks that were mined before the soft forks they violate were enforced,
+// in display order
+const SCRIPT_FLAG_EXCEPTIONS: [(Network, &str, u32); 3] = [
+    (
+        Network::Mainnet,
+        "00000000000002dc756eebf4f49723ed8d30cc28a5f108eb94b1ba88ac4f9c22",
+        VERIFY_NONE,
+    ),
+    (
+        Network::Mainnet,
+        "0000000000000000000f14c35b2d841e986ab5441de8c585d5ffe55ea1e395ad",
+        VERIFY_P2SH | VERIFY_WITNESS,
+    ),
+    (
+        Network::Testnet3,
+        "00000000dd30457c001f4095d208cc1296b0eed002427aa599874af7a432b105",
+        VERIFY_NONE,
+    ),
+];
+
+// Blocks whose coinbases duplicate earlier ones, allowed by Bitcoin Core
+const BIP30_EXCEPTIONS: [(u32, &str); 2] = [
+    (
+        91842,
+        "00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec",
+    ),
+    (
+        91880,
+        "00000000000743f190a18c5577a3c2d2a1f610ae9601ac046a38084ccb7cd721",
+    ),
+];
+
+// From this height a coinbase could repeat one of a block from before BIP34,
+// whose scriptSig didn't start with its height
+const BIP34_IMPLIES_BIP30_LIMIT: u32 = 1_983_702;
+
+pub fn block_subsidy(height: u32, network: Network) -> u64 {
+    let halvings = height / network.subsidy_halving_interval();
+    if halvings >= 64 {
+        return 0;
+    }
+    (50 * 100_000_000) >> halvings
+}
+
+// P2SH, segwit and taproot are checked from genesis, as Bitcoin Core does
+// now that they are buried. The exceptions only replace those, the height
+// activated flags still apply.
+pub fn script_flags(network: Network, height: u32, block_hash: &[u8]) -> u32 {
+    let id = encode_hex(block_hash);
+    let mut flags = SCRIPT_FLAG_EXCEPTIONS
+        .iter()
+        .find(|(n, hash, _)| n == &network && *hash == id)
+        .map(|(_, _, flags)| *flags)
+        .unwrap_or(VERIFY_P2SH | VERIFY_WITNESS | VERIFY_TAPROOT);
+    if height >= network.bip66_height() {
+        flags |= VERIFY_DERSIG;
+    }
+    if height >= network.bip65_height() {
+        flags |= VERIFY_CHECKLOCKTIMEVERIFY;
+    }
+    if height >= network.csv_height() {
+        flags |= VERIFY_CHECKSEQUENCEVERIFY;
+    }
+    if height >= network.segwit_height() {
+        flags |= VERIFY_NULLDUMMY;
+    }
+    flags
+}
+
+// BIP34 makes coinbases, and so every transaction, unique, which lets
+// Bitcoin Core skip the BIP30 lookups once it is active
+pub fn enforce_bip30(network: Network, height: u32, block_hash: &[u8]) -> bool {
+    let id = encode_hex(block_hash);
+    if network == Network::Mainnet
+        && BIP30_EXCEPTIONS
+            .iter()
+            .any(|(h, hash)| *h == height && *hash == id)
+    {
+        return false;
+    }
+    height < network.bip34_height() || height >= BIP34_IMPLIES_BIP30_LIMIT
+}
+
+// Checks which don't need the chain or the spent outputs
+pub fn check_transaction(tx: &Tx) -> Result<()> {
+    if tx.tx_ins.is_empty() {
+        return Err(anyhow!("Transaction {} has no inputs", tx.id()));
+    }
+    if tx.tx_outs.is_empty() {
+        return Err(anyhow!("Transaction {} has no outputs", tx.id()));
+    }
+    if tx.serialize_legacy().len() * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT {
+        return Err(anyhow!("Transaction {} is too large", tx.id()));
+    }
+    let mut total = 0u64;
+    for tx_out in &tx.tx_outs {
+        total += tx_out.amount;
+        if tx_out.amount > MAX_MONEY || total > MAX_MONEY {
+            return Err(anyhow!("Transaction {} pays out too much", tx.id()));
+        }
+    }
+    let mut outpoints = HashSet::new();
+    for tx_in in &tx.tx_ins {
+        if !outpoints.insert(tx_in.outpoint()) {
+            return Err(anyhow!("Transaction {} spends an input twice", tx.id()));
+        }
+    }
+    if tx.is_coinbase() {
+        let len = tx.tx_ins[0].script_sig.len();
+        if !(2..=100).contains(&len) {
+            return Err(anyhow!("Coinbase scriptSig has {} bytes", len));
+        }
+    } else if tx
+        .tx_ins
+        .iter()
+        .any(|tx_in| tx_in.prev_tx == [0u8; 32] && tx_in.prev_index == 0xffffffff)
+    {
+        return Err(anyhow!("Transaction {} spends a null output", tx.id()));
+    }
+    Ok(())
+}
+
+// Sigops in the scriptSigs and output scripts, counted the pre-P2SH way
+pub fn legacy_sigop_count(tx: &Tx) -> usize {
+    let inputs: usize = tx
+        .tx_ins
+        .iter()
+        .map(|tx_in| tx_in.script_sig.sigop_count(false))
+        .sum();
+    let outputs: usize = tx
+        .tx_outs
+        .iter()
+        .map(|tx_out| tx_out.script_pubkey.sigop_count(false))
+        .sum();
+    inputs + outputs
+}
+
+fn redeem_script(script_sig: &Script) -> Option<Script> {
+    if !script_sig.is_push_only() {
+        return None;
+    }
+    match script_sig.cmds().ok()?.pop()? {
+        Cmd::Data(data) => Some(Script::from_bytes(data)),
+        Cmd::Op(_) => Some(Script::new()),
+    }
+}
+
+// BIP141 sigop cost: legacy and P2SH sigops weigh 4, witness sigops 1
+pub fn sigop_cost(tx: &Tx, prevouts: &[TxOut], flags: u32) -> usize {
+    let mut cost = legacy_sigop_count(tx) * WITNESS_SCALE_FACTOR;
+    if tx.is_coinbase() {
+        return cost;
+    }
+    for (tx_in, prevout) in tx.tx_ins.iter().zip(prevouts) {
+        let mut program_script = prevout.script_pubkey.clone();
+        if flags & VERIFY_P2SH != 0 && prevout.script_pubkey.is_p2sh() {
+            if let Some(redeem) = redeem_script(&tx_in.script_sig) {
+                cost += redeem.sigop_count(true) * WITNESS_SCALE_FACTOR;
+                program_script = redeem;
+            }
+        }
+        if flags & VERIFY_WITNESS == 0 {
+            continue;
+        }
+        cost += match program_script.witness_program() {
+            Some((0, program)) if program.len() == 20 => 1,
+            Some((0, program)) if program.len() == 32 => match tx_in.witness.last() {
+                Some(script) => Script::from_bytes(script.clone()).sigop_count(true),
+                None => 0,
+            },
+            _ => 0,
+        };
+    }
+    cost
+}
+
+// The state a block is validated against: the headers up to its parent and
+// the coins unspent after it
+pub struct PrevState<'a, S> {
+    pub chain: &'a HeaderChain,
+    pub utxos: &'a UtxoSet<S>,
+}
+
+// BIP68: inputs of version 2 transactions can't be spent until their coins
+// are old enough, in blocks or in 512 second units of median time past
+pub fn check_sequence_locks(
+    tx: &Tx,
+    coins: &[Coin],
+    chain: &HeaderChain,
+    prev_hash: &[u8],
+    height: u32,
+) -> Result<()> {
+    if tx.version < 2 {
+        return Ok(());
+    }
+    let prev_mtp = chain.median_time_past(prev_hash).unwrap();
+    for (tx_in, coin) in tx.tx_ins.iter().zip(coins) {
+        if tx_in.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            continue;
+        }
+        let value = tx_in.sequence & SEQUENCE_LOCKTIME_MASK;
+        let locked = if tx_in.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            // Measured from the median time past of the block before the coin's
+            let coin_block = chain
+                .ancestor(prev_hash, coin.height.max(1) - 1)
+                .ok_or_else(|| anyhow!("No block at height {}", coin.height))?;
+            let coin_mtp = chain.median_time_past(&coin_block.header.hash()).unwrap();
+            coin_mtp as u64 + ((value as u64) << SEQUENCE_LOCKTIME_GRANULARITY) > prev_mtp as u64
+        } else {
+            coin.height + value > height
+        };
+        if locked {
+            return Err(anyhow!(
+                "Transaction {} spends {} before its sequence lock",
+                tx.id(),
+                OutPoint::from_tx_in(tx_in)
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Full consensus validation of a block extending `prev`. Returns the fees it
+// collects.
+pub fn validate_block<S>(block: &Block, prev: &PrevState<S>) -> Result<u64>
+where
+    S: UtxoStore,
+{
+    block.validate()?;
+    let network = prev.chain.network;
+    let prev_hash = block.header.prev_hash();
+    let parent = prev.chain.get(&prev_hash).ok_or_else(|| {
+        anyhow!(
+            "Block {} has unknown parent {}",
+            block.id(),
+            block.header.prev_block_id()
+        )
+    })?;
+    let height = parent.height + 1;
+    prev.chain.check_header(&block.header)?;
+
+    if block.weight() > MAX_BLOCK_WEIGHT {
+        return Err(anyhow!("Block {} is too heavy", block.id()));
+    }
+    if height < network.segwit_height() && block.txs.iter().any(|tx| tx.is_segwit()) {
+        return Err(anyhow!(
+            "Block {} has witness data before segwit",
+            block.id()
+        ));
+    }
+    for tx in &block.txs {
+        check_transaction(tx)?;
+    }
+    let coinbase = &block.txs[0];
+    if height >= network.bip34_height() {
+        let expected = Script::from_cmds(&[Cmd::num(height as i64)]);
+        if !coinbase.tx_ins[0]
+            .script_sig
+            .as_bytes()
+            .starts_with(expected.as_bytes())
+        {
+            return Err(anyhow!("Coinbase doesn't start with height {}", height));
+        }
+    }
+
+    // BIP113: locktimes are compared to the median time past once CSV is
+    // active
+    let locktime_cutoff = if height >= network.csv_height() {
+        prev.chain.median_time_past(&prev_hash).unwrap()
+    } else {
+        block.header.timestamp
+    };
+    let flags = script_flags(network, height, &block.hash());
+    let bip30 = enforce_bip30(network, height, &block.hash());
+    let mut created: HashMap<OutPoint, Coin> = HashMap::new();
+    let mut spent = HashSet::new();
+    let mut fees = 0u64;
+    let mut sigops = 0;
+    for tx in &block.txs {
+        if !tx.is_final(height, locktime_cutoff) {
+            return Err(anyhow!("Transaction {} is not final", tx.id()));
+        }
+        let mut coins = vec![];
+        if !tx.is_coinbase() {
+            for tx_in in &tx.tx_ins {
+                let outpoint = OutPoint::from_tx_in(tx_in);
+                let coin = created
+                    .get(&outpoint)
+                    .or_else(|| prev.utxos.get(&outpoint))
+                    .filter(|_| !spent.contains(&outpoint))
+                    .ok_or_else(|| {
+                        anyhow!("Transaction {} spends missing output {}", tx.id(), outpoint)
+                    })?;
+                if coin.is_coinbase && height - coin.height < COINBASE_MATURITY {
+                    return Err(anyhow!(
+                        "Transaction {} spends immature coinbase {}",
+                        tx.id(),
+                        outpoint
+                    ));
+                }
+                coins.push(coin.clone());
+                spent.insert(outpoint);
+            }
+            let value_in: u64 = coins.iter().map(|coin| coin.tx_out.amount).sum();
+            let value_out: u64 = tx.tx_outs.iter().map(|tx_out| tx_out.amount).sum();
+            if value_in > MAX_MONEY || value_in < value_out {
+                return Err(anyhow!(
+                    "Transaction {} spends {} but pays {}",
+                    tx.id(),
+                    value_in,
+                    value_out
+                ));
+            }
+            fees += value_in - value_out;
+            if height >= network.csv_height() {
+                check_sequence_locks(tx, &coins, prev.chain, &prev_hash, height)?;
+            }
+        }
+
+        let prevouts: Vec<TxOut> = coins.iter().map(|coin| coin.tx_out.clone()).collect();
+        sigops += sigop_cost(tx, &prevouts, flags);
+        if sigops > MAX_BLOCK_SIGOPS_COST {
+            return Err(anyhow!("Block {} has too many sigops", block.id()));
+        }
+        if !tx.is_coinbase() {
+            for i in 0..tx.tx_ins.len() {
+                verify_input(tx, i, &prevouts, flags)
+                    .map_err(|e| anyhow!("Transaction {} input {} failed: {}", tx.id(), i, e))?;
+            }
+        }
+
+        // BIP30: a transaction may not overwrite an unspent one's outputs
+        let txid = tx.hash();
+        for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+            if tx_out.script_pubkey.is_unspendable() {
+                continue;
+            }
+            let outpoint = OutPoint::new(txid.clone(), vout as u32);
+            let unspent = (created.contains_key(&outpoint) || prev.utxos.get(&outpoint).is_some())
+                && !spent.contains(&outpoint);
+            if bip30 && unspent {
+                return Err(anyhow!(
+                    "Transaction {} overwrites unspent output {}",
+                    tx.id(),
+                    outpoint
+                ));
+            }
+            spent.remove(&outpoint);
+            created.insert(
+                outpoint,
+                Coin::new(tx_out.clone(), height, tx.is_coinbase()),
+            );
+        }
+    }
+
+    let reward: u64 = coinbase.tx_outs.iter().map(|tx_out| tx_out.amount).sum();
+    let allowed = block_subsidy(height, network) + fees;
+    if reward > allowed {
+        return Err(anyhow!(
+            "Coinbase pays {}, only {} allowed",
+            reward,
+            allowed
+        ));
+    }
+    Ok(fees)
+}
+
+#[cfg(test)]
+use crate::helper::hash160;
+#[cfg(test)]
+use crate::s256::PrivateKey;
+#[cfg(test)]
+use crate::testutil::{test_coinbase, test_mine, test_spend, test_submit};
+#[cfg(test)]
+use crate::transaction::TxIn;
+#[cfg(test)]
+use crate::utxo::MemoryStore;
+#[cfg(test)]
+use num_bigint::BigInt;
+
+#[test]
+fn test_block_subsidy() {
+    let mainnet = Network::Mainnet;
+    assert_eq!(block_subsidy(0, mainnet), 50_0000_0000);
+    assert_eq!(block_subsidy(209_999, mainnet), 50_0000_0000);
+    assert_eq!(block_subsidy(210_000, mainnet), 25_0000_0000);
+    assert_eq!(block_subsidy(840_000, mainnet), 3_1250_0000);
+    assert_eq!(block_subsidy(6_930_000, mainnet), 0);
+    assert_eq!(block_subsidy(64 * 210_000, mainnet), 0);
+    assert_eq!(block_subsidy(150, Network::Regtest), 25_0000_0000);
+
+    let total: u64 = (0..64)
+        .map(|i| block_subsidy(i * 210_000, mainnet) * 210_000)
+        .sum();
+    assert!(total < MAX_MONEY);
+}
+
+#[test]
+fn test_script_flags() {
+    let base = VERIFY_P2SH | VERIFY_WITNESS | VERIFY_TAPROOT;
+    let hash = [0u8; 32];
+    assert_eq!(script_flags(Network::Mainnet, 100_000, &hash), base);
+    assert_eq!(
+        script_flags(Network::Mainnet, 400_000, &hash),
+        base | VERIFY_DERSIG | VERIFY_CHECKLOCKTIMEVERIFY
+    );
+    let all = base
+        | VERIFY_DERSIG
+        | VERIFY_CHECKLOCKTIMEVERIFY
+        | VERIFY_CHECKSEQUENCEVERIFY
+        | VERIFY_NULLDUMMY;
+    assert_eq!(script_flags(Network::Mainnet, 481_824, &hash), all);
+    assert_eq!(script_flags(Network::Regtest, 1, &hash), all);
+
+    let hash = crate::helper::decode_hex(
+        "0000000000000000000f14c35b2d841e986ab5441de8c585d5ffe55ea1e395ad",
+    )
+    .unwrap();
+    assert_eq!(
+        script_flags(Network::Mainnet, 692_261, &hash),
+        all & !VERIFY_TAPROOT
+    );
+    assert_eq!(script_flags(Network::Testnet3, 900_000, &hash), all);
+}
+
+#[test]
+fn test_enforce_bip30() {
+    let hash = [0u8; 32];
+    assert!(enforce_bip30(Network::Mainnet, 91_842, &hash));
+    assert!(!enforce_bip30(Network::Mainnet, 227_931, &hash));
+    assert!(enforce_bip30(Network::Mainnet, 1_983_702, &hash));
+    assert!(enforce_bip30(Network::Regtest, 0, &hash));
+    assert!(!enforce_bip30(Network::Regtest, 1, &hash));
+    for (height, id) in BIP30_EXCEPTIONS.iter() {
+        let hash = crate::helper::decode_hex(id).unwrap();
+        assert!(!enforce_bip30(Network::Mainnet, *height, &hash));
+        assert!(enforce_bip30(Network::Mainnet, height + 1, &hash));
+    }
+}
+
+#[test]
+fn test_sigop_cost() {
+    let secs: Vec<Vec<u8>> = (1..=3)
+        .map(|i| PrivateKey::new(BigInt::from(i)).unwrap().point.sec(true))
+        .collect();
+    let multisig = Script::multisig(2, &secs);
+    let spend = |script_sig: Script, witness: Vec<Vec<u8>>| {
+        let mut tx_in = TxIn::new([1u8; 32], 0, Some(script_sig), 0xffffffff);
+        tx_in.witness = witness;
+        let tx_out = TxOut::new(1000, Script::p2pkh(&[0u8; 20]));
+        Tx::new(2, vec![tx_in], vec![tx_out], 0, Network::Regtest)
+    };
+    let flags = VERIFY_P2SH | VERIFY_WITNESS;
+
+    // Bare multisig counts 20, the P2PKH output 1
+    let tx = spend(Script::new(), vec![]);
+    let prevouts = vec![TxOut::new(1000, multisig.clone())];
+    assert_eq!(legacy_sigop_count(&tx), 1);
+    assert_eq!(sigop_cost(&tx, &prevouts, flags), 4);
+    let prevouts = vec![TxOut::new(
+        1000,
+        Script::p2sh(&hash160(multisig.as_bytes())),
+    )];
+    let tx = spend(
+        Script::from_cmds(&[Cmd::num(0), Cmd::Data(multisig.as_bytes().to_vec())]),
+        vec![],
+    );
+    assert_eq!(sigop_cost(&tx, &prevouts, flags), 4 + 3 * 4);
+    assert_eq!(sigop_cost(&tx, &prevouts, VERIFY_NONE), 4);
+
+    let prevouts = vec![TxOut::new(
+        1000,
+        Script::p2wsh(&crate::helper::sha256(multisig.as_bytes())),
+    )];
+    let tx = spend(Script::new(), vec![vec![], multisig.as_bytes().to_vec()]);
+    assert_eq!(sigop_cost(&tx, &prevouts, flags), 4 + 3);
+    assert_eq!(sigop_cost(&tx, &prevouts, VERIFY_P2SH), 4);
+    let prevouts = vec![TxOut::new(1000, Script::p2wpkh(&[0u8; 20]))];
+    assert_eq!(sigop_cost(&tx, &prevouts, flags), 4 + 1);
+}
+
+#[test]
+fn test_check_transaction() {
+    let payee = Script::p2wpkh(&[0xcb; 20]);
+    let coinbase = test_coinbase(1, 50_0000_0000, &payee);
+    check_transaction(&coinbase).unwrap();
+
+    let mut tx = coinbase.clone();
+    tx.tx_ins[0].script_sig = Script::from_cmds(&[Cmd::num(1)]);
+    assert!(check_transaction(&tx).is_err());
+    let mut tx = coinbase.clone();
+    tx.tx_outs[0].amount = MAX_MONEY + 1;
+    assert!(check_transaction(&tx).is_err());
+    let mut tx = coinbase.clone();
+    tx.tx_outs.push(tx.tx_outs[0].clone());
+    tx.tx_outs[0].amount = MAX_MONEY;
+    assert!(check_transaction(&tx).is_err());
+    let mut tx = coinbase.clone();
+    tx.tx_outs.clear();
+    assert!(check_transaction(&tx).is_err());
+
+    let key = PrivateKey::new(BigInt::from(42)).unwrap();
+    let outpoint = OutPoint::new(vec![1u8; 32], 0);
+    let coins = [(outpoint.clone(), 1000), (outpoint, 1000)];
+    assert!(check_transaction(&test_spend(&key, &coins, &[500], 0xffffffff)).is_err());
+    check_transaction(&test_spend(&key, &coins[..1], &[500], 0xffffffff)).unwrap();
+}
+
+#[test]
+fn test_validate_block() {
+    let mut chain = HeaderChain::new(Network::Regtest);
+    let mut utxos = UtxoSet::new(MemoryStore::new());
+    let key = PrivateKey::new(BigInt::from(8888)).unwrap();
+    let subsidy = 50_0000_0000;
+    let payee = Script::p2wpkh(&[0xcb; 20]);
+
+    let coinbase = test_coinbase(1, subsidy, &Script::p2wpkh(&key.point.hash160(true)));
+    let block = test_mine(&chain.tip().header, coinbase.clone(), vec![]);
+    assert_eq!(test_submit(&mut chain, &mut utxos, &block).unwrap(), 0);
+    let coin = (OutPoint::new(coinbase.hash(), 0), subsidy);
+    for height in 2..100 {
+        let block = test_mine(
+            &chain.tip().header,
+            test_coinbase(height, subsidy, &payee),
+            vec![],
+        );
+        test_submit(&mut chain, &mut utxos, &block).unwrap();
+    }
+
+    let spend = test_spend(
+        &key,
+        std::slice::from_ref(&coin),
+        &[subsidy - 10_000],
+        0xffffffff,
+    );
+    let block = test_mine(
+        &chain.tip().header,
+        test_coinbase(100, subsidy, &payee),
+        vec![spend.clone()],
+    );
+    let prev = PrevState {
+        chain: &chain,
+        utxos: &utxos,
+    };
+    let err = validate_block(&block, &prev).unwrap_err();
+    assert!(err.to_string().contains("immature coinbase"));
+    let block = test_mine(
+        &chain.tip().header,
+        test_coinbase(100, subsidy, &payee),
+        vec![],
+    );
+
+    // The header has to pass the same checks as in the header chain
+    let regrind = |mut block: Block| {
+        while !block.header.check_pow() {
+            block.header.nonce += 1;
+        }
+        block
+    };
+    let mut bad = block.clone();
+    while bad.header.check_pow() {
+        bad.header.nonce += 1;
+    }
+    let err = validate_block(&bad, &prev).unwrap_err();
+    assert!(err.to_string().contains("proof of work"));
+    let mut bad = block.clone();
+    bad.header.bits = 0x207ffffe;
+    assert!(validate_block(&regrind(bad), &prev).is_err());
+    let mut bad = block.clone();
+    bad.header.timestamp = chain.median_time_past(&chain.tip().header.hash()).unwrap();
+    assert!(validate_block(&regrind(bad), &prev).is_err());
+    test_submit(&mut chain, &mut utxos, &block).unwrap();
+
+    let prev = PrevState {
+        chain: &chain,
+        utxos: &utxos,
+    };
+    let invalid = |coinbase: Tx, txs: Vec<Tx>| {
+        let block = test_mine(&chain.tip().header, coinbase, txs);
+        assert!(validate_block(&block, &prev).is_err());
+    };
+    // Overpaying coinbase, wrong BIP34 height
+    invalid(
+        test_coinbase(101, subsidy + 10_001, &payee),
+        vec![spend.clone()],
+    );
+    invalid(test_coinbase(5, subsidy, &payee), vec![]);
+    // Bad signature
+    let mut bad = spend.clone();
+    bad.tx_ins[0].witness[0][10] ^= 1;
+    invalid(test_coinbase(101, subsidy, &payee), vec![bad]);
+    // Double spend
+    let other = test_spend(
+        &key,
+        std::slice::from_ref(&coin),
+        &[subsidy - 20_000],
+        0xffffffff,
+    );
+    invalid(
+        test_coinbase(101, subsidy, &payee),
+        vec![spend.clone(), other],
+    );
+    // Spending more than the coin
+    invalid(
+        test_coinbase(101, subsidy, &payee),
+        vec![test_spend(
+            &key,
+            std::slice::from_ref(&coin),
+            &[subsidy + 1],
+            0xffffffff,
+        )],
+    );
+    // Absolute and relative locktimes
+    let mut locked = spend.clone();
+    locked.locktime = 101;
+    locked.tx_ins[0].sequence = 0xfffffffe;
+    invalid(test_coinbase(101, subsidy, &payee), vec![locked]);
+    let locked = test_spend(&key, std::slice::from_ref(&coin), &[subsidy - 10_000], 101);
+    invalid(test_coinbase(101, subsidy, &payee), vec![locked]);
+    let time_locked = 1 << 22 | 200;
+    let locked = test_spend(
+        &key,
+        std::slice::from_ref(&coin),
+        &[subsidy - 10_000],
+        time_locked,
+    );
+    invalid(test_coinbase(101, subsidy, &payee), vec![locked]);
+    // Too many sigops
+    let mut coinbase = test_coinbase(101, subsidy, &payee);
+    let checksigs = Script::from_bytes(vec![crate::op::OP_CHECKSIG; 20_001]);
+    coinbase.tx_outs.push(TxOut::new(0, checksigs));
+    invalid(coinbase, vec![]);
+
+    let unlocked = test_spend(&key, std::slice::from_ref(&coin), &[subsidy - 10_000], 100);
+    let block = test_mine(
+        &chain.tip().header,
+        test_coinbase(101, subsidy + 10_000, &payee),
+        vec![unlocked.clone()],
+    );
+    assert_eq!(test_submit(&mut chain, &mut utxos, &block).unwrap(), 10_000);
+
+    // A chain of spends within one block
+    let coin = (OutPoint::new(unlocked.hash(), 0), subsidy - 10_000);
+    let first = test_spend(&key, std::slice::from_ref(&coin), &[subsidy - 11_000], 0);
+    let coin = (OutPoint::new(first.hash(), 0), subsidy - 11_000);
+    let second = test_spend(&key, std::slice::from_ref(&coin), &[subsidy - 13_000], 0);
+    let block = test_mine(
+        &chain.tip().header,
+        test_coinbase(102, subsidy + 3_000, &payee),
+        vec![first.clone(), second.clone()],
+    );
+    assert_eq!(test_submit(&mut chain, &mut utxos, &block).unwrap(), 3_000);
+    let block = test_mine(
+        &chain.tip().header,
+        test_coinbase(103, subsidy, &payee),
+        vec![second],
+    );
+    assert!(test_submit(&mut chain, &mut utxos, &block).is_err());
+}
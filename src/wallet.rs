@@ -0,0 +1,819 @@
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::amount::Amount;
+use crate::bip32::ExtendedPubKey;
+use crate::block::Block;
+use crate::descriptor::Descriptor;
+use crate::helper::{encode_hex, encode_variant, read_variant};
+use crate::network::Network;
+use crate::transaction::{Tx, TxOut};
+use crate::utxo::OutPoint;
+use crate::validation::COINBASE_MATURITY;
+
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+// Heights of unconfirmed outputs in the wallet file
+const UNCONFIRMED: u32 = u32::MAX;
+
+// Blocks this deep are taken as final: their hashes and the coins spent in
+// them are forgotten, so a reorg can't reach further back
+const REORG_DEPTH: u32 = 100;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Keychain {
+    Receive,
+    Change,
+}
+
+impl Keychain {
+    fn index(self) -> usize {
+        match self {
+            Keychain::Receive => 0,
+            Keychain::Change => 1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WalletUtxo {
+    pub outpoint: OutPoint,
+    pub tx_out: TxOut,
+    // None until the transaction is in a block
+    pub height: Option<u32>,
+    pub is_coinbase: bool,
+    pub keychain: Keychain,
+    pub index: u32,
+}
+
+// A coin of ours and the transaction that spent it
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct SpentUtxo {
+    utxo: WalletUtxo,
+    txid: Vec<u8>,
+    height: Option<u32>,
+}
+
+// Watches the scripts of a receive and an optional change descriptor,
+// deriving `gap_limit` unused ones past the last used index of each
+pub struct Wallet {
+    pub network: Network,
+    pub gap_limit: u32,
+    receive: Descriptor,
+    change: Option<Descriptor>,
+    // Raw scriptPubKey to the keychain and index it was derived at
+    scripts: HashMap<Vec<u8>, (Keychain, u32)>,
+    // How many scripts have been derived and how many are used
+    derived: [u32; 2],
+    used: [u32; 2],
+    utxos: HashMap<OutPoint, WalletUtxo>,
+    spent: HashMap<OutPoint, SpentUtxo>,
+    // Hashes of the scanned blocks that could still be reorganized away
+    block_hashes: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Wallet {
+    // Private keys in the descriptors are dropped
+    pub fn new(
+        receive: &Descriptor,
+        change: Option<&Descriptor>,
+        network: Network,
+        gap_limit: u32,
+    ) -> Result<Self> {
+        if gap_limit == 0 {
+            return Err(anyhow!("Gap limit must be at least 1"));
+        }
+        let mut wallet = Self {
+            network,
+            gap_limit,
+            receive: receive.to_public()?,
+            change: change.map(|change| change.to_public()).transpose()?,
+            scripts: HashMap::new(),
+            derived: [0, 0],
+            used: [0, 0],
+            utxos: HashMap::new(),
+            spent: HashMap::new(),
+            block_hashes: BTreeMap::new(),
+        };
+        wallet.fill_gap()?;
+        Ok(wallet)
+    }
+
+    // BIP84 style: native segwit receive and change chains under the xpub
+    pub fn from_xpub(xpub: &ExtendedPubKey, network: Network, gap_limit: u32) -> Result<Self> {
+        let receive = Descriptor::parse(&format!("wpkh({}/0/*)", xpub))?;
+        let change = Descriptor::parse(&format!("wpkh({}/1/*)", xpub))?;
+        Self::new(&receive, Some(&change), network, gap_limit)
+    }
+
+    fn descriptor(&self, keychain: Keychain) -> &Descriptor {
+        match (keychain, &self.change) {
+            (Keychain::Change, Some(change)) => change,
+            _ => &self.receive,
+        }
+    }
+
+    fn keychains(&self) -> Vec<Keychain> {
+        match self.change {
+            Some(_) => vec![Keychain::Receive, Keychain::Change],
+            None => vec![Keychain::Receive],
+        }
+    }
+
+    // A descriptor without a wildcard only has index 0
+    fn max_derived(&self, keychain: Keychain) -> u32 {
+        if self.descriptor(keychain).is_ranged() {
+            self.used[keychain.index()] + self.gap_limit
+        } else {
+            1
+        }
+    }
+
+    fn fill_gap(&mut self) -> Result<()> {
+        for keychain in self.keychains() {
+            let k = keychain.index();
+            while self.derived[k] < self.max_derived(keychain) {
+                let index = self.derived[k];
+                let script = self.descriptor(keychain).script_pubkey(index)?;
+                self.scripts
+                    .insert(script.raw_serialize(), (keychain, index));
+                self.derived[k] += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn address(&self, keychain: Keychain, index: u32) -> Result<String> {
+        self.descriptor(keychain).address(index, self.network)
+    }
+
+    // The first address that hasn't received anything. It stays the same
+    // until a payment to it is seen.
+    pub fn next_address(&self, keychain: Keychain) -> Result<String> {
+        let index = if self.descriptor(keychain).is_ranged() {
+            self.used[keychain.index()]
+        } else {
+            0
+        };
+        self.address(keychain, index)
+    }
+
+    // Number of scripts watched, for each keychain
+    pub fn derived(&self, keychain: Keychain) -> u32 {
+        self.derived[keychain.index()]
+    }
+
+    pub fn tip_height(&self) -> u32 {
+        self.block_hashes.keys().next_back().copied().unwrap_or(0)
+    }
+
+    pub fn block_hash(&self, height: u32) -> Option<&[u8]> {
+        self.block_hashes.get(&height).map(|hash| hash.as_slice())
+    }
+
+    pub fn is_mine(&self, tx_out: &TxOut) -> bool {
+        self.scripts
+            .contains_key(&tx_out.script_pubkey.raw_serialize())
+    }
+
+    // Spends our outputs and adds new ones. Returns whether the transaction
+    // touched the wallet. A confirmed transaction evicts unconfirmed ones
+    // double spending it.
+    pub fn scan_tx(&mut self, tx: &Tx, height: Option<u32>) -> Result<bool> {
+        let mut relevant = false;
+        let txid = tx.hash();
+        if !tx.is_coinbase() {
+            for tx_in in &tx.tx_ins {
+                let outpoint = OutPoint::from_tx_in(tx_in);
+                let conflict = match self.spent.get(&outpoint) {
+                    Some(spent)
+                        if spent.txid != txid && spent.height.is_none() && height.is_some() =>
+                    {
+                        Some(spent.txid.clone())
+                    }
+                    _ => None,
+                };
+                if let Some(conflict) = conflict {
+                    self.remove_txs(&self.descendants(&conflict));
+                }
+                if let Some(utxo) = self.utxos.remove(&outpoint) {
+                    let spent = SpentUtxo {
+                        utxo,
+                        txid: txid.clone(),
+                        height,
+                    };
+                    self.spent.insert(outpoint, spent);
+                    relevant = true;
+                } else if let Some(spent) = self.spent.get_mut(&outpoint) {
+                    if spent.txid == txid {
+                        spent.height = height;
+                        relevant = true;
+                    }
+                }
+            }
+        }
+        for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+            let (keychain, index) = match self.scripts.get(&tx_out.script_pubkey.raw_serialize()) {
+                Some(found) => *found,
+                None => continue,
+            };
+            relevant = true;
+            let used = &mut self.used[keychain.index()];
+            *used = (*used).max(index + 1);
+            let outpoint = OutPoint::new(txid.clone(), vout as u32);
+            let utxo = WalletUtxo {
+                outpoint: outpoint.clone(),
+                tx_out: tx_out.clone(),
+                height,
+                is_coinbase: tx.is_coinbase(),
+                keychain,
+                index,
+            };
+            // An output may already have been spent by a child seen first
+            match self.spent.get_mut(&outpoint) {
+                Some(spent) => spent.utxo = utxo,
+                None => {
+                    self.utxos.insert(outpoint, utxo);
+                }
+            }
+            // Later outputs may pay the newly derived scripts
+            self.fill_gap()?;
+        }
+        Ok(relevant)
+    }
+
+    // A block replacing one already scanned has to wait until that one is
+    // disconnected
+    pub fn scan_block(&mut self, block: &Block, height: u32) -> Result<()> {
+        let hash = block.header.hash();
+        if self
+            .block_hash(height)
+            .is_some_and(|scanned| scanned != hash.as_slice())
+        {
+            return Err(anyhow!(
+                "Block {} conflicts with the wallet's chain at height {}",
+                encode_hex(&hash),
+                height
+            ));
+        }
+        for tx in &block.txs {
+            self.scan_tx(tx, Some(height))?;
+        }
+        self.block_hashes.insert(height, hash);
+        let final_height = self.tip_height().saturating_sub(REORG_DEPTH);
+        self.block_hashes = self.block_hashes.split_off(&(final_height + 1));
+        self.spent
+            .retain(|_, spent| spent.height.is_none_or(|height| height > final_height));
+        Ok(())
+    }
+
+    // Undoes the wallet's tip block: its transactions go back to being
+    // unconfirmed, and its coinbase disappears along with anything spending it
+    pub fn disconnect_block(&mut self, block: &Block, height: u32) -> Result<()> {
+        let hash = block.header.hash();
+        if height != self.tip_height() || self.block_hash(height) != Some(hash.as_slice()) {
+            return Err(anyhow!(
+                "Block {} isn't the wallet's tip",
+                encode_hex(&hash)
+            ));
+        }
+        let confirmed = Some(height);
+        for utxo in self.utxos.values_mut() {
+            if utxo.height == confirmed {
+                utxo.height = None;
+            }
+        }
+        for spent in self.spent.values_mut() {
+            if spent.utxo.height == confirmed {
+                spent.utxo.height = None;
+            }
+            if spent.height == confirmed {
+                spent.height = None;
+            }
+        }
+        for tx in block.txs.iter().filter(|tx| tx.is_coinbase()) {
+            self.remove_txs(&self.descendants(&tx.hash()));
+        }
+        self.block_hashes.remove(&height);
+        Ok(())
+    }
+
+    // Forgets an unconfirmed transaction that will never confirm, and the
+    // ones spending its outputs. The coins it spent are ours again.
+    pub fn abandon_tx(&mut self, txid: &[u8]) -> Result<()> {
+        let txids = self.descendants(txid);
+        let outputs = self
+            .utxos
+            .values()
+            .chain(self.spent.values().map(|spent| &spent.utxo))
+            .filter(|utxo| txids.contains(&utxo.outpoint.txid))
+            .map(|utxo| utxo.height);
+        let spends = self
+            .spent
+            .values()
+            .filter(|spent| txids.contains(&spent.txid))
+            .map(|spent| spent.height);
+        let heights: Vec<Option<u32>> = outputs.chain(spends).collect();
+        if heights.is_empty() {
+            return Err(anyhow!("Unknown transaction {}", encode_hex(txid)));
+        }
+        if heights.iter().any(|height| height.is_some()) {
+            return Err(anyhow!("Transaction {} is confirmed", encode_hex(txid)));
+        }
+        self.remove_txs(&txids);
+        Ok(())
+    }
+
+    // The transaction and everything spending its outputs, recursively
+    fn descendants(&self, txid: &[u8]) -> Vec<Vec<u8>> {
+        let mut txids = vec![txid.to_vec()];
+        let mut i = 0;
+        while i < txids.len() {
+            for (outpoint, spent) in &self.spent {
+                if outpoint.txid == txids[i] && !txids.contains(&spent.txid) {
+                    txids.push(spent.txid.clone());
+                }
+            }
+            i += 1;
+        }
+        txids
+    }
+
+    fn remove_txs(&mut self, txids: &[Vec<u8>]) {
+        self.utxos
+            .retain(|outpoint, _| !txids.contains(&outpoint.txid));
+        self.spent
+            .retain(|outpoint, _| !txids.contains(&outpoint.txid));
+        let restored: Vec<OutPoint> = self
+            .spent
+            .iter()
+            .filter(|(_, spent)| txids.contains(&spent.txid))
+            .map(|(outpoint, _)| outpoint.clone())
+            .collect();
+        for outpoint in restored {
+            let spent = self.spent.remove(&outpoint).unwrap();
+            self.utxos.insert(outpoint, spent.utxo);
+        }
+    }
+
+    pub fn utxos(&self) -> Vec<&WalletUtxo> {
+        let mut result: Vec<&WalletUtxo> = self.utxos.values().collect();
+        result.sort_by_key(|utxo| (utxo.height, utxo.outpoint.clone()));
+        result
+    }
+
+    pub fn confirmations(&self, utxo: &WalletUtxo) -> u32 {
+        let tip_height = self.tip_height();
+        match utxo.height {
+            Some(height) if height <= tip_height => tip_height - height + 1,
+            _ => 0,
+        }
+    }
+
+    // Everything, unconfirmed and immature coins included
+    pub fn balance(&self) -> Amount {
+        self.utxos
+            .values()
+            .map(|utxo| Amount::from_sat(utxo.tx_out.amount))
+            .sum()
+    }
+
+    // Coins with at least `min_conf` confirmations, coinbases only once
+    // they can be spent
+    pub fn confirmed_balance(&self, min_conf: u32) -> Amount {
+        self.utxos
+            .values()
+            .filter(|utxo| {
+                let confirmations = self.confirmations(utxo);
+                confirmations >= min_conf.max(1)
+                    && (!utxo.is_coinbase || confirmations >= COINBASE_MATURITY)
+            })
+            .map(|utxo| Amount::from_sat(utxo.tx_out.amount))
+            .sum()
+    }
+
+    pub fn save<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let write_str = |writer: &mut W, s: &str| -> Result<()> {
+            writer.write_all(&encode_variant(s.len() as u64))?;
+            writer.write_all(s.as_bytes())?;
+            Ok(())
+        };
+        writer.write_all(&self.network.magic())?;
+        write_str(writer, &self.receive.to_string_with_checksum())?;
+        let change = self
+            .change
+            .as_ref()
+            .map(|change| change.to_string_with_checksum());
+        write_str(writer, change.as_deref().unwrap_or(""))?;
+        for n in [self.gap_limit, self.used[0], self.used[1]].iter() {
+            writer.write_all(&n.to_le_bytes())?;
+        }
+        writer.write_all(&encode_variant(self.utxos.len() as u64))?;
+        for utxo in self.utxos() {
+            write_utxo(writer, utxo)?;
+        }
+        let mut spent: Vec<&SpentUtxo> = self.spent.values().collect();
+        spent.sort_by_key(|spent| spent.utxo.outpoint.clone());
+        writer.write_all(&encode_variant(spent.len() as u64))?;
+        for spent in spent {
+            write_utxo(writer, &spent.utxo)?;
+            writer.write_all(&spent.txid)?;
+            writer.write_all(&spent.height.unwrap_or(UNCONFIRMED).to_le_bytes())?;
+        }
+        writer.write_all(&encode_variant(self.block_hashes.len() as u64))?;
+        for (height, hash) in &self.block_hashes {
+            writer.write_all(&height.to_le_bytes())?;
+            writer.write_all(hash)?;
+        }
+        Ok(())
+    }
+
+    pub fn load<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let read_str = |reader: &mut R| -> Result<String> {
+            let len = read_variant(reader)?;
+            let mut buf = vec![];
+            reader.take(len).read_to_end(&mut buf)?;
+            if buf.len() as u64 != len {
+                return Err(anyhow!("Wallet file is truncated"));
+            }
+            Ok(String::from_utf8(buf)?)
+        };
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let network = Network::from_magic(magic)?;
+        let receive = Descriptor::parse(&read_str(reader)?)?;
+        let change = match read_str(reader)?.as_str() {
+            "" => None,
+            s => Some(Descriptor::parse(s)?),
+        };
+        let gap_limit = read_u32(reader)?;
+        let mut wallet = Self::new(&receive, change.as_ref(), network, gap_limit)?;
+        wallet.used = [read_u32(reader)?, read_u32(reader)?];
+        wallet.fill_gap()?;
+        let count = read_variant(reader)?;
+        for _ in 0..count {
+            let utxo = read_utxo(reader)?;
+            wallet.utxos.insert(utxo.outpoint.clone(), utxo);
+        }
+        let count = read_variant(reader)?;
+        for _ in 0..count {
+            let utxo = read_utxo(reader)?;
+            let mut txid = vec![0u8; 32];
+            reader.read_exact(&mut txid)?;
+            let spent = SpentUtxo {
+                utxo,
+                txid,
+                height: read_height(reader)?,
+            };
+            wallet.spent.insert(spent.utxo.outpoint.clone(), spent);
+        }
+        let count = read_variant(reader)?;
+        for _ in 0..count {
+            let height = read_u32(reader)?;
+            let mut hash = vec![0u8; 32];
+            reader.read_exact(&mut hash)?;
+            wallet.block_hashes.insert(height, hash);
+        }
+        Ok(wallet)
+    }
+
+    // Writes a temporary file first so a crash never leaves half a wallet
+    pub fn save_file<P>(&self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        self.save(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load_file<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::load(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+fn read_u32<R>(reader: &mut R) -> Result<u32>
+where
+    R: Read,
+{
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_height<R>(reader: &mut R) -> Result<Option<u32>>
+where
+    R: Read,
+{
+    Ok(match read_u32(reader)? {
+        UNCONFIRMED => None,
+        height => Some(height),
+    })
+}
+
+fn write_utxo<W>(writer: &mut W, utxo: &WalletUtxo) -> Result<()>
+where
+    W: Write,
+{
+    writer.write_all(&utxo.outpoint.serialize())?;
+    writer.write_all(&utxo.tx_out.serialize())?;
+    writer.write_all(&utxo.height.unwrap_or(UNCONFIRMED).to_le_bytes())?;
+    let flags = utxo.is_coinbase as u8 | ((utxo.keychain == Keychain::Change) as u8) << 1;
+    writer.write_all(&[flags])?;
+    writer.write_all(&utxo.index.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_utxo<R>(reader: &mut R) -> Result<WalletUtxo>
+where
+    R: Read,
+{
+    let outpoint = OutPoint::parse(reader)?;
+    let tx_out = TxOut::parse(reader)?;
+    let height = read_height(reader)?;
+    let mut flags = [0u8; 1];
+    reader.read_exact(&mut flags)?;
+    let keychain = if flags[0] & 2 != 0 {
+        Keychain::Change
+    } else {
+        Keychain::Receive
+    };
+    Ok(WalletUtxo {
+        outpoint,
+        tx_out,
+        height,
+        is_coinbase: flags[0] & 1 != 0,
+        keychain,
+        index: read_u32(reader)?,
+    })
+}
+
+#[cfg(test)]
+use crate::bip32::ExtendedPrivKey;
+#[cfg(test)]
+use crate::block::BlockHeader;
+#[cfg(test)]
+use crate::miner::coinbase_tx;
+#[cfg(test)]
+use crate::script::Script;
+#[cfg(test)]
+use crate::transaction::TxIn;
+#[cfg(test)]
+use std::io::Cursor;
+
+#[cfg(test)]
+fn test_wallet(gap_limit: u32) -> Wallet {
+    let master = ExtendedPrivKey::new_master(&[7u8; 32], Network::Regtest).unwrap();
+    Wallet::from_xpub(&master.to_pub(), Network::Regtest, gap_limit).unwrap()
+}
+
+#[cfg(test)]
+fn test_pay(wallet: &Wallet, spends: &[&OutPoint], payees: &[(Keychain, u32, u64)]) -> Tx {
+    // Funding transactions spend some coin that isn't ours
+    let funding = OutPoint::new(vec![0xaa; 32], 0);
+    let spends = if spends.is_empty() {
+        vec![&funding]
+    } else {
+        spends.to_vec()
+    };
+    let tx_ins = spends
+        .iter()
+        .map(|outpoint| {
+            let mut prev_tx = [0u8; 32];
+            prev_tx.copy_from_slice(&outpoint.serialize()[..32]);
+            TxIn::new(prev_tx, outpoint.vout, None, 0xffffffff)
+        })
+        .collect();
+    let tx_outs = payees
+        .iter()
+        .map(|(keychain, index, amount)| {
+            let script = wallet.descriptor(*keychain).script_pubkey(*index).unwrap();
+            TxOut::new(*amount, script)
+        })
+        .collect();
+    // Round trip through the wire format as a tx from the network would be
+    let tx = Tx::new(2, tx_ins, tx_outs, 0, Network::Regtest);
+    Tx::parse(&mut Cursor::new(tx.serialize()), Network::Regtest).unwrap()
+}
+
+#[test]
+fn test_gap_limit() {
+    let mut wallet = test_wallet(3);
+    assert_eq!(wallet.derived(Keychain::Receive), 3);
+    assert_eq!(wallet.derived(Keychain::Change), 3);
+    assert_eq!(
+        wallet.next_address(Keychain::Receive).unwrap(),
+        wallet.address(Keychain::Receive, 0).unwrap()
+    );
+    assert!(wallet
+        .next_address(Keychain::Receive)
+        .unwrap()
+        .starts_with("bcrt1q"));
+
+    // Index 4 is past the gap until index 2 has been used
+    let far = test_pay(&wallet, &[], &[(Keychain::Receive, 4, 1000)]);
+    assert!(!wallet.scan_tx(&far, None).unwrap());
+    let near = test_pay(&wallet, &[], &[(Keychain::Receive, 2, 2000)]);
+    assert!(wallet.scan_tx(&near, None).unwrap());
+    assert_eq!(wallet.derived(Keychain::Receive), 6);
+    assert!(wallet.scan_tx(&far, None).unwrap());
+    assert_eq!(wallet.derived(Keychain::Receive), 8);
+    assert_eq!(wallet.derived(Keychain::Change), 3);
+    assert_eq!(
+        wallet.next_address(Keychain::Receive).unwrap(),
+        wallet.address(Keychain::Receive, 5).unwrap()
+    );
+
+    // Outputs in one transaction extend the gap for the ones after them
+    let chained = test_pay(
+        &wallet,
+        &[],
+        &[(Keychain::Change, 2, 3000), (Keychain::Change, 4, 4000)],
+    );
+    assert!(wallet.scan_tx(&chained, None).unwrap());
+    assert_eq!(wallet.utxos().len(), 4);
+    assert_eq!(wallet.balance(), Amount::from_sat(10_000));
+}
+
+#[test]
+fn test_scan_blocks() {
+    let mut wallet = test_wallet(DEFAULT_GAP_LIMIT);
+    let script = wallet
+        .descriptor(Keychain::Receive)
+        .script_pubkey(0)
+        .unwrap();
+    let header = BlockHeader::new(0x20000000, [0u8; 32], [0u8; 32], 0, 0x207fffff, 0);
+    let coinbase = coinbase_tx(1, 0, 50_0000_0000, &script, Network::Regtest);
+    let funding = test_pay(&wallet, &[], &[(Keychain::Receive, 1, 30_000)]);
+    wallet
+        .scan_block(
+            &Block::new(header.clone(), vec![coinbase, funding.clone()]),
+            1,
+        )
+        .unwrap();
+    assert_eq!(wallet.tip_height(), 1);
+    assert_eq!(wallet.balance(), Amount::from_sat(50_0003_0000));
+    assert_eq!(wallet.confirmed_balance(1), Amount::from_sat(30_000));
+    assert_eq!(wallet.confirmed_balance(2), Amount::ZERO);
+
+    // Spend the funding output to ourselves and someone else
+    let outpoint = OutPoint::new(funding.hash(), 0);
+    let spend = test_pay(&wallet, &[&outpoint], &[(Keychain::Change, 0, 20_000)]);
+    let mut spend_with_payment = spend.clone();
+    spend_with_payment
+        .tx_outs
+        .push(TxOut::new(9_000, Script::p2wpkh(&[1u8; 20])));
+    assert!(wallet.scan_tx(&spend_with_payment, None).unwrap());
+    let change = wallet
+        .utxos()
+        .into_iter()
+        .find(|utxo| utxo.keychain == Keychain::Change)
+        .unwrap()
+        .clone();
+    assert_eq!(change.height, None);
+    assert_eq!(wallet.confirmations(&change), 0);
+    assert_eq!(wallet.confirmed_balance(1), Amount::ZERO);
+
+    // The spend confirms, then the coinbase matures
+    let coinbase = coinbase_tx(
+        2,
+        0,
+        50_0000_0000,
+        &Script::p2wpkh(&[1u8; 20]),
+        Network::Regtest,
+    );
+    wallet
+        .scan_block(
+            &Block::new(header.clone(), vec![coinbase, spend_with_payment]),
+            2,
+        )
+        .unwrap();
+    let change = wallet.utxos.get(&change.outpoint).unwrap().clone();
+    assert_eq!(change.height, Some(2));
+    assert_eq!(wallet.confirmations(&change), 1);
+    assert_eq!(wallet.confirmed_balance(1), Amount::from_sat(20_000));
+    wallet.scan_block(&Block::new(header, vec![]), 100).unwrap();
+    assert_eq!(wallet.confirmations(&change), 99);
+    assert_eq!(wallet.confirmed_balance(1), Amount::from_sat(50_0002_0000));
+}
+
+#[test]
+fn test_save_load() {
+    let mut wallet = test_wallet(5);
+    let tx = test_pay(
+        &wallet,
+        &[],
+        &[(Keychain::Receive, 3, 1000), (Keychain::Change, 1, 2000)],
+    );
+    wallet.scan_tx(&tx, Some(7)).unwrap();
+    let unconfirmed = test_pay(&wallet, &[], &[(Keychain::Receive, 7, 3000)]);
+    wallet.scan_tx(&unconfirmed, None).unwrap();
+    let outpoint = OutPoint::new(tx.hash(), 0);
+    let spend = test_pay(&wallet, &[&outpoint], &[(Keychain::Change, 0, 500)]);
+    wallet.scan_tx(&spend, None).unwrap();
+    let header = BlockHeader::new(0x20000000, [0u8; 32], [0u8; 32], 0, 0x207fffff, 0);
+    let block = Block::new(header, vec![]);
+    wallet.scan_block(&block, 8).unwrap();
+
+    let path = std::env::temp_dir().join(format!("wallet-{}.dat", std::process::id()));
+    wallet.save_file(&path).unwrap();
+    let mut loaded = Wallet::load_file(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.network, Network::Regtest);
+    assert_eq!(loaded.gap_limit, 5);
+    assert_eq!(loaded.utxos(), wallet.utxos());
+    assert_eq!(loaded.balance(), Amount::from_sat(5500));
+    assert_eq!(loaded.tip_height(), 8);
+    assert_eq!(loaded.block_hash(8), wallet.block_hash(8));
+    assert_eq!(loaded.derived(Keychain::Receive), 13);
+    assert_eq!(loaded.derived(Keychain::Change), 7);
+    assert_eq!(
+        loaded.next_address(Keychain::Change).unwrap(),
+        wallet.next_address(Keychain::Change).unwrap()
+    );
+    // The spent coin is remembered, so the spend can still be abandoned
+    loaded.abandon_tx(&spend.hash()).unwrap();
+    assert_eq!(loaded.balance(), Amount::from_sat(6000));
+
+    // Only public keys are ever written
+    let mut buf = vec![];
+    wallet.save(&mut buf).unwrap();
+    assert!(!String::from_utf8_lossy(&buf).contains("tprv"));
+    assert!(Wallet::load(&mut Cursor::new(&buf[..buf.len() - 1])).is_err());
+}
+
+#[test]
+fn test_reorg() {
+    let mut wallet = test_wallet(DEFAULT_GAP_LIMIT);
+    let script = wallet
+        .descriptor(Keychain::Receive)
+        .script_pubkey(0)
+        .unwrap();
+    let header = |nonce| BlockHeader::new(0x20000000, [0u8; 32], [0u8; 32], 0, 0x207fffff, nonce);
+    let coinbase = coinbase_tx(1, 0, 50_0000_0000, &script, Network::Regtest);
+    let funding = test_pay(&wallet, &[], &[(Keychain::Receive, 1, 30_000)]);
+    let first = Block::new(header(1), vec![coinbase, funding.clone()]);
+    wallet.scan_block(&first, 1).unwrap();
+    let outpoint = OutPoint::new(funding.hash(), 0);
+    let spend = test_pay(&wallet, &[&outpoint], &[(Keychain::Change, 0, 20_000)]);
+    let coinbase = coinbase_tx(2, 0, 0, &Script::p2wpkh(&[1u8; 20]), Network::Regtest);
+    let second = Block::new(header(2), vec![coinbase, spend.clone()]);
+    wallet.scan_block(&second, 2).unwrap();
+    assert_eq!(wallet.block_hash(2), Some(second.header.hash().as_slice()));
+    assert_eq!(wallet.balance(), Amount::from_sat(50_0002_0000));
+
+    // Another block at a scanned height has to wait for a disconnect
+    let other = Block::new(header(3), vec![]);
+    assert!(wallet.scan_block(&other, 2).is_err());
+    assert!(wallet.disconnect_block(&first, 1).is_err());
+    assert!(wallet.disconnect_block(&other, 2).is_err());
+
+    // The spend goes back to unconfirmed, and can then be abandoned
+    wallet.disconnect_block(&second, 2).unwrap();
+    assert_eq!(wallet.tip_height(), 1);
+    assert_eq!(wallet.balance(), Amount::from_sat(50_0002_0000));
+    assert_eq!(wallet.confirmed_balance(1), Amount::ZERO);
+    assert!(wallet.abandon_tx(&funding.hash()).is_err());
+    wallet.abandon_tx(&spend.hash()).unwrap();
+    assert_eq!(wallet.balance(), Amount::from_sat(50_0003_0000));
+    assert_eq!(wallet.confirmed_balance(1), Amount::from_sat(30_000));
+    assert!(wallet.abandon_tx(&spend.hash()).is_err());
+
+    // Once unconfirmed the funding can go too, taking its spends with it,
+    // while the coinbase is gone with its block
+    wallet.scan_tx(&spend, None).unwrap();
+    wallet.disconnect_block(&first, 1).unwrap();
+    assert_eq!(wallet.tip_height(), 0);
+    assert_eq!(wallet.balance(), Amount::from_sat(20_000));
+    wallet.abandon_tx(&funding.hash()).unwrap();
+    assert!(wallet.utxos().is_empty());
+
+    // A confirmed spend evicts an unconfirmed one of the same coin
+    wallet.scan_block(&first, 1).unwrap();
+    wallet.scan_tx(&spend, None).unwrap();
+    let double = test_pay(&wallet, &[&outpoint], &[(Keychain::Change, 1, 25_000)]);
+    let third = Block::new(header(4), vec![double.clone()]);
+    wallet.scan_block(&third, 2).unwrap();
+    let utxos = wallet.utxos();
+    assert_eq!(utxos.len(), 2);
+    assert_eq!(utxos[1].outpoint, OutPoint::new(double.hash(), 0));
+    assert_eq!(wallet.balance(), Amount::from_sat(50_0002_5000));
+}